@@ -20,7 +20,7 @@ use std::sync::Arc;
 use arrow_array::types::Float32Type;
 use arrow_array::UInt8Array;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use lance_index::vector::pq::{ProductQuantizer, ProductQuantizerImpl};
+use lance_index::vector::pq::{ProductQuantizer, ProductQuantizerImpl, SUPPORTED_TILE_SIZES};
 use lance_linalg::distance::MetricType;
 use lance_testing::datagen::generate_random_array_with_seed;
 use rand::{prelude::StdRng, Rng, SeedableRng};
@@ -43,7 +43,8 @@ fn dist_table(c: &mut Criterion) {
     let code = UInt8Array::from_iter_values(repeat(rnd.gen::<u8>()).take(TOTAL * PQ));
 
     let l2_pq =
-        ProductQuantizerImpl::<Float32Type>::new(PQ, 8, DIM, codebook.clone(), MetricType::L2);
+        ProductQuantizerImpl::<Float32Type>::new(PQ, 8, DIM, codebook.clone(), MetricType::L2)
+            .unwrap();
 
     c.bench_function(
         format!("{},L2,PQ={},DIM={}", TOTAL, PQ, DIM).as_str(),
@@ -55,7 +56,8 @@ fn dist_table(c: &mut Criterion) {
     );
 
     let cosine_pq =
-        ProductQuantizerImpl::<Float32Type>::new(PQ, 8, DIM, codebook.clone(), MetricType::Cosine);
+        ProductQuantizerImpl::<Float32Type>::new(PQ, 8, DIM, codebook.clone(), MetricType::Cosine)
+            .unwrap();
 
     c.bench_function(
         format!("{},Cosine,PQ={},DIM={}", TOTAL, PQ, DIM).as_str(),
@@ -67,17 +69,94 @@ fn dist_table(c: &mut Criterion) {
     );
 }
 
+fn dist_table_tile_size(c: &mut Criterion) {
+    let codebook = Arc::new(generate_random_array_with_seed::<Float32Type>(
+        256 * DIM,
+        [88; 32],
+    ));
+    let query = generate_random_array_with_seed::<Float32Type>(DIM, [32; 32]);
+
+    let mut rnd = StdRng::from_seed([32; 32]);
+    let code = UInt8Array::from_iter_values(repeat(rnd.gen::<u8>()).take(TOTAL * PQ));
+
+    for tile_size in SUPPORTED_TILE_SIZES {
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(PQ, 8, DIM, codebook.clone(), MetricType::L2)
+                .unwrap()
+                .with_tile_size(*tile_size)
+                .unwrap();
+        c.bench_function(
+            format!("{},L2,PQ={},DIM={},tile={}", TOTAL, PQ, DIM, tile_size).as_str(),
+            |b| {
+                b.iter(|| {
+                    black_box(pq.compute_distances(&query, &code).unwrap().len());
+                })
+            },
+        );
+    }
+}
+
+fn dist_table_small_sub_vectors(c: &mut Criterion) {
+    const SMALL_PQ: usize = 8;
+    const SMALL_DIM: usize = 128;
+    let codebook = Arc::new(generate_random_array_with_seed::<Float32Type>(
+        256 * SMALL_DIM,
+        [88; 32],
+    ));
+    let query = generate_random_array_with_seed::<Float32Type>(SMALL_DIM, [32; 32]);
+
+    let mut rnd = StdRng::from_seed([32; 32]);
+    let code = UInt8Array::from_iter_values(repeat(rnd.gen::<u8>()).take(TOTAL * SMALL_PQ));
+
+    let pq =
+        ProductQuantizerImpl::<Float32Type>::new(SMALL_PQ, 8, SMALL_DIM, codebook, MetricType::L2)
+            .unwrap();
+    c.bench_function(
+        format!("{},L2,PQ={},DIM={}", TOTAL, SMALL_PQ, SMALL_DIM).as_str(),
+        |b| {
+            b.iter(|| {
+                black_box(pq.compute_distances(&query, &code).unwrap().len());
+            })
+        },
+    );
+}
+
+fn dist_table_half_precision(c: &mut Criterion) {
+    let codebook = Arc::new(generate_random_array_with_seed::<Float32Type>(
+        256 * DIM,
+        [88; 32],
+    ));
+    let query = generate_random_array_with_seed::<Float32Type>(DIM, [32; 32]);
+
+    let mut rnd = StdRng::from_seed([32; 32]);
+    let code = UInt8Array::from_iter_values(repeat(rnd.gen::<u8>()).take(TOTAL * PQ));
+
+    let pq = ProductQuantizerImpl::<Float32Type>::new(PQ, 8, DIM, codebook, MetricType::L2)
+        .unwrap()
+        .with_half_precision_distance_table(true);
+    c.bench_function(
+        format!("{},L2,PQ={},DIM={},f16_table", TOTAL, PQ, DIM).as_str(),
+        |b| {
+            b.iter(|| {
+                black_box(pq.compute_distances(&query, &code).unwrap().len());
+            })
+        },
+    );
+}
+
 #[cfg(target_os = "linux")]
 criterion_group!(
     name=benches;
     config = Criterion::default().significance_level(0.1).sample_size(10)
         .with_profiler(PProfProfiler::new(100, Output::Flamegraph(None)));
-    targets = dist_table);
+    targets = dist_table, dist_table_tile_size, dist_table_small_sub_vectors,
+        dist_table_half_precision);
 
 #[cfg(not(target_os = "linux"))]
 criterion_group!(
     name=benches;
     config = Criterion::default().significance_level(0.1).sample_size(10);
-    targets = dist_table);
+    targets = dist_table, dist_table_tile_size, dist_table_small_sub_vectors,
+        dist_table_half_precision);
 
 criterion_main!(benches);
@@ -28,6 +28,8 @@ fn main() -> Result<()> {
             e => Err(e),
         })
         .unwrap();
+    // The 'nightly' feature is not in Cargo.toml, it is only set here
+    println!("cargo:rustc-check-cfg=cfg(feature, values(\"nightly\"))");
     if rust_toolchain.starts_with("nightly") {
         // enable the 'nightly' feature flag
         println!("cargo:rustc-cfg=feature=\"nightly\"");
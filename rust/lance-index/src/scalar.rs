@@ -17,13 +17,13 @@
 use std::collections::HashMap;
 use std::{any::Any, ops::Bound, sync::Arc};
 
-use arrow_array::{RecordBatch, UInt64Array};
-use arrow_schema::Schema;
+use arrow_array::{Array, RecordBatch, UInt64Array};
+use arrow_schema::{DataType, Schema};
 use async_trait::async_trait;
 use datafusion::physical_plan::SendableRecordBatchStream;
 use datafusion_common::{scalar::ScalarValue, Column};
 
-use datafusion_expr::Expr;
+use datafusion_expr::{BinaryExpr, Expr, Like, Operator};
 use lance_core::Result;
 
 use crate::Index;
@@ -31,6 +31,7 @@ use crate::Index;
 pub mod btree;
 pub mod expression;
 pub mod flat;
+pub mod hll;
 pub mod lance_format;
 
 /// Trait for storing an index (or parts of an index) into storage
@@ -51,6 +52,10 @@ pub trait IndexReader: Send + Sync {
     async fn read_record_batch(&self, n: u32) -> Result<RecordBatch>;
     /// Return the number of batches in the file
     async fn num_batches(&self) -> u32;
+    /// Read a range of rows from the file, the range may span several batches
+    async fn read_range(&self, range: std::ops::Range<usize>) -> Result<RecordBatch>;
+    /// Return the number of rows in the file
+    fn num_rows(&self) -> usize;
 }
 
 /// Trait abstracting I/O away from index logic
@@ -88,17 +93,75 @@ pub trait IndexStore: std::fmt::Debug + Send + Sync {
 pub enum ScalarQuery {
     /// Retrieve all row ids where the value is in the given [min, max) range
     Range(Bound<ScalarValue>, Bound<ScalarValue>),
+    /// Retrieve all row ids where the value is in the given [min, max) range or is null
+    ///
+    /// This is `col BETWEEN min AND max OR col IS NULL` as a single query.
+    RangeOrNull(Bound<ScalarValue>, Bound<ScalarValue>),
     /// Retrieve all row ids where the value is in the given set of values
     IsIn(Vec<ScalarValue>),
+    /// Retrieve all row ids where the value is not in the given set of values
+    ///
+    /// This follows SQL semantics: null values never match, and nothing matches if
+    /// the set contains a null literal.
+    NotIn(Vec<ScalarValue>),
     /// Retrieve all row ids where the value is exactly the given value
     Equals(ScalarValue),
+    /// Retrieve all row ids where the value is not equal to the given value
+    ///
+    /// This follows SQL semantics: null values never match, and nothing matches a null
+    /// literal.
+    NotEquals(ScalarValue),
     /// Retrieve all row ids where the value is null
     IsNull(),
+    /// Retrieve all row ids where the value is not distinct from the given value
+    ///
+    /// This is a null-safe equality: a null value matches only a null literal and
+    /// a non-null literal never matches null values.
+    IsNotDistinctFrom(ScalarValue),
+    /// Retrieve all row ids where the value matches the given regular expression
+    ///
+    /// Only string values can be matched, null values never match.
+    Regex(String),
+    /// Retrieve all row ids where the value starts with the given prefix
+    ///
+    /// Only string values can be matched, null values never match.  This is the same as
+    /// `LIKE 'prefix%'`, the empty prefix matches all non-null values.
+    StartsWith(String),
 }
 
 impl ScalarQuery {
+    /// Values in `[lower, upper]`
+    pub fn between_inclusive(lower: ScalarValue, upper: ScalarValue) -> Self {
+        Self::Range(Bound::Included(lower), Bound::Included(upper))
+    }
+
+    /// Values in `(lower, upper)`
+    pub fn between_exclusive(lower: ScalarValue, upper: ScalarValue) -> Self {
+        Self::Range(Bound::Excluded(lower), Bound::Excluded(upper))
+    }
+
+    /// Values `>= value`
+    pub fn at_least(value: ScalarValue) -> Self {
+        Self::Range(Bound::Included(value), Bound::Unbounded)
+    }
+
+    /// Values `<= value`
+    pub fn at_most(value: ScalarValue) -> Self {
+        Self::Range(Bound::Unbounded, Bound::Included(value))
+    }
+
+    /// Values `> value`
+    pub fn greater_than(value: ScalarValue) -> Self {
+        Self::Range(Bound::Excluded(value), Bound::Unbounded)
+    }
+
+    /// Values `< value`
+    pub fn less_than(value: ScalarValue) -> Self {
+        Self::Range(Bound::Unbounded, Bound::Excluded(value))
+    }
+
     pub fn to_expr(&self, col: String) -> Expr {
-        let col_expr = Expr::Column(Column::new_unqualified(col));
+        let col_expr = Expr::Column(Column::new_unqualified(col.clone()));
         match self {
             Self::Range(lower, upper) => match (lower, upper) {
                 (Bound::Unbounded, Bound::Unbounded) => {
@@ -128,6 +191,9 @@ impl ScalarQuery {
                     .gt(Expr::Literal(lhs.clone()))
                     .and(col_expr.lt(Expr::Literal(rhs.clone()))),
             },
+            Self::RangeOrNull(lower, upper) => Self::Range(lower.clone(), upper.clone())
+                .to_expr(col)
+                .or(col_expr.is_null()),
             Self::IsIn(values) => col_expr.in_list(
                 values
                     .iter()
@@ -135,8 +201,42 @@ impl ScalarQuery {
                     .collect::<Vec<_>>(),
                 false,
             ),
+            Self::NotIn(values) => col_expr.in_list(
+                values
+                    .iter()
+                    .map(|val| Expr::Literal(val.clone()))
+                    .collect::<Vec<_>>(),
+                true,
+            ),
             Self::IsNull() => col_expr.is_null(),
             Self::Equals(value) => col_expr.eq(Expr::Literal(value.clone())),
+            Self::NotEquals(value) => col_expr.not_eq(Expr::Literal(value.clone())),
+            Self::IsNotDistinctFrom(value) => Expr::BinaryExpr(BinaryExpr::new(
+                Box::new(col_expr),
+                Operator::IsNotDistinctFrom,
+                Box::new(Expr::Literal(value.clone())),
+            )),
+            Self::Regex(pattern) => Expr::BinaryExpr(BinaryExpr::new(
+                Box::new(col_expr),
+                Operator::RegexMatch,
+                Box::new(Expr::Literal(ScalarValue::Utf8(Some(pattern.clone())))),
+            )),
+            Self::StartsWith(prefix) => {
+                let escaped = prefix
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+                Expr::Like(Like::new(
+                    false,
+                    Box::new(col_expr),
+                    Box::new(Expr::Literal(ScalarValue::Utf8(Some(format!(
+                        "{}%",
+                        escaped
+                    ))))),
+                    Some('\\'),
+                    false,
+                ))
+            }
         }
     }
 
@@ -161,6 +261,11 @@ impl ScalarQuery {
                     format!("{} > {} && {} < {}", col, lhs, col, rhs)
                 }
             },
+            Self::RangeOrNull(lower, upper) => format!(
+                "({}) || {} IS NULL",
+                Self::Range(lower.clone(), upper.clone()).fmt_with_col(col),
+                col
+            ),
             Self::IsIn(values) => {
                 format!(
                     "{} IN [{}]",
@@ -172,12 +277,153 @@ impl ScalarQuery {
                         .join(",")
                 )
             }
+            Self::NotIn(values) => {
+                format!(
+                    "{} NOT IN [{}]",
+                    col,
+                    values
+                        .iter()
+                        .map(|val| val.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )
+            }
             Self::IsNull() => {
                 format!("{} IS NULL", col)
             }
             Self::Equals(val) => {
                 format!("{} = {}", col, val)
             }
+            Self::NotEquals(val) => {
+                format!("{} != {}", col, val)
+            }
+            Self::IsNotDistinctFrom(val) => {
+                format!("{} IS NOT DISTINCT FROM {}", col, val)
+            }
+            Self::Regex(pattern) => {
+                format!("{} ~ '{}'", col, pattern)
+            }
+            Self::StartsWith(prefix) => {
+                format!("{} LIKE '{}%'", col, prefix)
+            }
+        }
+    }
+}
+
+/// A query against several columns, the rows must match the query of every column
+///
+/// This is used by indices over several columns, e.g. `a = 10 AND b IN (1, 2)` is the
+/// query `a = 10` on column `a` and the query `b IN (1, 2)` on column `b`.  A composite
+/// query without column queries matches every row.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CompositeQuery {
+    /// The column name and query of each column
+    pub column_queries: Vec<(String, ScalarQuery)>,
+}
+
+impl CompositeQuery {
+    pub fn new(column_queries: Vec<(String, ScalarQuery)>) -> Self {
+        Self { column_queries }
+    }
+
+    /// Add a query on `column`
+    pub fn and(mut self, column: impl Into<String>, query: ScalarQuery) -> Self {
+        self.column_queries.push((column.into(), query));
+        self
+    }
+
+    pub fn to_expr(&self) -> Expr {
+        self.column_queries
+            .iter()
+            .map(|(column, query)| query.to_expr(column.clone()))
+            .reduce(Expr::and)
+            .unwrap_or(Expr::Literal(ScalarValue::Boolean(Some(true))))
+    }
+}
+
+impl std::fmt::Display for CompositeQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.column_queries.is_empty() {
+            return write!(f, "true");
+        }
+        let queries = self
+            .column_queries
+            .iter()
+            .map(|(column, query)| query.fmt_with_col(column))
+            .collect::<Vec<_>>();
+        write!(f, "{}", queries.join(" AND "))
+    }
+}
+
+/// The range `[prefix, upper)` of string values of type `data_type` that start with `prefix`
+///
+/// Strings compare by their UTF-8 bytes, which is the same as comparing code points, so
+/// the upper bound is the prefix with its last character incremented.  Trailing characters
+/// that can not be incremented (`char::MAX`) are dropped first, and if nothing is left the
+/// range has no upper bound.  Returns `None` if `data_type` is not a string type.
+pub fn prefix_range(
+    prefix: &str,
+    data_type: &DataType,
+) -> Option<(Bound<ScalarValue>, Bound<ScalarValue>)> {
+    let to_scalar = |value: String| match data_type {
+        DataType::Utf8 => Some(ScalarValue::Utf8(Some(value))),
+        DataType::LargeUtf8 => Some(ScalarValue::LargeUtf8(Some(value))),
+        _ => None,
+    };
+    let lower = Bound::Included(to_scalar(prefix.to_string())?);
+
+    let mut upper = prefix.chars().collect::<Vec<_>>();
+    while let Some(last) = upper.pop() {
+        // Skip over the surrogate range, which are not valid chars
+        let next = match last {
+            '\u{D7FF}' => Some('\u{E000}'),
+            _ => char::from_u32(last as u32 + 1),
+        };
+        if let Some(next) = next {
+            upper.push(next);
+            let upper = Bound::Excluded(to_scalar(upper.into_iter().collect())?);
+            return Some((lower, upper));
+        }
+    }
+    Some((lower, Bound::Unbounded))
+}
+
+/// The row ids matched by a scalar index search, and whether they are exact
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchResult {
+    /// The row ids are exactly the rows that satisfy the query
+    Exact(UInt64Array),
+    /// The row ids were found by searching a sample of the index
+    ///
+    /// Every returned row id satisfies the query, but other matching rows may be missing.
+    Approximate {
+        row_ids: UInt64Array,
+        /// Estimated number of matching rows in the whole index
+        estimated_matches: u64,
+    },
+}
+
+impl SearchResult {
+    /// The matched row ids
+    pub fn row_ids(&self) -> &UInt64Array {
+        match self {
+            Self::Exact(row_ids) => row_ids,
+            Self::Approximate { row_ids, .. } => row_ids,
+        }
+    }
+
+    /// Whether the result contains every row that satisfies the query
+    pub fn is_exact(&self) -> bool {
+        matches!(self, Self::Exact(_))
+    }
+
+    /// The (estimated) number of rows that satisfy the query
+    pub fn estimated_matches(&self) -> u64 {
+        match self {
+            Self::Exact(row_ids) => row_ids.len() as u64,
+            Self::Approximate {
+                estimated_matches, ..
+            } => *estimated_matches,
         }
     }
 }
@@ -51,7 +51,8 @@ use snafu::{location, Location};
 use crate::{Index, IndexType};
 
 use super::{
-    flat::FlatIndexMetadata, IndexReader, IndexStore, IndexWriter, ScalarIndex, ScalarQuery,
+    flat::{FlatIndexMetadata, FlatValueTransform},
+    prefix_range, IndexReader, IndexStore, IndexWriter, ScalarIndex, ScalarQuery,
 };
 
 const BTREE_LOOKUP_NAME: &str = "page_lookup.lance";
@@ -534,11 +535,34 @@ pub struct BTreeLookup {
     tree: BTreeMap<OrderableScalarValue, Vec<PageRecord>>,
     /// Pages where the value may be null
     null_pages: Vec<u32>,
+    /// Whether the value ranges of some pages overlap
+    ///
+    /// The pages are trained from values sorted in order, so their ranges are disjoint,
+    /// unless the sub-index transforms the values in a way that does not preserve their
+    /// order (e.g. lowercasing strings).
+    overlapping: bool,
 }
 
 impl BTreeLookup {
     fn new(tree: BTreeMap<OrderableScalarValue, Vec<PageRecord>>, null_pages: Vec<u32>) -> Self {
-        Self { tree, null_pages }
+        let mut overlapping = false;
+        let mut max_so_far: Option<&OrderableScalarValue> = None;
+        for (min, pages) in tree.iter() {
+            if max_so_far.map_or(false, |max| min.cmp(max) == Ordering::Less) {
+                overlapping = true;
+                break;
+            }
+            for page in pages {
+                if max_so_far.map_or(true, |max| page.max.cmp(max) == Ordering::Greater) {
+                    max_so_far = Some(&page.max);
+                }
+            }
+        }
+        Self {
+            tree,
+            null_pages,
+            overlapping,
+        }
     }
 
     fn all_page_ids(&self) -> Vec<u32> {
@@ -612,6 +636,21 @@ impl BTreeLookup {
             // matches an upper bound.  This will all be moot if/when we merge pages.
             Bound::Excluded(upper) => Bound::Included(upper),
         };
+        if self.overlapping {
+            // A page with a smaller min than the node left of the range can still have
+            // values in the range, so check the max of all the pages up to the upper bound
+            return self
+                .tree
+                .range((Bound::Unbounded, upper_bound))
+                .flat_map(|(_, pages)| pages)
+                .filter(|page| match range.0 {
+                    Bound::Unbounded => true,
+                    Bound::Included(lower) => page.max.cmp(lower) != Ordering::Less,
+                    Bound::Excluded(lower) => page.max.cmp(lower) == Ordering::Greater,
+                })
+                .map(|page| page.page_number)
+                .collect();
+        }
         let candidates = self
             .tree
             .range((lower_bound, upper_bound))
@@ -730,7 +769,13 @@ impl BTreeIndex {
         let data_type = mins.data_type();
 
         // TODO: Support other page types?
-        let sub_index = Arc::new(FlatIndexMetadata::new(data_type.clone()));
+        // The transform of the values is recorded in the metadata of the min column
+        let sub_index = FlatIndexMetadata::new(data_type.clone());
+        let sub_index = match FlatValueTransform::from_field(data.schema().field(0))? {
+            Some(transform) => sub_index.with_value_transform(transform),
+            None => sub_index,
+        };
+        let sub_index = Arc::new(sub_index);
 
         Ok(Self::new(map, null_pages, store, sub_index))
     }
@@ -830,17 +875,56 @@ impl Index for BTreeIndex {
 #[async_trait]
 impl ScalarIndex for BTreeIndex {
     async fn search(&self, query: &ScalarQuery) -> Result<UInt64Array> {
-        let pages = match query {
+        // The page statistics are computed on the values as the sub-index stores them, the
+        // pages themselves are searched with the original query
+        let lookup_query = self.sub_index.transform_query(query)?;
+        let pages = match &lookup_query {
             ScalarQuery::Equals(val) => self
                 .page_lookup
                 .pages_eq(&OrderableScalarValue(val.clone())),
             ScalarQuery::Range(start, end) => self
                 .page_lookup
                 .pages_between((wrap_bound(start).as_ref(), wrap_bound(end).as_ref())),
+            ScalarQuery::RangeOrNull(start, end) => {
+                let mut pages = self
+                    .page_lookup
+                    .pages_between((wrap_bound(start).as_ref(), wrap_bound(end).as_ref()));
+                pages.extend(self.page_lookup.pages_null());
+                pages.sort_unstable();
+                pages.dedup();
+                pages
+            }
             ScalarQuery::IsIn(values) => self
                 .page_lookup
                 .pages_in(values.iter().map(|val| OrderableScalarValue(val.clone()))),
             ScalarQuery::IsNull() => self.page_lookup.pages_null(),
+            ScalarQuery::IsNotDistinctFrom(val) => {
+                if val.is_null() {
+                    self.page_lookup.pages_null()
+                } else {
+                    self.page_lookup
+                        .pages_eq(&OrderableScalarValue(val.clone()))
+                }
+            }
+            ScalarQuery::StartsWith(prefix) => {
+                let range = self
+                    .page_lookup
+                    .tree
+                    .first_key_value()
+                    .and_then(|(key, _)| prefix_range(prefix, &key.0.data_type()));
+                match range {
+                    Some((start, end)) => self
+                        .page_lookup
+                        .pages_between((wrap_bound(&start).as_ref(), wrap_bound(&end).as_ref())),
+                    // Not a string column, the pages report the error
+                    None => self.page_lookup.all_page_ids(),
+                }
+            }
+            // A regular expression or an exclusion can match values anywhere in the sort
+            // order
+            ScalarQuery::Regex(_) | ScalarQuery::NotIn(_) | ScalarQuery::NotEquals(_) => {
+                self.page_lookup.all_page_ids()
+            }
         };
         let sub_index_reader = self.store.open_index_file(BTREE_PAGES_NAME).await?;
         let page_tasks = pages
@@ -980,6 +1064,15 @@ pub trait BTreeSubIndex: Debug + Send + Sync {
     /// The schema of the subindex when serialized to Arrow
     fn schema(&self) -> &Arc<Schema>;
 
+    /// Transform a query to apply to the values as they are stored by the subindex
+    ///
+    /// The statistics of a page are computed on the first column of the trained page,
+    /// so a subindex that transforms the values must transform the queries the same way
+    /// for the page lookup to find all the matching pages.
+    fn transform_query(&self, query: &ScalarQuery) -> Result<ScalarQuery> {
+        Ok(query.clone())
+    }
+
     /// Given a serialized page, deserialize it, remap the row ids, and re-serialize it
     async fn remap_subindex(
         &self,
@@ -999,8 +1092,9 @@ async fn train_btree_page(
     sub_index_trainer: &dyn BTreeSubIndex,
     writer: &mut dyn IndexWriter,
 ) -> Result<EncodedBatch> {
-    let stats = analyze_batch(&batch)?;
     let trained = sub_index_trainer.train(batch).await?;
+    // The statistics must describe the values as stored, e.g. after a value transform
+    let stats = analyze_batch(&trained)?;
     writer.write_record_batch(trained).await?;
     Ok(EncodedBatch {
         stats,
@@ -1008,14 +1102,18 @@ async fn train_btree_page(
     })
 }
 
-fn btree_stats_as_batch(stats: Vec<EncodedBatch>) -> Result<RecordBatch> {
+/// Serialize the page statistics, `value_field` is the field of the values of the subindex
+///
+/// The metadata of `value_field`, e.g. a value transform, is kept on the min column.
+fn btree_stats_as_batch(stats: Vec<EncodedBatch>, value_field: &Field) -> Result<RecordBatch> {
     let mins = ScalarValue::iter_to_array(stats.iter().map(|stat| stat.stats.min.clone()))?;
     let maxs = ScalarValue::iter_to_array(stats.iter().map(|stat| stat.stats.max.clone()))?;
     let null_counts = UInt32Array::from_iter_values(stats.iter().map(|stat| stat.stats.null_count));
     let page_numbers = UInt32Array::from_iter_values(stats.iter().map(|stat| stat.page_number));
 
     let schema = Arc::new(Schema::new(vec![
-        Field::new("min", mins.data_type().clone(), false),
+        Field::new("min", mins.data_type().clone(), false)
+            .with_metadata(value_field.metadata().clone()),
         Field::new("max", maxs.data_type().clone(), false),
         Field::new("null_count", null_counts.data_type().clone(), false),
         Field::new("page_idx", page_numbers.data_type().clone(), false),
@@ -1071,7 +1169,7 @@ pub async fn train_btree_index(
         batch_idx += 1;
     }
     sub_index_file.finish().await?;
-    let record_batch = btree_stats_as_batch(encoded_batches)?;
+    let record_batch = btree_stats_as_batch(encoded_batches, sub_index_trainer.schema().field(0))?;
     let mut btree_index_file = index_store
         .new_index_file(BTREE_LOOKUP_NAME, record_batch.schema())
         .await?;
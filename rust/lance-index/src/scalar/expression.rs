@@ -392,6 +392,7 @@ fn visit_comparison_normalized(scalar: ScalarValue, op: &Operator) -> ScalarQuer
         Operator::Gt => ScalarQuery::Range(Bound::Excluded(scalar), Bound::Unbounded),
         Operator::GtEq => ScalarQuery::Range(Bound::Included(scalar), Bound::Unbounded),
         Operator::Eq => ScalarQuery::Equals(scalar),
+        Operator::IsNotDistinctFrom => ScalarQuery::IsNotDistinctFrom(scalar),
         // This will be negated by the caller
         Operator::NotEq => ScalarQuery::Equals(scalar),
         _ => unreachable!(),
@@ -457,9 +458,12 @@ fn visit_binary_expr(
     index_info: &dyn IndexInformationProvider,
 ) -> Option<IndexedExpression> {
     match &expr.op {
-        Operator::Lt | Operator::LtEq | Operator::Gt | Operator::GtEq | Operator::Eq => {
-            visit_comparison(expr, index_info)
-        }
+        Operator::Lt
+        | Operator::LtEq
+        | Operator::Gt
+        | Operator::GtEq
+        | Operator::Eq
+        | Operator::IsNotDistinctFrom => visit_comparison(expr, index_info),
         // visit_comparison will maybe create an Eq query which we negate
         Operator::NotEq => visit_comparison(expr, index_info).and_then(|node| node.maybe_not()),
         Operator::And => visit_and(expr, index_info),
@@ -12,24 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashMap;
+use std::cmp::{min, Reverse};
+use std::collections::{BinaryHeap, HashMap};
 use std::{any::Any, ops::Bound, sync::Arc};
 
+use arrow::compute::kernels::regexp::regexp_is_match_utf8_scalar;
 use arrow_array::{
-    cast::AsArray, types::UInt64Type, ArrayRef, BooleanArray, RecordBatch, UInt64Array,
+    cast::AsArray,
+    types::{
+        Date64Type, Float64Type, Int64Type, TimestampMicrosecondType, TimestampMillisecondType,
+        TimestampNanosecondType, TimestampSecondType, UInt64Type,
+    },
+    Array, ArrayRef, ArrowPrimitiveType, BooleanArray, LargeStringArray, PrimitiveArray,
+    RecordBatch, StringArray, UInt32Array, UInt64Array,
 };
-use arrow_schema::{DataType, Field, Schema};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
 use async_trait::async_trait;
+use futures::{stream, StreamExt, TryStreamExt};
 
-use datafusion::physical_plan::SendableRecordBatchStream;
-use datafusion_physical_expr::expressions::{in_list, lit, Column};
+use arrow::row::{RowConverter, SortField};
+use datafusion::physical_plan::{stream::RecordBatchStreamAdapter, SendableRecordBatchStream};
+use datafusion_common::{DataFusionError, ScalarValue};
+use datafusion_expr::Accumulator;
+use datafusion_physical_expr::expressions::{in_list, lit, Column, MaxAccumulator, MinAccumulator};
+use lance_arrow::RecordBatchExt;
 use lance_core::utils::address::RowAddress;
-use lance_core::Result;
+use lance_core::{Error, Result};
+use rayon::prelude::*;
 use roaring::RoaringBitmap;
+use snafu::{location, Location};
 
 use crate::{Index, IndexType};
 
-use super::{btree::BTreeSubIndex, IndexStore, ScalarIndex, ScalarQuery};
+use super::hll::{self, HyperLogLog};
+use super::{
+    btree::BTreeSubIndex, prefix_range, CompositeQuery, IndexReader, IndexStore, ScalarIndex,
+    ScalarQuery, SearchResult,
+};
 
 /// A flat index is just a batch of value/row-id pairs
 ///
@@ -42,7 +61,814 @@ pub struct FlatIndex {
     data: Arc<RecordBatch>,
 }
 
+/// The name of the file a flat index stores its value/row-id pairs in
+pub const FLAT_INDEX_DATA_NAME: &str = "data.lance";
+
+/// The default number of rows read at once by [`FlatIndex::search_streaming`], and the
+/// number of rows searched per batch by [`FlatIndex::search_stream`]
+pub const DEFAULT_SEARCH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Row offsets at or above this bound are considered corrupt by [`FlatIndex::validate`]
+///
+/// No fragment can hold anywhere near this many rows.
+pub const MAX_PLAUSIBLE_ROW_OFFSET: u32 = i32::MAX as u32;
+
+/// The HyperLogLog precision used for `approx_distinct` in the index statistics
+pub const DEFAULT_HLL_PRECISION: u8 = 12;
+
+/// The most hash functions used by a Bloom filter from [`FlatIndex::build_bloom_filter`]
+const MAX_BLOOM_FILTER_HASHES: u8 = 16;
+
+/// The bytes before the bits of a Bloom filter: the number of hashes and the value transform
+const BLOOM_FILTER_HEADER_LEN: usize = 2;
+
+/// The schema metadata key that marks the values of a flat index as sorted
+///
+/// It is set by [`FlatIndex::sort_by_value`], see [`FlatIndex::is_sorted`].
+pub const SORTED_META_KEY: &str = "lance:flat:sorted";
+
+/// The number of rows evaluated at once by [`FlatIndex::search_limited`]
+pub const SEARCH_LIMITED_CHUNK_SIZE: usize = 4 * 1024;
+
+/// [`FlatIndex::search`] evaluates the query on chunks of this many rows in parallel,
+/// if there are more rows than that
+pub const PARALLEL_SEARCH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Summary statistics of the values in a flat index
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatIndexStatistics {
+    /// Number of values, including nulls
+    pub num_rows: usize,
+    /// Number of null values
+    pub null_count: usize,
+    /// The smallest non-null value, null if there are no non-null values
+    pub min: ScalarValue,
+    /// The largest non-null value, null if there are no non-null values
+    pub max: ScalarValue,
+    /// Number of distinct non-null values
+    pub num_distinct: usize,
+}
+
+/// The selectivity assumed for predicates the statistics can't reason about
+const DEFAULT_SELECTIVITY: f32 = 1.0 / 3.0;
+
+/// The schema metadata key of the [`FlatIndexSummary`] stored by [`ScalarIndex::update`]
+pub const SUMMARY_META_KEY: &str = "lance:flat:summary";
+
+/// The count, null count, range and a distinct count sketch of the values in a flat index
+///
+/// Unlike the exact number of distinct values these can be maintained as values are added,
+/// so [`ScalarIndex::update`] stores them in the index metadata, see [`FlatIndex::summary`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatIndexSummary {
+    /// Number of values, including nulls
+    pub num_rows: usize,
+    /// Number of null values
+    pub null_count: usize,
+    /// The smallest non-null value, null if there are no non-null values
+    pub min: ScalarValue,
+    /// The largest non-null value, null if there are no non-null values
+    pub max: ScalarValue,
+    /// A sketch of the distinct non-null values, with [`DEFAULT_HLL_PRECISION`]
+    pub distinct: HyperLogLog,
+}
+
+impl FlatIndexSummary {
+    /// Compute the summary of `values` from scratch
+    pub fn compute(values: &ArrayRef) -> Result<Self> {
+        let mut min_acc = MinAccumulator::try_new(values.data_type())?;
+        min_acc.update_batch(&[values.clone()])?;
+        let mut max_acc = MaxAccumulator::try_new(values.data_type())?;
+        max_acc.update_batch(&[values.clone()])?;
+        Ok(Self {
+            num_rows: values.len(),
+            null_count: values.null_count(),
+            min: min_acc.evaluate()?,
+            max: max_acc.evaluate()?,
+            distinct: distinct_sketch(values.as_ref(), DEFAULT_HLL_PRECISION)?,
+        })
+    }
+
+    /// The summary of the values summarized by `self` followed by those of `other`
+    pub fn merge(&self, other: &Self) -> Result<Self> {
+        let pick = |a: &ScalarValue, b: &ScalarValue, ordering: std::cmp::Ordering| {
+            if a.is_null() || (!b.is_null() && b.partial_cmp(a) == Some(ordering)) {
+                b.clone()
+            } else {
+                a.clone()
+            }
+        };
+        let mut distinct = self.distinct.clone();
+        distinct.merge(&other.distinct)?;
+        Ok(Self {
+            num_rows: self.num_rows + other.num_rows,
+            null_count: self.null_count + other.null_count,
+            min: pick(&self.min, &other.min, std::cmp::Ordering::Less),
+            max: pick(&self.max, &other.max, std::cmp::Ordering::Greater),
+            distinct,
+        })
+    }
+
+    fn to_metadata(&self) -> String {
+        serde_json::json!({
+            "num_rows": self.num_rows,
+            "null_count": self.null_count,
+            "min": scalar_to_json(&self.min),
+            "max": scalar_to_json(&self.max),
+            "distinct": self
+                .distinct
+                .registers()
+                .iter()
+                .map(|register| format!("{:02x}", register))
+                .collect::<String>(),
+        })
+        .to_string()
+    }
+
+    /// Parse a summary stored by [`Self::to_metadata`] for values of type `data_type`
+    ///
+    /// Returns `None` if it can not be parsed, if min and max do not parse back to the
+    /// values they were written from, or if there is no distinct count sketch (it was
+    /// added later), so the summary is computed from scratch instead.
+    fn from_metadata(metadata: &str, data_type: &DataType) -> Option<Self> {
+        let json = serde_json::from_str::<serde_json::Value>(metadata).ok()?;
+        let parse_scalar = |json: &serde_json::Value| match json {
+            serde_json::Value::Null => ScalarValue::try_from(data_type).ok(),
+            serde_json::Value::String(value) => {
+                ScalarValue::try_from_string(value.clone(), data_type)
+                    .ok()
+                    .filter(|parsed| &parsed.to_string() == value)
+            }
+            _ => None,
+        };
+        let distinct = json.get("distinct")?.as_str()?;
+        let registers = (0..distinct.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(distinct.get(idx..idx + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            num_rows: json.get("num_rows")?.as_u64()? as usize,
+            null_count: json.get("null_count")?.as_u64()? as usize,
+            min: parse_scalar(json.get("min")?)?,
+            max: parse_scalar(json.get("max")?)?,
+            distinct: HyperLogLog::try_from_registers(registers)
+                .ok()
+                .filter(|sketch| sketch.registers().len() == 1 << DEFAULT_HLL_PRECISION)?,
+        })
+    }
+}
+
+impl FlatIndexStatistics {
+    fn non_null_fraction(&self) -> f32 {
+        if self.num_rows == 0 {
+            return 0.0;
+        }
+        (self.num_rows - self.null_count) as f32 / self.num_rows as f32
+    }
+
+    fn equals_selectivity(&self, value: &ScalarValue) -> f32 {
+        if value.is_null() || self.num_distinct == 0 {
+            return 0.0;
+        }
+        let out_of_range = matches!(value.partial_cmp(&self.min), Some(std::cmp::Ordering::Less))
+            || matches!(
+                value.partial_cmp(&self.max),
+                Some(std::cmp::Ordering::Greater)
+            );
+        if out_of_range {
+            return 0.0;
+        }
+        self.non_null_fraction() / self.num_distinct as f32
+    }
+
+    fn range_selectivity(&self, lower: &Bound<ScalarValue>, upper: &Bound<ScalarValue>) -> f32 {
+        fn as_f64(value: &ScalarValue) -> Option<f64> {
+            match value.cast_to(&DataType::Float64) {
+                Ok(ScalarValue::Float64(Some(v))) => Some(v),
+                _ => None,
+            }
+        }
+        let (Some(min), Some(max)) = (as_f64(&self.min), as_f64(&self.max)) else {
+            return DEFAULT_SELECTIVITY * self.non_null_fraction();
+        };
+        let lower = match lower {
+            Bound::Unbounded => Some(min),
+            Bound::Included(v) | Bound::Excluded(v) => as_f64(v),
+        };
+        let upper = match upper {
+            Bound::Unbounded => Some(max),
+            Bound::Included(v) | Bound::Excluded(v) => as_f64(v),
+        };
+        let (Some(lower), Some(upper)) = (lower, upper) else {
+            return DEFAULT_SELECTIVITY * self.non_null_fraction();
+        };
+        let (lower, upper) = (lower.max(min), upper.min(max));
+        let fraction = if lower > upper {
+            0.0
+        } else if max > min {
+            // Assume the values are uniformly distributed between min and max, a range
+            // covering a single point still matches at least one distinct value.
+            ((upper - lower) / (max - min)).max(1.0 / self.num_distinct.max(1) as f64)
+        } else {
+            1.0
+        };
+        fraction as f32 * self.non_null_fraction()
+    }
+}
+
 impl FlatIndex {
+    /// Check that a batch read from disk has the layout of a flat index
+    ///
+    /// The first column can be of any type.  The second column holds the row ids, which
+    /// are cast to UInt64 if they were written with another integer type.  It is an error
+    /// if the ids can not be cast without loss (e.g. negative ids).
+    fn validate_schema(batch: RecordBatch) -> Result<RecordBatch> {
+        if batch.num_columns() < 2 {
+            return Err(Error::Index {
+                message: format!(
+                    "Flat index data must have at least 2 columns (values, row_ids), got {}",
+                    batch.num_columns()
+                ),
+                location: location!(),
+            });
+        }
+        let ids = batch.column(1);
+        if ids.data_type() == &DataType::UInt64 {
+            return Ok(batch);
+        }
+        let options = arrow::compute::CastOptions {
+            safe: false,
+            ..Default::default()
+        };
+        let ids =
+            arrow::compute::cast_with_options(ids, &DataType::UInt64, &options).map_err(|err| {
+                Error::Index {
+                    message: format!(
+                        "Flat index row ids must be UInt64, and the {} ids could not be cast: {}",
+                        ids.data_type(),
+                        err
+                    ),
+                    location: location!(),
+                }
+            })?;
+        let schema = batch.schema();
+        let mut fields = schema.fields().iter().cloned().collect::<Vec<_>>();
+        fields[1] = Arc::new(fields[1].as_ref().clone().with_data_type(DataType::UInt64));
+        let mut columns = batch.columns().to_vec();
+        columns[1] = ids;
+        Ok(RecordBatch::try_new(
+            Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone())),
+            columns,
+        )?)
+    }
+
+    /// Sort a batch of (value, row id) pairs by value, nulls first, and mark it as sorted
+    ///
+    /// An index on the sorted batch answers equality and range queries with a binary search
+    /// instead of a scan, see [`Self::is_sorted`].  The mark is kept in the schema metadata,
+    /// so it is written and loaded along with the data.
+    pub fn sort_by_value(batch: RecordBatch) -> Result<RecordBatch> {
+        let indices = arrow::compute::sort_to_indices(
+            batch.column(0),
+            Some(arrow::compute::SortOptions {
+                descending: false,
+                nulls_first: true,
+            }),
+            None,
+        )?;
+        let sorted = batch.take(&indices)?;
+        with_schema_metadata(sorted, |metadata| {
+            metadata.insert(SORTED_META_KEY.to_string(), "true".to_string());
+        })
+    }
+
+    /// Whether the values are marked as sorted by [`Self::sort_by_value`]
+    pub fn is_sorted(&self) -> bool {
+        is_marked_sorted(&self.data.schema())
+    }
+
+    /// Check that data marked as sorted has the nulls first, then the values in order
+    fn validate_sorted(batch: &RecordBatch) -> Result<()> {
+        if !is_marked_sorted(&batch.schema()) {
+            return Ok(());
+        }
+        let values = batch.column(0);
+        let null_count = values.null_count();
+        let non_null = values.slice(null_count, values.len() - null_count);
+        let nulls_first = values.slice(0, null_count).null_count() == null_count;
+        let in_order = non_null.len() < 2
+            || arrow_ord::cmp::lt_eq(
+                &non_null.slice(0, non_null.len() - 1),
+                &non_null.slice(1, non_null.len() - 1),
+            )?
+            .true_count()
+                == non_null.len() - 1;
+        if nulls_first && in_order {
+            Ok(())
+        } else {
+            Err(Error::Index {
+                message: "Flat index data is marked as sorted, but the values are not sorted"
+                    .to_string(),
+                location: location!(),
+            })
+        }
+    }
+
+    /// Merge this index with `others` into a single sorted index
+    ///
+    /// All indices must be sorted, see [`Self::sort_by_value`].  The values are merged
+    /// instead of concatenated and sorted again, which is cheaper when compaction combines
+    /// sorted indices.  Rows with equal values keep the order of the inputs.
+    pub fn merge_sorted(&self, others: &[&Self]) -> Result<Self> {
+        let inputs = std::iter::once(self)
+            .chain(others.iter().copied())
+            .collect::<Vec<_>>();
+        let data_type = self.values().data_type();
+        for input in &inputs {
+            if !input.is_sorted() {
+                return Err(Error::invalid_input(
+                    "Only sorted flat indices can be merged with merge_sorted",
+                    location!(),
+                ));
+            }
+            if input.values().data_type() != data_type {
+                return Err(Error::invalid_input(
+                    format!(
+                        "Can not merge flat indices of {} and {} values",
+                        data_type,
+                        input.values().data_type()
+                    ),
+                    location!(),
+                ));
+            }
+        }
+
+        // The row format orders nulls first, the same as sort_by_value
+        let converter = RowConverter::new(vec![SortField::new(data_type.clone())])?;
+        let rows = inputs
+            .iter()
+            .map(|input| converter.convert_columns(&[input.values().clone()]))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let mut heap = BinaryHeap::with_capacity(inputs.len());
+        for (input_idx, input_rows) in rows.iter().enumerate() {
+            if input_rows.num_rows() > 0 {
+                heap.push(Reverse((input_rows.row(0), input_idx, 0)));
+            }
+        }
+        let num_rows = rows.iter().map(|input_rows| input_rows.num_rows()).sum();
+        let mut indices = Vec::with_capacity(num_rows);
+        while let Some(Reverse((_, input_idx, row_idx))) = heap.pop() {
+            indices.push((input_idx, row_idx));
+            let next = row_idx + 1;
+            if next < rows[input_idx].num_rows() {
+                heap.push(Reverse((rows[input_idx].row(next), input_idx, next)));
+            }
+        }
+
+        let values = inputs
+            .iter()
+            .map(|input| input.values().as_ref())
+            .collect::<Vec<_>>();
+        let ids = inputs
+            .iter()
+            .map(|input| input.ids().as_ref())
+            .collect::<Vec<_>>();
+        let values = arrow_select::interleave::interleave(&values, &indices)?;
+        let ids = arrow_select::interleave::interleave(&ids, &indices)?;
+        // The other indices may have nulls where this one has none
+        let schema = self.data.schema();
+        let mut fields = schema.fields().iter().cloned().collect::<Vec<_>>();
+        fields[0] = Arc::new(
+            fields[0]
+                .as_ref()
+                .clone()
+                .with_nullable(fields[0].is_nullable() || values.null_count() > 0),
+        );
+        let mut metadata = schema.metadata().clone();
+        // The summary of this index does not cover the merged rows
+        metadata.remove(SUMMARY_META_KEY);
+        let merged = RecordBatch::try_new(
+            Arc::new(Schema::new_with_metadata(fields, metadata)),
+            vec![values, ids],
+        )?;
+        Ok(Self {
+            data: Arc::new(merged),
+        })
+    }
+
+    /// Search a sorted index with a binary search
+    ///
+    /// Returns `None` if the index is not sorted, or the query can not be answered by
+    /// a binary search, in which case the index is scanned instead.
+    fn search_sorted(&self, query: &ScalarQuery) -> Result<Option<UInt64Array>> {
+        Ok(self.sorted_range(query)?.map(|range| {
+            self.ids()
+                .as_primitive::<UInt64Type>()
+                .slice(range.start, range.len())
+        }))
+    }
+
+    /// The positions of the rows of a sorted index that match `query`, found with a
+    /// binary search
+    ///
+    /// Returns `None` in the same cases as [`Self::search_sorted`].
+    fn sorted_range(&self, query: &ScalarQuery) -> Result<Option<std::ops::Range<usize>>> {
+        if !self.is_sorted() {
+            return Ok(None);
+        }
+        let query = match FlatValueTransform::from_field(self.data.schema().field(0))? {
+            Some(transform) => transform.transform_query(query)?,
+            None => query.clone(),
+        };
+        let values = self.values();
+        let (lower, upper) = match query {
+            ScalarQuery::Equals(value) => (Bound::Included(value.clone()), Bound::Included(value)),
+            // The scan rejects a range without bounds
+            ScalarQuery::Range(Bound::Unbounded, Bound::Unbounded) => return Ok(None),
+            ScalarQuery::Range(lower, upper) => (lower, upper),
+            // The values starting with a prefix are a range of the sorted strings, other
+            // types are rejected by the scan
+            ScalarQuery::StartsWith(prefix) => match prefix_range(&prefix, values.data_type()) {
+                Some(range) => range,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        let bounds = [&lower, &upper];
+        let bound_values = bounds.iter().filter_map(|bound| match bound {
+            Bound::Included(value) | Bound::Excluded(value) => Some(value),
+            Bound::Unbounded => None,
+        });
+        for value in bound_values {
+            if value.is_null() {
+                // Nothing compares to null
+                return Ok(Some(0..0));
+            }
+            if &value.data_type() != values.data_type() {
+                return Ok(None);
+            }
+        }
+
+        // Nulls are first, and never match
+        let non_null = values.null_count()..values.len();
+        let start = match &lower {
+            Bound::Unbounded => non_null.start,
+            Bound::Included(lower) => partition_point(values, non_null.clone(), |v| v < lower)?,
+            Bound::Excluded(lower) => partition_point(values, non_null.clone(), |v| v <= lower)?,
+        };
+        let end = match &upper {
+            Bound::Unbounded => non_null.end,
+            Bound::Included(upper) => partition_point(values, non_null.clone(), |v| v <= upper)?,
+            Bound::Excluded(upper) => partition_point(values, non_null.clone(), |v| v < upper)?,
+        };
+        Ok(Some(start..end.max(start)))
+    }
+
+    /// Write a batch of (value, row id) pairs to `store` as a flat index
+    ///
+    /// The batch must have exactly two columns, and the row ids must be UInt64.  The index
+    /// can then be opened with [`FlatIndex::load`].
+    pub async fn write(batch: RecordBatch, store: &dyn IndexStore) -> Result<()> {
+        if batch.num_columns() != 2 {
+            return Err(Error::Index {
+                message: format!(
+                    "Flat index data must have exactly 2 columns (values, row_ids), got {}",
+                    batch.num_columns()
+                ),
+                location: location!(),
+            });
+        }
+        if batch.column(1).data_type() != &DataType::UInt64 {
+            return Err(Error::Index {
+                message: format!(
+                    "Flat index row ids must be UInt64, got {}",
+                    batch.column(1).data_type()
+                ),
+                location: location!(),
+            });
+        }
+        let mut writer = store
+            .new_index_file(FLAT_INDEX_DATA_NAME, batch.schema())
+            .await?;
+        writer.write_record_batch(batch).await?;
+        writer.finish().await?;
+        Ok(())
+    }
+
+    /// Compute summary statistics of the indexed values
+    pub fn value_statistics(&self) -> Result<FlatIndexStatistics> {
+        let values = self.values();
+        let summary = self.summary()?;
+
+        let converter = RowConverter::new(vec![SortField::new(values.data_type().clone())])?;
+        let rows = converter.convert_columns(&[values.clone()])?;
+        let num_distinct = rows
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| values.is_valid(*idx))
+            .map(|(_, row)| row)
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+
+        Ok(FlatIndexStatistics {
+            num_rows: summary.num_rows,
+            null_count: summary.null_count,
+            min: summary.min,
+            max: summary.max,
+            num_distinct,
+        })
+    }
+
+    /// The count, null count and range of the indexed values
+    ///
+    /// This is read from the index metadata if it was stored by [`ScalarIndex::update`],
+    /// and computed from the values otherwise.
+    pub fn summary(&self) -> Result<FlatIndexSummary> {
+        let stored = self
+            .data
+            .schema()
+            .metadata()
+            .get(SUMMARY_META_KEY)
+            .and_then(|summary| FlatIndexSummary::from_metadata(summary, self.values().data_type()))
+            .filter(|summary| summary.num_rows == self.data.num_rows());
+        match stored {
+            Some(summary) => Ok(summary),
+            None => FlatIndexSummary::compute(self.values()),
+        }
+    }
+
+    /// Find the row whose value is nearest to `target`
+    ///
+    /// The distance is the absolute difference `|value - target|`, so this only supports
+    /// numeric and temporal columns.  Integer and temporal values are compared exactly as
+    /// 64-bit integers, unsigned for UInt64 columns, floating point values as f64.  Null values never match.  When several
+    /// rows are equally near the smallest row id is returned.  Returns `None` if there are no
+    /// non-null values.
+    pub fn nearest_value(&self, target: &ScalarValue) -> Result<Option<u64>> {
+        let values = self.values();
+        let row_ids = self.ids().as_primitive::<UInt64Type>().values();
+        let value_type = values.data_type();
+        if value_type.is_floating() {
+            let ScalarValue::Float64(Some(target)) = target.cast_to(&DataType::Float64)? else {
+                return Ok(None);
+            };
+            let values = arrow::compute::cast(values, &DataType::Float64)?;
+            Ok(values
+                .as_primitive::<Float64Type>()
+                .iter()
+                .zip(row_ids.iter())
+                .filter_map(|(value, row_id)| value.map(|v| ((v - target).abs(), *row_id)))
+                .filter(|(dist, _)| !dist.is_nan())
+                .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+                .map(|(_, row_id)| row_id))
+        } else if value_type == &DataType::UInt64 {
+            // Values above i64::MAX do not fit the signed comparison below.
+            let ScalarValue::UInt64(Some(target)) = target.cast_to(value_type)? else {
+                return Ok(None);
+            };
+            Ok(values
+                .as_primitive::<UInt64Type>()
+                .iter()
+                .zip(row_ids.iter())
+                .filter_map(|(value, row_id)| value.map(|v| (v.abs_diff(target), *row_id)))
+                .min()
+                .map(|(_, row_id)| row_id))
+        } else if value_type.is_integer() || value_type.is_temporal() {
+            let ScalarValue::Int64(Some(target)) =
+                target.cast_to(value_type)?.cast_to(&DataType::Int64)?
+            else {
+                return Ok(None);
+            };
+            let values = arrow::compute::cast(values, &DataType::Int64)?;
+            Ok(values
+                .as_primitive::<Int64Type>()
+                .iter()
+                .zip(row_ids.iter())
+                .filter_map(|(value, row_id)| {
+                    value.map(|v| ((v as i128 - target as i128).unsigned_abs(), *row_id))
+                })
+                .min()
+                .map(|(_, row_id)| row_id))
+        } else {
+            Err(Error::invalid_input(
+                format!(
+                    "Nearest value query requires a numeric or temporal column, got {}",
+                    value_type
+                ),
+                location!(),
+            ))
+        }
+    }
+
+    /// Check that every row id in the index is a plausible row address
+    ///
+    /// A corrupt index may hold null ids, or ids that decode to impossible row offsets, which
+    /// would then turn into bogus fragment ids in [`Index::calculate_included_frags`].  The
+    /// error reports the first invalid id found.
+    pub fn validate(&self) -> Result<()> {
+        let ids = self.ids().as_primitive::<UInt64Type>();
+        for (idx, id) in ids.iter().enumerate() {
+            let Some(id) = id else {
+                return Err(Error::Index {
+                    message: format!("Flat index has a null row id at position {}", idx),
+                    location: location!(),
+                });
+            };
+            let address = RowAddress::new_from_id(id);
+            if address.row_id() >= MAX_PLAUSIBLE_ROW_OFFSET {
+                return Err(Error::Index {
+                    message: format!(
+                        "Flat index has an invalid row id {} at position {}: row address {} has an implausible row offset",
+                        id, idx, address
+                    ),
+                    location: location!(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the index into one sub-index per fragment
+    ///
+    /// Rows are grouped by the fragment id of their row address, keeping their relative
+    /// order, so each fragment can be remapped or compacted on its own.
+    pub fn split_by_fragment(&self) -> Result<HashMap<u32, Self>> {
+        let ids = self.ids().as_primitive::<UInt64Type>();
+        let mut positions: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (idx, id) in ids.iter().enumerate() {
+            let Some(id) = id else {
+                return Err(Error::Index {
+                    message: format!("Flat index has a null row id at position {}", idx),
+                    location: location!(),
+                });
+            };
+            positions
+                .entry(RowAddress::new_from_id(id).fragment_id())
+                .or_default()
+                .push(idx as u32);
+        }
+        positions
+            .into_iter()
+            .map(|(fragment_id, positions)| {
+                let data = self.data.take(&UInt32Array::from(positions))?;
+                Ok((
+                    fragment_id,
+                    Self {
+                        data: Arc::new(data),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Approximate number of distinct non-null values, using a HyperLogLog sketch
+    ///
+    /// Unlike the exact count in [`Self::value_statistics`] the memory used does not grow
+    /// with the cardinality.  `precision` is between [`hll::MIN_PRECISION`] and
+    /// [`hll::MAX_PRECISION`], the relative error is about `1.04 / sqrt(2^precision)`.
+    pub fn approx_distinct(&self, precision: u8) -> Result<f64> {
+        Ok(distinct_sketch(self.values().as_ref(), precision)?.estimate())
+    }
+
+    /// Build a Bloom filter over the non-null values, using about `num_bits` bits
+    ///
+    /// The filter can be sent to other nodes, which then use [`Self::might_contain`] to skip
+    /// loading the index when a value is definitely not indexed.  There are no false negatives,
+    /// but a value that is not indexed may still be reported as present.  For `n` distinct
+    /// values the false positive rate is about `(1 - e^(-k * n / num_bits))^k`, where `k` is
+    /// the number of hash functions, chosen to minimize it.  For example, 10 bits per distinct
+    /// value give a rate of about 1%.
+    ///
+    /// The first byte of the filter is `k` and the second byte the [`FlatValueTransform`] of
+    /// the index, followed by `num_bits` bits, rounded up to a byte.  Fixed-width values are
+    /// hashed as their little-endian bytes and strings as their UTF-8 bytes.
+    pub fn build_bloom_filter(&self, num_bits: usize) -> Result<Vec<u8>> {
+        if num_bits == 0 {
+            return Err(Error::invalid_input(
+                "Bloom filter must have at least one bit",
+                location!(),
+            ));
+        }
+        let values = self.values();
+        let num_bytes = (num_bits + 7) / 8;
+        let num_values = values.len() - values.null_count();
+        let num_hashes = if num_values == 0 {
+            1
+        } else {
+            ((num_bytes * 8) as f64 / num_values as f64 * std::f64::consts::LN_2)
+                .round()
+                .clamp(1.0, MAX_BLOOM_FILTER_HASHES as f64) as u8
+        };
+
+        let mut filter = vec![0_u8; num_bytes + BLOOM_FILTER_HEADER_LEN];
+        filter[0] = num_hashes;
+        filter[1] = bloom_filter_transform_tag(FlatValueTransform::from_field(
+            self.data.schema().field(0),
+        )?);
+        let bits = &mut filter[BLOOM_FILTER_HEADER_LEN..];
+        for_each_value_bytes(values.as_ref(), |key| {
+            for bit in bloom_filter_bits(key, num_hashes, num_bytes * 8) {
+                bits[bit / 8] |= 1 << (bit % 8);
+            }
+        })?;
+        Ok(filter)
+    }
+
+    /// Check if `value` may be in a filter built with [`Self::build_bloom_filter`]
+    ///
+    /// `value` must have the same type as the indexed values, otherwise it is hashed
+    /// differently and the result is meaningless.  If the index has a [`FlatValueTransform`]
+    /// the value is transformed first, as in a search.  Null values are never in the filter.
+    pub fn might_contain(filter: &[u8], value: &ScalarValue) -> Result<bool> {
+        if filter.len() <= BLOOM_FILTER_HEADER_LEN || filter[0] == 0 {
+            return Err(Error::invalid_input(
+                format!("Invalid Bloom filter of {} bytes", filter.len()),
+                location!(),
+            ));
+        }
+        if value.is_null() {
+            return Ok(false);
+        }
+        let value = match bloom_filter_transform_from_tag(filter[1])? {
+            Some(transform) => transform.transform_scalar(value)?,
+            None => value.clone(),
+        };
+        let bits = &filter[BLOOM_FILTER_HEADER_LEN..];
+        let mut contains = true;
+        for_each_value_bytes(value.to_array()?.as_ref(), |key| {
+            contains = bloom_filter_bits(key, filter[0], bits.len() * 8)
+                .all(|bit| bits[bit / 8] & (1 << (bit % 8)) != 0);
+        })?;
+        Ok(contains)
+    }
+
+    /// Estimate the fraction of rows that match `query`, between 0 and 1
+    ///
+    /// The estimate is based on the [`Self::summary`], with the number of distinct values
+    /// estimated from its sketch, and assumes the values are uniformly distributed.  The
+    /// summary is read from the index metadata if it is stored, so this is cheap compared to
+    /// evaluating the query, and a planner can use it to evaluate the most selective of
+    /// several ANDed queries first.
+    pub fn estimated_selectivity(&self, query: &ScalarQuery) -> Result<f32> {
+        let summary = self.summary()?;
+        let stats = FlatIndexStatistics {
+            num_distinct: summary.distinct.estimate().round() as usize,
+            num_rows: summary.num_rows,
+            null_count: summary.null_count,
+            min: summary.min,
+            max: summary.max,
+        };
+        if stats.num_rows == 0 {
+            return Ok(0.0);
+        }
+        let null_fraction = stats.null_count as f32 / stats.num_rows as f32;
+        let selectivity = match query {
+            ScalarQuery::Equals(value) => stats.equals_selectivity(value),
+            ScalarQuery::NotEquals(value) => {
+                if value.is_null() {
+                    0.0
+                } else {
+                    stats.non_null_fraction() - stats.equals_selectivity(value)
+                }
+            }
+            ScalarQuery::IsNull() => null_fraction,
+            ScalarQuery::IsNotDistinctFrom(value) => {
+                if value.is_null() {
+                    null_fraction
+                } else {
+                    stats.equals_selectivity(value)
+                }
+            }
+            ScalarQuery::IsIn(choices) => choices
+                .iter()
+                .map(|value| stats.equals_selectivity(value))
+                .sum(),
+            ScalarQuery::NotIn(choices) => {
+                if choices.iter().any(|value| value.is_null()) {
+                    0.0
+                } else {
+                    stats.non_null_fraction()
+                        - choices
+                            .iter()
+                            .map(|value| stats.equals_selectivity(value))
+                            .sum::<f32>()
+                }
+            }
+            ScalarQuery::Range(lower, upper) => stats.range_selectivity(lower, upper),
+            ScalarQuery::RangeOrNull(lower, upper) => {
+                stats.range_selectivity(lower, upper) + null_fraction
+            }
+            ScalarQuery::Regex(_) => DEFAULT_SELECTIVITY * stats.non_null_fraction(),
+            ScalarQuery::StartsWith(prefix) => {
+                match prefix_range(prefix, self.values().data_type()) {
+                    Some((lower, upper)) => stats.range_selectivity(&lower, &upper),
+                    None => DEFAULT_SELECTIVITY * stats.non_null_fraction(),
+                }
+            }
+        };
+        Ok(selectivity.clamp(0.0, 1.0))
+    }
+
     fn values(&self) -> &ArrayRef {
         self.data.column(0)
     }
@@ -50,10 +876,189 @@ impl FlatIndex {
     fn ids(&self) -> &ArrayRef {
         self.data.column(1)
     }
+
+    /// Search the index, returning the matching values alongside their row ids
+    ///
+    /// The returned values array is aligned with the row ids, i.e. the i-th value is the
+    /// value of the i-th row id.  This saves a second lookup when the caller needs both.
+    /// The row ids are in the same order as [`ScalarIndex::search`] returns them.
+    pub fn search_with_values(&self, query: &ScalarQuery) -> Result<(UInt64Array, ArrayRef)> {
+        if let Some(range) = self.sorted_range(query)? {
+            let matches = self.data.slice(range.start, range.len());
+            return Ok((
+                matches.column(1).as_primitive::<UInt64Type>().clone(),
+                matches.column(0).clone(),
+            ));
+        }
+        let predicate = build_predicate(&self.data, query)?;
+        let filtered = arrow_select::filter::filter_record_batch(&self.data, &predicate)?;
+        let row_ids = filtered
+            .column(1)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .expect("Result of arrow_select::filter::filter did not match input type")
+            .clone();
+        Ok((row_ids, filtered.column(0).clone()))
+    }
+
+    /// Count the rows that match `query`
+    ///
+    /// This is the length of the result of [`ScalarIndex::search`], but the matching row ids
+    /// are never materialized, which is all a `COUNT(*)` needs.
+    pub fn count(&self, query: &ScalarQuery) -> Result<u64> {
+        if let Some(range) = self.sorted_range(query)? {
+            return Ok(range.len() as u64);
+        }
+        let predicate = build_predicate(&self.data, query)?;
+        Ok(predicate.true_count() as u64)
+    }
+
+    /// Search the index, returning at most `limit` matching row ids
+    ///
+    /// The index is evaluated in chunks of [`SEARCH_LIMITED_CHUNK_SIZE`] rows and the search
+    /// stops as soon as `limit` matches are found, which is useful for existence checks and
+    /// `LIMIT` queries.  The matches are the first `limit` row ids that [`ScalarIndex::search`]
+    /// would return.
+    pub fn search_limited(&self, query: &ScalarQuery, limit: usize) -> Result<UInt64Array> {
+        let num_rows = self.data.num_rows();
+        let mut row_ids = Vec::with_capacity(min(limit, num_rows));
+        let mut start = 0;
+        while start < num_rows && row_ids.len() < limit {
+            let len = min(SEARCH_LIMITED_CHUNK_SIZE, num_rows - start);
+            let chunk = search_batch(&self.data.slice(start, len), query)?;
+            let remaining = limit - row_ids.len();
+            row_ids.extend(chunk.values().iter().take(remaining));
+            start += len;
+        }
+        Ok(UInt64Array::from(row_ids))
+    }
+
+    /// Search an evenly spaced sample of `sample_size` rows of the index
+    ///
+    /// This is cheaper than a full search on large indices but may miss matches, so the
+    /// result is [`SearchResult::Approximate`], with the number of matches extrapolated to
+    /// the whole index.  If `sample_size` covers the whole index the result is
+    /// [`SearchResult::Exact`].
+    pub fn search_sampled(&self, query: &ScalarQuery, sample_size: usize) -> Result<SearchResult> {
+        let num_rows = self.data.num_rows();
+        if sample_size >= num_rows {
+            return Ok(SearchResult::Exact(search_batch(&self.data, query)?));
+        }
+        if sample_size == 0 {
+            return Err(Error::invalid_input(
+                "Flat index search sample size must be greater than 0",
+                location!(),
+            ));
+        }
+        let positions = UInt32Array::from_iter_values(
+            (0..sample_size).map(|i| (i * num_rows / sample_size) as u32),
+        );
+        let sample = self.data.take(&positions)?;
+        let row_ids = search_batch(&sample, query)?;
+        let estimated_matches =
+            (row_ids.len() as f64 * num_rows as f64 / sample_size as f64).round() as u64;
+        Ok(SearchResult::Approximate {
+            row_ids,
+            estimated_matches,
+        })
+    }
+
+    /// Fetch the indexed values of the given row ids
+    ///
+    /// The result is aligned with `ids`, i.e. the i-th value is the value of the i-th id.
+    /// Ids that are not in the index get a null value.  If the row ids of the index are
+    /// sorted they are binary searched, otherwise a hash map of the row ids is built.
+    pub fn lookup_values(&self, ids: &[u64]) -> Result<ArrayRef> {
+        let row_ids = self.ids().as_primitive::<UInt64Type>().values();
+        let is_sorted = row_ids.windows(2).all(|w| w[0] <= w[1]);
+        let positions = if is_sorted {
+            UInt64Array::from_iter(
+                ids.iter()
+                    .map(|id| row_ids.binary_search(id).ok().map(|pos| pos as u64)),
+            )
+        } else {
+            let id_to_pos = row_ids
+                .iter()
+                .enumerate()
+                .map(|(pos, id)| (*id, pos as u64))
+                .collect::<HashMap<_, _>>();
+            UInt64Array::from_iter(ids.iter().map(|id| id_to_pos.get(id).copied()))
+        };
+        Ok(arrow_select::take::take(self.values(), &positions, None)?)
+    }
+
+    /// Export the index data as a plain [`RecordBatch`]
+    ///
+    /// The returned batch always has the schema `values: <value type>, row_ids: UInt64`
+    /// (both nullable), regardless of the column names the index was trained with, so it
+    /// can be written out as Parquet or handed to other query engines as-is.
+    pub fn to_batch(&self) -> RecordBatch {
+        let schema = FlatIndexMetadata::new(self.values().data_type().clone()).schema;
+        RecordBatch::try_new(schema, vec![self.values().clone(), self.ids().clone()])
+            .expect("flat index data should always match the flat index schema")
+    }
+
+    /// Search the index, streaming the matching value/row id pairs
+    ///
+    /// The index is searched lazily, [`DEFAULT_SEARCH_CHUNK_SIZE`] rows at a time, and
+    /// each batch has the same schema as the index data.  This lets the index be used as
+    /// the input of a physical plan without materializing all the results.
+    pub fn search_stream(&self, query: &ScalarQuery) -> SendableRecordBatchStream {
+        let data = self.data.clone();
+        let schema = data.schema();
+        let query = query.clone();
+        let num_rows = data.num_rows();
+        let batches =
+            stream::iter((0..num_rows).step_by(DEFAULT_SEARCH_CHUNK_SIZE)).map(move |start| {
+                let chunk = data.slice(start, min(DEFAULT_SEARCH_CHUNK_SIZE, num_rows - start));
+                let predicate = build_predicate(&chunk, &query)?;
+                arrow_select::filter::filter_record_batch(&chunk, &predicate)
+                    .map_err(DataFusionError::from)
+            });
+        Box::pin(RecordBatchStreamAdapter::new(schema, batches))
+    }
+
+    /// Search a flat index in `store` without loading all of it into memory
+    ///
+    /// The index file is read `chunk_size` rows at a time and the query is evaluated
+    /// against each chunk, so peak memory is bounded by the chunk size instead of the
+    /// size of the index.  The result is the same as [`ScalarIndex::search`] on the
+    /// fully loaded index.
+    pub async fn search_streaming(
+        store: &dyn IndexStore,
+        query: &ScalarQuery,
+        chunk_size: usize,
+    ) -> Result<UInt64Array> {
+        if chunk_size == 0 {
+            return Err(Error::invalid_input(
+                "Flat index search chunk size must be greater than 0",
+                location!(),
+            ));
+        }
+        let reader = store.open_index_file(FLAT_INDEX_DATA_NAME).await?;
+        let num_rows = reader.num_rows();
+        let mut row_ids = Vec::new();
+        for start in (0..num_rows).step_by(chunk_size) {
+            let end = min(start + chunk_size, num_rows);
+            let chunk = reader.read_range(start..end).await?;
+            row_ids.extend(search_batch(&chunk, query)?.values());
+        }
+        Ok(UInt64Array::from(row_ids))
+    }
 }
 
 fn remap_batch(batch: RecordBatch, mapping: &HashMap<u64, Option<u64>>) -> Result<RecordBatch> {
-    let row_ids = batch.column(1).as_primitive::<UInt64Type>();
+    remap_batch_with_ids(batch, 1, mapping)
+}
+
+/// Remap the row ids in column `id_idx` of `batch`, the other columns are kept for the
+/// rows that are not removed
+fn remap_batch_with_ids(
+    batch: RecordBatch,
+    id_idx: usize,
+    mapping: &HashMap<u64, Option<u64>>,
+) -> Result<RecordBatch> {
+    let row_ids = batch.column(id_idx).as_primitive::<UInt64Type>();
     let val_idx_and_new_id = row_ids
         .values()
         .iter()
@@ -74,11 +1079,245 @@ fn remap_batch(batch: RecordBatch, mapping: &HashMap<u64, Option<u64>>) -> Resul
             .into_iter()
             .map(|(val_idx, _)| val_idx as u64),
     );
-    let new_vals = arrow_select::take::take(batch.column(0), &new_val_indices, None)?;
-    Ok(RecordBatch::try_new(
-        batch.schema().clone(),
-        vec![new_vals, new_ids],
-    )?)
+    let columns = batch
+        .columns()
+        .iter()
+        .enumerate()
+        .map(|(idx, column)| {
+            if idx == id_idx {
+                Ok(new_ids.clone() as ArrayRef)
+            } else {
+                Ok(arrow_select::take::take(column, &new_val_indices, None)?)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let remapped = RecordBatch::try_new(batch.schema().clone(), columns)?;
+    // Rows may have been removed, so a stored summary no longer applies
+    with_schema_metadata(remapped, |metadata| {
+        metadata.remove(SUMMARY_META_KEY);
+    })
+}
+
+/// Replace the schema metadata of `batch` with the result of `update` on it
+fn with_schema_metadata(
+    batch: RecordBatch,
+    update: impl FnOnce(&mut HashMap<String, String>),
+) -> Result<RecordBatch> {
+    let schema = batch.schema();
+    let mut metadata = schema.metadata().clone();
+    update(&mut metadata);
+    // `RecordBatch::with_schema` can only add metadata, not change or remove it
+    let schema = Arc::new(Schema::new_with_metadata(schema.fields().clone(), metadata));
+    Ok(RecordBatch::try_new(schema, batch.columns().to_vec())?)
+}
+
+/// The metadata key of the "values" field that records the [`FlatValueTransform`] of an index
+pub const VALUE_TRANSFORM_META_KEY: &str = "lance:flat:value_transform";
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// A transform applied to the values of a flat index before they are indexed
+///
+/// The values in the query are transformed in the same way, so a query matches on the
+/// transformed values, e.g. with [`FlatValueTransform::Lowercase`] a search for "Apple"
+/// finds "APPLE" and "apple".  Regex patterns are not transformed and are matched against
+/// the transformed values.
+///
+/// Only built-in transforms are supported so that the transform can be stored with the
+/// index, in the metadata of the "values" field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlatValueTransform {
+    /// Lowercase string values
+    Lowercase,
+    /// Truncate timestamp and date values to the start of their day (in UTC)
+    TruncateToDay,
+}
+
+impl FlatValueTransform {
+    /// The name the transform is stored as
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Lowercase => "lowercase",
+            Self::TruncateToDay => "truncate_to_day",
+        }
+    }
+
+    /// Parse a transform from its [`Self::name`]
+    pub fn try_from_name(name: &str) -> Result<Self> {
+        match name {
+            "lowercase" => Ok(Self::Lowercase),
+            "truncate_to_day" => Ok(Self::TruncateToDay),
+            _ => Err(Error::Index {
+                message: format!("Unknown flat index value transform '{}'", name),
+                location: location!(),
+            }),
+        }
+    }
+
+    /// The transform recorded in the metadata of a "values" field, if any
+    pub(crate) fn from_field(field: &Field) -> Result<Option<Self>> {
+        field
+            .metadata()
+            .get(VALUE_TRANSFORM_META_KEY)
+            .map(|name| Self::try_from_name(name))
+            .transpose()
+    }
+
+    fn unsupported_type(&self, data_type: &DataType) -> Error {
+        Error::invalid_input(
+            format!(
+                "Flat index value transform '{}' does not support {} values",
+                self.name(),
+                data_type
+            ),
+            location!(),
+        )
+    }
+
+    /// Transform an array of values
+    pub fn transform_array(&self, values: &ArrayRef) -> Result<ArrayRef> {
+        match (self, values.data_type()) {
+            (Self::Lowercase, DataType::Utf8) => Ok(Arc::new(
+                values
+                    .as_string::<i32>()
+                    .iter()
+                    .map(|v| v.map(str::to_lowercase))
+                    .collect::<StringArray>(),
+            )),
+            (Self::Lowercase, DataType::LargeUtf8) => Ok(Arc::new(
+                values
+                    .as_string::<i64>()
+                    .iter()
+                    .map(|v| v.map(str::to_lowercase))
+                    .collect::<LargeStringArray>(),
+            )),
+            (Self::TruncateToDay, DataType::Date32) => Ok(values.clone()),
+            (Self::TruncateToDay, DataType::Date64) => Ok(Arc::new(truncate_values::<Date64Type>(
+                values,
+                MILLIS_PER_DAY,
+            ))),
+            (Self::TruncateToDay, DataType::Timestamp(unit, tz)) => {
+                let units_per_day = units_per_day(unit);
+                let truncated: ArrayRef = match unit {
+                    TimeUnit::Second => Arc::new(
+                        truncate_values::<TimestampSecondType>(values, units_per_day)
+                            .with_timezone_opt(tz.clone()),
+                    ),
+                    TimeUnit::Millisecond => Arc::new(
+                        truncate_values::<TimestampMillisecondType>(values, units_per_day)
+                            .with_timezone_opt(tz.clone()),
+                    ),
+                    TimeUnit::Microsecond => Arc::new(
+                        truncate_values::<TimestampMicrosecondType>(values, units_per_day)
+                            .with_timezone_opt(tz.clone()),
+                    ),
+                    TimeUnit::Nanosecond => Arc::new(
+                        truncate_values::<TimestampNanosecondType>(values, units_per_day)
+                            .with_timezone_opt(tz.clone()),
+                    ),
+                };
+                Ok(truncated)
+            }
+            (_, data_type) => Err(self.unsupported_type(data_type)),
+        }
+    }
+
+    /// Transform a single value, nulls are left as they are
+    pub fn transform_scalar(&self, value: &ScalarValue) -> Result<ScalarValue> {
+        let truncate = |v: &Option<i64>, units_per_day: i64| {
+            v.map(|v| v.div_euclid(units_per_day) * units_per_day)
+        };
+        match (self, value) {
+            (Self::Lowercase, ScalarValue::Utf8(v)) => {
+                Ok(ScalarValue::Utf8(v.as_deref().map(str::to_lowercase)))
+            }
+            (Self::Lowercase, ScalarValue::LargeUtf8(v)) => {
+                Ok(ScalarValue::LargeUtf8(v.as_deref().map(str::to_lowercase)))
+            }
+            (Self::TruncateToDay, ScalarValue::Date32(_)) => Ok(value.clone()),
+            (Self::TruncateToDay, ScalarValue::Date64(v)) => {
+                Ok(ScalarValue::Date64(truncate(v, MILLIS_PER_DAY)))
+            }
+            (Self::TruncateToDay, ScalarValue::TimestampSecond(v, tz)) => {
+                Ok(ScalarValue::TimestampSecond(
+                    truncate(v, units_per_day(&TimeUnit::Second)),
+                    tz.clone(),
+                ))
+            }
+            (Self::TruncateToDay, ScalarValue::TimestampMillisecond(v, tz)) => {
+                Ok(ScalarValue::TimestampMillisecond(
+                    truncate(v, units_per_day(&TimeUnit::Millisecond)),
+                    tz.clone(),
+                ))
+            }
+            (Self::TruncateToDay, ScalarValue::TimestampMicrosecond(v, tz)) => {
+                Ok(ScalarValue::TimestampMicrosecond(
+                    truncate(v, units_per_day(&TimeUnit::Microsecond)),
+                    tz.clone(),
+                ))
+            }
+            (Self::TruncateToDay, ScalarValue::TimestampNanosecond(v, tz)) => {
+                Ok(ScalarValue::TimestampNanosecond(
+                    truncate(v, units_per_day(&TimeUnit::Nanosecond)),
+                    tz.clone(),
+                ))
+            }
+            (_, value) if value.is_null() => Ok(value.clone()),
+            (_, value) => Err(self.unsupported_type(&value.data_type())),
+        }
+    }
+
+    /// Transform the values of a query so it can be evaluated against transformed values
+    pub fn transform_query(&self, query: &ScalarQuery) -> Result<ScalarQuery> {
+        let transform_bound = |bound: &Bound<ScalarValue>| -> Result<Bound<ScalarValue>> {
+            Ok(match bound {
+                Bound::Included(value) => Bound::Included(self.transform_scalar(value)?),
+                Bound::Excluded(value) => Bound::Excluded(self.transform_scalar(value)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+        let transform_all = |values: &[ScalarValue]| -> Result<Vec<ScalarValue>> {
+            values.iter().map(|v| self.transform_scalar(v)).collect()
+        };
+        Ok(match query {
+            ScalarQuery::Range(lower, upper) => {
+                ScalarQuery::Range(transform_bound(lower)?, transform_bound(upper)?)
+            }
+            ScalarQuery::RangeOrNull(lower, upper) => {
+                ScalarQuery::RangeOrNull(transform_bound(lower)?, transform_bound(upper)?)
+            }
+            ScalarQuery::IsIn(values) => ScalarQuery::IsIn(transform_all(values)?),
+            ScalarQuery::NotIn(values) => ScalarQuery::NotIn(transform_all(values)?),
+            ScalarQuery::Equals(value) => ScalarQuery::Equals(self.transform_scalar(value)?),
+            ScalarQuery::NotEquals(value) => ScalarQuery::NotEquals(self.transform_scalar(value)?),
+            ScalarQuery::IsNotDistinctFrom(value) => {
+                ScalarQuery::IsNotDistinctFrom(self.transform_scalar(value)?)
+            }
+            ScalarQuery::StartsWith(prefix) => match self {
+                Self::Lowercase => ScalarQuery::StartsWith(prefix.to_lowercase()),
+                Self::TruncateToDay => return Err(self.unsupported_type(&DataType::Utf8)),
+            },
+            ScalarQuery::IsNull() | ScalarQuery::Regex(_) => query.clone(),
+        })
+    }
+}
+
+fn units_per_day(unit: &TimeUnit) -> i64 {
+    match unit {
+        TimeUnit::Second => MILLIS_PER_DAY / 1000,
+        TimeUnit::Millisecond => MILLIS_PER_DAY,
+        TimeUnit::Microsecond => MILLIS_PER_DAY * 1000,
+        TimeUnit::Nanosecond => MILLIS_PER_DAY * 1_000_000,
+    }
+}
+
+fn truncate_values<T: ArrowPrimitiveType<Native = i64>>(
+    values: &ArrayRef,
+    units_per_day: i64,
+) -> PrimitiveArray<T> {
+    values
+        .as_primitive::<T>()
+        .unary(|v| v.div_euclid(units_per_day) * units_per_day)
 }
 
 /// Trains a flat index from a record batch of values & ids by simply storing the batch
@@ -87,6 +1326,7 @@ fn remap_batch(batch: RecordBatch, mapping: &HashMap<u64, Option<u64>>) -> Resul
 #[derive(Debug)]
 pub struct FlatIndexMetadata {
     schema: Arc<Schema>,
+    value_transform: Option<FlatValueTransform>,
 }
 
 impl FlatIndexMetadata {
@@ -95,7 +1335,38 @@ impl FlatIndexMetadata {
             Field::new("values", value_type, true),
             Field::new("row_ids", DataType::UInt64, true),
         ]));
-        Self { schema }
+        Self {
+            schema,
+            value_transform: None,
+        }
+    }
+
+    /// Index the values with `transform` applied to them
+    ///
+    /// The transform is recorded in the schema of the trained data, so the queries against
+    /// the loaded index are transformed in the same way.
+    pub fn with_value_transform(self, transform: FlatValueTransform) -> Self {
+        let fields = self
+            .schema
+            .fields()
+            .iter()
+            .enumerate()
+            .map(|(idx, field)| {
+                let field = field.as_ref().clone();
+                if idx == 0 {
+                    field.with_metadata(HashMap::from([(
+                        VALUE_TRANSFORM_META_KEY.to_string(),
+                        transform.name().to_string(),
+                    )]))
+                } else {
+                    field
+                }
+            })
+            .collect::<Vec<_>>();
+        Self {
+            schema: Arc::new(Schema::new(fields)),
+            value_transform: Some(transform),
+        }
     }
 }
 
@@ -106,12 +1377,14 @@ impl BTreeSubIndex for FlatIndexMetadata {
     }
 
     async fn train(&self, batch: RecordBatch) -> Result<RecordBatch> {
-        // The data source may not call the columns "values" and "row_ids" so we need to replace
-        // the schema
-        Ok(RecordBatch::try_new(
-            self.schema.clone(),
-            vec![batch.column(0).clone(), batch.column(1).clone()],
-        )?)
+        to_index_batch(&self.schema, self.value_transform, &batch)
+    }
+
+    fn transform_query(&self, query: &ScalarQuery) -> Result<ScalarQuery> {
+        match self.value_transform {
+            Some(transform) => transform.transform_query(query),
+            None => Ok(query.clone()),
+        }
     }
 
     async fn load_subindex(&self, serialized: RecordBatch) -> Result<Arc<dyn ScalarIndex>> {
@@ -147,10 +1420,20 @@ impl Index for FlatIndex {
         IndexType::Scalar
     }
 
+    /// The statistics come from the [`FlatIndex::summary`], which is stored by
+    /// [`ScalarIndex::update`], so they don't need a pass over the values
     fn statistics(&self) -> Result<serde_json::Value> {
-        Ok(serde_json::json!({
+        let mut stats = serde_json::json!({
             "num_values": self.data.num_rows(),
-        }))
+            "num_nulls": self.values().null_count(),
+        });
+        // Not all types can be compared or hashed, the summary is left out for those
+        if let Ok(summary) = self.summary() {
+            stats["min"] = scalar_to_json(&summary.min);
+            stats["max"] = scalar_to_json(&summary.max);
+            stats["approx_distinct"] = (summary.distinct.estimate().round() as u64).into();
+        }
+        Ok(stats)
     }
 
     async fn calculate_included_frags(&self) -> Result<RoaringBitmap> {
@@ -166,188 +1449,2280 @@ impl Index for FlatIndex {
     }
 }
 
+/// The byte a Bloom filter records its value transform as
+fn bloom_filter_transform_tag(transform: Option<FlatValueTransform>) -> u8 {
+    match transform {
+        None => 0,
+        Some(FlatValueTransform::Lowercase) => 1,
+        Some(FlatValueTransform::TruncateToDay) => 2,
+    }
+}
+
+fn bloom_filter_transform_from_tag(tag: u8) -> Result<Option<FlatValueTransform>> {
+    match tag {
+        0 => Ok(None),
+        1 => Ok(Some(FlatValueTransform::Lowercase)),
+        2 => Ok(Some(FlatValueTransform::TruncateToDay)),
+        _ => Err(Error::invalid_input(
+            format!("Invalid Bloom filter value transform {}", tag),
+            location!(),
+        )),
+    }
+}
+
+/// Call `f` with the bytes of each non-null value, as they are hashed by Bloom filters and
+/// the distinct count sketch of a [`FlatIndexSummary`]
+///
+/// Fixed-width values are hashed as their native (little-endian) bytes, strings as their
+/// UTF-8 bytes and binary values as they are.  Unlike the [`RowConverter`] format this does
+/// not depend on the arrow release, so the hashes can be stored or shared between nodes.
+fn for_each_value_bytes(values: &dyn Array, mut f: impl FnMut(&[u8])) -> Result<()> {
+    match values.data_type() {
+        DataType::Utf8 => values
+            .as_string::<i32>()
+            .iter()
+            .flatten()
+            .for_each(|value| f(value.as_bytes())),
+        DataType::LargeUtf8 => values
+            .as_string::<i64>()
+            .iter()
+            .flatten()
+            .for_each(|value| f(value.as_bytes())),
+        DataType::Binary => values.as_binary::<i32>().iter().flatten().for_each(f),
+        DataType::LargeBinary => values.as_binary::<i64>().iter().flatten().for_each(f),
+        DataType::Boolean => values
+            .as_boolean()
+            .iter()
+            .flatten()
+            .for_each(|value| f(&[value as u8])),
+        data_type => {
+            let width = data_type.primitive_width().ok_or_else(|| {
+                Error::invalid_input(format!("Can not hash {} values", data_type), location!())
+            })?;
+            let data = values.to_data();
+            let bytes = &data.buffers()[0].as_slice()
+                [data.offset() * width..(data.offset() + data.len()) * width];
+            bytes
+                .chunks_exact(width)
+                .enumerate()
+                .filter(|(idx, _)| values.is_valid(*idx))
+                .for_each(|(_, value)| f(value));
+        }
+    }
+    Ok(())
+}
+
+/// The positions of the `num_hashes` bits of a Bloom filter of `num_bits` bits set for `bytes`
+///
+/// The positions come from two hashes, as `h1 + i * h2`.  The hashes must not change between
+/// releases since the filters are shared between nodes, so this is FNV-1a and not
+/// `DefaultHasher`.
+fn bloom_filter_bits(bytes: &[u8], num_hashes: u8, num_bits: usize) -> impl Iterator<Item = usize> {
+    let h1 = fnv1a(bytes);
+    // Mix h1 to get an independent second hash, odd so that it never maps all positions to
+    // the same bit.
+    let h2 = splitmix64(h1) | 1;
+    (0..num_hashes as u64)
+        .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits as u64) as usize)
+}
+
+/// The FNV-1a hash of `bytes`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// The splitmix64 finalizer, which spreads every bit of `hash` over all the bits
+fn splitmix64(hash: u64) -> u64 {
+    let mut hash = hash ^ (hash >> 30);
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// A HyperLogLog sketch of the distinct non-null `values`
+///
+/// The values are hashed with a stable hash, so sketches of different batches can be merged,
+/// including sketches stored by an earlier process.
+fn distinct_sketch(values: &dyn Array, precision: u8) -> Result<HyperLogLog> {
+    let mut sketch = HyperLogLog::try_new(precision)?;
+    for_each_value_bytes(values, |bytes| sketch.add_hash(splitmix64(fnv1a(bytes))))?;
+    Ok(sketch)
+}
+
+/// The display form of a scalar for the index statistics, null for nulls
+fn scalar_to_json(value: &ScalarValue) -> serde_json::Value {
+    if value.is_null() {
+        serde_json::Value::Null
+    } else {
+        serde_json::Value::String(value.to_string())
+    }
+}
+
+fn is_marked_sorted(schema: &Schema) -> bool {
+    schema
+        .metadata()
+        .get(SORTED_META_KEY)
+        .map(|sorted| sorted == "true")
+        .unwrap_or(false)
+}
+
+/// The first position in `range` of the sorted `values` where `pred` is false
+///
+/// `pred` must be true for a prefix of the range and false for the rest.
+fn partition_point(
+    values: &ArrayRef,
+    range: std::ops::Range<usize>,
+    pred: impl Fn(&ScalarValue) -> bool,
+) -> Result<usize> {
+    let (mut low, mut high) = (range.start, range.end);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if pred(&ScalarValue::try_from_array(values, mid)?) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
+
+/// Convert a batch of (value, row id) columns to the index `schema`
+///
+/// The data source may not call the columns "values" and "row_ids" so we need to replace
+/// the schema.  The values are transformed with `transform`, if any.
+fn to_index_batch(
+    schema: &Arc<Schema>,
+    transform: Option<FlatValueTransform>,
+    batch: &RecordBatch,
+) -> Result<RecordBatch> {
+    let values = match transform {
+        Some(transform) => transform.transform_array(batch.column(0))?,
+        None => batch.column(0).clone(),
+    };
+    Ok(RecordBatch::try_new(
+        schema.clone(),
+        vec![values, batch.column(1).clone()],
+    )?)
+}
+
+/// Evaluate a scalar query against a batch of value/row-id pairs
+///
+/// Since all the values are in memory we can use basic arrow-rs compute
+/// functions to satisfy scalar queries.
+///
+/// If the values were indexed with a [`FlatValueTransform`] the query is transformed first.
+fn build_predicate(data: &RecordBatch, query: &ScalarQuery) -> Result<BooleanArray> {
+    match FlatValueTransform::from_field(data.schema().field(0))? {
+        Some(transform) => build_untransformed_predicate(data, &transform.transform_query(query)?),
+        None => build_untransformed_predicate(data, query),
+    }
+}
+
+fn build_untransformed_predicate(data: &RecordBatch, query: &ScalarQuery) -> Result<BooleanArray> {
+    let values = data.column(0);
+    Ok(match query {
+        ScalarQuery::Equals(value) => arrow_ord::cmp::eq(values, &value.to_scalar()?)?,
+        // Comparisons with nulls are null, which the search filters out
+        ScalarQuery::NotEquals(value) => arrow_ord::cmp::neq(values, &value.to_scalar()?)?,
+        ScalarQuery::IsNull() => arrow::compute::is_null(values)?,
+        ScalarQuery::IsNotDistinctFrom(value) => {
+            if value.is_null() {
+                arrow::compute::is_null(values)?
+            } else {
+                arrow_ord::cmp::not_distinct(values, &value.to_scalar()?)?
+            }
+        }
+        ScalarQuery::IsIn(choices) => {
+            let matches = in_list_match(data, choices, false)?;
+            // Null values are null rather than false in the result.  Unless null is one of
+            // the choices they are not in the set, so make them false, the result may be
+            // combined with other predicates downstream.
+            if matches.null_count() > 0 && !choices.iter().any(|choice| choice.is_null()) {
+                arrow_select::filter::prep_null_mask_filter(&matches)
+            } else {
+                matches
+            }
+        }
+        ScalarQuery::NotIn(choices) => in_list_match(data, choices, true)?,
+        ScalarQuery::Regex(pattern) => regex_match(values, pattern)?,
+        ScalarQuery::StartsWith(prefix) => prefix_match(values, prefix)?,
+        ScalarQuery::RangeOrNull(Bound::Unbounded, Bound::Unbounded) => {
+            BooleanArray::from(vec![true; values.len()])
+        }
+        ScalarQuery::RangeOrNull(lower_bound, upper_bound) => {
+            let in_range = build_untransformed_predicate(
+                data,
+                &ScalarQuery::Range(lower_bound.clone(), upper_bound.clone()),
+            )?;
+            // The range is null for null values, so use Kleene logic to make `null OR true` true
+            arrow::compute::or_kleene(&in_range, &arrow::compute::is_null(values)?)?
+        }
+        ScalarQuery::Range(lower_bound, upper_bound) => match (lower_bound, upper_bound) {
+            (Bound::Unbounded, Bound::Unbounded) => {
+                panic!("Scalar range query received with no upper or lower bound")
+            }
+            (Bound::Unbounded, Bound::Included(upper)) => {
+                arrow_ord::cmp::lt_eq(values, &upper.to_scalar()?)?
+            }
+            (Bound::Unbounded, Bound::Excluded(upper)) => {
+                arrow_ord::cmp::lt(values, &upper.to_scalar()?)?
+            }
+            (Bound::Included(lower), Bound::Unbounded) => {
+                arrow_ord::cmp::gt_eq(values, &lower.to_scalar()?)?
+            }
+            (Bound::Included(lower), Bound::Included(upper)) => arrow::compute::and(
+                &arrow_ord::cmp::gt_eq(values, &lower.to_scalar()?)?,
+                &arrow_ord::cmp::lt_eq(values, &upper.to_scalar()?)?,
+            )?,
+            (Bound::Included(lower), Bound::Excluded(upper)) => arrow::compute::and(
+                &arrow_ord::cmp::gt_eq(values, &lower.to_scalar()?)?,
+                &arrow_ord::cmp::lt(values, &upper.to_scalar()?)?,
+            )?,
+            (Bound::Excluded(lower), Bound::Unbounded) => {
+                arrow_ord::cmp::gt(values, &lower.to_scalar()?)?
+            }
+            (Bound::Excluded(lower), Bound::Included(upper)) => arrow::compute::and(
+                &arrow_ord::cmp::gt(values, &lower.to_scalar()?)?,
+                &arrow_ord::cmp::lt_eq(values, &upper.to_scalar()?)?,
+            )?,
+            (Bound::Excluded(lower), Bound::Excluded(upper)) => arrow::compute::and(
+                &arrow_ord::cmp::gt(values, &lower.to_scalar()?)?,
+                &arrow_ord::cmp::lt(values, &upper.to_scalar()?)?,
+            )?,
+        },
+    })
+}
+
+/// Evaluate `values IN (choices)`, or `values NOT IN (choices)` if `negated`
+///
+/// The result follows SQL three-valued logic: it is null for null values, and for values
+/// that are not found if `choices` contains a null.
+fn in_list_match(
+    data: &RecordBatch,
+    choices: &[ScalarValue],
+    negated: bool,
+) -> Result<BooleanArray> {
+    let choices = choices
+        .iter()
+        .map(|val| lit(val.clone()))
+        .collect::<Vec<_>>();
+    let in_list_expr = in_list(
+        Arc::new(Column::new("values", 0)),
+        choices,
+        &negated,
+        &data.schema(),
+    )?;
+    let result_col = in_list_expr.evaluate(data)?;
+    Ok(result_col
+        .into_array(data.num_rows())?
+        .as_any()
+        .downcast_ref::<BooleanArray>()
+        .expect("InList evaluation should return boolean array")
+        .clone())
+}
+
+/// Match string values that start with `prefix`, nulls never match
+fn prefix_match(values: &ArrayRef, prefix: &str) -> Result<BooleanArray> {
+    let matches = match values.data_type() {
+        DataType::Utf8 => arrow::compute::kernels::comparison::starts_with(
+            values,
+            &StringArray::new_scalar(prefix),
+        ),
+        DataType::LargeUtf8 => arrow::compute::kernels::comparison::starts_with(
+            values,
+            &LargeStringArray::new_scalar(prefix),
+        ),
+        _ => {
+            return Err(Error::invalid_input(
+                format!(
+                    "Prefix query requires a Utf8 or LargeUtf8 column, got {}",
+                    values.data_type()
+                ),
+                location!(),
+            ))
+        }
+    };
+    Ok(matches?)
+}
+
+/// Match string values against a regular expression, nulls never match
+fn regex_match(values: &ArrayRef, pattern: &str) -> Result<BooleanArray> {
+    let matches = match values.data_type() {
+        DataType::Utf8 => regexp_is_match_utf8_scalar(values.as_string::<i32>(), pattern, None),
+        DataType::LargeUtf8 => {
+            regexp_is_match_utf8_scalar(values.as_string::<i64>(), pattern, None)
+        }
+        _ => {
+            return Err(Error::invalid_input(
+                format!(
+                    "Regex query requires a Utf8 or LargeUtf8 column, got {}",
+                    values.data_type()
+                ),
+                location!(),
+            ))
+        }
+    };
+    matches.map_err(|err| {
+        Error::invalid_input(
+            format!("Invalid regex pattern '{}': {}", pattern, err),
+            location!(),
+        )
+    })
+}
+
+/// Return the row ids of the batch that satisfy the query, like [search_batch], but
+/// evaluating the query on chunks of `rows_per_chunk` rows in parallel
+///
+/// The row ids are in the same order as [search_batch] returns them.
+fn search_batch_parallel(
+    data: &RecordBatch,
+    query: &ScalarQuery,
+    rows_per_chunk: usize,
+) -> Result<UInt64Array> {
+    let chunks = (0..data.num_rows())
+        .step_by(rows_per_chunk)
+        .map(|offset| data.slice(offset, rows_per_chunk.min(data.num_rows() - offset)))
+        .collect::<Vec<_>>();
+    let row_ids = chunks
+        .par_iter()
+        .map(|chunk| search_batch(chunk, query))
+        .collect::<Result<Vec<_>>>()?;
+    let row_ids = row_ids
+        .iter()
+        .map(|ids| ids as &dyn Array)
+        .collect::<Vec<_>>();
+    Ok(arrow_select::concat::concat(&row_ids)?
+        .as_primitive::<UInt64Type>()
+        .clone())
+}
+
+/// Return the row ids of the batch that satisfy the query
+fn search_batch(data: &RecordBatch, query: &ScalarQuery) -> Result<UInt64Array> {
+    let predicate = build_predicate(data, query)?;
+    Ok(arrow_select::filter::filter(data.column(1), &predicate)?
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .expect("Result of arrow_select::filter::filter did not match input type")
+        .clone())
+}
+
 #[async_trait]
 impl ScalarIndex for FlatIndex {
     async fn search(&self, query: &ScalarQuery) -> Result<UInt64Array> {
-        // Since we have all the values in memory we can use basic arrow-rs compute
-        // functions to satisfy scalar queries.
-        let predicate = match query {
-            ScalarQuery::Equals(value) => arrow_ord::cmp::eq(self.values(), &value.to_scalar()?)?,
-            ScalarQuery::IsNull() => arrow::compute::is_null(self.values())?,
-            ScalarQuery::IsIn(values) => {
-                let choices = values
-                    .iter()
-                    .map(|val| lit(val.clone()))
-                    .collect::<Vec<_>>();
-                let in_list_expr = in_list(
-                    Arc::new(Column::new("values", 0)),
-                    choices,
-                    &false,
-                    &self.data.schema(),
-                )?;
-                let result_col = in_list_expr.evaluate(&self.data)?;
-                result_col
-                    .into_array(self.data.num_rows())?
-                    .as_any()
-                    .downcast_ref::<BooleanArray>()
-                    .expect("InList evaluation should return boolean array")
-                    .clone()
-            }
-            ScalarQuery::Range(lower_bound, upper_bound) => match (lower_bound, upper_bound) {
-                (Bound::Unbounded, Bound::Unbounded) => {
-                    panic!("Scalar range query received with no upper or lower bound")
-                }
-                (Bound::Unbounded, Bound::Included(upper)) => {
-                    arrow_ord::cmp::lt_eq(self.values(), &upper.to_scalar()?)?
-                }
-                (Bound::Unbounded, Bound::Excluded(upper)) => {
-                    arrow_ord::cmp::lt(self.values(), &upper.to_scalar()?)?
-                }
-                (Bound::Included(lower), Bound::Unbounded) => {
-                    arrow_ord::cmp::gt_eq(self.values(), &lower.to_scalar()?)?
-                }
-                (Bound::Included(lower), Bound::Included(upper)) => arrow::compute::and(
-                    &arrow_ord::cmp::gt_eq(self.values(), &lower.to_scalar()?)?,
-                    &arrow_ord::cmp::lt_eq(self.values(), &upper.to_scalar()?)?,
-                )?,
-                (Bound::Included(lower), Bound::Excluded(upper)) => arrow::compute::and(
-                    &arrow_ord::cmp::gt_eq(self.values(), &lower.to_scalar()?)?,
-                    &arrow_ord::cmp::lt(self.values(), &upper.to_scalar()?)?,
-                )?,
-                (Bound::Excluded(lower), Bound::Unbounded) => {
-                    arrow_ord::cmp::gt(self.values(), &lower.to_scalar()?)?
-                }
-                (Bound::Excluded(lower), Bound::Included(upper)) => arrow::compute::and(
-                    &arrow_ord::cmp::gt(self.values(), &lower.to_scalar()?)?,
-                    &arrow_ord::cmp::lt_eq(self.values(), &upper.to_scalar()?)?,
-                )?,
-                (Bound::Excluded(lower), Bound::Excluded(upper)) => arrow::compute::and(
-                    &arrow_ord::cmp::gt(self.values(), &lower.to_scalar()?)?,
-                    &arrow_ord::cmp::lt(self.values(), &upper.to_scalar()?)?,
-                )?,
-            },
+        if let Some(row_ids) = self.search_sorted(query)? {
+            return Ok(row_ids);
+        }
+        if self.data.num_rows() > PARALLEL_SEARCH_CHUNK_SIZE {
+            search_batch_parallel(&self.data, query, PARALLEL_SEARCH_CHUNK_SIZE)
+        } else {
+            search_batch(&self.data, query)
+        }
+    }
+
+    // The data is written as a single batch named data.lance, see [`FlatIndex::write`]
+    async fn load(store: Arc<dyn IndexStore>) -> Result<Arc<Self>> {
+        let batches = store.open_index_file(FLAT_INDEX_DATA_NAME).await?;
+        let batch = Self::validate_schema(batches.read_record_batch(0).await?)?;
+        Self::validate_sorted(&batch)?;
+        Ok(Arc::new(Self {
+            data: Arc::new(batch),
+        }))
+    }
+
+    async fn remap(
+        &self,
+        mapping: &HashMap<u64, Option<u64>>,
+        dest_store: &dyn IndexStore,
+    ) -> Result<()> {
+        let remapped = remap_batch((*self.data).clone(), mapping)?;
+        Self::write(remapped, dest_store).await
+    }
+
+    async fn update(
+        &self,
+        new_data: SendableRecordBatchStream,
+        dest_store: &dyn IndexStore,
+    ) -> Result<()> {
+        // The new values are transformed the same way as the indexed values
+        let schema = self.data.schema();
+        let transform = FlatValueTransform::from_field(schema.field(0))?;
+        let new_batches = new_data.try_collect::<Vec<_>>().await?;
+        let mut batches = Vec::with_capacity(new_batches.len() + 1);
+        batches.push(self.data.as_ref().clone());
+        // The summary is maintained as the new values are appended, if the values can be
+        // summarized at all
+        let mut summary = self.summary().ok();
+        for batch in new_batches.iter().filter(|batch| batch.num_rows() > 0) {
+            let batch = to_index_batch(&schema, transform, batch)?;
+            summary = summary.and_then(|summary| {
+                FlatIndexSummary::compute(batch.column(0))
+                    .ok()
+                    .and_then(|new_summary| summary.merge(&new_summary).ok())
+            });
+            batches.push(batch);
+        }
+        let merged = arrow_select::concat::concat_batches(&schema, &batches)?;
+        // Keep a sorted index sorted
+        let merged = if self.is_sorted() {
+            Self::sort_by_value(merged)?
+        } else {
+            merged
         };
-        Ok(arrow_select::filter::filter(self.ids(), &predicate)?
-            .as_any()
-            .downcast_ref::<UInt64Array>()
-            .expect("Result of arrow_select::filter::filter did not match input type")
-            .clone())
+        let merged = with_schema_metadata(merged, |metadata| match &summary {
+            Some(summary) => {
+                metadata.insert(SUMMARY_META_KEY.to_string(), summary.to_metadata());
+            }
+            None => {
+                metadata.remove(SUMMARY_META_KEY);
+            }
+        })?;
+        Self::write(merged, dest_store).await
+    }
+}
+
+/// How [`FlatIndex::load_with_mode`] keeps the index data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlatIndexLoadMode {
+    /// Read all of the data into memory on load
+    #[default]
+    Eager,
+    /// Keep the data in the store, and scan it `rows_per_chunk` rows at a time on every search
+    ///
+    /// This is for indices too large to fit in memory, see [`ChunkedFlatIndex`].
+    Chunked { rows_per_chunk: usize },
+    /// Only keep the row ids of the null values, and read the values on the first query
+    /// that needs them
+    ///
+    /// This is for indices mostly queried for nulls, see [`LazyFlatIndex`].
+    Lazy,
+}
+
+impl FlatIndex {
+    /// Load a flat index from `store`, keeping the data as described by `mode`
+    pub async fn load_with_mode(
+        store: Arc<dyn IndexStore>,
+        mode: FlatIndexLoadMode,
+    ) -> Result<Arc<dyn ScalarIndex>> {
+        Ok(match mode {
+            FlatIndexLoadMode::Eager => Self::load(store).await? as Arc<dyn ScalarIndex>,
+            FlatIndexLoadMode::Chunked { rows_per_chunk } => {
+                ChunkedFlatIndex::load_with_chunk_size(store, rows_per_chunk).await?
+            }
+            FlatIndexLoadMode::Lazy => LazyFlatIndex::load(store).await?,
+        })
+    }
+}
+
+/// A [FlatIndex] that reads its data from the store in chunks during each search
+///
+/// Only the reader is kept open, so the memory needed for a search is bounded by the
+/// chunk size rather than the size of the index.  Each chunk is searched like a [FlatIndex]
+/// and the matching row ids are accumulated.  Remapping and updating load the whole index.
+#[derive(Debug)]
+pub struct ChunkedFlatIndex {
+    store: Arc<dyn IndexStore>,
+    reader: Arc<dyn IndexReader>,
+    rows_per_chunk: usize,
+}
+
+impl ChunkedFlatIndex {
+    /// The default number of rows read at a time
+    pub const DEFAULT_ROWS_PER_CHUNK: usize = 64 * 1024;
+
+    /// Open the flat index in `store`, reading `rows_per_chunk` rows at a time
+    pub async fn load_with_chunk_size(
+        store: Arc<dyn IndexStore>,
+        rows_per_chunk: usize,
+    ) -> Result<Arc<Self>> {
+        if rows_per_chunk == 0 {
+            return Err(Error::invalid_input(
+                "Chunked flat index must read at least one row per chunk",
+                location!(),
+            ));
+        }
+        let reader = store.open_index_file(FLAT_INDEX_DATA_NAME).await?;
+        Ok(Arc::new(Self {
+            store,
+            reader,
+            rows_per_chunk,
+        }))
+    }
+
+    /// The ranges of rows read by a search
+    fn chunk_ranges(&self) -> impl Iterator<Item = std::ops::Range<usize>> {
+        let num_rows = self.reader.num_rows();
+        let rows_per_chunk = self.rows_per_chunk;
+        (0..num_rows)
+            .step_by(rows_per_chunk)
+            .map(move |start| start..min(start + rows_per_chunk, num_rows))
+    }
+
+    async fn read_chunk(&self, range: std::ops::Range<usize>) -> Result<RecordBatch> {
+        FlatIndex::validate_schema(self.reader.read_range(range).await?)
+    }
+
+    async fn full(&self) -> Result<Arc<FlatIndex>> {
+        FlatIndex::load(self.store.clone()).await
+    }
+}
+
+#[async_trait]
+impl Index for ChunkedFlatIndex {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_index(self: Arc<Self>) -> Arc<dyn Index> {
+        self
+    }
+
+    fn index_type(&self) -> IndexType {
+        IndexType::Scalar
+    }
+
+    fn statistics(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "num_values": self.reader.num_rows(),
+            "rows_per_chunk": self.rows_per_chunk,
+        }))
+    }
+
+    async fn calculate_included_frags(&self) -> Result<RoaringBitmap> {
+        let mut frag_ids = RoaringBitmap::new();
+        for range in self.chunk_ranges() {
+            let chunk = self.read_chunk(range).await?;
+            frag_ids |= FlatIndex {
+                data: Arc::new(chunk),
+            }
+            .calculate_included_frags()
+            .await?;
+        }
+        Ok(frag_ids)
+    }
+}
+
+#[async_trait]
+impl ScalarIndex for ChunkedFlatIndex {
+    async fn search(&self, query: &ScalarQuery) -> Result<UInt64Array> {
+        let mut row_ids = Vec::new();
+        for range in self.chunk_ranges() {
+            let chunk = self.read_chunk(range).await?;
+            row_ids.extend(search_batch(&chunk, query)?.values());
+        }
+        Ok(UInt64Array::from(row_ids))
+    }
+
+    async fn load(store: Arc<dyn IndexStore>) -> Result<Arc<Self>> {
+        Self::load_with_chunk_size(store, Self::DEFAULT_ROWS_PER_CHUNK).await
+    }
+
+    async fn remap(
+        &self,
+        mapping: &HashMap<u64, Option<u64>>,
+        dest_store: &dyn IndexStore,
+    ) -> Result<()> {
+        self.full().await?.remap(mapping, dest_store).await
+    }
+
+    async fn update(
+        &self,
+        new_data: SendableRecordBatchStream,
+        dest_store: &dyn IndexStore,
+    ) -> Result<()> {
+        self.full().await?.update(new_data, dest_store).await
+    }
+}
+
+/// A [FlatIndex] that only keeps the null mask of the values in memory until they are needed
+///
+/// `IsNull` queries (and `IsNotDistinctFrom(NULL)`) are answered from the row ids of the null
+/// values, which are computed on load.  The values themselves are loaded from the store on the
+/// first query that depends on them, and kept from then on.  This saves memory for workloads
+/// where most queries only check for nulls.
+///
+/// If the index has a stored [`FlatIndexSummary`] without nulls, loading does not read the
+/// values at all.  Otherwise the values are scanned once, [`DEFAULT_SEARCH_CHUNK_SIZE`] rows
+/// at a time, to find the nulls, so only one chunk of values is in memory at once.
+#[derive(Debug)]
+pub struct LazyFlatIndex {
+    store: Arc<dyn IndexStore>,
+    num_values: usize,
+    null_ids: UInt64Array,
+    full: tokio::sync::OnceCell<Arc<FlatIndex>>,
+}
+
+impl LazyFlatIndex {
+    /// Whether the values have been loaded by a value-dependent query
+    pub fn is_values_loaded(&self) -> bool {
+        self.full.initialized()
+    }
+
+    async fn full(&self) -> Result<&Arc<FlatIndex>> {
+        self.full
+            .get_or_try_init(|| FlatIndex::load(self.store.clone()))
+            .await
+    }
+}
+
+#[async_trait]
+impl Index for LazyFlatIndex {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_index(self: Arc<Self>) -> Arc<dyn Index> {
+        self
+    }
+
+    fn index_type(&self) -> IndexType {
+        IndexType::Scalar
+    }
+
+    fn statistics(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "num_values": self.num_values,
+            "num_nulls": self.null_ids.len(),
+            "values_loaded": self.is_values_loaded(),
+        }))
+    }
+
+    async fn calculate_included_frags(&self) -> Result<RoaringBitmap> {
+        self.full().await?.calculate_included_frags().await
+    }
+}
+
+#[async_trait]
+impl ScalarIndex for LazyFlatIndex {
+    async fn search(&self, query: &ScalarQuery) -> Result<UInt64Array> {
+        match query {
+            ScalarQuery::IsNull() => Ok(self.null_ids.clone()),
+            ScalarQuery::IsNotDistinctFrom(value) if value.is_null() => Ok(self.null_ids.clone()),
+            _ => self.full().await?.search(query).await,
+        }
     }
 
-    // Note that there is no write/train method for flat index at the moment and so it isn't
-    // really possible for this method to be called.  If there was we assume it will write all
-    // data as a single batch named data.lance
     async fn load(store: Arc<dyn IndexStore>) -> Result<Arc<Self>> {
-        let batches = store.open_index_file("data.lance").await?;
-        let batch = batches.read_record_batch(0).await?;
+        let reader = store.open_index_file(FLAT_INDEX_DATA_NAME).await?;
+        let num_values = reader.num_rows();
+        // The schema metadata, with the summary if it was stored, comes with any range
+        let head = FlatIndex::validate_schema(reader.read_range(0..num_values.min(1)).await?)?;
+        let stored_null_count = head
+            .schema()
+            .metadata()
+            .get(SUMMARY_META_KEY)
+            .and_then(|summary| {
+                FlatIndexSummary::from_metadata(summary, head.column(0).data_type())
+            })
+            .filter(|summary| summary.num_rows == num_values)
+            .map(|summary| summary.null_count);
+        let mut null_ids = Vec::with_capacity(stored_null_count.unwrap_or(0));
+        if stored_null_count != Some(0) {
+            for offset in (0..num_values).step_by(DEFAULT_SEARCH_CHUNK_SIZE) {
+                let end = (offset + DEFAULT_SEARCH_CHUNK_SIZE).min(num_values);
+                let chunk = FlatIndex::validate_schema(reader.read_range(offset..end).await?)?;
+                let is_null = arrow::compute::is_null(chunk.column(0))?;
+                let ids = arrow_select::filter::filter(chunk.column(1), &is_null)?;
+                null_ids.extend_from_slice(ids.as_primitive::<UInt64Type>().values());
+            }
+        }
         Ok(Arc::new(Self {
+            store,
+            num_values,
+            null_ids: UInt64Array::from(null_ids),
+            full: tokio::sync::OnceCell::new(),
+        }))
+    }
+
+    async fn remap(
+        &self,
+        mapping: &HashMap<u64, Option<u64>>,
+        dest_store: &dyn IndexStore,
+    ) -> Result<()> {
+        self.full().await?.remap(mapping, dest_store).await
+    }
+
+    async fn update(
+        &self,
+        new_data: SendableRecordBatchStream,
+        dest_store: &dyn IndexStore,
+    ) -> Result<()> {
+        self.full().await?.update(new_data, dest_store).await
+    }
+}
+
+/// The name of the file a composite flat index stores its values and row ids in
+pub const COMPOSITE_FLAT_INDEX_DATA_NAME: &str = "composite_data.lance";
+
+/// Converts batches of several value columns and a row id column to the layout of a
+/// [CompositeFlatIndex]
+#[derive(Debug)]
+pub struct CompositeFlatIndexMetadata {
+    schema: Arc<Schema>,
+}
+
+impl CompositeFlatIndexMetadata {
+    /// The index has a column for each of `value_fields`, followed by the row ids
+    pub fn new(value_fields: Vec<Field>) -> Self {
+        let mut fields = value_fields;
+        fields.push(Field::new("row_ids", DataType::UInt64, true));
+        Self {
+            schema: Arc::new(Schema::new(fields)),
+        }
+    }
+
+    pub fn schema(&self) -> &Arc<Schema> {
+        &self.schema
+    }
+
+    /// Convert a batch of the value columns, followed by the row ids, to the index layout
+    ///
+    /// The columns are matched by position, since the data source may not use the same
+    /// names as the index.
+    pub fn train(&self, batch: &RecordBatch) -> Result<RecordBatch> {
+        Ok(RecordBatch::try_new(
+            self.schema.clone(),
+            batch.columns().to_vec(),
+        )?)
+    }
+}
+
+/// A flat index over several columns
+///
+/// The data has a column for each of the indexed columns, followed by the row ids.  A
+/// [CompositeQuery] is answered by evaluating the query of each column the same way as
+/// [FlatIndex] does, and keeping the rows that match all of them.  This filters on tuples
+/// of values, e.g. `a = 10 AND b IN (1, 2)`, without post-filtering the results of a single
+/// column index.
+#[derive(Debug)]
+pub struct CompositeFlatIndex {
+    data: Arc<RecordBatch>,
+}
+
+impl CompositeFlatIndex {
+    /// Create an index from data in the layout of [CompositeFlatIndexMetadata::train]
+    pub fn try_new(data: RecordBatch) -> Result<Self> {
+        if data.num_columns() < 2 {
+            return Err(Error::Index {
+                message: format!(
+                    "Composite flat index data must have value columns and a row id column, got {} columns",
+                    data.num_columns()
+                ),
+                location: location!(),
+            });
+        }
+        let ids = data.column(data.num_columns() - 1);
+        if ids.data_type() != &DataType::UInt64 {
+            return Err(Error::Index {
+                message: format!(
+                    "Composite flat index row ids must be UInt64, got {}",
+                    ids.data_type()
+                ),
+                location: location!(),
+            });
+        }
+        Ok(Self {
+            data: Arc::new(data),
+        })
+    }
+
+    /// The number of indexed columns
+    pub fn num_value_columns(&self) -> usize {
+        self.data.num_columns() - 1
+    }
+
+    fn ids(&self) -> &ArrayRef {
+        self.data.column(self.num_value_columns())
+    }
+
+    /// Return the row ids of the rows that match every column query of `query`
+    pub fn search(&self, query: &CompositeQuery) -> Result<UInt64Array> {
+        let schema = self.data.schema();
+        let ids_field = schema.field(self.num_value_columns()).clone();
+        let mut matches: Option<BooleanArray> = None;
+        for (column, column_query) in &query.column_queries {
+            let column_idx = schema
+                .index_of(column)
+                .ok()
+                .filter(|idx| *idx < self.num_value_columns())
+                .ok_or_else(|| {
+                    Error::invalid_input(
+                        format!("Composite flat index has no column {}", column),
+                        location!(),
+                    )
+                })?;
+            // Each column is searched as the values of a flat index
+            let column_data = RecordBatch::try_new(
+                Arc::new(Schema::new(vec![
+                    schema.field(column_idx).clone(),
+                    ids_field.clone(),
+                ])),
+                vec![self.data.column(column_idx).clone(), self.ids().clone()],
+            )?;
+            let predicate = build_predicate(&column_data, column_query)?;
+            matches = Some(match matches {
+                Some(matches) => arrow::compute::and_kleene(&matches, &predicate)?,
+                None => predicate,
+            });
+        }
+        let ids = match matches {
+            Some(matches) => arrow_select::filter::filter(self.ids(), &matches)?,
+            None => self.ids().clone(),
+        };
+        Ok(ids.as_primitive::<UInt64Type>().clone())
+    }
+
+    /// Write the index to `store`
+    pub async fn write(&self, store: &dyn IndexStore) -> Result<()> {
+        let mut writer = store
+            .new_index_file(COMPOSITE_FLAT_INDEX_DATA_NAME, self.data.schema())
+            .await?;
+        writer
+            .write_record_batch(self.data.as_ref().clone())
+            .await?;
+        writer.finish().await?;
+        Ok(())
+    }
+
+    /// Load an index written by [`Self::write`]
+    pub async fn load(store: Arc<dyn IndexStore>) -> Result<Arc<Self>> {
+        let batches = store
+            .open_index_file(COMPOSITE_FLAT_INDEX_DATA_NAME)
+            .await?;
+        Ok(Arc::new(Self::try_new(
+            batches.read_record_batch(0).await?,
+        )?))
+    }
+
+    /// Remap the row ids, writing the remapped index to `dest_store`
+    pub async fn remap(
+        &self,
+        mapping: &HashMap<u64, Option<u64>>,
+        dest_store: &dyn IndexStore,
+    ) -> Result<()> {
+        let remapped = remap_batch_with_ids(
+            self.data.as_ref().clone(),
+            self.num_value_columns(),
+            mapping,
+        )?;
+        Self::try_new(remapped)?.write(dest_store).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::types::Int32Type;
+    use arrow_array::types::UInt64Type;
+    use arrow_array::{Array, Float64Array, Int32Array, StringArray};
+    use datafusion::physical_plan::RecordBatchStream;
+    use datafusion_common::ScalarValue;
+    use futures::TryStreamExt;
+    use lance_datagen::{array, gen, RowCount};
+    use lance_io::object_store::ObjectStore;
+    use tempfile::tempdir;
+
+    use crate::scalar::lance_format::LanceIndexStore;
+
+    fn example_index() -> FlatIndex {
+        let batch = gen()
+            .col(
+                Some("values".to_string()),
+                array::cycle::<Int32Type>(vec![10, 100, 1000, 1234]),
+            )
+            .col(
+                Some("ids".to_string()),
+                array::cycle::<UInt64Type>(vec![5, 0, 3, 100]),
+            )
+            .into_batch_rows(RowCount::from(4))
+            .unwrap();
+
+        FlatIndex {
+            data: Arc::new(batch),
+        }
+    }
+
+    async fn check_index(query: &ScalarQuery, expected: &[u64]) {
+        let index = example_index();
+        let actual = index.search(query).await.unwrap();
+        let expected = UInt64Array::from_iter_values(expected.iter().copied());
+        assert_eq!(actual, expected);
+    }
+
+    #[tokio::test]
+    async fn test_equality() {
+        check_index(&ScalarQuery::Equals(ScalarValue::from(100)), &[0]).await;
+        check_index(&ScalarQuery::Equals(ScalarValue::from(10)), &[5]).await;
+        check_index(&ScalarQuery::Equals(ScalarValue::from(5)), &[]).await;
+    }
+
+    #[tokio::test]
+    async fn test_not_equals() {
+        check_index(
+            &ScalarQuery::NotEquals(ScalarValue::from(100)),
+            &[5, 3, 100],
+        )
+        .await;
+        check_index(
+            &ScalarQuery::NotEquals(ScalarValue::from(5)),
+            &[5, 0, 3, 100],
+        )
+        .await;
+        // Nothing is not equal to null
+        check_index(&ScalarQuery::NotEquals(ScalarValue::Int32(None)), &[]).await;
+
+        // Null values are neither equal nor not equal
+        let index = example_nullable_index();
+        let actual = index
+            .search(&ScalarQuery::NotEquals(ScalarValue::from(5)))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from_iter_values([2]));
+        let selectivity = index
+            .estimated_selectivity(&ScalarQuery::NotEquals(ScalarValue::from(5)))
+            .unwrap();
+        assert!((0.0..=0.6).contains(&selectivity));
+        assert_eq!(
+            index
+                .estimated_selectivity(&ScalarQuery::NotEquals(ScalarValue::Int32(None)))
+                .unwrap(),
+            0.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_range() {
+        check_index(
+            &ScalarQuery::Range(
+                Bound::Included(ScalarValue::from(100)),
+                Bound::Excluded(ScalarValue::from(1234)),
+            ),
+            &[0, 3],
+        )
+        .await;
+        check_index(
+            &ScalarQuery::Range(Bound::Unbounded, Bound::Excluded(ScalarValue::from(1000))),
+            &[5, 0],
+        )
+        .await;
+        check_index(
+            &ScalarQuery::Range(Bound::Included(ScalarValue::from(0)), Bound::Unbounded),
+            &[5, 0, 3, 100],
+        )
+        .await;
+        check_index(
+            &ScalarQuery::Range(Bound::Included(ScalarValue::from(100000)), Bound::Unbounded),
+            &[],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_range_helpers() {
+        let value = |v: i32| ScalarValue::from(v);
+        let cases = [
+            (
+                ScalarQuery::between_inclusive(value(100), value(1000)),
+                ScalarQuery::Range(Bound::Included(value(100)), Bound::Included(value(1000))),
+                vec![0, 3],
+            ),
+            (
+                ScalarQuery::between_exclusive(value(100), value(1234)),
+                ScalarQuery::Range(Bound::Excluded(value(100)), Bound::Excluded(value(1234))),
+                vec![3],
+            ),
+            (
+                ScalarQuery::at_least(value(1000)),
+                ScalarQuery::Range(Bound::Included(value(1000)), Bound::Unbounded),
+                vec![3, 100],
+            ),
+            (
+                ScalarQuery::at_most(value(100)),
+                ScalarQuery::Range(Bound::Unbounded, Bound::Included(value(100))),
+                vec![5, 0],
+            ),
+            (
+                ScalarQuery::greater_than(value(1000)),
+                ScalarQuery::Range(Bound::Excluded(value(1000)), Bound::Unbounded),
+                vec![100],
+            ),
+            (
+                ScalarQuery::less_than(value(100)),
+                ScalarQuery::Range(Bound::Unbounded, Bound::Excluded(value(100))),
+                vec![5],
+            ),
+        ];
+        for (query, expected_query, expected_ids) in cases {
+            assert_eq!(query, expected_query);
+            check_index(&query, &expected_ids).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_in() {
+        check_index(
+            &ScalarQuery::IsIn(vec![
+                ScalarValue::from(100),
+                ScalarValue::from(1234),
+                ScalarValue::from(3000),
+            ]),
+            &[0, 100],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_not_in() {
+        check_index(
+            &ScalarQuery::NotIn(vec![
+                ScalarValue::from(100),
+                ScalarValue::from(1234),
+                ScalarValue::from(3000),
+            ]),
+            &[5, 3],
+        )
+        .await;
+        // Nothing is NOT IN a set with a null
+        check_index(
+            &ScalarQuery::NotIn(vec![ScalarValue::from(100), ScalarValue::Int32(None)]),
+            &[],
+        )
+        .await;
+
+        // Null values are neither IN nor NOT IN
+        let index = example_nullable_index();
+        let actual = index
+            .search(&ScalarQuery::NotIn(vec![ScalarValue::from(5)]))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from_iter_values([2]));
+        let actual = index
+            .search(&ScalarQuery::IsIn(vec![ScalarValue::from(5)]))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from_iter_values([0, 4]));
+
+        assert_eq!(
+            index
+                .estimated_selectivity(&ScalarQuery::NotIn(vec![ScalarValue::Int32(None)]))
+                .unwrap(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_is_in_with_null_values() {
+        let index = example_nullable_index();
+        let predicate = build_predicate(
+            &index.data,
+            &ScalarQuery::IsIn(vec![ScalarValue::from(5), ScalarValue::from(7)]),
+        )
+        .unwrap();
+        assert_eq!(predicate.null_count(), 0);
+        assert_eq!(
+            predicate,
+            BooleanArray::from(vec![true, false, true, false, true])
+        );
+
+        let predicate =
+            build_predicate(&index.data, &ScalarQuery::IsIn(vec![ScalarValue::from(6)])).unwrap();
+        assert_eq!(predicate.null_count(), 0);
+        assert_eq!(predicate.true_count(), 0);
+    }
+
+    fn example_nullable_index() -> FlatIndex {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Int32, true),
+            Field::new("row_ids", DataType::UInt64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![
+                    Some(5),
+                    None,
+                    Some(7),
+                    None,
+                    Some(5),
+                ])),
+                Arc::new(UInt64Array::from_iter_values(0..5)),
+            ],
+        )
+        .unwrap();
+        FlatIndex {
+            data: Arc::new(batch),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_not_distinct_from() {
+        let index = example_nullable_index();
+
+        let actual = index
+            .search(&ScalarQuery::IsNotDistinctFrom(ScalarValue::Int32(None)))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from_iter_values([1, 3]));
+
+        let actual = index
+            .search(&ScalarQuery::IsNotDistinctFrom(ScalarValue::from(5)))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from_iter_values([0, 4]));
+    }
+
+    #[tokio::test]
+    async fn test_search_stream() {
+        let index = example_nullable_index();
+        for query in [
+            ScalarQuery::Equals(ScalarValue::from(5)),
+            ScalarQuery::IsNull(),
+            ScalarQuery::Range(Bound::Included(ScalarValue::from(6)), Bound::Unbounded),
+        ] {
+            let stream = index.search_stream(&query);
+            assert_eq!(stream.schema(), index.data.schema());
+            let batches = stream.try_collect::<Vec<_>>().await.unwrap();
+            let row_ids = batches
+                .iter()
+                .flat_map(|batch| {
+                    batch
+                        .column(1)
+                        .as_primitive::<UInt64Type>()
+                        .values()
+                        .to_vec()
+                })
+                .collect::<Vec<_>>();
+            let expected = index.search(&query).await.unwrap();
+            assert_eq!(row_ids, expected.values().to_vec());
+
+            let (_, expected_values) = index.search_with_values(&query).unwrap();
+            let values = batches
+                .iter()
+                .map(|batch| batch.column(0).clone())
+                .collect::<Vec<_>>();
+            let values =
+                arrow::compute::concat(&values.iter().map(|v| v.as_ref()).collect::<Vec<_>>())
+                    .unwrap();
+            assert_eq!(values.as_ref(), expected_values.as_ref());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_and_load() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+
+        let index = example_index();
+        FlatIndex::write((*index.data).clone(), store.as_ref())
+            .await
+            .unwrap();
+        let loaded = FlatIndex::load(store.clone()).await.unwrap();
+        assert_eq!(loaded.data.num_rows(), 4);
+        let actual = loaded
+            .search(&ScalarQuery::Equals(ScalarValue::from(1000)))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from(vec![3]));
+
+        // Extra columns and non UInt64 row ids are rejected
+        let three_columns = gen()
+            .col(Some("values".to_string()), array::step::<Int32Type>())
+            .col(Some("row_ids".to_string()), array::step::<UInt64Type>())
+            .col(Some("extra".to_string()), array::step::<Int32Type>())
+            .into_batch_rows(RowCount::from(10))
+            .unwrap();
+        assert!(FlatIndex::write(three_columns, store.as_ref())
+            .await
+            .is_err());
+        let signed_ids = gen()
+            .col(Some("values".to_string()), array::step::<Int32Type>())
+            .col(Some("row_ids".to_string()), array::step::<Int64Type>())
+            .into_batch_rows(RowCount::from(10))
+            .unwrap();
+        assert!(FlatIndex::write(signed_ids, store.as_ref()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_casts_row_ids() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+
+        let batch = gen()
+            .col(Some("values".to_string()), array::step::<Int32Type>())
+            .col(Some("row_ids".to_string()), array::step::<Int64Type>())
+            .into_batch_rows(RowCount::from(100))
+            .unwrap();
+        let schema = batch.schema();
+        let mut writer = store
+            .new_index_file(FLAT_INDEX_DATA_NAME, batch.schema())
+            .await
+            .unwrap();
+        writer.write_record_batch(batch).await.unwrap();
+        writer.finish().await.unwrap();
+
+        let index = FlatIndex::load(store.clone()).await.unwrap();
+        assert_eq!(index.data.schema().field(1).data_type(), &DataType::UInt64);
+        assert_eq!(
+            index.ids().as_primitive::<UInt64Type>(),
+            &UInt64Array::from_iter_values(0..100)
+        );
+        let actual = index
+            .search(&ScalarQuery::Equals(ScalarValue::from(42)))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from(vec![42]));
+
+        // Negative ids can not be row ids.
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2])),
+                Arc::new(arrow_array::Int64Array::from(vec![0, -1])),
+            ],
+        )
+        .unwrap();
+        let mut writer = store
+            .new_index_file(FLAT_INDEX_DATA_NAME, batch.schema())
+            .await
+            .unwrap();
+        writer.write_record_batch(batch).await.unwrap();
+        writer.finish().await.unwrap();
+        assert!(FlatIndex::load(store).await.is_err());
+    }
+
+    #[test]
+    fn test_search_batch_parallel() {
+        let batch = gen()
+            .col(Some("values".to_string()), array::rand::<Int32Type>())
+            .col(Some("row_ids".to_string()), array::step::<UInt64Type>())
+            .into_batch_rows(RowCount::from(10_000))
+            .unwrap();
+        let values = batch.column(0).as_primitive::<Int32Type>();
+        let (lower, upper) = (values.value(10), values.value(20));
+        let (lower, upper) = (lower.min(upper), lower.max(upper));
+
+        for query in [
+            ScalarQuery::Range(
+                Bound::Included(ScalarValue::from(lower)),
+                Bound::Excluded(ScalarValue::from(upper)),
+            ),
+            ScalarQuery::Range(Bound::Unbounded, Bound::Included(ScalarValue::from(0))),
+            ScalarQuery::Equals(ScalarValue::from(lower)),
+        ] {
+            let expected = search_batch(&batch, &query).unwrap();
+            assert!(!expected.is_empty());
+            for rows_per_chunk in [1, 7, 1000, 10_000, 20_000] {
+                let actual = search_batch_parallel(&batch, &query, rows_per_chunk).unwrap();
+                assert_eq!(actual, expected, "{} rows per chunk", rows_per_chunk);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunked_search() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Int32, true),
+            Field::new("row_ids", DataType::UInt64, false),
+        ]));
+        let values = (0..300).map(|i| (i % 10 != 0).then_some((i * 37) % 101));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from_iter(values)),
+                Arc::new(UInt64Array::from_iter_values(0..300)),
+            ],
+        )
+        .unwrap();
+        FlatIndex::write(batch, store.as_ref()).await.unwrap();
+
+        let eager = FlatIndex::load_with_mode(store.clone(), FlatIndexLoadMode::Eager)
+            .await
+            .unwrap();
+        let chunked = FlatIndex::load_with_mode(
+            store.clone(),
+            FlatIndexLoadMode::Chunked { rows_per_chunk: 7 },
+        )
+        .await
+        .unwrap();
+        assert!(chunked.as_any().is::<ChunkedFlatIndex>());
+
+        let queries = [
+            ScalarQuery::Equals(ScalarValue::from(50)),
+            ScalarQuery::NotEquals(ScalarValue::from(50)),
+            ScalarQuery::Range(
+                Bound::Included(ScalarValue::from(20)),
+                Bound::Excluded(ScalarValue::from(40)),
+            ),
+            ScalarQuery::IsIn(vec![ScalarValue::from(3), ScalarValue::from(99)]),
+            ScalarQuery::IsNull(),
+            ScalarQuery::Equals(ScalarValue::from(1000)),
+        ];
+        for query in &queries {
+            let expected = eager.search(query).await.unwrap();
+            assert_eq!(
+                chunked.search(query).await.unwrap(),
+                expected,
+                "{:?}",
+                query
+            );
+        }
+        assert_eq!(
+            chunked.calculate_included_frags().await.unwrap(),
+            eager.calculate_included_frags().await.unwrap()
+        );
+
+        assert!(
+            FlatIndex::load_with_mode(store, FlatIndexLoadMode::Chunked { rows_per_chunk: 0 })
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lazy_null_search() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("values", DataType::Utf8, true),
+                Field::new("row_ids", DataType::UInt64, false),
+            ])),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    Some("a"),
+                    None,
+                    Some("b"),
+                    None,
+                    None,
+                ])),
+                Arc::new(UInt64Array::from(vec![10, 11, 12, 13, 14])),
+            ],
+        )
+        .unwrap();
+        let mut writer = store
+            .new_index_file(FLAT_INDEX_DATA_NAME, batch.schema())
+            .await
+            .unwrap();
+        writer.write_record_batch(batch).await.unwrap();
+        writer.finish().await.unwrap();
+
+        let index = LazyFlatIndex::load(store.clone()).await.unwrap();
+        assert!(!index.is_values_loaded());
+        let actual = index.search(&ScalarQuery::IsNull()).await.unwrap();
+        assert_eq!(actual, UInt64Array::from(vec![11, 13, 14]));
+        let actual = index
+            .search(&ScalarQuery::IsNotDistinctFrom(ScalarValue::Utf8(None)))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from(vec![11, 13, 14]));
+        assert!(!index.is_values_loaded());
+
+        // A value query loads the values.
+        let actual = index
+            .search(&ScalarQuery::Equals(ScalarValue::from("b")))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from(vec![12]));
+        assert!(index.is_values_loaded());
+
+        // It is a load mode of the flat index.
+        let index = FlatIndex::load_with_mode(store.clone(), FlatIndexLoadMode::Lazy)
+            .await
+            .unwrap();
+        let lazy = index.as_any().downcast_ref::<LazyFlatIndex>().unwrap();
+        assert!(!lazy.is_values_loaded());
+        let actual = index.search(&ScalarQuery::IsNull()).await.unwrap();
+        assert_eq!(actual, UInt64Array::from(vec![11, 13, 14]));
+
+        // An index summarized without nulls is loaded without scanning the values.
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            SUMMARY_META_KEY.to_string(),
+            FlatIndexSummary::compute(&(Arc::new(StringArray::from(vec!["a", "b"])) as ArrayRef))
+                .unwrap()
+                .to_metadata(),
+        );
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new_with_metadata(
+                vec![
+                    Field::new("values", DataType::Utf8, true),
+                    Field::new("ids", DataType::UInt64, true),
+                ],
+                metadata,
+            )),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b"])),
+                Arc::new(UInt64Array::from(vec![20, 21])),
+            ],
+        )
+        .unwrap();
+        let mut writer = store
+            .new_index_file(FLAT_INDEX_DATA_NAME, batch.schema())
+            .await
+            .unwrap();
+        writer.write_record_batch(batch).await.unwrap();
+        writer.finish().await.unwrap();
+        let index = LazyFlatIndex::load(store).await.unwrap();
+        assert_eq!(index.search(&ScalarQuery::IsNull()).await.unwrap().len(), 0);
+        assert!(!index.is_values_loaded());
+    }
+
+    #[tokio::test]
+    async fn test_range_or_null() {
+        let index = FlatIndex {
+            data: Arc::new(
+                RecordBatch::try_new(
+                    Arc::new(Schema::new(vec![
+                        Field::new("values", DataType::Int32, true),
+                        Field::new("ids", DataType::UInt64, false),
+                    ])),
+                    vec![
+                        Arc::new(Int32Array::from(vec![
+                            Some(10),
+                            None,
+                            Some(100),
+                            Some(1000),
+                            None,
+                        ])),
+                        Arc::new(UInt64Array::from(vec![0, 1, 2, 3, 4])),
+                    ],
+                )
+                .unwrap(),
+            ),
+        };
+        let check = |query: ScalarQuery, expected: Vec<u64>| {
+            let index = &index;
+            async move {
+                let actual = index.search(&query).await.unwrap();
+                assert_eq!(actual, UInt64Array::from(expected), "{:?}", query);
+            }
+        };
+
+        check(
+            ScalarQuery::RangeOrNull(
+                Bound::Included(ScalarValue::from(10)),
+                Bound::Included(ScalarValue::from(100)),
+            ),
+            vec![0, 1, 2, 4],
+        )
+        .await;
+        check(
+            ScalarQuery::RangeOrNull(Bound::Excluded(ScalarValue::from(100)), Bound::Unbounded),
+            vec![1, 3, 4],
+        )
+        .await;
+        // An empty range still matches the nulls
+        check(
+            ScalarQuery::RangeOrNull(
+                Bound::Included(ScalarValue::from(2000)),
+                Bound::Included(ScalarValue::from(3000)),
+            ),
+            vec![1, 4],
+        )
+        .await;
+        check(
+            ScalarQuery::RangeOrNull(Bound::Unbounded, Bound::Unbounded),
+            vec![0, 1, 2, 3, 4],
+        )
+        .await;
+        // The plain range never matches nulls
+        check(
+            ScalarQuery::Range(
+                Bound::Included(ScalarValue::from(10)),
+                Bound::Included(ScalarValue::from(100)),
+            ),
+            vec![0, 2],
+        )
+        .await;
+
+        let selectivity = index
+            .estimated_selectivity(&ScalarQuery::RangeOrNull(
+                Bound::Included(ScalarValue::from(2000)),
+                Bound::Unbounded,
+            ))
+            .unwrap();
+        assert!((selectivity - 0.4).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_starts_with() {
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("values", DataType::Utf8, true),
+                Field::new("row_ids", DataType::UInt64, false),
+            ])),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    Some("apple"),
+                    Some("app"),
+                    None,
+                    Some("apricot"),
+                    Some(""),
+                    Some("ap\u{10FFFF}x"),
+                    Some("aq"),
+                    Some("b"),
+                ])),
+                Arc::new(UInt64Array::from_iter_values(0..8)),
+            ],
+        )
+        .unwrap();
+        let unsorted = FlatIndex {
+            data: Arc::new(batch.clone()),
+        };
+        let sorted = FlatIndex {
+            data: Arc::new(FlatIndex::sort_by_value(batch).unwrap()),
+        };
+
+        for (prefix, expected) in [
+            ("ap", vec![0, 1, 3, 5]),
+            ("app", vec![0, 1]),
+            ("apple", vec![0]),
+            ("ap\u{10FFFF}", vec![5]),
+            ("\u{10FFFF}", vec![]),
+            ("a%", vec![]),
+            ("c", vec![]),
+            // The empty prefix matches everything but nulls
+            ("", vec![0, 1, 3, 4, 5, 6, 7]),
+        ] {
+            let query = ScalarQuery::StartsWith(prefix.to_string());
+            for index in [&unsorted, &sorted] {
+                let mut actual = index.search(&query).await.unwrap().values().to_vec();
+                actual.sort();
+                assert_eq!(actual, expected, "prefix: {:?}", prefix);
+            }
+        }
+
+        // Only string columns can be matched against a prefix
+        let err = example_index()
+            .search(&ScalarQuery::StartsWith("1".to_string()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Utf8"));
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let utf8 = |value: &str| ScalarValue::Utf8(Some(value.to_string()));
+        assert_eq!(
+            prefix_range("ab", &DataType::Utf8),
+            Some((Bound::Included(utf8("ab")), Bound::Excluded(utf8("ac"))))
+        );
+        assert_eq!(
+            prefix_range("", &DataType::Utf8),
+            Some((Bound::Included(utf8("")), Bound::Unbounded))
+        );
+        // Characters that can not be incremented are dropped
+        assert_eq!(
+            prefix_range("a\u{10FFFF}\u{10FFFF}", &DataType::Utf8),
+            Some((
+                Bound::Included(utf8("a\u{10FFFF}\u{10FFFF}")),
+                Bound::Excluded(utf8("b"))
+            ))
+        );
+        assert_eq!(
+            prefix_range("\u{10FFFF}", &DataType::Utf8),
+            Some((Bound::Included(utf8("\u{10FFFF}")), Bound::Unbounded))
+        );
+        // The surrogates are skipped
+        assert_eq!(
+            prefix_range("\u{D7FF}", &DataType::Utf8),
+            Some((
+                Bound::Included(utf8("\u{D7FF}")),
+                Bound::Excluded(utf8("\u{E000}"))
+            ))
+        );
+        assert_eq!(
+            prefix_range("ab", &DataType::LargeUtf8),
+            Some((
+                Bound::Included(ScalarValue::LargeUtf8(Some("ab".to_string()))),
+                Bound::Excluded(ScalarValue::LargeUtf8(Some("ac".to_string())))
+            ))
+        );
+        assert_eq!(prefix_range("ab", &DataType::Int32), None);
+    }
+
+    #[tokio::test]
+    async fn test_search_streaming() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = LanceIndexStore::new(object_store, path);
+
+        let batch = gen()
+            .col(Some("values".to_string()), array::step::<Int32Type>())
+            .col(Some("row_ids".to_string()), array::step::<UInt64Type>())
+            .into_batch_rows(RowCount::from(1000))
+            .unwrap();
+        let mut writer = store
+            .new_index_file(FLAT_INDEX_DATA_NAME, batch.schema())
+            .await
+            .unwrap();
+        writer.write_record_batch(batch.clone()).await.unwrap();
+        writer.finish().await.unwrap();
+
+        let index = FlatIndex {
+            data: Arc::new(batch),
+        };
+        for query in [
+            ScalarQuery::Equals(ScalarValue::from(500)),
+            ScalarQuery::Range(
+                Bound::Included(ScalarValue::from(100)),
+                Bound::Excluded(ScalarValue::from(900)),
+            ),
+            ScalarQuery::IsIn(vec![ScalarValue::from(3), ScalarValue::from(999)]),
+        ] {
+            let expected = index.search(&query).await.unwrap();
+            let actual = FlatIndex::search_streaming(&store, &query, 64)
+                .await
+                .unwrap();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_regex() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Utf8, true),
+            Field::new("row_ids", DataType::UInt64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec![
+                    Some("abcd"),
+                    Some("xabd"),
+                    None,
+                    Some("bcd"),
+                ])),
+                Arc::new(UInt64Array::from_iter_values(0..4)),
+            ],
+        )
+        .unwrap();
+        let index = FlatIndex {
+            data: Arc::new(batch),
+        };
+
+        for (pattern, expected) in [
+            ("^ab", vec![0]),
+            ("ab", vec![0, 1]),
+            ("b.d$", vec![0, 3]),
+            (".*", vec![0, 1, 3]),
+        ] {
+            let actual = index
+                .search(&ScalarQuery::Regex(pattern.to_string()))
+                .await
+                .unwrap();
+            assert_eq!(actual, UInt64Array::from(expected), "pattern: {}", pattern);
+        }
+        assert!(index
+            .search(&ScalarQuery::Regex("(ab".to_string()))
+            .await
+            .is_err());
+
+        // Only string columns can be matched against a regex
+        let err = example_index()
+            .search(&ScalarQuery::Regex("1.*".to_string()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Utf8"));
+    }
+
+    #[tokio::test]
+    async fn test_search_with_values() {
+        let index = example_index();
+        let query = ScalarQuery::Range(Bound::Included(ScalarValue::from(100)), Bound::Unbounded);
+        let (row_ids, values) = index.search_with_values(&query).unwrap();
+        assert_eq!(row_ids, index.search(&query).await.unwrap());
+        assert_eq!(row_ids, UInt64Array::from_iter_values([0, 3, 100]));
+        assert_eq!(
+            values.as_primitive::<Int32Type>(),
+            &Int32Array::from_iter_values([100, 1000, 1234])
+        );
+
+        let (row_ids, values) = index
+            .search_with_values(&ScalarQuery::Equals(ScalarValue::from(5)))
+            .unwrap();
+        assert!(row_ids.is_empty());
+        assert!(values.is_empty());
+
+        // A sorted index finds the values with a binary search
+        let unsorted = example_nullable_index();
+        let sorted = FlatIndex {
+            data: Arc::new(FlatIndex::sort_by_value((*unsorted.data).clone()).unwrap()),
+        };
+        let query = ScalarQuery::Range(Bound::Included(ScalarValue::from(5)), Bound::Unbounded);
+        for index in [&unsorted, &sorted] {
+            let (row_ids, values) = index.search_with_values(&query).unwrap();
+            assert_eq!(row_ids, index.search(&query).await.unwrap());
+            let mut pairs = row_ids
+                .values()
+                .iter()
+                .copied()
+                .zip(values.as_primitive::<Int32Type>().values().iter().copied())
+                .collect::<Vec<_>>();
+            pairs.sort();
+            assert_eq!(pairs, vec![(0, 5), (2, 7), (4, 5)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_count() {
+        let unsorted = example_nullable_index();
+        let sorted = FlatIndex {
+            data: Arc::new(FlatIndex::sort_by_value((*unsorted.data).clone()).unwrap()),
+        };
+        let queries = [
+            ScalarQuery::Equals(ScalarValue::from(5)),
+            ScalarQuery::Equals(ScalarValue::from(6)),
+            ScalarQuery::Range(Bound::Excluded(ScalarValue::from(5)), Bound::Unbounded),
+            ScalarQuery::IsIn(vec![ScalarValue::from(5), ScalarValue::from(7)]),
+            ScalarQuery::IsNull(),
+        ];
+        for index in [&unsorted, &sorted] {
+            for query in &queries {
+                let row_ids = index.search(query).await.unwrap();
+                assert_eq!(index.count(query).unwrap(), row_ids.len() as u64);
+            }
+        }
+        assert_eq!(
+            unsorted
+                .count(&ScalarQuery::Equals(ScalarValue::from(5)))
+                .unwrap(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_limited() {
+        let batch = gen()
+            .col(Some("values".to_string()), array::step::<Int32Type>())
+            .col(Some("row_ids".to_string()), array::step::<UInt64Type>())
+            .into_batch_rows(RowCount::from(3 * SEARCH_LIMITED_CHUNK_SIZE as u64))
+            .unwrap();
+        let index = FlatIndex {
+            data: Arc::new(batch),
+        };
+        let query = ScalarQuery::Range(Bound::Included(ScalarValue::from(10)), Bound::Unbounded);
+        let all = index.search(&query).await.unwrap();
+
+        for limit in [0, 1, 100, SEARCH_LIMITED_CHUNK_SIZE + 7] {
+            let limited = index.search_limited(&query, limit).unwrap();
+            assert_eq!(limited.len(), limit);
+            assert_eq!(limited, all.slice(0, limit));
+        }
+        // Fewer matches than the limit
+        let limited = index.search_limited(&query, all.len() + 10).unwrap();
+        assert_eq!(limited, all);
+    }
+
+    #[test]
+    fn test_search_limited_equality() {
+        // One match every 1000 rows, so the matches are spread over all the chunks
+        let batch = gen()
+            .col(
+                Some("values".to_string()),
+                array::cycle::<Int32Type>((0..1000).collect()),
+            )
+            .col(Some("row_ids".to_string()), array::step::<UInt64Type>())
+            .into_batch_rows(RowCount::from(3 * SEARCH_LIMITED_CHUNK_SIZE as u64))
+            .unwrap();
+        let index = FlatIndex {
+            data: Arc::new(batch),
+        };
+        let query = ScalarQuery::Equals(ScalarValue::from(7));
+        let num_matches = (3 * SEARCH_LIMITED_CHUNK_SIZE + 999 - 7) / 1000;
+
+        let limited = index.search_limited(&query, 3).unwrap();
+        assert_eq!(limited, UInt64Array::from(vec![7, 1007, 2007]));
+        for limit in [num_matches, num_matches + 1, usize::MAX] {
+            let limited = index.search_limited(&query, limit).unwrap();
+            assert_eq!(limited.len(), num_matches, "limit {}", limit);
+            assert!(limited.values().iter().all(|id| id % 1000 == 7));
+        }
+        let no_match = ScalarQuery::Equals(ScalarValue::from(5000));
+        assert!(index.search_limited(&no_match, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_estimated_selectivity() {
+        let batch = gen()
+            .col(Some("values".to_string()), array::step::<Int32Type>())
+            .col(Some("row_ids".to_string()), array::step::<UInt64Type>())
+            .into_batch_rows(RowCount::from(1000))
+            .unwrap();
+        let index = FlatIndex {
             data: Arc::new(batch),
-        }))
-    }
+        };
+        let stats = index.value_statistics().unwrap();
+        assert_eq!(stats.min, ScalarValue::Int32(Some(0)));
+        assert_eq!(stats.max, ScalarValue::Int32(Some(999)));
+        assert_eq!(stats.num_distinct, 1000);
+        assert_eq!(stats.null_count, 0);
 
-    // Same as above, this is dead code at the moment but should work
-    async fn remap(
-        &self,
-        mapping: &HashMap<u64, Option<u64>>,
-        dest_store: &dyn IndexStore,
-    ) -> Result<()> {
-        let remapped = remap_batch((*self.data).clone(), mapping)?;
-        let mut writer = dest_store
-            .new_index_file("data.lance", remapped.schema())
-            .await?;
-        writer.write_record_batch(remapped).await?;
-        writer.finish().await?;
-        Ok(())
-    }
+        let tight = index
+            .estimated_selectivity(&ScalarQuery::Range(
+                Bound::Included(ScalarValue::from(100)),
+                Bound::Excluded(ScalarValue::from(110)),
+            ))
+            .unwrap();
+        let broad = index
+            .estimated_selectivity(&ScalarQuery::Range(
+                Bound::Included(ScalarValue::from(100)),
+                Bound::Unbounded,
+            ))
+            .unwrap();
+        assert!(tight < broad, "tight: {}, broad: {}", tight, broad);
+        assert!((broad - 0.9).abs() < 0.01);
 
-    async fn update(
-        &self,
-        _new_data: SendableRecordBatchStream,
-        _dest_store: &dyn IndexStore,
-    ) -> Result<()> {
-        // If this was desired, then you would need to merge new_data and data and write it back out
-        todo!()
-    }
-}
+        let equals = index
+            .estimated_selectivity(&ScalarQuery::Equals(ScalarValue::from(5)))
+            .unwrap();
+        // The number of distinct values is estimated
+        assert!((equals - 0.001).abs() < 0.00005, "{}", equals);
+        let missing = index
+            .estimated_selectivity(&ScalarQuery::Equals(ScalarValue::from(5000)))
+            .unwrap();
+        assert_eq!(missing, 0.0);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use arrow_array::types::Int32Type;
-    use arrow_array::types::UInt64Type;
-    use datafusion_common::ScalarValue;
-    use lance_datagen::{array, gen, RowCount};
+        let nulls = example_nullable_index()
+            .estimated_selectivity(&ScalarQuery::IsNull())
+            .unwrap();
+        assert!((nulls - 0.4).abs() < 1e-6);
+    }
 
-    fn example_index() -> FlatIndex {
+    #[test]
+    fn test_approx_distinct() {
+        // 20000 distinct values, each repeated 3 times
         let batch = gen()
             .col(
                 Some("values".to_string()),
-                array::cycle::<Int32Type>(vec![10, 100, 1000, 1234]),
-            )
-            .col(
-                Some("ids".to_string()),
-                array::cycle::<UInt64Type>(vec![5, 0, 3, 100]),
+                array::cycle::<Int32Type>((0..20_000).collect()),
             )
-            .into_batch_rows(RowCount::from(4))
+            .col(Some("row_ids".to_string()), array::step::<UInt64Type>())
+            .into_batch_rows(RowCount::from(60_000))
             .unwrap();
-
-        FlatIndex {
+        let index = FlatIndex {
             data: Arc::new(batch),
+        };
+        for precision in [10, DEFAULT_HLL_PRECISION, 14] {
+            let estimate = index.approx_distinct(precision).unwrap();
+            // 3 standard errors
+            let bound = 3.0 * 1.04 / ((1_u64 << precision) as f64).sqrt();
+            let error = (estimate - 20_000.0).abs() / 20_000.0;
+            assert!(error < bound, "precision {}: error {}", precision, error);
         }
+        assert!(index.approx_distinct(2).is_err());
+
+        let stats = index.statistics().unwrap();
+        assert_eq!(stats["num_values"], 60_000);
+        assert!(stats["approx_distinct"].as_u64().unwrap() > 0);
+
+        // Nulls are not counted
+        let estimate = example_nullable_index().approx_distinct(12).unwrap();
+        assert!((estimate - 2.0).abs() < 0.1);
     }
 
-    async fn check_index(query: &ScalarQuery, expected: &[u64]) {
-        let index = example_index();
-        let actual = index.search(query).await.unwrap();
-        let expected = UInt64Array::from_iter_values(expected.iter().copied());
-        assert_eq!(actual, expected);
+    #[test]
+    fn test_statistics() {
+        let stats = example_index().statistics().unwrap();
+        assert_eq!(stats["num_values"], 4);
+        assert_eq!(stats["num_nulls"], 0);
+        assert_eq!(stats["min"], "10");
+        assert_eq!(stats["max"], "1234");
+        assert_eq!(stats["approx_distinct"], 4);
+
+        let stats = example_nullable_index().statistics().unwrap();
+        assert_eq!(stats["num_values"], 5);
+        assert_eq!(stats["num_nulls"], 2);
+        assert_eq!(stats["min"], "5");
+        assert_eq!(stats["max"], "7");
+        assert_eq!(stats["approx_distinct"], 2);
+
+        // An empty index has no min or max
+        let empty = FlatIndex {
+            data: Arc::new(example_index().data.slice(0, 0)),
+        };
+        let stats = empty.statistics().unwrap();
+        assert_eq!(stats["num_values"], 0);
+        assert!(stats["min"].is_null());
+        assert!(stats["max"].is_null());
+        assert_eq!(stats["approx_distinct"], 0);
     }
 
-    #[tokio::test]
-    async fn test_equality() {
-        check_index(&ScalarQuery::Equals(ScalarValue::from(100)), &[0]).await;
-        check_index(&ScalarQuery::Equals(ScalarValue::from(10)), &[5]).await;
-        check_index(&ScalarQuery::Equals(ScalarValue::from(5)), &[]).await;
+    #[test]
+    fn test_bloom_filter() {
+        let batch = gen()
+            .col(Some("values".to_string()), array::step::<Int32Type>())
+            .col(Some("row_ids".to_string()), array::step::<UInt64Type>())
+            .into_batch_rows(RowCount::from(1000))
+            .unwrap();
+        let index = FlatIndex {
+            data: Arc::new(batch),
+        };
+        // 10 bits per value, about 1% false positives
+        let filter = index.build_bloom_filter(10_000).unwrap();
+        assert_eq!(filter.len(), 2 + 10_000 / 8);
+        assert_eq!(&filter[..2], &[7, 0]);
+
+        // No false negatives
+        for value in 0..1000 {
+            assert!(FlatIndex::might_contain(&filter, &ScalarValue::Int32(Some(value))).unwrap());
+        }
+        let false_positives = (1000..11_000)
+            .filter(|value| {
+                FlatIndex::might_contain(&filter, &ScalarValue::Int32(Some(*value))).unwrap()
+            })
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+        assert!(!FlatIndex::might_contain(&filter, &ScalarValue::Int32(None)).unwrap());
+
+        // Nulls are skipped
+        let filter = example_nullable_index().build_bloom_filter(64).unwrap();
+        assert!(FlatIndex::might_contain(&filter, &ScalarValue::Int32(Some(5))).unwrap());
+        assert!(FlatIndex::might_contain(&filter, &ScalarValue::Int32(Some(7))).unwrap());
+        assert!(!FlatIndex::might_contain(&filter, &ScalarValue::Int32(None)).unwrap());
+
+        // The keys are the little-endian bytes of the values
+        let mut expected = vec![0_u8; 2 + 10_000 / 8];
+        expected[0] = 7;
+        for value in 0..1000_i32 {
+            for bit in bloom_filter_bits(&value.to_le_bytes(), 7, 10_000) {
+                expected[2 + bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        assert_eq!(filter, expected);
+
+        assert!(index.build_bloom_filter(0).is_err());
+        assert!(FlatIndex::might_contain(&[], &ScalarValue::Int32(Some(1))).is_err());
+        assert!(FlatIndex::might_contain(&[1, 3, 0], &ScalarValue::Int32(Some(1))).is_err());
     }
 
     #[tokio::test]
-    async fn test_range() {
-        check_index(
-            &ScalarQuery::Range(
-                Bound::Included(ScalarValue::from(100)),
-                Bound::Excluded(ScalarValue::from(1234)),
-            ),
-            &[0, 3],
+    async fn test_bloom_filter_value_transform() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Utf8, true),
+            Field::new("row_ids", DataType::UInt64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec![Some("Apple"), None, Some("BANANA")])),
+                Arc::new(UInt64Array::from(vec![0, 1, 2])),
+            ],
         )
-        .await;
-        check_index(
-            &ScalarQuery::Range(Bound::Unbounded, Bound::Excluded(ScalarValue::from(1000))),
-            &[5, 0],
+        .unwrap();
+        let metadata = FlatIndexMetadata::new(DataType::Utf8)
+            .with_value_transform(FlatValueTransform::Lowercase);
+        let index = FlatIndex {
+            data: Arc::new(metadata.train(batch).await.unwrap()),
+        };
+        let filter = index.build_bloom_filter(1024).unwrap();
+        assert_eq!(filter[1], 1);
+
+        for value in ["apple", "APPLE", "Banana", "banana"] {
+            let value = ScalarValue::Utf8(Some(value.to_string()));
+            assert!(FlatIndex::might_contain(&filter, &value).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        example_index().validate().unwrap();
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Int32, true),
+            Field::new("row_ids", DataType::UInt64, true),
+        ]));
+        let make_index = |ids: UInt64Array| FlatIndex {
+            data: Arc::new(
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![Arc::new(Int32Array::from(vec![1, 2, 3])), Arc::new(ids)],
+                )
+                .unwrap(),
+            ),
+        };
+
+        let valid = make_index(UInt64Array::from(vec![
+            0,
+            u64::from(RowAddress::new_from_parts(3, 10)),
+            u64::from(RowAddress::new_from_parts(7, 0)),
+        ]));
+        valid.validate().unwrap();
+
+        let corrupt = make_index(UInt64Array::from(vec![
+            0,
+            u64::from(RowAddress::new_from_parts(3, u32::MAX)),
+            u64::from(RowAddress::new_from_parts(4, u32::MAX)),
+        ]));
+        let err = corrupt.validate().unwrap_err().to_string();
+        assert!(err.contains("position 1"), "{}", err);
+
+        let null = make_index(UInt64Array::from(vec![Some(0), None, Some(1)]));
+        let err = null.validate().unwrap_err().to_string();
+        assert!(err.contains("null row id at position 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_search_sampled() {
+        let index = FlatIndex {
+            data: Arc::new(
+                RecordBatch::try_new(
+                    Arc::new(Schema::new(vec![
+                        Field::new("values", DataType::Int32, true),
+                        Field::new("row_ids", DataType::UInt64, true),
+                    ])),
+                    vec![
+                        Arc::new(Int32Array::from_iter_values((0..100).map(|v| v % 4))),
+                        Arc::new(UInt64Array::from_iter_values(0..100)),
+                    ],
+                )
+                .unwrap(),
+            ),
+        };
+        let query = ScalarQuery::Equals(ScalarValue::Int32(Some(0)));
+
+        let exact = index.search_sampled(&query, 100).unwrap();
+        assert!(exact.is_exact());
+        assert_eq!(exact.row_ids().len(), 25);
+        assert_eq!(exact.estimated_matches(), 25);
+
+        // Every 5th row is sampled, a quarter of which have a value of 0
+        let approx = index.search_sampled(&query, 20).unwrap();
+        assert!(!approx.is_exact());
+        assert_ne!(approx, exact);
+        assert_eq!(approx.row_ids().values(), &[0, 20, 40, 60, 80],);
+        assert_eq!(approx.estimated_matches(), 25);
+        assert!(matches!(
+            approx,
+            SearchResult::Approximate {
+                estimated_matches: 25,
+                ..
+            }
+        ));
+
+        assert!(index.search_sampled(&query, 0).is_err());
+    }
+
+    #[test]
+    fn test_split_by_fragment() {
+        let index = example_index();
+        let split = index.split_by_fragment().unwrap();
+        // All of the example ids are in fragment 0
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[&0].data.as_ref(), index.data.as_ref());
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Int32, true),
+            Field::new("row_ids", DataType::UInt64, true),
+        ]));
+        let index = FlatIndex {
+            data: Arc::new(
+                RecordBatch::try_new(
+                    schema,
+                    vec![
+                        Arc::new(Int32Array::from(vec![1, 2, 3, 4, 5])),
+                        Arc::new(UInt64Array::from(vec![
+                            u64::from(RowAddress::new_from_parts(2, 0)),
+                            u64::from(RowAddress::new_from_parts(0, 7)),
+                            u64::from(RowAddress::new_from_parts(2, 1)),
+                            u64::from(RowAddress::new_from_parts(5, 3)),
+                            u64::from(RowAddress::new_from_parts(0, 1)),
+                        ])),
+                    ],
+                )
+                .unwrap(),
+            ),
+        };
+        let split = index.split_by_fragment().unwrap();
+        let mut fragment_ids = split.keys().copied().collect::<Vec<_>>();
+        fragment_ids.sort();
+        assert_eq!(fragment_ids, vec![0, 2, 5]);
+
+        let check = |fragment_id: u32, values: &[i32], offsets: &[u32]| {
+            let sub_index = &split[&fragment_id];
+            assert_eq!(
+                sub_index.values().as_primitive::<Int32Type>().values(),
+                values
+            );
+            let expected_ids = offsets
+                .iter()
+                .map(|offset| u64::from(RowAddress::new_from_parts(fragment_id, *offset)))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                sub_index.ids().as_primitive::<UInt64Type>().values(),
+                expected_ids.as_slice()
+            );
+        };
+        check(0, &[2, 5], &[7, 1]);
+        check(2, &[1, 3], &[0, 1]);
+        check(5, &[4], &[3]);
+    }
+
+    #[test]
+    fn test_nearest_value() {
+        // values: [10, 100, 1000, 1234], row ids: [5, 0, 3, 100]
+        let index = example_index();
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(90)).unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(1200)).unwrap(),
+            Some(100)
+        );
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(-50)).unwrap(),
+            Some(5)
+        );
+        // 55 is as far from 10 as from 100, the smallest row id wins.
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(55)).unwrap(),
+            Some(0)
+        );
+        // Targets of a different numeric type are cast to the column type.
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(999_i64)).unwrap(),
+            Some(3)
+        );
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Float64, true),
+            Field::new("row_ids", DataType::UInt64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Float64Array::from(vec![
+                    Some(1.5),
+                    None,
+                    Some(2.5),
+                    Some(-1.0),
+                ])),
+                Arc::new(UInt64Array::from_iter_values(0..4)),
+            ],
         )
-        .await;
-        check_index(
-            &ScalarQuery::Range(Bound::Included(ScalarValue::from(0)), Bound::Unbounded),
-            &[5, 0, 3, 100],
+        .unwrap();
+        let index = FlatIndex {
+            data: Arc::new(batch),
+        };
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(2.4)).unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(0.0)).unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            index.nearest_value(&ScalarValue::Float64(None)).unwrap(),
+            None
+        );
+
+        // UInt64 values above i64::MAX are compared as unsigned.
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::UInt64, true),
+            Field::new("row_ids", DataType::UInt64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(UInt64Array::from(vec![
+                    Some(10),
+                    Some(u64::MAX - 1),
+                    None,
+                    Some(i64::MAX as u64),
+                ])),
+                Arc::new(UInt64Array::from_iter_values(0..4)),
+            ],
         )
-        .await;
-        check_index(
-            &ScalarQuery::Range(Bound::Included(ScalarValue::from(100000)), Bound::Unbounded),
-            &[],
+        .unwrap();
+        let index = FlatIndex {
+            data: Arc::new(batch),
+        };
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(u64::MAX)).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            index
+                .nearest_value(&ScalarValue::from(i64::MAX as u64 + 10))
+                .unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            index.nearest_value(&ScalarValue::from(20_i32)).unwrap(),
+            Some(0)
+        );
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Utf8, true),
+            Field::new("row_ids", DataType::UInt64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec!["a"])),
+                Arc::new(UInt64Array::from_iter_values(0..1)),
+            ],
         )
-        .await;
+        .unwrap();
+        let index = FlatIndex {
+            data: Arc::new(batch),
+        };
+        assert!(index.nearest_value(&ScalarValue::from("a")).is_err());
     }
 
-    #[tokio::test]
-    async fn test_is_in() {
-        check_index(
-            &ScalarQuery::IsIn(vec![
-                ScalarValue::from(100),
-                ScalarValue::from(1234),
-                ScalarValue::from(3000),
-            ]),
-            &[0, 100],
-        )
-        .await;
+    #[test]
+    fn test_lookup_values() {
+        // Unsorted row ids: [5, 0, 3, 100]
+        let index = example_index();
+        let values = index.lookup_values(&[3, 7, 5, 100, 3]).unwrap();
+        assert_eq!(
+            values.as_primitive::<Int32Type>(),
+            &Int32Array::from(vec![Some(1000), None, Some(10), Some(1234), Some(1000)])
+        );
+
+        // Sorted row ids: [0, 1, 2, 3, 4]
+        let index = example_nullable_index();
+        let values = index.lookup_values(&[4, 2, 9, 1]).unwrap();
+        assert_eq!(
+            values.as_primitive::<Int32Type>(),
+            &Int32Array::from(vec![Some(5), Some(7), None, None])
+        );
+        assert!(index.lookup_values(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_to_batch() {
+        let index = example_index();
+        let batch = index.to_batch();
+        assert_eq!(batch.num_rows(), 4);
+        assert_eq!(
+            batch.schema().as_ref(),
+            &Schema::new(vec![
+                Field::new("values", DataType::Int32, true),
+                Field::new("row_ids", DataType::UInt64, true),
+            ])
+        );
+        assert_eq!(batch.column(0), index.values());
+        assert_eq!(batch.column(1), index.ids());
     }
 
     #[tokio::test]
@@ -377,8 +3752,115 @@ mod tests {
         assert_eq!(remapped, expected);
     }
 
+    #[tokio::test]
+    async fn test_update() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+
+        // The new data has different column names, and some batches are empty
+        let new_data = gen()
+            .col(
+                Some("x".to_string()),
+                array::cycle::<Int32Type>(vec![10, 7, 1000]),
+            )
+            .col(
+                Some("y".to_string()),
+                array::cycle::<UInt64Type>(vec![200, 201, 202]),
+            )
+            .into_batch_rows(RowCount::from(3))
+            .unwrap();
+        let schema = new_data.schema();
+        let batches = vec![
+            Ok::<_, DataFusionError>(new_data.slice(0, 0)),
+            Ok(new_data.slice(0, 2)),
+            Ok(RecordBatch::new_empty(schema.clone())),
+            Ok(new_data.slice(2, 1)),
+        ];
+        let new_data = Box::pin(RecordBatchStreamAdapter::new(schema, stream::iter(batches)));
+
+        let index = example_index();
+        index.update(new_data, store.as_ref()).await.unwrap();
+
+        let updated = FlatIndex::load(store).await.unwrap();
+        assert_eq!(updated.data.num_rows(), 7);
+        let check = |query: ScalarQuery, expected: Vec<u64>| {
+            let updated = updated.clone();
+            async move {
+                let actual = updated.search(&query).await.unwrap();
+                assert_eq!(actual, UInt64Array::from(expected), "{:?}", query);
+            }
+        };
+        check(ScalarQuery::Equals(ScalarValue::from(10)), vec![5, 200]).await;
+        check(ScalarQuery::Equals(ScalarValue::from(7)), vec![201]).await;
+        check(
+            ScalarQuery::Range(Bound::Included(ScalarValue::from(1000)), Bound::Unbounded),
+            vec![3, 100, 202],
+        )
+        .await;
+    }
+
     // It's possible, during compaction, that an entire page of values is deleted.  We just serialize
     // it as an empty record batch.
+    #[tokio::test]
+    async fn test_update_summary() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+
+        let new_data = RecordBatch::try_new(
+            example_nullable_index().data.schema(),
+            vec![
+                Arc::new(Int32Array::from(vec![Some(-3), None, Some(100)])),
+                Arc::new(UInt64Array::from(vec![10, 11, 12])),
+            ],
+        )
+        .unwrap();
+        let schema = new_data.schema();
+        let batches = vec![
+            Ok::<_, DataFusionError>(new_data.slice(0, 2)),
+            Ok(new_data.slice(2, 1)),
+        ];
+        let new_data = Box::pin(RecordBatchStreamAdapter::new(schema, stream::iter(batches)));
+
+        // The index has no stored summary, so it is computed before the update
+        let index = example_nullable_index();
+        assert!(!index
+            .data
+            .schema()
+            .metadata()
+            .contains_key(SUMMARY_META_KEY));
+        index.update(new_data, store.as_ref()).await.unwrap();
+
+        let updated = FlatIndex::load(store.clone()).await.unwrap();
+        assert!(updated
+            .data
+            .schema()
+            .metadata()
+            .contains_key(SUMMARY_META_KEY));
+        let expected = FlatIndexSummary::compute(updated.values()).unwrap();
+        assert_eq!(updated.summary().unwrap(), expected);
+        assert_eq!((expected.num_rows, expected.null_count), (8, 3),);
+        assert_eq!(
+            (&expected.min, &expected.max),
+            (&ScalarValue::from(-3), &ScalarValue::from(100))
+        );
+        // The sketch of the merged values is the same as the sketch of all of the values
+        assert_eq!(updated.statistics().unwrap()["approx_distinct"], 4);
+        let stats = updated.value_statistics().unwrap();
+        assert_eq!((stats.min, stats.max), (expected.min, expected.max));
+
+        // Remapping removes rows, so the stored summary is dropped
+        let remapped = remap_batch(
+            (*updated.data).clone(),
+            &HashMap::from([(12, None), (11, None)]),
+        )
+        .unwrap();
+        assert!(!remapped.schema().metadata().contains_key(SUMMARY_META_KEY));
+    }
+
     #[tokio::test]
     async fn test_remap_to_nothing() {
         let index = example_index();
@@ -395,4 +3877,300 @@ mod tests {
             .unwrap();
         assert_eq!(remapped.num_rows(), 0);
     }
+
+    #[tokio::test]
+    async fn test_lowercase_value_transform() {
+        let metadata = FlatIndexMetadata::new(DataType::Utf8)
+            .with_value_transform(FlatValueTransform::Lowercase);
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("name", DataType::Utf8, true),
+                Field::new("ids", DataType::UInt64, true),
+            ])),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    Some("Apple"),
+                    Some("APPLE"),
+                    Some("banana"),
+                    None,
+                ])),
+                Arc::new(UInt64Array::from(vec![0, 1, 2, 3])),
+            ],
+        )
+        .unwrap();
+        let trained = metadata.train(batch).await.unwrap();
+        assert_eq!(
+            trained.column(0).as_string::<i32>(),
+            &StringArray::from(vec![Some("apple"), Some("apple"), Some("banana"), None])
+        );
+
+        let index = metadata.load_subindex(trained).await.unwrap();
+        let check = |query: ScalarQuery, expected: Vec<u64>| {
+            let index = index.clone();
+            async move {
+                let actual = index.search(&query).await.unwrap();
+                assert_eq!(actual, UInt64Array::from(expected), "{:?}", query);
+            }
+        };
+        let utf8 = |v: &str| ScalarValue::Utf8(Some(v.to_string()));
+        check(ScalarQuery::Equals(utf8("apple")), vec![0, 1]).await;
+        check(ScalarQuery::Equals(utf8("aPPle")), vec![0, 1]).await;
+        check(ScalarQuery::IsIn(vec![utf8("BANANA")]), vec![2]).await;
+        check(ScalarQuery::IsNull(), vec![3]).await;
+
+        // The transform is stored with the data, so it survives a round trip through the store
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+        let trained = metadata
+            .train(
+                RecordBatch::try_new(
+                    metadata.schema().clone(),
+                    vec![
+                        Arc::new(StringArray::from(vec!["Cherry"])),
+                        Arc::new(UInt64Array::from(vec![7])),
+                    ],
+                )
+                .unwrap(),
+            )
+            .await
+            .unwrap();
+        let mut writer = store
+            .new_index_file(FLAT_INDEX_DATA_NAME, trained.schema())
+            .await
+            .unwrap();
+        writer.write_record_batch(trained).await.unwrap();
+        writer.finish().await.unwrap();
+
+        let loaded = FlatIndex::load(store).await.unwrap();
+        let actual = loaded
+            .search(&ScalarQuery::Equals(utf8("CHERRY")))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from(vec![7]));
+    }
+
+    #[test]
+    fn test_merge_sorted() {
+        let sorted = |index: FlatIndex| FlatIndex {
+            data: Arc::new(FlatIndex::sort_by_value((*index.data).clone()).unwrap()),
+        };
+        let first = sorted(example_nullable_index());
+        let second = sorted(example_index());
+        let merged = first.merge_sorted(&[&second]).unwrap();
+
+        assert!(merged.is_sorted());
+        FlatIndex::validate_sorted(&merged.data).unwrap();
+        let pairs = merged
+            .values()
+            .as_primitive::<Int32Type>()
+            .iter()
+            .zip(
+                merged
+                    .ids()
+                    .as_primitive::<UInt64Type>()
+                    .values()
+                    .iter()
+                    .copied(),
+            )
+            .collect::<Vec<_>>();
+        let mut sorted_pairs = pairs.clone();
+        sorted_pairs.sort();
+        assert_eq!(
+            sorted_pairs,
+            vec![
+                (None, 1),
+                (None, 3),
+                (Some(5), 0),
+                (Some(5), 4),
+                (Some(7), 2),
+                (Some(10), 5),
+                (Some(100), 0),
+                (Some(1000), 3),
+                (Some(1234), 100),
+            ]
+        );
+        // The values are in order, and the row ids stay with their values
+        let values = pairs.iter().map(|(value, _)| *value).collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            sorted_pairs
+                .iter()
+                .map(|(value, _)| *value)
+                .collect::<Vec<_>>()
+        );
+
+        // Merging nothing is a copy of the index
+        let copy = second.merge_sorted(&[]).unwrap();
+        assert_eq!(copy.data, second.data);
+
+        assert!(first.merge_sorted(&[&example_index()]).is_err());
+        assert!(example_index().merge_sorted(&[&first]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sorted_search() {
+        async fn sorted_ids(index: &FlatIndex, query: &ScalarQuery) -> Vec<u64> {
+            let mut ids = index.search(query).await.unwrap().values().to_vec();
+            ids.sort();
+            ids
+        }
+
+        for unsorted in [example_index(), example_nullable_index()] {
+            let sorted = FlatIndex {
+                data: Arc::new(FlatIndex::sort_by_value((*unsorted.data).clone()).unwrap()),
+            };
+            assert!(sorted.is_sorted());
+            assert!(!unsorted.is_sorted());
+
+            let queries = [
+                ScalarQuery::Equals(ScalarValue::from(5)),
+                ScalarQuery::Equals(ScalarValue::from(1000)),
+                ScalarQuery::Equals(ScalarValue::from(6)),
+                ScalarQuery::Equals(ScalarValue::Int32(None)),
+                ScalarQuery::Range(Bound::Included(ScalarValue::from(5)), Bound::Unbounded),
+                ScalarQuery::Range(Bound::Excluded(ScalarValue::from(5)), Bound::Unbounded),
+                ScalarQuery::Range(Bound::Unbounded, Bound::Included(ScalarValue::from(100))),
+                ScalarQuery::Range(Bound::Unbounded, Bound::Excluded(ScalarValue::from(100))),
+                ScalarQuery::Range(
+                    Bound::Included(ScalarValue::from(7)),
+                    Bound::Excluded(ScalarValue::from(1234)),
+                ),
+                ScalarQuery::Range(
+                    Bound::Excluded(ScalarValue::from(1234)),
+                    Bound::Included(ScalarValue::from(10)),
+                ),
+                ScalarQuery::Range(Bound::Included(ScalarValue::Int32(None)), Bound::Unbounded),
+                ScalarQuery::IsNull(),
+            ];
+            for query in &queries {
+                assert_eq!(
+                    sorted_ids(&sorted, query).await,
+                    sorted_ids(&unsorted, query).await,
+                    "query {:?}",
+                    query
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sorted_write_and_load() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+
+        let sorted = FlatIndex::sort_by_value((*example_nullable_index().data).clone()).unwrap();
+        FlatIndex::write(sorted.clone(), store.as_ref())
+            .await
+            .unwrap();
+        let loaded = FlatIndex::load(store.clone()).await.unwrap();
+        assert!(loaded.is_sorted());
+        let actual = loaded
+            .search(&ScalarQuery::Equals(ScalarValue::from(5)))
+            .await
+            .unwrap();
+        assert_eq!(actual, UInt64Array::from(vec![0, 4]));
+
+        // Data marked as sorted that is out of order is rejected at load
+        let reversed = sorted
+            .take(&UInt32Array::from_iter_values((0..5).rev()))
+            .unwrap();
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+        FlatIndex::write(reversed, store.as_ref()).await.unwrap();
+        assert!(FlatIndex::load(store).await.is_err());
+    }
+
+    fn example_composite_index() -> CompositeFlatIndex {
+        let metadata = CompositeFlatIndexMetadata::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+        ]);
+        let batch = gen()
+            .col(
+                Some("x".to_string()),
+                array::cycle::<Int32Type>(vec![10, 10, 20, 10, 30]),
+            )
+            .col(
+                Some("y".to_string()),
+                array::cycle_utf8_literals(&["foo", "bar", "foo", "baz", "bar"]),
+            )
+            .col(
+                Some("ids".to_string()),
+                array::cycle::<UInt64Type>(vec![0, 1, 2, 3, 4]),
+            )
+            .into_batch_rows(RowCount::from(5))
+            .unwrap();
+        CompositeFlatIndex::try_new(metadata.train(&batch).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_composite_search() {
+        let index = example_composite_index();
+        let query = CompositeQuery::default()
+            .and("a", ScalarQuery::Equals(ScalarValue::from(10)))
+            .and(
+                "b",
+                ScalarQuery::IsIn(vec![ScalarValue::from("foo"), ScalarValue::from("baz")]),
+            );
+        assert_eq!(query.to_string(), "a = 10 AND b IN [foo,baz]");
+        assert_eq!(index.search(&query).unwrap(), UInt64Array::from(vec![0, 3]));
+
+        let query =
+            CompositeQuery::default().and("b", ScalarQuery::Equals(ScalarValue::from("bar")));
+        assert_eq!(index.search(&query).unwrap(), UInt64Array::from(vec![1, 4]));
+
+        // An empty query matches every row
+        assert_eq!(
+            index.search(&CompositeQuery::default()).unwrap(),
+            UInt64Array::from(vec![0, 1, 2, 3, 4])
+        );
+
+        // The row ids are not a column that can be queried
+        let query = CompositeQuery::default().and("row_ids", ScalarQuery::IsNull());
+        assert!(index.search(&query).is_err());
+        let query = CompositeQuery::default().and("c", ScalarQuery::IsNull());
+        assert!(index.search(&query).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_composite_write_load_remap() {
+        let tempdir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(tempdir.path().as_os_str().to_str().unwrap()).unwrap();
+        let store = Arc::new(LanceIndexStore::new(object_store, path));
+
+        let index = example_composite_index();
+        index.write(store.as_ref()).await.unwrap();
+        let loaded = CompositeFlatIndex::load(store.clone()).await.unwrap();
+        assert_eq!(loaded.data, index.data);
+
+        let remapped_dir = tempdir().unwrap();
+        let (object_store, path) =
+            ObjectStore::from_path(remapped_dir.path().as_os_str().to_str().unwrap()).unwrap();
+        let remapped_store = Arc::new(LanceIndexStore::new(object_store, path));
+        let mapping = HashMap::<u64, Option<u64>>::from_iter(vec![(0, Some(2000)), (1, None)]);
+        loaded
+            .remap(&mapping, remapped_store.as_ref())
+            .await
+            .unwrap();
+        let remapped = CompositeFlatIndex::load(remapped_store).await.unwrap();
+        assert_eq!(remapped.num_value_columns(), 2);
+
+        let query = CompositeQuery::default()
+            .and("a", ScalarQuery::Equals(ScalarValue::from(10)))
+            .and("b", ScalarQuery::Equals(ScalarValue::from("foo")));
+        assert_eq!(
+            remapped.search(&query).unwrap(),
+            UInt64Array::from(vec![2000])
+        );
+        let query =
+            CompositeQuery::default().and("b", ScalarQuery::Equals(ScalarValue::from("bar")));
+        assert_eq!(remapped.search(&query).unwrap(), UInt64Array::from(vec![4]));
+    }
 }
@@ -0,0 +1,185 @@
+// Copyright 2024 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HyperLogLog sketch for approximate distinct counts
+
+use lance_core::{Error, Result};
+use snafu::{location, Location};
+
+/// The smallest supported precision
+pub const MIN_PRECISION: u8 = 4;
+/// The largest supported precision
+pub const MAX_PRECISION: u8 = 18;
+
+/// A HyperLogLog sketch over 64-bit hashes
+///
+/// It uses `2^precision` one-byte registers, and the relative standard error of
+/// the estimate is about `1.04 / sqrt(2^precision)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn try_new(precision: u8) -> Result<Self> {
+        Self::check_precision(precision)?;
+        Ok(Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        })
+    }
+
+    /// Restore a sketch from its [`Self::registers`]
+    pub fn try_from_registers(registers: Vec<u8>) -> Result<Self> {
+        let precision = registers.len().trailing_zeros() as u8;
+        if !registers.len().is_power_of_two() {
+            return Err(Error::invalid_input(
+                format!(
+                    "HyperLogLog must have a power of two registers, got {}",
+                    registers.len()
+                ),
+                location!(),
+            ));
+        }
+        Self::check_precision(precision)?;
+        Ok(Self {
+            precision,
+            registers,
+        })
+    }
+
+    fn check_precision(precision: u8) -> Result<()> {
+        if !(MIN_PRECISION..=MAX_PRECISION).contains(&precision) {
+            return Err(Error::invalid_input(
+                format!(
+                    "HyperLogLog precision must be between {} and {}, got {}",
+                    MIN_PRECISION, MAX_PRECISION, precision
+                ),
+                location!(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The registers of the sketch, one byte each
+    pub fn registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Add a hashed value to the sketch
+    pub fn add_hash(&mut self, hash: u64) {
+        let idx = (hash >> (64 - self.precision)) as usize;
+        // Set a sentinel bit so the rank is bounded when the remaining bits are all zero
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[idx] = self.registers[idx].max(rank);
+    }
+
+    /// Add the hashes added to `other` to this sketch
+    ///
+    /// Both sketches must have the same precision.
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        if self.precision != other.precision {
+            return Err(Error::invalid_input(
+                format!(
+                    "Can not merge HyperLogLog sketches of precision {} and {}",
+                    self.precision, other.precision
+                ),
+                location!(),
+            ));
+        }
+        self.registers
+            .iter_mut()
+            .zip(other.registers.iter())
+            .for_each(|(r, o)| *r = (*r).max(*o));
+        Ok(())
+    }
+
+    /// Estimate the number of distinct hashes added so far
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum = self
+            .registers
+            .iter()
+            .map(|r| 2.0_f64.powi(-(*r as i32)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // Small range correction, fall back to linear counting
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut hll = HyperLogLog::try_new(12).unwrap();
+        assert_eq!(hll.estimate(), 0.0);
+        for round in 0..3 {
+            for value in 0..50_000_u64 {
+                let mut hasher = DefaultHasher::new();
+                value.hash(&mut hasher);
+                hll.add_hash(hasher.finish());
+            }
+            let error = (hll.estimate() - 50_000.0).abs() / 50_000.0;
+            // Duplicates do not change the estimate, 3 standard errors at precision 12
+            assert!(error < 0.05, "round {}: error {}", round, error);
+        }
+
+        assert!(HyperLogLog::try_new(MIN_PRECISION - 1).is_err());
+        assert!(HyperLogLog::try_new(MAX_PRECISION + 1).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let add = |hll: &mut HyperLogLog, values: std::ops::Range<u64>| {
+            for value in values {
+                let mut hasher = DefaultHasher::new();
+                value.hash(&mut hasher);
+                hll.add_hash(hasher.finish());
+            }
+        };
+        let mut all = HyperLogLog::try_new(12).unwrap();
+        add(&mut all, 0..30_000);
+        let mut first = HyperLogLog::try_new(12).unwrap();
+        add(&mut first, 0..20_000);
+        let mut second = HyperLogLog::try_new(12).unwrap();
+        add(&mut second, 10_000..30_000);
+        first.merge(&second).unwrap();
+        assert_eq!(first, all);
+
+        let restored = HyperLogLog::try_from_registers(all.registers().to_vec()).unwrap();
+        assert_eq!(restored, all);
+        assert!(HyperLogLog::try_from_registers(vec![0; 100]).is_err());
+        assert!(HyperLogLog::try_from_registers(vec![0; 4]).is_err());
+        assert!(first.merge(&HyperLogLog::try_new(10).unwrap()).is_err());
+    }
+}
@@ -81,6 +81,14 @@ impl IndexReader for FileReader {
     async fn num_batches(&self) -> u32 {
         self.num_batches() as u32
     }
+
+    async fn read_range(&self, range: std::ops::Range<usize>) -> Result<RecordBatch> {
+        self.read_range(range, self.schema(), None).await
+    }
+
+    fn num_rows(&self) -> usize {
+        self.len()
+    }
 }
 
 #[async_trait]
@@ -157,7 +165,7 @@ mod tests {
 
     use crate::scalar::{
         btree::{train_btree_index, BTreeIndex, BtreeTrainingSource},
-        flat::FlatIndexMetadata,
+        flat::{FlatIndexMetadata, FlatValueTransform},
         ScalarIndex, ScalarQuery,
     };
 
@@ -165,7 +173,7 @@ mod tests {
     use arrow_array::{
         cast::AsArray,
         types::{Float32Type, Int32Type, UInt64Type},
-        RecordBatchIterator, RecordBatchReader, UInt64Array,
+        RecordBatchIterator, RecordBatchReader, StringArray, UInt64Array,
     };
     use arrow_schema::{DataType, Field, TimeUnit};
     use arrow_select::take::TakeOptions;
@@ -535,6 +543,59 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn test_btree_value_transform() {
+        let tempdir = tempdir().unwrap();
+        let index_store = test_store(&tempdir);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("values", DataType::Utf8, false),
+            Field::new("row_ids", DataType::UInt64, false),
+        ]));
+        // The pages are sorted by the original values, which are not in the order of the
+        // lowercase values
+        let batches = [
+            (vec!["Apple", "Zebra"], vec![0, 1]),
+            (vec!["apple", "banana"], vec![2, 3]),
+            (vec!["cherry", "zoo"], vec![4, 5]),
+        ]
+        .into_iter()
+        .map(|(values, row_ids)| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(StringArray::from(values)),
+                    Arc::new(UInt64Array::from(row_ids)),
+                ],
+            )
+        })
+        .collect::<Vec<_>>();
+        let sub_index_trainer = FlatIndexMetadata::new(DataType::Utf8)
+            .with_value_transform(FlatValueTransform::Lowercase);
+        let data =
+            Box::new(MockTrainingSource::new(RecordBatchIterator::new(batches, schema)).await);
+        train_btree_index(data, &sub_index_trainer, index_store.as_ref())
+            .await
+            .unwrap();
+        let index = BTreeIndex::load(index_store).await.unwrap();
+
+        let utf8 = |v: &str| ScalarValue::Utf8(Some(v.to_string()));
+        check(&index, ScalarQuery::Equals(utf8("APPLE")), &[0, 2]).await;
+        check(&index, ScalarQuery::Equals(utf8("zebra")), &[1]).await;
+        check(
+            &index,
+            ScalarQuery::IsIn(vec![utf8("Banana"), utf8("ZOO")]),
+            &[3, 5],
+        )
+        .await;
+        check(&index, ScalarQuery::StartsWith("Z".to_string()), &[1, 5]).await;
+        check(
+            &index,
+            ScalarQuery::Range(Bound::Included(utf8("B")), Bound::Excluded(utf8("D"))),
+            &[3, 4],
+        )
+        .await;
+    }
+
     #[tokio::test]
     async fn test_btree_types() {
         for data_type in &[
@@ -37,7 +37,7 @@ use lance_linalg::{
 use crate::vector::ivf::transform::IvfTransformer;
 use crate::vector::{
     pq::{transform::PQTransformer, ProductQuantizer},
-    residual::ResidualTransform,
+    residual::{self, ResidualTransform},
     transform::Transformer,
 };
 
@@ -188,13 +188,16 @@ pub trait Ivf: Send + Sync + std::fmt::Debug + Transformer {
 
     /// Compute residual vector.
     ///
-    /// A residual vector is `original vector - centroids`.
+    /// A residual vector is `original vector - centroids`, see
+    /// [`residual::compute_residual`].
     ///
     /// Parameters:
     ///  - *original*: original vector.
     ///  - *partitions*: partition ID of each original vector. If not provided, it will be computed
     ///   on the flight.
     ///
+    /// Raises [Error] if a partition ID is out of range.
+    ///
     async fn compute_residual(
         &self,
         original: &FixedSizeListArray,
@@ -328,36 +331,13 @@ impl<T: ArrowFloatType + Dot + L2 + ArrowPrimitiveType> Ivf for IvfImpl<T> {
         original: &FixedSizeListArray,
         partitions: Option<&UInt32Array>,
     ) -> Result<FixedSizeListArray> {
-        let flatten_arr = original
-            .values()
-            .as_any()
-            .downcast_ref::<T::ArrayType>()
-            .ok_or(Error::Index {
-                message: format!(
-                    "Ivf::compute_residual: original is not expected type: {} got {}",
-                    T::FLOAT_TYPE,
-                    original.values().data_type()
-                ),
-                location: Default::default(),
-            })?;
-
         let part_ids = if let Some(part_ids) = partitions {
             part_ids.clone()
         } else {
             self.compute_partitions(original).await?
         };
-        let dim = original.value_length() as usize;
-        let residual_arr = flatten_arr
-            .as_slice()
-            .chunks_exact(dim)
-            .zip(part_ids.values())
-            .flat_map(|(vector, &part_id)| {
-                let centroid = self.centroids.row(part_id as usize).unwrap();
-                vector.iter().zip(centroid.iter()).map(|(&v, &c)| v - c)
-            })
-            .collect::<Vec<_>>();
-        let arr = T::ArrayType::from(residual_arr);
-        Ok(FixedSizeListArray::try_new_from_values(arr, dim as i32)?)
+        residual::compute_residual(&self.centroids, original, Some(&part_ids), self.metric_type)
+            .await
     }
 
     fn find_partitions(&self, query: &dyn Array, nprobes: usize) -> Result<UInt32Array> {
@@ -22,10 +22,12 @@ use std::sync::Arc;
 use lance_core::{Error, Result};
 use lance_linalg::{
     distance::{Dot, MetricType, L2},
-    kmeans::{KMeans, KMeansParams},
+    kmeans::{KMeanInit, KMeans, KMeansParams},
 };
 
 /// Train KMeans model and returns the centroids of each cluster.
+///
+/// `init` is ignored if `centroids` are provided.
 #[allow(clippy::too_many_arguments)]
 pub async fn train_kmeans<T: ArrowFloatType + Dot + L2>(
     array: &T::ArrayType,
@@ -34,10 +36,42 @@ pub async fn train_kmeans<T: ArrowFloatType + Dot + L2>(
     k: usize,
     max_iterations: u32,
     redos: usize,
-    mut rng: impl Rng,
+    rng: impl Rng,
     metric_type: MetricType,
     sample_rate: usize,
+    init: KMeanInit,
 ) -> Result<T::ArrayType> {
+    Ok(train_kmeans_with_sample_size::<T>(
+        array,
+        centroids,
+        dimension,
+        k,
+        max_iterations,
+        redos,
+        rng,
+        metric_type,
+        sample_rate,
+        init,
+    )
+    .await?
+    .0)
+}
+
+/// Same as [`train_kmeans`], and also returns the number of vectors the model was
+/// trained on, after sampling.
+#[allow(clippy::too_many_arguments)]
+pub async fn train_kmeans_with_sample_size<T: ArrowFloatType + Dot + L2>(
+    array: &T::ArrayType,
+    centroids: Option<Arc<T::ArrayType>>,
+    dimension: usize,
+    k: usize,
+    max_iterations: u32,
+    redos: usize,
+    mut rng: impl Rng,
+    metric_type: MetricType,
+    sample_rate: usize,
+    init: KMeanInit,
+) -> Result<(T::ArrayType, usize)> {
     let num_rows = array.len() / dimension;
     if num_rows < k {
         return Err(Error::Index{message: format!(
@@ -71,9 +105,12 @@ pub async fn train_kmeans<T: ArrowFloatType + Dot + L2>(
         metric_type,
         centroids,
         redos,
+        init,
+        // The centroids are initialized with the same random generator as the sampling.
+        seed: Some(rng.gen()),
         ..Default::default()
     };
     let data = FixedSizeListArray::try_new_from_values(data, dimension as i32)?;
     let model = KMeans::<T>::new_with_params(&data, k, &params).await?;
-    Ok(model.centroids.as_ref().clone())
+    Ok((model.centroids.as_ref().clone(), data.len()))
 }
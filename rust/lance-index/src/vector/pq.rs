@@ -18,14 +18,22 @@
 use std::any::Any;
 use std::sync::Arc;
 
-use arrow_array::{cast::AsArray, Array, FixedSizeListArray, UInt8Array};
+use arrow_array::types::{Float32Type, Int8Type, UInt8Type};
+use arrow_array::{cast::AsArray, Array, BinaryArray, FixedSizeListArray, UInt64Array, UInt8Array};
 use arrow_array::{ArrayRef, Float32Array};
+use arrow_schema::{DataType, Field};
 use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use half::f16;
 use lance_arrow::*;
 use lance_core::{Error, Result};
-use lance_linalg::distance::{dot_distance_batch, l2_distance_batch, Dot, L2};
-use lance_linalg::kernels::{argmin, argmin_value_float};
+use lance_linalg::distance::{dot_distance_batch, l2, l2_distance_batch, Dot, L2};
+use lance_linalg::kernels::{argmin, argmin_value, argmin_value_float};
 use lance_linalg::{distance::MetricType, MatrixView};
+use log::warn;
+use num_traits::{AsPrimitive, FromPrimitive, Zero};
+use rayon::prelude::*;
 use snafu::{location, Location};
 pub mod builder;
 mod distance;
@@ -33,12 +41,27 @@ pub mod storage;
 pub mod transform;
 pub(crate) mod utils;
 
-use self::distance::{build_distance_table_l2, compute_l2_distance};
-pub use self::utils::num_centroids;
+#[cfg(all(feature = "nightly", target_arch = "x86_64"))]
+use self::distance::compute_l2_distance_avx512;
+use self::distance::{
+    compute_distance_f64_accumulated, compute_l2_distance_f16,
+    compute_l2_distance_small_sub_vectors, compute_l2_distance_with_tile,
+};
+pub use self::distance::{
+    suggest_tile_size, SymmetricDistanceTable, DEFAULT_TILE_SIZE, SUPPORTED_TILE_SIZES,
+};
+use self::utils::{
+    apply_rotation, code_length, codes_to_fsl, decode_code, downcast_float_array, is_bit_packed,
+    unpack_codes,
+};
+pub use self::utils::{num_centroids, pq_codes_from_binary, pq_codes_to_binary};
 use super::pb;
-pub use builder::PQBuildParams;
+pub use builder::{PQBuildParams, PQTrainingInfo};
 use utils::get_sub_vector_centroids;
 
+/// [`ProductQuantizer::transform`] encodes the vectors in parallel from this many vectors.
+const PARALLEL_TRANSFORM_MIN_ROWS: usize = 1024;
+
 /// Product Quantization
 #[async_trait::async_trait]
 pub trait ProductQuantizer: Send + Sync + std::fmt::Debug {
@@ -48,30 +71,324 @@ pub trait ProductQuantizer: Send + Sync + std::fmt::Debug {
     ///
     /// Parameters
     /// ----------
-    /// *data*: vector array, must be a `FixedSizeListArray`
+    /// *data*: vector array, must be a `FixedSizeListArray` of floats, or of `Int8`
+    ///         which are converted to the codebook float type first.
     ///
     /// Returns
     /// -------
-    ///   PQ code column
+    ///   PQ code column. Null vectors are null in the output, with an all-zero code.
+    ///   With 4 or 8 bits there is one byte per sub-vector, other `num_bits` are
+    ///   bit-packed into `ceil(num_sub_vectors * num_bits / 8)` bytes per vector.
+    ///
+    /// Each sub-vector is assigned to its closest centroid under [`Self::metric_type`],
+    /// the metric type the PQ was created with, regardless of how the codes are used
+    /// later. Use [`Self::transform_with_metric_type`] to be warned about a mismatch.
     async fn transform(&self, data: &dyn Array) -> Result<ArrayRef>;
 
+    /// Transform a vector column to PQ codes, for data meant to be searched with `metric_type`.
+    ///
+    /// The codes are the same as [`Self::transform`], they are still assigned with
+    /// [`Self::metric_type`]. If it differs from `metric_type`, i.e., an L2 PQ is used
+    /// on dot-product data, a warning is logged because the codes will likely not
+    /// preserve the order of the dot-product distances.
+    async fn transform_with_metric_type(
+        &self,
+        data: &dyn Array,
+        metric_type: MetricType,
+    ) -> Result<ArrayRef> {
+        // Cosine is computed as L2 over normalized vectors.
+        let expected = match metric_type {
+            MetricType::Cosine => MetricType::L2,
+            _ => metric_type,
+        };
+        if expected != self.metric_type() {
+            warn!(
+                "PQ codes are assigned with {:?}, but the data is expected to be searched with {:?}",
+                self.metric_type(),
+                metric_type
+            );
+        }
+        self.transform(data).await
+    }
+
+    /// Transform a vector column to PQ codes, with one binary value per vector.
+    ///
+    /// Each value holds the bit-packed PQ code of one vector, i.e., two 4-bit codes
+    /// per byte. Use [`pq_codes_to_binary`] and [`pq_codes_from_binary`] to convert
+    /// between this layout and the `FixedSizeList<UInt8>` layout returned by
+    /// [`ProductQuantizer::transform`].
+    async fn transform_binary(&self, data: &dyn Array) -> Result<BinaryArray> {
+        let codes = self.transform(data).await?;
+        pq_codes_to_binary(codes.as_fixed_size_list(), self.num_bits())
+    }
+
+    /// Transform a vector column to PQ codes, `chunk_size` vectors at a time.
+    ///
+    /// Up to `parallelism` chunks are transformed concurrently, so the peak memory is
+    /// bounded by `chunk_size * parallelism` vectors instead of the whole input. The
+    /// returned stream yields the PQ codes of each chunk in input order, their
+    /// concatenation is the same as [`ProductQuantizer::transform`] on the whole input.
+    fn transform_chunked<'a>(
+        &'a self,
+        data: &'a dyn Array,
+        chunk_size: usize,
+        parallelism: usize,
+    ) -> BoxStream<'a, Result<ArrayRef>> {
+        if chunk_size == 0 || parallelism == 0 {
+            return stream::once(async {
+                Err(Error::invalid_input(
+                    "PQ transform chunk size and parallelism must be greater than 0",
+                    location!(),
+                ))
+            })
+            .boxed();
+        }
+        stream::iter((0..data.len()).step_by(chunk_size))
+            .map(move |offset| {
+                let chunk = data.slice(offset, chunk_size.min(data.len() - offset));
+                async move { self.transform(chunk.as_ref()).await }
+            })
+            .buffered(parallelism)
+            .boxed()
+    }
+
     /// Compute the distance between query vector to the PQ code.
     ///
+    /// See [`ProductQuantizerImpl::distance_is_squared`] for how to interpret the distances.
     fn compute_distances(&self, query: &dyn Array, code: &UInt8Array) -> Result<Float32Array>;
 
+    /// Compute the distances from each of the `queries` to the PQ code.
+    ///
+    /// It is the same as calling [`ProductQuantizer::compute_distances`] once per query,
+    /// but the queries are computed in parallel on the rayon thread pool. Each query still
+    /// builds its own distance table. Returns one array of distances per query, in query
+    /// order.
+    fn compute_distances_batch(
+        &self,
+        queries: &FixedSizeListArray,
+        code: &UInt8Array,
+    ) -> Result<Vec<Float32Array>> {
+        if queries.null_count() > 0 {
+            return Err(Error::invalid_input(
+                format!("PQ queries contain {} null vectors", queries.null_count()),
+                location!(),
+            ));
+        }
+        (0..queries.len())
+            .into_par_iter()
+            .map(|i| self.compute_distances(queries.value(i).as_ref(), code))
+            .collect()
+    }
+
+    /// Compute the `m x n` distance matrix between `m` queries and `n` PQ codes.
+    ///
+    /// The matrix is returned in row-major order, along with `(m, n)`: the distance
+    /// from query `i` to code `j` is at `i * n + j`. The rows are computed with
+    /// [`ProductQuantizer::compute_distances_batch`], building one distance table per
+    /// query.
+    fn compute_distance_matrix(
+        &self,
+        queries: &FixedSizeListArray,
+        code: &UInt8Array,
+    ) -> Result<(Float32Array, usize, usize)> {
+        let rows = self.compute_distances_batch(queries, code)?;
+        let num_codes = match rows.first() {
+            Some(row) => row.len(),
+            None => code.len() / self.code_length(),
+        };
+        let mut values = Vec::with_capacity(rows.len() * num_codes);
+        for row in rows.iter() {
+            values.extend_from_slice(row.values());
+        }
+        Ok((Float32Array::from(values), rows.len(), num_codes))
+    }
+
+    /// Lookup table of the distances between the centroids of each sub-vector.
+    ///
+    /// It is built once from the codebook and can be reused to compute code-to-code
+    /// distances with [`SymmetricDistanceTable::compute_distances`].
+    fn symmetric_distance_table(&self) -> SymmetricDistanceTable;
+
+    /// Distances from a query, given as a PQ code, to all `codes`, without reconstructing
+    /// any vector.
+    ///
+    /// Both the query and the codes use the packing of [`ProductQuantizer::reconstruct`].
+    /// To compute the distances of many queries, build the [`SymmetricDistanceTable`] once
+    /// with [`ProductQuantizer::symmetric_distance_table`] instead.
+    fn symmetric_distances(
+        &self,
+        query_code: &UInt8Array,
+        codes: &UInt8Array,
+    ) -> Result<Float32Array> {
+        Ok(Float32Array::from(
+            self.symmetric_distance_table()
+                .compute_distances(query_code.values(), codes.values())?,
+        ))
+    }
+
+    /// Decode PQ codes back to approximate vectors.
+    ///
+    /// Each vector is the concatenation of the centroids its PQ code refers to, one
+    /// per sub-vector. The codes use the layout of [`ProductQuantizer::transform`]: one
+    /// byte per sub-vector for 4 and 8 bits, bit-packed for the other widths, i.e., with
+    /// `num_bits = 6`, four codes are packed in three bytes.
+    ///
+    /// The returned vectors have the same value type as the codebook.
+    fn reconstruct(&self, codes: &UInt8Array) -> Result<FixedSizeListArray>;
+
     /// Get the centroids for one sub-vector.
     fn num_bits(&self) -> u32;
 
     /// Number of sub-vectors
     fn num_sub_vectors(&self) -> usize;
 
+    /// Number of bytes of the PQ code of one vector, as returned by
+    /// [`ProductQuantizer::transform`].
+    ///
+    /// It is `num_sub_vectors` for 4 and 8 bits, and the bit-packed length, i.e.,
+    /// `ceil(num_sub_vectors * num_bits / 8)`, for the other widths.
+    fn code_length(&self) -> usize {
+        code_length(self.num_bits(), self.num_sub_vectors())
+    }
+
     fn dimension(&self) -> usize;
 
+    /// The metric type used to assign the PQ codes and compute the distances.
+    ///
+    /// [`MetricType::Cosine`] is reported as [`MetricType::L2`], PQ computes it as L2
+    /// over normalized vectors.
+    fn metric_type(&self) -> MetricType;
+
+    /// The codebook as a `FixedSizeListArray`, sharing the buffer of the codebook.
     // TODO: move to pub(crate) once the refactor of lance::index to lance-index is done.
     fn codebook_as_fsl(&self) -> FixedSizeListArray;
 
+    /// The shape of the codebook, `(num_centroids, dimension)`.
+    ///
+    /// It is the shape the codebook is stored with, see [`Self::codebook_as_fsl`]. Note
+    /// that the values are laid out sub-vector major, so a row of this shape is not one
+    /// centroid, see [`ProductQuantizerImpl::codebook_flat`].
+    fn codebook_shape(&self) -> (usize, usize) {
+        (num_centroids(self.num_bits()), self.dimension())
+    }
+
+    /// The rotation applied to the vectors before they are quantized, if any.
+    ///
+    /// It is a `dimension x dimension` orthogonal matrix, with one row per list.
+    fn rotation(&self) -> Option<FixedSizeListArray>;
+
     /// Whether to use residual as input or not.
     fn use_residual(&self) -> bool;
+
+    /// The parameters used to train the codebook.
+    ///
+    /// Returns `None` for codebooks provided by users or loaded from older indices.
+    fn training_info(&self) -> Option<&PQTrainingInfo>;
+
+    /// Variance of the residual distances of each sub-vector.
+    ///
+    /// For every sub-vector, it computes the squared L2 distance from each vector's
+    /// sub-vector to its closest centroid, and returns the variance of these distances.
+    /// Sub-vectors with a high variance are quantized less evenly, and are candidates
+    /// to be given more bits.
+    ///
+    /// *vectors* must be a `FixedSizeListArray` of the same float type and dimension
+    /// as the codebook.
+    fn sub_vector_variance(&self, vectors: &dyn Array) -> Result<Vec<f32>>;
+
+    /// Mean squared reconstruction error of `vectors`.
+    ///
+    /// It quantizes the vectors with [`Self::transform`], decodes them back with
+    /// [`Self::reconstruct`], and returns the average squared L2 distance between the
+    /// original and the reconstructed vectors. Null vectors are ignored.
+    ///
+    /// It can be used on a sample of the data to compare `num_sub_vectors` and
+    /// `num_bits` settings.
+    async fn distortion(&self, vectors: &FixedSizeListArray) -> Result<f64>;
+
+    /// The effective number of bits used by the codes of each sub-vector.
+    ///
+    /// It is the Shannon entropy, in bits, of the histogram of the PQ codes of `vectors`
+    /// for each sub-vector. It is at most `num_bits`, reached when all centroids are used
+    /// equally often. A much lower value means many centroids are dead or rarely used,
+    /// i.e., a smaller `num_bits` would do. Null vectors are ignored.
+    async fn effective_bits(&self, vectors: &dyn Array) -> Result<Vec<f32>> {
+        let codes = self.transform(vectors).await?;
+        let codes = codes.as_fixed_size_list();
+        let num_bits = self.num_bits();
+        let num_sub_vectors = self.num_sub_vectors();
+        let values = codes.values().as_primitive::<UInt8Type>().values();
+        let values = if is_bit_packed(num_bits) {
+            unpack_codes(values, num_bits, num_sub_vectors)
+        } else {
+            values.to_vec()
+        };
+
+        let mut histograms = vec![vec![0_usize; num_centroids(num_bits)]; num_sub_vectors];
+        for (row, code) in values.chunks_exact(num_sub_vectors).enumerate() {
+            if codes.is_null(row) {
+                continue;
+            }
+            for (histogram, c) in histograms.iter_mut().zip(code) {
+                histogram[*c as usize] += 1;
+            }
+        }
+        Ok(histograms
+            .iter()
+            .map(|histogram| {
+                let total = histogram.iter().sum::<usize>() as f64;
+                histogram
+                    .iter()
+                    .filter(|count| **count > 0)
+                    .map(|count| {
+                        let p = *count as f64 / total;
+                        -p * p.log2()
+                    })
+                    .sum::<f64>() as f32
+            })
+            .collect())
+    }
+
+    /// Mean L2 distance between the corresponding centroids of two codebooks.
+    ///
+    /// It measures how much a codebook changed between two trainings, i.e., to
+    /// decide whether an index should be rebuilt. Both quantizers must have the
+    /// same `num_bits`, `num_sub_vectors` and `dimension`.
+    fn codebook_drift(&self, other: &dyn ProductQuantizer) -> Result<f32> {
+        if self.num_bits() != other.num_bits()
+            || self.num_sub_vectors() != other.num_sub_vectors()
+            || self.dimension() != other.dimension()
+        {
+            return Err(Error::Index {
+                message: format!(
+                    "Codebook drift: shape mismatch: (num_bits={}, num_sub_vectors={}, dimension={}) vs (num_bits={}, num_sub_vectors={}, dimension={})",
+                    self.num_bits(),
+                    self.num_sub_vectors(),
+                    self.dimension(),
+                    other.num_bits(),
+                    other.num_sub_vectors(),
+                    other.dimension()
+                ),
+                location: location!(),
+            });
+        }
+        let to_f32 = |codebook: FixedSizeListArray| -> Result<Float32Array> {
+            let values = arrow::compute::cast(codebook.values(), &DataType::Float32)?;
+            Ok(values.as_primitive::<Float32Type>().clone())
+        };
+        let this = to_f32(self.codebook_as_fsl())?;
+        let other = to_f32(other.codebook_as_fsl())?;
+
+        let sub_vector_length = self.dimension() / self.num_sub_vectors();
+        let num_centroids = this.len() / sub_vector_length;
+        let total = this
+            .values()
+            .chunks_exact(sub_vector_length)
+            .zip(other.values().chunks_exact(sub_vector_length))
+            .map(|(a, b)| l2(a, b).sqrt() as f64)
+            .sum::<f64>();
+        Ok((total / num_centroids as f64) as f32)
+    }
 }
 
 /// Product Quantization, optimized for [Apache Arrow] buffer memory layout.
@@ -111,42 +428,196 @@ pub struct ProductQuantizerImpl<T: ArrowFloatType + Dot + L2> {
     /// // Centroids for a sub-vector.
     /// Codebook[sub_vector_id][pq_code]
     /// ```
+    ///
+    /// The codebook is shared behind an [`Arc`], so quantizers that use the same
+    /// codebook, i.e., one per partition, or clones of a quantizer, do not copy it.
     pub codebook: Arc<T::ArrayType>,
+
+    /// Parameters used to train the codebook, if known.
+    pub training_info: Option<PQTrainingInfo>,
+
+    /// Tile size, in number of sub-vectors, used to compute L2 distances.
+    ///
+    /// If it is `None`, [`DEFAULT_TILE_SIZE`] is used.
+    pub tile_size: Option<usize>,
+
+    /// Partitions with fewer PQ codes than this threshold compute distances against
+    /// the reconstructed vectors directly, instead of building a distance table.
+    ///
+    /// If it is `None`, the distance table is always used.
+    pub exact_distance_threshold: Option<usize>,
+
+    /// Store the L2 distance table in f16 instead of f32.
+    ///
+    /// It halves the size of the table at the cost of some precision,
+    /// see [`Self::with_half_precision_distance_table`].
+    pub half_precision_distance_table: bool,
+
+    /// Accumulate the distance table entries in f64 instead of f32.
+    ///
+    /// See [`Self::with_f64_accumulation`].
+    pub f64_accumulation: bool,
+
+    /// Truncate vectors longer than `dimension` to their first `dimension` values when
+    /// they are transformed, instead of returning an error.
+    ///
+    /// See [`Self::with_truncate_padded_vectors`].
+    pub truncate_padded_vectors: bool,
+
+    /// Optional `dimension x dimension` rotation matrix, in row-major order.
+    ///
+    /// With Optimized Product Quantization (OPQ), the vectors are multiplied by this
+    /// orthogonal matrix before they are divided into sub-vectors. The codebook is
+    /// trained in the rotated space, and queries are rotated the same way. Since the
+    /// rotation is orthogonal, it preserves L2 and dot distances.
+    pub rotation: Option<Arc<T::ArrayType>>,
+}
+
+impl<T: ArrowFloatType + Dot + L2> Clone for ProductQuantizerImpl<T> {
+    /// Cheap clone, the codebook is shared with the new quantizer.
+    fn clone(&self) -> Self {
+        Self {
+            num_bits: self.num_bits,
+            num_sub_vectors: self.num_sub_vectors,
+            dimension: self.dimension,
+            metric_type: self.metric_type,
+            codebook: self.codebook.clone(),
+            training_info: self.training_info.clone(),
+            tile_size: self.tile_size,
+            exact_distance_threshold: self.exact_distance_threshold,
+            half_precision_distance_table: self.half_precision_distance_table,
+            f64_accumulation: self.f64_accumulation,
+            truncate_padded_vectors: self.truncate_padded_vectors,
+            rotation: self.rotation.clone(),
+        }
+    }
 }
 
 impl<T: ArrowFloatType + Dot + L2> ProductQuantizerImpl<T> {
     /// Create a [`ProductQuantizer`] with pre-trained codebook.
+    ///
+    /// PQ does not support cosine directly, [`MetricType::Cosine`] is mapped to L2 over
+    /// normalized vectors, the same as [`builder::from_proto`]. The caller is responsible
+    /// for normalizing the vectors and the queries.
+    ///
+    /// Returns an error if `nbits` is not between 1 and 8, if `dimension` is not divisible
+    /// by `m`, or if the codebook does not hold `num_centroids(nbits) * m` centroids of
+    /// `dimension / m` values.
     pub fn new(
         m: usize,
         nbits: u32,
         dimension: usize,
         codebook: Arc<T::ArrayType>,
         metric_type: MetricType,
-    ) -> Self {
-        assert_ne!(
-            metric_type,
-            MetricType::Cosine,
-            "Product quantization does not support cosine, use normalized L2 instead"
-        );
-        assert_eq!(nbits, 8, "nbits can only be 8");
-        Self {
+    ) -> Result<Self> {
+        let metric_type = match metric_type {
+            MetricType::Cosine => MetricType::L2,
+            _ => metric_type,
+        };
+        Self::validate_codebook(m, nbits, dimension, codebook.len())?;
+        Ok(Self {
             num_bits: nbits,
             num_sub_vectors: m,
             dimension,
             codebook,
             metric_type,
+            training_info: None,
+            tile_size: None,
+            exact_distance_threshold: None,
+            half_precision_distance_table: false,
+            f64_accumulation: false,
+            truncate_padded_vectors: false,
+            rotation: None,
+        })
+    }
+
+    /// Check the shape of a codebook of `codebook_len` values.
+    fn validate_codebook(
+        num_sub_vectors: usize,
+        num_bits: u32,
+        dimension: usize,
+        codebook_len: usize,
+    ) -> Result<()> {
+        if !(1..=8).contains(&num_bits) {
+            return Err(Error::Index {
+                message: format!("PQ: num_bits must be between 1 and 8, got {}", num_bits),
+                location: location!(),
+            });
+        }
+        if num_sub_vectors == 0 || dimension % num_sub_vectors != 0 {
+            return Err(Error::Index {
+                message: format!(
+                    "PQ: dimension {} is not divisible by num_sub_vectors {}",
+                    dimension, num_sub_vectors
+                ),
+                location: location!(),
+            });
+        }
+        let num_rows = Self::num_centroids(num_bits) * num_sub_vectors;
+        let sub_vector_width = dimension / num_sub_vectors;
+        if codebook_len != num_rows * sub_vector_width {
+            return Err(Error::Index {
+                message: format!(
+                    "PQ: expect a codebook of {} centroids of {} values, got {} values",
+                    num_rows, sub_vector_width, codebook_len
+                ),
+                location: location!(),
+            });
         }
+        Ok(())
+    }
+
+    /// Attach the parameters used to train the codebook.
+    pub fn with_training_info(mut self, training_info: PQTrainingInfo) -> Self {
+        self.training_info = Some(training_info);
+        self
     }
 
     pub fn num_centroids(num_bits: u32) -> usize {
         2_usize.pow(num_bits)
     }
 
+    /// Whether [`ProductQuantizer::compute_distances`] returns squared distances for
+    /// `metric_type`.
+    ///
+    /// - `L2` returns the squared L2 distance.
+    /// - `Cosine` is computed as `L2` over normalized vectors, so it is squared too.
+    /// - `Dot` returns the negative dot product, which is not a squared distance.
+    pub fn distance_is_squared(metric_type: MetricType) -> bool {
+        match metric_type {
+            MetricType::L2 | MetricType::Cosine => true,
+            MetricType::Dot => false,
+        }
+    }
+
+    /// Estimate the memory used by a PQ model and the PQ codes of `num_rows` vectors.
+    ///
+    /// It is the size of the codebook (`num_centroids * dimension` values of `T`), plus
+    /// the PQ code of each row: one byte per sub-vector for 4 and 8 bits, bit-packed for
+    /// the other widths. It can be used to size an index before building it.
+    pub fn estimate_memory(num_rows: usize, dimension: usize, params: &PQBuildParams) -> usize {
+        let codebook_size = Self::num_centroids(params.num_bits as u32)
+            * dimension
+            * std::mem::size_of::<T::Native>();
+        let code_size = num_rows * code_length(params.num_bits as u32, params.num_sub_vectors);
+        codebook_size + code_size
+    }
+
     /// Calculate codebook length.
     pub fn codebook_length(num_bits: u32, num_sub_vectors: usize) -> usize {
         Self::num_centroids(num_bits) * num_sub_vectors
     }
 
+    /// The codebook values as one contiguous buffer, of [`ProductQuantizer::codebook_shape`].
+    ///
+    /// The values are laid out sub-vector major: the value `j` of centroid `c` of
+    /// sub-vector `s` is at `(s * num_centroids + c) * sub_vector_width + j`. It can be
+    /// reshaped to `(num_sub_vectors, num_centroids, sub_vector_width)` without copying,
+    /// which is the layout of the centroids of a faiss `ProductQuantizer`.
+    pub fn codebook_flat(&self) -> &[T::Native] {
+        self.codebook.as_slice()
+    }
+
     /// Get the centroids for one sub-vector.
     ///
     /// Returns a flatten `num_centroids * sub_vector_width` f32 array.
@@ -160,10 +631,272 @@ impl<T: ArrowFloatType + Dot + L2> ProductQuantizerImpl<T> {
         )
     }
 
+    /// Re-partition the trained centroids into `new_num_sub_vectors` sub-vectors.
+    ///
+    /// This is only possible when each existing sub-vector is split into several
+    /// smaller ones, i.e., `new_num_sub_vectors` is a multiple of `num_sub_vectors`.
+    /// Each new sub-vector reuses the projection of the original centroids onto its
+    /// dimensions, so no training is involved. Merging sub-vectors would require
+    /// centroids of the joint space, which needs a full retrain. The rotation, if any,
+    /// is kept since the centroids live in the rotated space.
+    pub fn reshape_sub_vectors(&self, new_num_sub_vectors: usize) -> Result<Self> {
+        if new_num_sub_vectors == 0
+            || new_num_sub_vectors % self.num_sub_vectors != 0
+            || self.dimension % new_num_sub_vectors != 0
+        {
+            return Err(Error::Index {
+                message: format!(
+                    "Can not reshape PQ from {} to {} sub-vectors with dimension {}: \
+                    the new number of sub-vectors must be a multiple of the current one \
+                    and divide the dimension, otherwise the PQ needs to be retrained",
+                    self.num_sub_vectors, new_num_sub_vectors, self.dimension
+                ),
+                location: location!(),
+            });
+        }
+
+        let splits = new_num_sub_vectors / self.num_sub_vectors;
+        let sub_vector_width = self.dimension / self.num_sub_vectors;
+        let new_sub_vector_width = self.dimension / new_num_sub_vectors;
+        let mut builder = Vec::with_capacity(self.codebook.len());
+        for sub_vector_idx in 0..self.num_sub_vectors {
+            let centroids = self.centroids(sub_vector_idx);
+            for split in 0..splits {
+                let offset = split * new_sub_vector_width;
+                for centroid in centroids.chunks_exact(sub_vector_width) {
+                    builder.extend_from_slice(&centroid[offset..offset + new_sub_vector_width]);
+                }
+            }
+        }
+
+        Ok(Self {
+            num_bits: self.num_bits,
+            num_sub_vectors: new_num_sub_vectors,
+            dimension: self.dimension,
+            metric_type: self.metric_type,
+            codebook: Arc::new(T::ArrayType::from(builder)),
+            training_info: None,
+            tile_size: None,
+            exact_distance_threshold: None,
+            half_precision_distance_table: false,
+            f64_accumulation: false,
+            truncate_padded_vectors: false,
+            rotation: self.rotation.clone(),
+        })
+    }
+
+    /// Count the number of vectors assigned to each centroid, from their PQ codes.
+    ///
+    /// Returns a flatten `num_sub_vectors * num_centroids` array, in the same order
+    /// as the codebook.
+    pub fn centroid_counts(&self, codes: &[u8]) -> Vec<u64> {
+        let num_centroids = num_centroids(self.num_bits);
+        let mut counts = vec![0_u64; self.num_sub_vectors * num_centroids];
+        for code in codes.chunks_exact(self.num_sub_vectors) {
+            for (sub_vector_idx, c) in code.iter().enumerate() {
+                counts[sub_vector_idx * num_centroids + *c as usize] += 1;
+            }
+        }
+        counts
+    }
+
+    /// Remove the unused centroids of each sub-vector from the codebook.
+    ///
+    /// `usage[sub_vector_idx][code]` tells whether a centroid is used by any vector,
+    /// i.e., from [`Self::centroid_counts`]. The used centroids move to the front of the
+    /// codebook of their sub-vector, keeping their relative order.
+    ///
+    /// If the used centroids of every sub-vector fit into 4 bits, an 8-bit codebook is
+    /// shrunk to 4 bits, which keeps the one byte per sub-vector code layout. Otherwise
+    /// the codebook keeps `2^num_bits` slots per sub-vector. Either way, the dead slots
+    /// left are filled with a copy of the first used centroid, which always wins ties when
+    /// encoding so the dead slots are never assigned again.
+    ///
+    /// Returns, for each sub-vector, the mapping from old code to new code. Apply it to
+    /// stored codes with [`Self::remap_codes`].
+    pub fn compact_codebook(&mut self, usage: &[Vec<bool>]) -> Result<Vec<Vec<u8>>> {
+        let num_centroids = num_centroids(self.num_bits);
+        if usage.len() != self.num_sub_vectors || usage.iter().any(|u| u.len() != num_centroids) {
+            return Err(Error::Index {
+                message: format!(
+                    "Compact PQ codebook: expect usage of {} sub-vectors with {} centroids each",
+                    self.num_sub_vectors, num_centroids
+                ),
+                location: location!(),
+            });
+        }
+
+        let max_used = usage
+            .iter()
+            .map(|used| used.iter().filter(|u| **u).count())
+            .max()
+            .unwrap_or(0);
+        let new_num_bits = if self.num_bits == 8 && max_used <= Self::num_centroids(4) {
+            4
+        } else {
+            self.num_bits
+        };
+        let new_num_centroids = Self::num_centroids(new_num_bits);
+
+        let sub_vector_width = self.dimension / self.num_sub_vectors;
+        let mut builder: Vec<T::Native> =
+            Vec::with_capacity(self.num_sub_vectors * new_num_centroids * sub_vector_width);
+        let mut remapping = Vec::with_capacity(self.num_sub_vectors);
+        for (sub_vector_idx, used) in usage.iter().enumerate() {
+            let centroids = self.centroids(sub_vector_idx);
+            let start = builder.len();
+            let mut mapping = vec![0_u8; num_centroids];
+            let mut next = 0;
+            for (code, centroid) in centroids.chunks_exact(sub_vector_width).enumerate() {
+                if used[code] {
+                    mapping[code] = next as u8;
+                    next += 1;
+                    builder.extend_from_slice(centroid);
+                }
+            }
+            let filler = if next > 0 {
+                builder[start..start + sub_vector_width].to_vec()
+            } else {
+                centroids[..sub_vector_width].to_vec()
+            };
+            for _ in next..new_num_centroids {
+                builder.extend_from_slice(&filler);
+            }
+            remapping.push(mapping);
+        }
+        self.num_bits = new_num_bits;
+        self.codebook = Arc::new(T::ArrayType::from(builder));
+        Ok(remapping)
+    }
+
+    /// Rewrite PQ codes with the remapping returned by [`Self::compact_codebook`].
+    pub fn remap_codes(codes: &mut [u8], remapping: &[Vec<u8>]) {
+        let num_sub_vectors = remapping.len();
+        for code in codes.chunks_exact_mut(num_sub_vectors) {
+            for (c, mapping) in code.iter_mut().zip(remapping.iter()) {
+                *c = mapping[*c as usize];
+            }
+        }
+    }
+
+    /// Merge the quantizers trained on different shards of the data into one.
+    ///
+    /// Each shard comes with the counts of vectors assigned to each of its centroids,
+    /// see [`Self::centroid_counts`]. The centroids of the first shard are used as the
+    /// reference: every centroid of the other shards is merged into its closest reference
+    /// centroid of the same sub-vector, and each merged centroid is the mean of its members
+    /// weighted by their counts.
+    ///
+    /// All shards must share the same `num_bits`, `num_sub_vectors`, `dimension`,
+    /// metric type and rotation.
+    pub fn merge_shards(shards: &[(&Self, &[u64])]) -> Result<Self> {
+        let Some((first, _)) = shards.first() else {
+            return Err(Error::Index {
+                message: "Merge PQ shards: no shard to merge".to_string(),
+                location: location!(),
+            });
+        };
+        let num_centroids = num_centroids(first.num_bits);
+        let codebook_length = first.num_sub_vectors * num_centroids;
+        for (shard, counts) in shards {
+            if shard.num_bits != first.num_bits
+                || shard.num_sub_vectors != first.num_sub_vectors
+                || shard.dimension != first.dimension
+                || shard.metric_type != first.metric_type
+            {
+                return Err(Error::Index {
+                    message: format!(
+                        "Merge PQ shards: parameters mismatch: (num_bits={}, num_sub_vectors={}, dimension={}, metric_type={}) vs (num_bits={}, num_sub_vectors={}, dimension={}, metric_type={})",
+                        first.num_bits,
+                        first.num_sub_vectors,
+                        first.dimension,
+                        first.metric_type,
+                        shard.num_bits,
+                        shard.num_sub_vectors,
+                        shard.dimension,
+                        shard.metric_type,
+                    ),
+                    location: location!(),
+                });
+            }
+            if shard.rotation.as_ref().map(|r| r.as_slice())
+                != first.rotation.as_ref().map(|r| r.as_slice())
+            {
+                return Err(Error::Index {
+                    message: "Merge PQ shards: the shards must have the same rotation".to_string(),
+                    location: location!(),
+                });
+            }
+            if counts.len() != codebook_length {
+                return Err(Error::Index {
+                    message: format!(
+                        "Merge PQ shards: expect {} centroid counts, got {}",
+                        codebook_length,
+                        counts.len()
+                    ),
+                    location: location!(),
+                });
+            }
+        }
+
+        let sub_vector_width = first.dimension / first.num_sub_vectors;
+        let mut builder: Vec<T::Native> = Vec::with_capacity(first.codebook.len());
+        for sub_vector_idx in 0..first.num_sub_vectors {
+            let reference = first.centroids(sub_vector_idx);
+            let mut sums = vec![0.0_f64; num_centroids * sub_vector_width];
+            let mut weights = vec![0_u64; num_centroids];
+            for (shard_idx, (shard, counts)) in shards.iter().enumerate() {
+                let counts = &counts[sub_vector_idx * num_centroids..][..num_centroids];
+                for (centroid_idx, centroid) in shard
+                    .centroids(sub_vector_idx)
+                    .chunks_exact(sub_vector_width)
+                    .enumerate()
+                {
+                    let weight = counts[centroid_idx];
+                    if weight == 0 {
+                        continue;
+                    }
+                    let target = if shard_idx == 0 {
+                        centroid_idx
+                    } else {
+                        argmin_value_float(l2_distance_batch(centroid, reference, sub_vector_width))
+                            .map(|(idx, _)| idx as usize)
+                            .unwrap_or(centroid_idx)
+                    };
+                    weights[target] += weight;
+                    sums[target * sub_vector_width..][..sub_vector_width]
+                        .iter_mut()
+                        .zip(centroid.iter())
+                        .for_each(|(sum, v)| *sum += v.as_() as f64 * weight as f64);
+                }
+            }
+            for (centroid_idx, weight) in weights.iter().enumerate() {
+                let offset = centroid_idx * sub_vector_width;
+                if *weight == 0 {
+                    // Keep the reference centroid if nothing was assigned to it.
+                    builder.extend_from_slice(&reference[offset..offset + sub_vector_width]);
+                } else {
+                    builder.extend(sums[offset..offset + sub_vector_width].iter().map(|sum| {
+                        T::Native::from_f64(sum / *weight as f64).unwrap_or(T::Native::zero())
+                    }));
+                }
+            }
+        }
+
+        let mut merged = Self::new(
+            first.num_sub_vectors,
+            first.num_bits,
+            first.dimension,
+            Arc::new(T::ArrayType::from(builder)),
+            first.metric_type,
+        )?;
+        merged.rotation = first.rotation.clone();
+        Ok(merged)
+    }
+
     /// Reconstruct a vector from its PQ code.
     ///
     /// It only supports U8 PQ code for now.
-    #[allow(dead_code)]
     pub(crate) fn reconstruct(&self, code: &[u8]) -> Arc<T::ArrayType> {
         assert_eq!(code.len(), self.num_sub_vectors);
         let mut builder = Vec::with_capacity(self.dimension);
@@ -178,6 +911,95 @@ impl<T: ArrowFloatType + Dot + L2> ProductQuantizerImpl<T> {
         Arc::new(T::ArrayType::from(builder))
     }
 
+    /// Re-encode PQ codes of the `old` codebook with the codebook of this quantizer.
+    ///
+    /// Each vector is reconstructed from its `old` PQ code, and the reconstruction is
+    /// quantized again with this codebook. It is useful after retraining the codebook
+    /// when the raw vectors are not available anymore.
+    ///
+    /// Note that the vectors are quantized twice, so the quantization error of the
+    /// re-encoded codes is larger than that of re-quantizing the raw vectors.
+    pub async fn reencode(&self, old: &Self, codes: &UInt8Array) -> Result<UInt8Array>
+    where
+        T: 'static,
+    {
+        if old.dimension != self.dimension {
+            return Err(Error::Index {
+                message: format!(
+                    "Re-encode PQ codes: dimension of the old codebook {} does not match {}",
+                    old.dimension, self.dimension
+                ),
+                location: location!(),
+            });
+        }
+        let old_code_length = code_length(old.num_bits, old.num_sub_vectors);
+        if codes.len() % old_code_length != 0 {
+            return Err(Error::Index {
+                message: format!(
+                    "Re-encode PQ codes: code length {} is not a multiple of {} bytes per vector",
+                    codes.len(),
+                    old_code_length
+                ),
+                location: location!(),
+            });
+        }
+        let unpacked;
+        let codes = if is_bit_packed(old.num_bits) {
+            unpacked = unpack_codes(codes.values(), old.num_bits, old.num_sub_vectors);
+            unpacked.as_slice()
+        } else {
+            codes.values().as_ref()
+        };
+        let mut values = Vec::with_capacity(codes.len() / old.num_sub_vectors * self.dimension);
+        for code in codes.chunks_exact(old.num_sub_vectors) {
+            values.extend_from_slice(old.reconstruct(code).as_slice());
+        }
+        let vectors = FixedSizeListArray::try_new_from_values(
+            T::ArrayType::from(values),
+            self.dimension as i32,
+        )?;
+        let vectors = old.rotate_vectors(&vectors, true)?;
+        let new_codes = self.transform(&vectors).await?;
+        Ok(new_codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>()
+            .clone())
+    }
+
+    /// Reconstruct one vector from a block of transposed PQ codes.
+    ///
+    /// In the transposed layout, the codes are stored sub-vector major, i.e.,
+    /// `codes[sub_vector_idx * num_vectors + column]`, see [`storage::transpose`].
+    /// Only the `num_sub_vectors` bytes of the given `column` are read, so re-ranking
+    /// a handful of candidates does not need to transpose the whole block back.
+    pub fn reconstruct_transposed(&self, codes: &[u8], column: usize) -> Result<Arc<T::ArrayType>> {
+        if codes.len() % self.num_sub_vectors != 0 {
+            return Err(Error::Index {
+                message: format!(
+                    "Transposed PQ code length {} is not a multiple of num_sub_vectors {}",
+                    codes.len(),
+                    self.num_sub_vectors
+                ),
+                location: location!(),
+            });
+        }
+        let num_vectors = codes.len() / self.num_sub_vectors;
+        if column >= num_vectors {
+            return Err(Error::Index {
+                message: format!(
+                    "Transposed PQ code column {} out of range, number of vectors: {}",
+                    column, num_vectors
+                ),
+                location: location!(),
+            });
+        }
+        let code = (0..self.num_sub_vectors)
+            .map(|sub_vector_idx| codes[sub_vector_idx * num_vectors + column])
+            .collect::<Vec<_>>();
+        Ok(self.reconstruct(&code))
+    }
+
     /// Compute the quantization distortion (E).
     ///
     /// Quantization distortion is the difference between the centroids
@@ -185,99 +1007,686 @@ impl<T: ArrowFloatType + Dot + L2> ProductQuantizerImpl<T> {
     ///
     /// This method is just for debugging purpose.
     #[allow(dead_code)]
-    pub(crate) async fn distortion(
+    pub(crate) async fn assignment_distortion(
         &self,
         data: &MatrixView<T>,
         metric_type: MetricType,
     ) -> Result<f64> {
+        Ok(self.assign_with_distortion(data, metric_type)?.1)
+    }
+
+    /// Assign the PQ codes of `data` and compute the quantization distortion in one pass.
+    ///
+    /// The codes are one byte per sub-vector, assigned the same way as
+    /// [`ProductQuantizer::transform`] does, and the distortion is the same as
+    /// [`Self::assignment_distortion`]. `data` must already be rotated if the PQ has a
+    /// rotation.
+    pub(crate) fn assign_with_distortion(
+        &self,
+        data: &MatrixView<T>,
+        metric_type: MetricType,
+    ) -> Result<(Vec<u8>, f64)> {
         let sub_vector_width = self.dimension / self.num_sub_vectors;
-        let total_distortion = data
-            .iter()
-            .map(|vector| {
-                vector
-                    .chunks_exact(sub_vector_width)
-                    .enumerate()
-                    .map(|(sub_vector_idx, sub_vec)| {
-                        let centroids = self.centroids(sub_vector_idx);
-                        let distances = match metric_type {
-                            lance_linalg::distance::DistanceType::L2 => {
-                                l2_distance_batch(sub_vec, centroids, sub_vector_width)
-                            }
-                            lance_linalg::distance::DistanceType::Dot => {
-                                dot_distance_batch(sub_vec, centroids, sub_vector_width)
-                            }
-                            lance_linalg::distance::DistanceType::Cosine => {
-                                panic!("There should not be cosine for PQ");
-                            }
-                        };
-                        argmin_value_float(distances).map(|(_, v)| v).unwrap_or(0.0)
-                    })
-                    .sum::<f32>() as f64
-            })
-            .sum::<f64>();
-        Ok(total_distortion / data.num_rows() as f64)
+        let mut codes = Vec::with_capacity(data.num_rows() * self.num_sub_vectors);
+        let mut total_distortion = 0.0_f64;
+        for vector in data.iter() {
+            let mut distortion = 0.0_f32;
+            for (sub_vector_idx, sub_vec) in vector.chunks_exact(sub_vector_width).enumerate() {
+                let centroids = self.centroids(sub_vector_idx);
+                let (code, distance) = match metric_type {
+                    lance_linalg::distance::DistanceType::L2 => {
+                        argmin_value(l2_distance_batch(sub_vec, centroids, sub_vector_width))
+                    }
+                    lance_linalg::distance::DistanceType::Dot => {
+                        argmin_value(dot_distance_batch(sub_vec, centroids, sub_vector_width))
+                    }
+                    lance_linalg::distance::DistanceType::Cosine => {
+                        panic!("There should not be cosine for PQ");
+                    }
+                }
+                .ok_or(Error::Index {
+                    message: format!(
+                        "Failed to assign PQ code: {}, sub-vector={:#?}",
+                        "it is likely that distance is NaN or Inf", sub_vec
+                    ),
+                    location: location!(),
+                })?;
+                codes.push(code as u8);
+                distortion += distance;
+            }
+            total_distortion += distortion as f64;
+        }
+        Ok((codes, total_distortion / data.num_rows() as f64))
     }
 
     fn build_l2_distance_table(&self, key: &dyn Array) -> Result<Vec<f32>> {
-        let key: &T::ArrayType = key.as_any().downcast_ref().ok_or(Error::Index {
+        let key: &T::ArrayType = &downcast_float_array::<T>(key).ok_or(Error::Index {
             message: format!(
                 "Build L2 distance table, type mismatch: {}",
                 key.data_type()
             ),
             location: Default::default(),
         })?;
-        Ok(build_distance_table_l2(
-            self.codebook.as_slice(),
-            self.num_bits,
-            self.num_sub_vectors,
-            key.as_slice(),
+        self.build_distance_table(self.query_values(key)?)
+    }
+
+    /// Build the distance table of a query, `[f32: num_sub_vectors(row) * num_centroids(column)]`.
+    ///
+    /// It is the concatenation of [`Self::sub_vector_distance_table`] of every sub-vector
+    /// of the query. The query must already be rotated if the PQ has a rotation.
+    pub fn build_distance_table(&self, query: &[T::Native]) -> Result<Vec<f32>> {
+        if query.len() != self.dimension {
+            return Err(Error::Index {
+                message: format!(
+                    "Build PQ distance table: query has {} dimensions, expect {}",
+                    query.len(),
+                    self.dimension
+                ),
+                location: location!(),
+            });
+        }
+        let sub_vector_length = self.dimension / self.num_sub_vectors;
+        let mut distance_table =
+            Vec::with_capacity(self.num_sub_vectors * num_centroids(self.num_bits));
+        for (sub_vector_idx, sub_vec) in query.chunks_exact(sub_vector_length).enumerate() {
+            distance_table.extend(self.sub_vector_distance_table(sub_vector_idx, sub_vec)?);
+        }
+        Ok(distance_table)
+    }
+
+    /// Distances from one sub-vector of a query to the centroids of that sub-vector.
+    ///
+    /// When only part of a query changes, i.e., during incremental query refinement,
+    /// only the rows of the changed sub-vectors need to be rebuilt, see
+    /// [`Self::update_distance_table`].
+    pub fn sub_vector_distance_table(
+        &self,
+        sub_vector_idx: usize,
+        sub_vec: &[T::Native],
+    ) -> Result<Vec<f32>> {
+        let sub_vector_length = self.dimension / self.num_sub_vectors;
+        if sub_vector_idx >= self.num_sub_vectors || sub_vec.len() != sub_vector_length {
+            return Err(Error::Index {
+                message: format!(
+                    "Build PQ distance table: invalid sub-vector {} of length {}, expect {} sub-vectors of length {}",
+                    sub_vector_idx,
+                    sub_vec.len(),
+                    self.num_sub_vectors,
+                    sub_vector_length
+                ),
+                location: location!(),
+            });
+        }
+        let centroids = self.centroids(sub_vector_idx);
+        Ok(match self.metric_type {
+            MetricType::L2 | MetricType::Cosine => {
+                l2_distance_batch(sub_vec, centroids, sub_vector_length).collect()
+            }
+            MetricType::Dot => dot_distance_batch(sub_vec, centroids, sub_vector_length).collect(),
+        })
+    }
+
+    /// Rebuild the row of `sub_vector_idx` in a distance table built by
+    /// [`Self::build_distance_table`], after that sub-vector of the query changed to `sub_vec`.
+    pub fn update_distance_table(
+        &self,
+        distance_table: &mut [f32],
+        sub_vector_idx: usize,
+        sub_vec: &[T::Native],
+    ) -> Result<()> {
+        let num_centroids = num_centroids(self.num_bits);
+        if distance_table.len() != self.num_sub_vectors * num_centroids {
+            return Err(Error::Index {
+                message: format!(
+                    "Update PQ distance table: table has {} entries, expect {}",
+                    distance_table.len(),
+                    self.num_sub_vectors * num_centroids
+                ),
+                location: location!(),
+            });
+        }
+        let distances = self.sub_vector_distance_table(sub_vector_idx, sub_vec)?;
+        distance_table[sub_vector_idx * num_centroids..(sub_vector_idx + 1) * num_centroids]
+            .copy_from_slice(&distances);
+        Ok(())
+    }
+
+    /// The values of the query vector to compute distances with.
+    ///
+    /// Some models pad their vectors, i.e., to a power of two, while the codebook
+    /// is trained on the true dimension. A query longer than `dimension` is
+    /// truncated to `dimension`, with a warning.
+    fn query_values<'a>(&self, key: &'a T::ArrayType) -> Result<&'a [T::Native]> {
+        let values = key.as_slice();
+        match values.len().cmp(&self.dimension) {
+            std::cmp::Ordering::Equal => Ok(values),
+            std::cmp::Ordering::Greater => {
+                warn!(
+                    "PQ query has {} dimensions, truncating it to the PQ dimension {}",
+                    values.len(),
+                    self.dimension
+                );
+                Ok(&values[..self.dimension])
+            }
+            std::cmp::Ordering::Less => Err(Error::Index {
+                message: format!(
+                    "PQ query has {} dimensions, expect at least {}",
+                    values.len(),
+                    self.dimension
+                ),
+                location: location!(),
+            }),
+        }
+    }
+
+    /// The tile size used to compute L2 distances.
+    ///
+    /// Falls back to [`DEFAULT_TILE_SIZE`] if it is not set explicitly.
+    pub fn tile_size(&self) -> usize {
+        self.tile_size.unwrap_or(DEFAULT_TILE_SIZE)
+    }
+
+    /// Set the tile size, in number of sub-vectors, used to compute L2 distances.
+    ///
+    /// The best tile size depends on the cache sizes of the hardware, it must be one
+    /// of [`SUPPORTED_TILE_SIZES`].
+    pub fn with_tile_size(mut self, tile_size: usize) -> Result<Self> {
+        if !SUPPORTED_TILE_SIZES.contains(&tile_size) {
+            return Err(Error::Index {
+                message: format!(
+                    "Unsupported PQ distance tile size: {}, supported: {:?}",
+                    tile_size, SUPPORTED_TILE_SIZES
+                ),
+                location: location!(),
+            });
+        }
+        self.tile_size = Some(tile_size);
+        Ok(self)
+    }
+
+    /// Compute the distances from `query` to the PQ `code`, like
+    /// [`ProductQuantizer::compute_distances`], with `tile_size` instead of [`Self::tile_size`].
+    ///
+    /// The tile size is the number of sub-vectors the L2 kernel sums over at once, which
+    /// keeps those rows of the distance table in the L1 cache. It only changes the speed,
+    /// not the distances, so it can be tuned per call, i.e., in benchmarks. It must be one
+    /// of [`SUPPORTED_TILE_SIZES`]. Dot distances are not tiled, so it has no effect on them.
+    pub fn compute_distances_with_tile_size(
+        &self,
+        query: &dyn Array,
+        code: &UInt8Array,
+        tile_size: usize,
+    ) -> Result<Float32Array> {
+        self.clone()
+            .with_tile_size(tile_size)?
+            .compute_distances(query, code)
+    }
+
+    /// Estimate the distances from `query` to the PQ `code` from only the first
+    /// `num_sub_vectors_used` sub-vectors.
+    ///
+    /// This is a cheaper, lower fidelity version of [`ProductQuantizer::compute_distances`]
+    /// for progressive scoring: candidates can be ranked with a few sub-vectors first, and
+    /// only the best ones refined with the full code. Each sub-vector adds a non-negative
+    /// term to an L2 distance, so the coarse L2 distance is a lower bound of the full one.
+    /// There is no such bound for dot distances. With all of the sub-vectors it is the
+    /// full distance.
+    pub fn coarse_distances(
+        &self,
+        query: &dyn Array,
+        code: &UInt8Array,
+        num_sub_vectors_used: usize,
+    ) -> Result<Float32Array> {
+        if num_sub_vectors_used == 0 || num_sub_vectors_used > self.num_sub_vectors {
+            return Err(Error::invalid_input(
+                format!(
+                    "PQ coarse distances must use between 1 and {} sub-vectors, got {}",
+                    self.num_sub_vectors, num_sub_vectors_used
+                ),
+                location!(),
+            ));
+        }
+        let rotated = self.rotate_query(query)?;
+        let query = rotated
+            .as_ref()
+            .map(|query| query as &dyn Array)
+            .unwrap_or(query);
+        let query: &T::ArrayType = &downcast_float_array::<T>(query).ok_or(Error::Index {
+            message: format!("PQ coarse distances, type mismatch: {}", query.data_type()),
+            location: location!(),
+        })?;
+        // Only the rows of the distance table of the used sub-vectors are built
+        let sub_vector_length = self.dimension / self.num_sub_vectors;
+        let mut distance_table =
+            Vec::with_capacity(num_sub_vectors_used * num_centroids(self.num_bits));
+        for (sub_vector_idx, sub_vec) in self
+            .query_values(query)?
+            .chunks_exact(sub_vector_length)
+            .take(num_sub_vectors_used)
+            .enumerate()
+        {
+            distance_table.extend(self.sub_vector_distance_table(sub_vector_idx, sub_vec)?);
+        }
+
+        let unpacked;
+        let code = if is_bit_packed(self.num_bits) {
+            unpacked = unpack_codes(code.values(), self.num_bits, self.num_sub_vectors);
+            unpacked.as_slice()
+        } else {
+            code.values().as_ref()
+        };
+        let num_centroids = num_centroids(self.num_bits);
+        Ok(Float32Array::from_iter_values(
+            code.chunks_exact(self.num_sub_vectors).map(|vector_code| {
+                vector_code[..num_sub_vectors_used]
+                    .iter()
+                    .enumerate()
+                    .map(|(sub_vector_idx, &centroid_idx)| {
+                        distance_table[sub_vector_idx * num_centroids + centroid_idx as usize]
+                    })
+                    .sum::<f32>()
+            }),
         ))
     }
 
-    /// Compute L2 distance from the query to all code.
+    /// Compute the L2 distances from `query` to the PQ `code`, stopping early on the codes
+    /// whose partial distance exceeds `threshold`.
     ///
-    /// Type parameters
-    /// ---------------
-    /// - C: the tile size of code-book to run at once.
-    /// - V: the tile size of PQ code to run at once.
+    /// For top-k search `threshold` is the running k-th best distance: every sub-vector adds
+    /// a non-negative term to an L2 distance, so once the partial sum of a code is above it
+    /// the candidate can not make the top k and its remaining sub-vectors are skipped.
+    /// Pruned codes are null in the result, the others have their full distance. There is
+    /// no such bound for dot distances, which are rejected.
+    pub fn compute_distances_with_threshold(
+        &self,
+        query: &dyn Array,
+        code: &UInt8Array,
+        threshold: f32,
+    ) -> Result<Float32Array> {
+        if self.metric_type != MetricType::L2 {
+            return Err(Error::invalid_input(
+                format!(
+                    "PQ distances with a threshold require the L2 metric, got {:?}",
+                    self.metric_type
+                ),
+                location!(),
+            ));
+        }
+        let rotated = self.rotate_query(query)?;
+        let query = rotated
+            .as_ref()
+            .map(|query| query as &dyn Array)
+            .unwrap_or(query);
+        let distance_table = self.build_l2_distance_table(query)?;
+
+        let unpacked;
+        let code = if is_bit_packed(self.num_bits) {
+            unpacked = unpack_codes(code.values(), self.num_bits, self.num_sub_vectors);
+            unpacked.as_slice()
+        } else {
+            code.values().as_ref()
+        };
+        let num_centroids = num_centroids(self.num_bits);
+        Ok(code
+            .chunks_exact(self.num_sub_vectors)
+            .map(|vector_code| {
+                let mut distance = 0.0;
+                for (sub_vector_idx, &centroid_idx) in vector_code.iter().enumerate() {
+                    distance +=
+                        distance_table[sub_vector_idx * num_centroids + centroid_idx as usize];
+                    if distance > threshold {
+                        return None;
+                    }
+                }
+                Some(distance)
+            })
+            .collect())
+    }
+
+    /// Compute distances against the reconstructed vectors for partitions with
+    /// fewer than `threshold` PQ codes.
     ///
-    /// Parameters
-    /// ----------
-    /// - distance_table: the pre-computed L2 distance table.
-    ///   It is a flatten array of [num_sub_vectors, num_centroids] f32.
-    /// - code: the PQ code to be used to compute the distances.
+    /// Building the distance table costs `num_centroids * dimension` operations for
+    /// each query, which is more than comparing the query to each reconstructed vector
+    /// when the partition is small.
+    pub fn with_exact_distance_threshold(mut self, threshold: usize) -> Self {
+        self.exact_distance_threshold = Some(threshold);
+        self
+    }
+
+    /// Rotate the vectors with an orthogonal `dimension x dimension` matrix, in
+    /// row-major order, before quantizing them, see [`Self::rotation`].
+    pub fn with_rotation(mut self, rotation: Arc<T::ArrayType>) -> Result<Self> {
+        if rotation.len() != self.dimension * self.dimension {
+            return Err(Error::Index {
+                message: format!(
+                    "PQ rotation must be a {}x{} matrix, got {} values",
+                    self.dimension,
+                    self.dimension,
+                    rotation.len()
+                ),
+                location: location!(),
+            });
+        }
+        self.rotation = Some(rotation);
+        Ok(self)
+    }
+
+    /// Apply the rotation, if any, to the vectors, or undo it if `inverse` is true.
+    fn rotate_vectors(
+        &self,
+        vectors: &FixedSizeListArray,
+        inverse: bool,
+    ) -> Result<FixedSizeListArray> {
+        let Some(rotation) = &self.rotation else {
+            return Ok(vectors.clone());
+        };
+        let values: &T::ArrayType =
+            &downcast_float_array::<T>(vectors.values()).ok_or(Error::Index {
+                message: format!(
+                    "Rotate PQ vectors: expect a {} vector array, got: {}",
+                    T::FLOAT_TYPE,
+                    vectors.value_type()
+                ),
+                location: location!(),
+            })?;
+        let rotated = apply_rotation(
+            values.as_slice(),
+            rotation.as_slice(),
+            self.dimension,
+            inverse,
+        );
+        Ok(FixedSizeListArray::try_new_from_values(
+            T::ArrayType::from(rotated),
+            self.dimension as i32,
+        )?)
+    }
+
+    /// Convert `Int8` vectors to vectors of the codebook float type.
+    fn int8_to_float(&self, vectors: &FixedSizeListArray) -> Result<FixedSizeListArray> {
+        let values = vectors
+            .values()
+            .as_primitive::<Int8Type>()
+            .values()
+            .iter()
+            .map(|v| T::Native::from_i8(*v).unwrap_or(T::Native::zero()))
+            .collect::<Vec<_>>();
+        Ok(FixedSizeListArray::try_new_from_values(
+            T::ArrayType::from(values),
+            vectors.value_length(),
+        )?)
+    }
+
+    /// Rotate the query, if there is a rotation.
+    fn rotate_query(&self, query: &dyn Array) -> Result<Option<T::ArrayType>> {
+        let Some(rotation) = &self.rotation else {
+            return Ok(None);
+        };
+        let key: &T::ArrayType = &downcast_float_array::<T>(query).ok_or(Error::Index {
+            message: format!("Rotate PQ query, type mismatch: {}", query.data_type()),
+            location: location!(),
+        })?;
+        Ok(Some(T::ArrayType::from(apply_rotation(
+            self.query_values(key)?,
+            rotation.as_slice(),
+            self.dimension,
+            false,
+        ))))
+    }
+
+    /// Use a f16 L2 distance table to score PQ codes.
     ///
-    /// Returns
-    /// -------
-    ///  The squared L2 distance.
-    #[inline]
-    fn compute_l2_distance<const C: usize, const V: usize>(
+    /// The table is half the size of the f32 table, which is more cache friendly for
+    /// large `num_sub_vectors`. The relative error of each distance is within `2^-11`
+    /// of the f32 table. It only applies to L2 and cosine distances.
+    pub fn with_half_precision_distance_table(mut self, enabled: bool) -> Self {
+        self.half_precision_distance_table = enabled;
+        self
+    }
+
+    /// Sum the distance table entries of each PQ code in f64.
+    ///
+    /// The distance table stays in f32, but the summation error of f32 accumulation grows
+    /// with `num_sub_vectors`, which matters for 64 sub-vectors and more. It is slower,
+    /// so the default is to accumulate in f32.
+    pub fn with_f64_accumulation(mut self, enabled: bool) -> Self {
+        self.f64_accumulation = enabled;
+        self
+    }
+
+    /// Truncate vectors padded beyond `dimension` when they are transformed.
+    ///
+    /// Some models pad their vectors, i.e., to a power of two, while the codebook is
+    /// trained on the true dimension. By default, transforming vectors of another
+    /// dimension is an error. If enabled, longer vectors are truncated to their first
+    /// `dimension` values, the padding is expected to be meaningless.
+    pub fn with_truncate_padded_vectors(mut self, enabled: bool) -> Self {
+        self.truncate_padded_vectors = enabled;
+        self
+    }
+
+    /// Whether to compute distances of `num_codes` PQ codes against the
+    /// reconstructed vectors, see [`Self::with_exact_distance_threshold`].
+    pub(crate) fn use_exact_distances(&self, num_codes: usize) -> bool {
+        self.exact_distance_threshold
+            .map(|threshold| num_codes < threshold)
+            .unwrap_or(false)
+    }
+
+    /// Compute distances from the query to the vectors reconstructed from the PQ code.
+    ///
+    /// L2 returns the squared L2 distance, and Dot returns the negative dot product,
+    /// the same as the distance table based methods. The codes use the layout of
+    /// [`ProductQuantizer::transform`].
+    fn exact_distances(&self, key: &dyn Array, code: &UInt8Array) -> Result<Float32Array> {
+        let key: &T::ArrayType = &downcast_float_array::<T>(key).ok_or(Error::Index {
+            message: format!(
+                "Compute exact PQ distance, type mismatch: {}",
+                key.data_type()
+            ),
+            location: location!(),
+        })?;
+        let key = self.query_values(key)?;
+        let unpacked;
+        let code = if is_bit_packed(self.num_bits) {
+            unpacked = unpack_codes(code.values(), self.num_bits, self.num_sub_vectors);
+            unpacked.as_slice()
+        } else {
+            code.values().as_ref()
+        };
+        Ok(Float32Array::from_iter_values(
+            code.chunks_exact(self.num_sub_vectors).map(|c| {
+                let vector = self.reconstruct(c);
+                match self.metric_type {
+                    MetricType::Dot => -T::dot(key, vector.as_slice()),
+                    _ => T::l2(key, vector.as_slice()),
+                }
+            }),
+        ))
+    }
+
+    /// Compute the distances from a query, given as PQ code, to all code.
+    ///
+    /// `query_code` holds one centroid index per sub-vector, and `code` uses the layout
+    /// of [`ProductQuantizer::transform`]. The distance table is taken from the
+    /// [`SymmetricDistanceTable`], without reconstructing the query vector. The distances
+    /// are the same as [`ProductQuantizer::compute_distances`] on the reconstructed query.
+    ///
+    /// Returns an error if any query code is not a valid centroid index.
+    pub fn compute_distances_code_query(
         &self,
-        distance_table: &[f32],
-        code: &[u8],
-    ) -> Float32Array {
-        Float32Array::from(compute_l2_distance::<C, V>(
-            distance_table,
+        query_code: &[u8],
+        code: &UInt8Array,
+    ) -> Result<Float32Array> {
+        let distance_table = self.symmetric_distance_table().distance_table(query_code)?;
+        let unpacked;
+        let code = if is_bit_packed(self.num_bits) {
+            unpacked = unpack_codes(code.values(), self.num_bits, self.num_sub_vectors);
+            unpacked.as_slice()
+        } else {
+            code.values()
+        };
+        let distances = compute_l2_distance_with_tile(
+            self.tile_size(),
+            &distance_table,
             self.num_bits,
             self.num_sub_vectors,
             code,
-        ))
+        )?;
+        Ok(Float32Array::from(distances))
+    }
+
+    /// Build the distance table of a query, with the part shared by all codes factored out.
+    ///
+    /// Returns `(table, constant)`, where the distance from the query to a PQ code is
+    /// `constant + sum(table[sub_vector_idx * num_centroids + code[sub_vector_idx]])`.
+    ///
+    /// - For L2, the distance from a query sub-vector `q` to a centroid `c` is
+    ///   `|q - c|^2 = |q|^2 + (|c|^2 - 2 q.c)`. The table holds `|c|^2 - 2 q.c`, and
+    ///   the constant is `|q|^2` summed over the sub-vectors, i.e., the squared norm of
+    ///   the whole query.
+    /// - For dot, the table holds `-q.c`, which is already the full distance, and the
+    ///   constant is 0.
+    ///
+    /// The constant does not depend on the code, so callers scoring many blocks of codes
+    /// can score them with [`Self::compute_distances_from_table`] and add the constant
+    /// once, or skip it if only the order of the distances matters.
+    pub fn build_factored_distance_table(&self, query: &dyn Array) -> Result<(Vec<f32>, f32)> {
+        let rotated = self.rotate_query(query)?;
+        let query = rotated
+            .as_ref()
+            .map(|query| query as &dyn Array)
+            .unwrap_or(query);
+        let key: &T::ArrayType = &downcast_float_array::<T>(query).ok_or(Error::Index {
+            message: format!(
+                "Build factored distance table, type mismatch: {}",
+                query.data_type()
+            ),
+            location: location!(),
+        })?;
+        let query = self.query_values(key)?;
+
+        let sub_vector_width = self.dimension / self.num_sub_vectors;
+        let table = query
+            .chunks_exact(sub_vector_width)
+            .enumerate()
+            .flat_map(|(sub_vector_idx, sub_vector)| {
+                self.centroids(sub_vector_idx)
+                    .chunks_exact(sub_vector_width)
+                    .map(move |centroid| match self.metric_type {
+                        MetricType::Dot => -T::dot(sub_vector, centroid),
+                        _ => T::dot(centroid, centroid) - 2.0 * T::dot(sub_vector, centroid),
+                    })
+            })
+            .collect::<Vec<_>>();
+        let constant = match self.metric_type {
+            MetricType::Dot => 0.0,
+            _ => T::dot(query, query),
+        };
+        Ok((table, constant))
+    }
+
+    /// Sum the entries of a distance table selected by each PQ code.
+    ///
+    /// The table has `num_centroids` entries per sub-vector, e.g., the table returned by
+    /// [`Self::build_factored_distance_table`], without its constant.
+    pub fn compute_distances_from_table(
+        &self,
+        table: &[f32],
+        code: &UInt8Array,
+    ) -> Result<Float32Array> {
+        let expected_len = self.num_sub_vectors * num_centroids(self.num_bits);
+        if table.len() != expected_len {
+            return Err(Error::Index {
+                message: format!(
+                    "PQ distance table has {} entries, expect {}",
+                    table.len(),
+                    expected_len
+                ),
+                location: location!(),
+            });
+        }
+        let unpacked;
+        let code = if is_bit_packed(self.num_bits) {
+            unpacked = unpack_codes(code.values(), self.num_bits, self.num_sub_vectors);
+            unpacked.as_slice()
+        } else {
+            code.values().as_ref()
+        };
+        Ok(Float32Array::from(compute_l2_distance_with_tile(
+            self.tile_size(),
+            table,
+            self.num_bits,
+            self.num_sub_vectors,
+            code,
+        )?))
     }
 
     /// Pre-compute L2 distance from the query to all code.
     ///
     /// It returns the squared L2 distance.
     fn l2_distances(&self, key: &dyn Array, code: &UInt8Array) -> Result<Float32Array> {
+        if self.use_exact_distances(code.len() / code_length(self.num_bits, self.num_sub_vectors)) {
+            return self.exact_distances(key, code);
+        }
         let distance_table = self.build_l2_distance_table(key)?;
-
-        #[cfg(target_feature = "avx512f")]
-        {
-            Ok(self.compute_l2_distance::<16, 64>(&distance_table, code.values()))
+        let unpacked;
+        let code = if is_bit_packed(self.num_bits) {
+            unpacked = unpack_codes(code.values(), self.num_bits, self.num_sub_vectors);
+            unpacked.as_slice()
+        } else {
+            code.values().as_ref()
+        };
+        if self.half_precision_distance_table {
+            let distance_table = distance_table
+                .iter()
+                .map(|d| f16::from_f32(*d))
+                .collect::<Vec<_>>();
+            return Ok(Float32Array::from(compute_l2_distance_f16(
+                &distance_table,
+                self.num_bits,
+                self.num_sub_vectors,
+                code,
+            )));
         }
-        #[cfg(not(target_feature = "avx512f"))]
-        {
-            Ok(self.compute_l2_distance::<8, 64>(&distance_table, code.values()))
+        if self.f64_accumulation {
+            return Ok(Float32Array::from(compute_distance_f64_accumulated(
+                &distance_table,
+                self.num_bits,
+                self.num_sub_vectors,
+                code,
+            )));
+        }
+        // An explicit tile size always uses the tiled kernel, the faster kernels are only
+        // picked when the tile size is left to the quantizer.
+        if self.tile_size.is_none() {
+            #[cfg(all(feature = "nightly", target_arch = "x86_64"))]
+            {
+                if let Some(distances) = compute_l2_distance_avx512(
+                    &distance_table,
+                    self.num_bits,
+                    self.num_sub_vectors,
+                    code,
+                ) {
+                    return Ok(Float32Array::from(distances));
+                }
+            }
+            if let Some(distances) = compute_l2_distance_small_sub_vectors(
+                &distance_table,
+                self.num_bits,
+                self.num_sub_vectors,
+                code,
+            ) {
+                return Ok(Float32Array::from(distances));
+            }
         }
+        Ok(Float32Array::from(compute_l2_distance_with_tile(
+            self.tile_size(),
+            &distance_table,
+            self.num_bits,
+            self.num_sub_vectors,
+            code,
+        )?))
     }
 
     /// Parameters
@@ -286,7 +1695,10 @@ impl<T: ArrowFloatType + Dot + L2> ProductQuantizerImpl<T> {
     ///  - code: the PQ code in one partition.
     ///
     fn dot_distances(&self, key: &dyn Array, code: &UInt8Array) -> Result<Float32Array> {
-        let key: &T::ArrayType = key.as_any().downcast_ref().ok_or(Error::Index {
+        if self.use_exact_distances(code.len() / code_length(self.num_bits, self.num_sub_vectors)) {
+            return self.exact_distances(key, code);
+        }
+        let key: &T::ArrayType = &downcast_float_array::<T>(key).ok_or(Error::Index {
             message: format!(
                 "Build Dot distance table, type mismatch: {}",
                 key.data_type()
@@ -294,27 +1706,43 @@ impl<T: ArrowFloatType + Dot + L2> ProductQuantizerImpl<T> {
             location: Default::default(),
         })?;
 
-        // Distance table: `[f32: num_sub_vectors(row) * num_centroids(column)]`.
-        let capacity = self.num_sub_vectors * num_centroids(self.num_bits);
-        let mut distance_table = Vec::with_capacity(capacity);
+        let distance_table = self.build_distance_table(self.query_values(key)?)?;
+        let unpacked;
+        let code = if is_bit_packed(self.num_bits) {
+            unpacked = unpack_codes(code.values(), self.num_bits, self.num_sub_vectors);
+            unpacked.as_slice()
+        } else {
+            code.values().as_ref()
+        };
 
-        let sub_vector_length = self.dimension / self.num_sub_vectors;
-        key.as_slice()
-            .chunks_exact(sub_vector_length)
-            .enumerate()
-            .for_each(|(sub_vec_id, sub_vec)| {
-                let subvec_centroids = self.centroids(sub_vec_id);
-                let distances = dot_distance_batch(sub_vec, subvec_centroids, sub_vector_length);
-                distance_table.extend(distances);
-            });
+        if self.f64_accumulation {
+            return Ok(Float32Array::from(compute_distance_f64_accumulated(
+                &distance_table,
+                self.num_bits,
+                self.num_sub_vectors,
+                code,
+            )));
+        }
 
         // Compute distance from the pre-compute table.
+        //
+        // Each entry of the table is `-q_i · c_i`, the negative dot product of the i-th query
+        // sub-vector and a centroid of the i-th sub-space. The sub-vectors split the dimensions
+        // without overlap, so for the reconstructed vector `x = [c_0, .., c_{m-1}]`:
+        //
+        //   -q · x = sum_i (-q_i · c_i)
+        //
+        // The sum of the table entries is the exact distance, no correction term is needed.
+        // A correction of `m - 1` only applies to tables of `1 - q_i · c_i`, which sum to
+        // `m - q · x`. This holds for any `num_bits`, as the codes are unpacked to one byte per
+        // sub-vector above, and the table has `num_centroids(num_bits)` entries per sub-vector.
+        let num_centroids = num_centroids(self.num_bits);
         Ok(Float32Array::from_iter_values(
-            code.values().chunks_exact(self.num_sub_vectors).map(|c| {
+            code.chunks_exact(self.num_sub_vectors).map(|c| {
                 c.iter()
                     .enumerate()
                     .map(|(sub_vec_idx, centroid)| {
-                        distance_table[sub_vec_idx * 256 + *centroid as usize]
+                        distance_table[sub_vec_idx * num_centroids + *centroid as usize]
                     })
                     .sum::<f32>()
             }),
@@ -322,6 +1750,22 @@ impl<T: ArrowFloatType + Dot + L2> ProductQuantizerImpl<T> {
     }
 }
 
+/// Keep the first `dimension` values of each vector.
+fn truncate_vectors(vectors: &FixedSizeListArray, dimension: usize) -> Result<FixedSizeListArray> {
+    let value_length = vectors.value_length() as usize;
+    let indices = UInt64Array::from_iter_values(
+        (0..vectors.len())
+            .flat_map(|row| (0..dimension).map(move |i| (row * value_length + i) as u64)),
+    );
+    let values = arrow_select::take::take(vectors.values(), &indices, None)?;
+    Ok(FixedSizeListArray::try_new(
+        Arc::new(Field::new("item", values.data_type().clone(), true)),
+        dimension as i32,
+        values,
+        None,
+    )?)
+}
+
 #[async_trait]
 impl<T: ArrowFloatType + Dot + L2 + 'static> ProductQuantizer for ProductQuantizerImpl<T> {
     fn as_any(&self) -> &dyn Any {
@@ -329,16 +1773,42 @@ impl<T: ArrowFloatType + Dot + L2 + 'static> ProductQuantizer for ProductQuantiz
     }
 
     async fn transform(&self, data: &dyn Array) -> Result<ArrayRef> {
-        let fsl = data
-            .as_fixed_size_list_opt()
-            .ok_or(Error::Index {
-                message: format!(
-                    "Expect to be a FixedSizeList<float> vector array, got: {:?} array",
-                    data.data_type()
-                ),
-                location: location!(),
-            })?
-            .clone();
+        let fsl = data.as_fixed_size_list_opt().ok_or(Error::Index {
+            message: format!(
+                "Expect to be a FixedSizeList<float> vector array, got: {:?} array",
+                data.data_type()
+            ),
+            location: location!(),
+        })?;
+        // Null vectors are assigned the all-zero code, and stay null in the output.
+        let nulls = fsl.nulls().cloned();
+        // Int8 vectors, i.e., quantized embeddings, are converted to the codebook type.
+        let converted;
+        let fsl = if fsl.value_type() == DataType::Int8 {
+            converted = self.int8_to_float(fsl)?;
+            &converted
+        } else {
+            fsl
+        };
+        let truncated;
+        let fsl = match (fsl.value_length() as usize).cmp(&self.dimension) {
+            std::cmp::Ordering::Equal => fsl,
+            std::cmp::Ordering::Greater if self.truncate_padded_vectors => {
+                truncated = truncate_vectors(fsl, self.dimension)?;
+                &truncated
+            }
+            _ => {
+                return Err(Error::Index {
+                    message: format!(
+                        "PQ transform: expect vectors of dimension {}, got {}",
+                        self.dimension,
+                        fsl.value_length()
+                    ),
+                    location: location!(),
+                })
+            }
+        };
+        let fsl = self.rotate_vectors(fsl, false)?;
 
         let num_sub_vectors = self.num_sub_vectors;
         let dim = self.dimension;
@@ -347,6 +1817,7 @@ impl<T: ArrowFloatType + Dot + L2 + 'static> ProductQuantizer for ProductQuantiz
         let codebook = self.codebook.clone();
 
         let metric_type = self.metric_type;
+        let row_nulls = nulls.clone();
         let values = tokio::task::spawn_blocking(move || {
             let all_centroids = (0..num_sub_vectors)
                 .map(|idx| {
@@ -359,29 +1830,24 @@ impl<T: ArrowFloatType + Dot + L2 + 'static> ProductQuantizer for ProductQuantiz
                     )
                 })
                 .collect::<Vec<_>>();
-            let flatten_data =
-                fsl.values()
-                    .as_any()
-                    .downcast_ref::<T::ArrayType>()
-                    .ok_or(Error::Index {
-                        message: format!(
-                            "Expect to be a float vector array, got: {:?}",
-                            fsl.value_type()
-                        ),
-                        location: location!(),
-                    })?;
+            let flatten_data = &downcast_float_array::<T>(fsl.values()).ok_or(Error::Index {
+                message: format!(
+                    "Expect to be a float vector array, got: {:?}",
+                    fsl.value_type()
+                ),
+                location: location!(),
+            })?;
 
-            let flatten_values = flatten_data.as_slice();
+            let flatten_values = &flatten_data.as_slice()[..num_rows * dim];
             let capacity = num_sub_vectors * num_rows;
             let mut builder: Vec<u8> = vec![0; capacity];
             // Dimension of each sub-vector.
             let sub_dim = dim / num_sub_vectors;
-            for i in 0..num_rows {
-                let row_offset = i * dim;
-
-                for sub_idx in 0..num_sub_vectors {
-                    let offset = row_offset + sub_idx * sub_dim;
-                    let sub_vector = &flatten_values[offset..offset + sub_dim];
+            let encode = |i: usize, row: &[T::Native], code: &mut [u8]| -> Result<()> {
+                if row_nulls.as_ref().map(|n| n.is_null(i)).unwrap_or(false) {
+                    return Ok(());
+                }
+                for (sub_idx, sub_vector) in row.chunks_exact(sub_dim).enumerate() {
                     let centroids = all_centroids[sub_idx];
 
                     let dist_iter = match metric_type {
@@ -390,63 +1856,283 @@ impl<T: ArrowFloatType + Dot + L2 + 'static> ProductQuantizer for ProductQuantiz
                         }
                         MetricType::Dot => dot_distance_batch(sub_vector, centroids, sub_dim),
                     };
-                    let code = argmin(dist_iter).ok_or(Error::Index {
+                    code[sub_idx] = argmin(dist_iter).ok_or(Error::Index {
                         message: format!(
                             "Failed to assign PQ code: {}, sub-vector={:#?}",
                             "it is likely that distance is NaN or Inf", sub_vector
                         ),
                         location: location!(),
                     })? as u8;
-                    builder[i * num_sub_vectors + sub_idx] = code as u8;
                 }
+                Ok(())
+            };
+            // Every vector writes to its own slot of the output, so the codes are in the
+            // same order either way. Codes are bit-packed afterwards, on the whole output.
+            if num_rows >= PARALLEL_TRANSFORM_MIN_ROWS {
+                builder
+                    .par_chunks_exact_mut(num_sub_vectors)
+                    .zip(flatten_values.par_chunks_exact(dim))
+                    .enumerate()
+                    .try_for_each(|(i, (code, row))| encode(i, row, code))?;
+            } else {
+                builder
+                    .chunks_exact_mut(num_sub_vectors)
+                    .zip(flatten_values.chunks_exact(dim))
+                    .enumerate()
+                    .try_for_each(|(i, (code, row))| encode(i, row, code))?;
             }
             Ok::<UInt8Array, Error>(UInt8Array::from(builder))
         })
         .await??;
 
-        Ok(Arc::new(FixedSizeListArray::try_new_from_values(
+        Ok(Arc::new(codes_to_fsl(
             values,
-            self.num_sub_vectors as i32,
+            self.num_bits,
+            self.num_sub_vectors,
+            nulls,
         )?))
     }
 
     fn compute_distances(&self, query: &dyn Array, code: &UInt8Array) -> Result<Float32Array> {
+        let rotated = self.rotate_query(query)?;
+        let query = rotated
+            .as_ref()
+            .map(|query| query as &dyn Array)
+            .unwrap_or(query);
         match self.metric_type {
-            MetricType::L2 => self.l2_distances(query, code),
-            MetricType::Cosine => {
-                // L2 over normalized vectors:  ||x - y|| = x^2 + y^2 - 2 * xy = 1 + 1 - 2 * xy = 2 * (1 - xy)
-                // Cosine distance: 1 - |xy| / (||x|| * ||y||) = 1 - xy / (x^2 * y^2) = 1 - xy / (1 * 1) = 1 - xy
-                // Therefore, Cosine = L2 / 2
-                let l2_dists = self.l2_distances(query, code)?;
-                Ok(l2_dists.values().iter().map(|v| *v / 2.0).collect())
-            }
+            // Cosine is mapped to L2 over normalized vectors when the PQ is created.
+            MetricType::L2 | MetricType::Cosine => self.l2_distances(query, code),
             MetricType::Dot => self.dot_distances(query, code),
         }
     }
 
-    fn num_bits(&self) -> u32 {
-        self.num_bits
-    }
-
-    fn num_sub_vectors(&self) -> usize {
-        self.num_sub_vectors
+    fn symmetric_distance_table(&self) -> SymmetricDistanceTable {
+        SymmetricDistanceTable::new(
+            self.codebook.as_slice(),
+            self.dimension,
+            self.num_bits,
+            self.num_sub_vectors,
+            self.metric_type,
+        )
     }
 
-    fn dimension(&self) -> usize {
-        self.dimension
-    }
+    fn reconstruct(&self, codes: &UInt8Array) -> Result<FixedSizeListArray> {
+        if self.num_bits == 0 || self.num_bits > 8 {
+            return Err(Error::Index {
+                message: format!(
+                    "Reconstruct PQ codes: num_bits must be between 1 and 8, got {}",
+                    self.num_bits
+                ),
+                location: location!(),
+            });
+        }
+        let code_length = code_length(self.num_bits, self.num_sub_vectors);
+        if codes.null_count() > 0 || codes.len() % code_length != 0 {
+            return Err(Error::Index {
+                message: format!(
+                    "Reconstruct PQ codes: expect {} non-null bytes per vector, got {} bytes",
+                    code_length,
+                    codes.len()
+                ),
+                location: location!(),
+            });
+        }
 
-    fn codebook_as_fsl(&self) -> FixedSizeListArray {
-        FixedSizeListArray::try_new_from_values(
-            self.codebook.as_ref().clone(),
+        let sub_vector_width = self.dimension / self.num_sub_vectors;
+        let mut values = Vec::with_capacity(codes.len() / code_length * self.dimension);
+        for code in codes.values().chunks_exact(code_length) {
+            for sub_vector_idx in 0..self.num_sub_vectors {
+                let centroid_idx = decode_code(code, self.num_bits, sub_vector_idx);
+                values.extend_from_slice(
+                    &self.centroids(sub_vector_idx)
+                        [centroid_idx * sub_vector_width..(centroid_idx + 1) * sub_vector_width],
+                );
+            }
+        }
+        let vectors = FixedSizeListArray::try_new_from_values(
+            T::ArrayType::from(values),
             self.dimension as i32,
-        )
-        .unwrap()
+        )?;
+        self.rotate_vectors(&vectors, true)
+    }
+
+    async fn distortion(&self, vectors: &FixedSizeListArray) -> Result<f64> {
+        let original =
+            downcast_float_array::<T>(vectors.values().as_ref()).ok_or(Error::Index {
+                message: format!(
+                    "Distortion: expect vectors of {}, got {}",
+                    T::FLOAT_TYPE,
+                    vectors.value_type()
+                ),
+                location: location!(),
+            })?;
+        let codes = self.transform(vectors).await?;
+        let codes = codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>();
+        let reconstructed = self.reconstruct(codes)?;
+        let reconstructed = downcast_float_array::<T>(reconstructed.values().as_ref())
+            .expect("reconstruct returns vectors of the codebook type");
+
+        let (total, count) = original
+            .as_slice()
+            .chunks_exact(self.dimension)
+            .zip(reconstructed.as_slice().chunks_exact(self.dimension))
+            .enumerate()
+            .filter(|(row, _)| vectors.is_valid(*row))
+            .fold((0.0_f64, 0_usize), |(total, count), (_, (x, y))| {
+                (total + T::l2(x, y) as f64, count + 1)
+            });
+        if count == 0 {
+            return Ok(0.0);
+        }
+        Ok(total / count as f64)
+    }
+
+    fn sub_vector_variance(&self, vectors: &dyn Array) -> Result<Vec<f32>> {
+        let fsl = vectors.as_fixed_size_list_opt().ok_or(Error::Index {
+            message: format!(
+                "Sub-vector variance: expect a FixedSizeList<float> vector array, got: {}",
+                vectors.data_type()
+            ),
+            location: location!(),
+        })?;
+        if fsl.value_length() as usize != self.dimension {
+            return Err(Error::Index {
+                message: format!(
+                    "Sub-vector variance: expect vectors of dimension {}, got {}",
+                    self.dimension,
+                    fsl.value_length()
+                ),
+                location: location!(),
+            });
+        }
+        let fsl = self.rotate_vectors(fsl, false)?;
+        let data: &T::ArrayType = &downcast_float_array::<T>(fsl.values()).ok_or(Error::Index {
+            message: format!(
+                "Sub-vector variance: expect a {} vector array, got: {}",
+                T::FLOAT_TYPE,
+                fsl.value_type()
+            ),
+            location: location!(),
+        })?;
+        if fsl.is_empty() {
+            return Ok(vec![0.0; self.num_sub_vectors]);
+        }
+
+        let sub_vector_width = self.dimension / self.num_sub_vectors;
+        let mut sums = vec![0.0_f64; self.num_sub_vectors];
+        let mut square_sums = vec![0.0_f64; self.num_sub_vectors];
+        for vector in data.as_slice().chunks_exact(self.dimension) {
+            for (sub_vector_idx, sub_vec) in vector.chunks_exact(sub_vector_width).enumerate() {
+                let dist = argmin_value_float(l2_distance_batch(
+                    sub_vec,
+                    self.centroids(sub_vector_idx),
+                    sub_vector_width,
+                ))
+                .map(|(_, d)| d as f64)
+                .unwrap_or(0.0);
+                sums[sub_vector_idx] += dist;
+                square_sums[sub_vector_idx] += dist * dist;
+            }
+        }
+        let n = fsl.len() as f64;
+        Ok(sums
+            .iter()
+            .zip(square_sums.iter())
+            .map(|(sum, square_sum)| {
+                let mean = sum / n;
+                (square_sum / n - mean * mean).max(0.0) as f32
+            })
+            .collect())
+    }
+
+    fn num_bits(&self) -> u32 {
+        self.num_bits
+    }
+
+    fn num_sub_vectors(&self) -> usize {
+        self.num_sub_vectors
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn codebook_as_fsl(&self) -> FixedSizeListArray {
+        FixedSizeListArray::try_new_from_values(
+            self.codebook.as_ref().clone(),
+            self.dimension as i32,
+        )
+        .unwrap()
+    }
+
+    fn rotation(&self) -> Option<FixedSizeListArray> {
+        self.rotation.as_ref().map(|rotation| {
+            FixedSizeListArray::try_new_from_values(
+                rotation.as_ref().clone(),
+                self.dimension as i32,
+            )
+            .unwrap()
+        })
+    }
+
+    fn metric_type(&self) -> MetricType {
+        self.metric_type
     }
 
     fn use_residual(&self) -> bool {
         matches!(self.metric_type, MetricType::L2 | MetricType::Cosine)
     }
+
+    fn training_info(&self) -> Option<&PQTrainingInfo> {
+        self.training_info.as_ref()
+    }
+}
+
+/// Convert distances computed by [`ProductQuantizer::compute_distances`] under the metric
+/// `from` to the metric `to`.
+///
+/// The metrics only determine each other for unit-norm vectors, which the caller must
+/// guarantee by setting `unit_norm`, e.g. the vectors and queries of a `Cosine` index are
+/// normalized. For unit vectors `x` and `q`:
+///
+/// - `L2` is the squared distance `|x - q|^2 = 2 - 2 * x·q`.
+/// - `Cosine` is computed as `L2` over the normalized vectors, so it is the same value.
+/// - `Dot` is the negative dot product `-x·q`.
+///
+/// PQ distances are computed against the centroids, which are not exactly unit vectors, so
+/// the converted distances are approximations of the same order as the PQ distances. It
+/// is an error to convert between different metrics without `unit_norm`.
+pub fn convert_distances(
+    distances: &Float32Array,
+    from: MetricType,
+    to: MetricType,
+    unit_norm: bool,
+) -> Result<Float32Array> {
+    let is_l2 = |metric_type| matches!(metric_type, MetricType::L2 | MetricType::Cosine);
+    if from == to {
+        return Ok(distances.clone());
+    }
+    if !unit_norm {
+        return Err(Error::invalid_input(
+            format!(
+                "PQ distances can only be converted from {:?} to {:?} for unit-norm vectors",
+                from, to
+            ),
+            location!(),
+        ));
+    }
+    let convert: fn(f32) -> f32 = match (is_l2(from), is_l2(to)) {
+        (true, true) => |distance| distance,
+        // -x·q = (|x - q|^2 - 2) / 2
+        (true, false) => |distance| distance / 2.0 - 1.0,
+        // |x - q|^2 = 2 + 2 * (-x·q)
+        (false, true) => |distance| 2.0 + 2.0 * distance,
+        (false, false) => unreachable!("the same metric is returned above"),
+    };
+    Ok(distances.unary::<_, Float32Type>(convert))
 }
 
 #[allow(clippy::fallible_impl_from)]
@@ -462,6 +2148,11 @@ impl TryFrom<&dyn ProductQuantizer> for pb::Pq {
             dimension: pq.dimension() as u32,
             codebook: vec![],
             codebook_tensor: Some(tensor),
+            training_info: pq.training_info().map(pb::PqTrainingInfo::from),
+            rotation: pq
+                .rotation()
+                .map(|rotation| pb::Tensor::try_from(&rotation))
+                .transpose()?,
         })
     }
 }
@@ -475,8 +2166,9 @@ mod tests {
     use approx::assert_relative_eq;
     use arrow_array::{
         types::{Float16Type, Float32Type},
-        Float16Array,
+        Float16Array, Int8Array,
     };
+    use futures::TryStreamExt;
     use half::f16;
     use lance_testing::datagen::generate_random_array;
     use num_traits::Zero;
@@ -491,6 +2183,13 @@ mod tests {
                 repeat(f16::zero()).take(256 * 16),
             )),
             metric_type: MetricType::L2,
+            training_info: None,
+            tile_size: None,
+            exact_distance_threshold: None,
+            half_precision_distance_table: false,
+            f64_accumulation: false,
+            truncate_padded_vectors: false,
+            rotation: None,
         };
         let proto: pb::Pq = pb::Pq::try_from(&pq as &dyn ProductQuantizer).unwrap();
         assert_eq!(proto.num_bits, 8);
@@ -515,6 +2214,13 @@ mod tests {
             dimension: DIM,
             codebook: codebook.clone(),
             metric_type: MetricType::L2,
+            training_info: None,
+            tile_size: None,
+            exact_distance_threshold: None,
+            half_precision_distance_table: false,
+            f64_accumulation: false,
+            truncate_padded_vectors: false,
+            rotation: None,
         };
         let pq_code = UInt8Array::from_iter_values((0..16 * TOTAL).map(|v| v as u8));
         let query = generate_random_array(DIM);
@@ -550,4 +2256,2090 @@ mod tests {
                 assert_relative_eq!(*v, *e, epsilon = 1e-4);
             });
     }
+
+    #[tokio::test]
+    async fn test_l2_distance_bf16() {
+        use half::bf16;
+        use lance_arrow::bfloat16::{BFloat16Array, BFloat16Type};
+
+        const DIM: usize = 512;
+        const TOTAL: usize = 66; // 64 + 2 to make sure reminder is handled correctly.
+        let codebook = Arc::new(BFloat16Array::from_iter_values(
+            generate_random_array(256 * DIM)
+                .values()
+                .iter()
+                .map(|v| bf16::from_f32(*v)),
+        ));
+        let pq = ProductQuantizerImpl::<BFloat16Type>::new(16, 8, DIM, codebook, MetricType::L2)
+            .unwrap();
+        let pq_code = UInt8Array::from_iter_values((0..16 * TOTAL).map(|v| v as u8));
+        let query = BFloat16Array::from_iter_values(
+            generate_random_array(DIM)
+                .values()
+                .iter()
+                .map(|v| bf16::from_f32(*v)),
+        );
+
+        let dists = pq.compute_distances(&query, &pq_code).unwrap();
+
+        let sub_vec_len = DIM / 16;
+        let expected = pq_code
+            .values()
+            .chunks(16)
+            .map(|code| {
+                code.iter()
+                    .enumerate()
+                    .flat_map(|(sub_idx, c)| {
+                        let subvec_centroids = pq.centroids(sub_idx);
+                        let subvec =
+                            &query.as_slice()[sub_idx * sub_vec_len..(sub_idx + 1) * sub_vec_len];
+                        l2_distance_batch(
+                            subvec,
+                            &subvec_centroids
+                                [*c as usize * sub_vec_len..(*c as usize + 1) * sub_vec_len],
+                            sub_vec_len,
+                        )
+                    })
+                    .sum::<f32>()
+            })
+            .collect::<Vec<_>>();
+        dists
+            .values()
+            .iter()
+            .zip(expected.iter())
+            .for_each(|(v, e)| {
+                assert_relative_eq!(*v, *e, epsilon = 1e-4);
+            });
+
+        // The bf16 codebook survives a protobuf round trip.
+        let proto = pb::Pq::try_from(&pq as &dyn ProductQuantizer).unwrap();
+        assert_eq!(
+            proto.codebook_tensor.as_ref().unwrap().data_type,
+            pb::tensor::DataType::Bfloat16 as i32
+        );
+        let loaded = builder::from_proto(&proto, MetricType::L2).unwrap();
+        assert_eq!(loaded.compute_distances(&query, &pq_code).unwrap(), dists);
+
+        // bf16 vectors can be quantized.
+        let vectors = FixedSizeListArray::try_new_from_values(
+            BFloat16Array::from_iter_values(pq.codebook.as_slice()[..4 * DIM].iter().copied()),
+            DIM as i32,
+        )
+        .unwrap();
+        let codes = pq.transform(&vectors).await.unwrap();
+        assert_eq!(codes.len(), 4);
+    }
+
+    #[test]
+    fn test_l2_distance_tile_sizes() {
+        const DIM: usize = 128;
+        const TOTAL: usize = 130;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code = UInt8Array::from_iter_values((0..32 * TOTAL).map(|v| (v * 7) as u8));
+        let query = generate_random_array(DIM);
+
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(32, 8, DIM, codebook.clone(), MetricType::L2)
+                .unwrap();
+        assert_eq!(pq.tile_size(), DEFAULT_TILE_SIZE);
+        assert!(SUPPORTED_TILE_SIZES.contains(&pq.tile_size()));
+        let expected = pq.compute_distances(&query, &pq_code).unwrap();
+
+        for tile_size in SUPPORTED_TILE_SIZES {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                32,
+                8,
+                DIM,
+                codebook.clone(),
+                MetricType::L2,
+            )
+            .unwrap()
+            .with_tile_size(*tile_size)
+            .unwrap();
+            let dists = pq.compute_distances(&query, &pq_code).unwrap();
+            dists
+                .values()
+                .iter()
+                .zip(expected.values().iter())
+                .for_each(|(v, e)| {
+                    assert_relative_eq!(*v, *e, epsilon = 1e-4);
+                });
+        }
+
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(32, 8, DIM, codebook, MetricType::L2).unwrap();
+        assert!(pq.with_tile_size(3).is_err());
+    }
+
+    #[test]
+    fn test_l2_distance_small_sub_vectors() {
+        const TOTAL: usize = 67;
+        for num_sub_vectors in [4, 8, 16] {
+            let distance_table = generate_random_array(num_sub_vectors * num_centroids(8))
+                .values()
+                .to_vec();
+            let code = (0..num_sub_vectors * TOTAL)
+                .map(|v| (v * 13) as u8)
+                .collect::<Vec<_>>();
+            let actual =
+                compute_l2_distance_small_sub_vectors(&distance_table, 8, num_sub_vectors, &code)
+                    .unwrap();
+            let expected =
+                compute_l2_distance_with_tile(4, &distance_table, 8, num_sub_vectors, &code)
+                    .unwrap();
+            assert_eq!(actual.len(), TOTAL);
+            actual.iter().zip(expected.iter()).for_each(|(v, e)| {
+                assert_relative_eq!(*v, *e, epsilon = 1e-4);
+            });
+        }
+        assert!(compute_l2_distance_small_sub_vectors(&[], 8, 32, &[]).is_none());
+    }
+
+    #[test]
+    fn test_exact_distance_threshold() {
+        const DIM: usize = 32;
+        const TOTAL: usize = 20;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code = UInt8Array::from_iter_values((0..4 * TOTAL).map(|v| (v * 11) as u8));
+        let query = generate_random_array(DIM);
+
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq =
+                ProductQuantizerImpl::<Float32Type>::new(4, 8, DIM, codebook.clone(), metric_type)
+                    .unwrap();
+            assert!(!pq.use_exact_distances(TOTAL));
+            let table_dists = pq.compute_distances(&query, &pq_code).unwrap();
+
+            let pq = pq.with_exact_distance_threshold(TOTAL + 1);
+            assert!(pq.use_exact_distances(TOTAL));
+            assert!(!pq.use_exact_distances(TOTAL + 1));
+            let dists = pq.compute_distances(&query, &pq_code).unwrap();
+
+            let expected = pq_code
+                .values()
+                .chunks_exact(4)
+                .map(|c| {
+                    let vector = pq.reconstruct(c);
+                    match metric_type {
+                        MetricType::Dot => -Float32Type::dot(query.values(), vector.values()),
+                        _ => Float32Type::l2(query.values(), vector.values()),
+                    }
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(dists.values(), expected.as_slice());
+            dists
+                .values()
+                .iter()
+                .zip(table_dists.values().iter())
+                .for_each(|(v, e)| {
+                    assert_relative_eq!(*v, *e, epsilon = 1e-3);
+                });
+        }
+
+        // Bit-packed codes: 20 codes of 4 sub-vectors take 3 bytes each.
+        let codebook = Arc::new(generate_random_array(64 * DIM));
+        let codes = (0..4 * TOTAL)
+            .map(|v| (v * 11 % 64) as u8)
+            .collect::<Vec<_>>();
+        let pq_code = UInt8Array::from(utils::pack_codes(&codes, 6, 4));
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq =
+                ProductQuantizerImpl::<Float32Type>::new(4, 6, DIM, codebook.clone(), metric_type)
+                    .unwrap();
+            let table_dists = pq.compute_distances(&query, &pq_code).unwrap();
+            assert_eq!(table_dists.len(), TOTAL);
+
+            // There are exactly TOTAL codes, so the table is still used.
+            let pq = pq.with_exact_distance_threshold(TOTAL);
+            assert_eq!(pq.compute_distances(&query, &pq_code).unwrap(), table_dists);
+
+            let pq = pq.with_exact_distance_threshold(TOTAL + 1);
+            let dists = pq.compute_distances(&query, &pq_code).unwrap();
+            let expected = codes
+                .chunks_exact(4)
+                .map(|c| {
+                    let vector = pq.reconstruct(c);
+                    match metric_type {
+                        MetricType::Dot => -Float32Type::dot(query.values(), vector.values()),
+                        _ => Float32Type::l2(query.values(), vector.values()),
+                    }
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(dists.values(), expected.as_slice());
+            dists
+                .values()
+                .iter()
+                .zip(table_dists.values().iter())
+                .for_each(|(v, e)| {
+                    assert_relative_eq!(*v, *e, epsilon = 1e-3);
+                });
+        }
+    }
+
+    #[test]
+    fn test_padded_query() {
+        const DIM: usize = 24;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code = UInt8Array::from_iter_values((0..4 * 10).map(|v| (v * 7) as u8));
+        let query = generate_random_array(DIM);
+        let padded = Float32Array::from_iter_values(
+            query
+                .values()
+                .iter()
+                .copied()
+                .chain(std::iter::repeat(0.0).take(32 - DIM)),
+        );
+
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq =
+                ProductQuantizerImpl::<Float32Type>::new(4, 8, DIM, codebook.clone(), metric_type)
+                    .unwrap();
+            let expected = pq.compute_distances(&query, &pq_code).unwrap();
+            let actual = pq.compute_distances(&padded, &pq_code).unwrap();
+            assert_eq!(actual, expected);
+
+            let short = Float32Array::from_iter_values(query.values()[..DIM - 1].iter().copied());
+            assert!(pq.compute_distances(&short, &pq_code).is_err());
+        }
+    }
+
+    #[test]
+    fn test_merge_shards() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        // Centroids are far away from each other, so the shard centroids line up.
+        let codebook = Float32Array::from_iter_values(
+            (0..256 * DIM).map(|v| ((v / (DIM / NUM_SUB_VECTORS)) * 10) as f32),
+        );
+        let shifted = Float32Array::from_iter_values(codebook.values().iter().map(|v| v + 1.0));
+        let shard1 = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            Arc::new(codebook.clone()),
+            MetricType::L2,
+        )
+        .unwrap();
+        let shard2 = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            Arc::new(shifted),
+            MetricType::L2,
+        )
+        .unwrap();
+
+        let codes = (0..NUM_SUB_VECTORS * 256)
+            .map(|v| (v / NUM_SUB_VECTORS) as u8)
+            .collect::<Vec<_>>();
+        let counts1 = shard1.centroid_counts(&codes);
+        assert_eq!(counts1.len(), NUM_SUB_VECTORS * 256);
+        assert!(counts1.iter().all(|c| *c == 1));
+        // Shard 2 has 3 times more vectors in each cluster.
+        let counts2 = counts1.iter().map(|c| c * 3).collect::<Vec<_>>();
+
+        let merged =
+            ProductQuantizerImpl::merge_shards(&[(&shard1, &counts1), (&shard2, &counts2)])
+                .unwrap();
+        assert_eq!(merged.codebook.len(), codebook.len());
+        assert_eq!(merged.num_sub_vectors, NUM_SUB_VECTORS);
+        merged
+            .codebook
+            .values()
+            .iter()
+            .zip(codebook.values().iter())
+            .for_each(|(m, c)| assert_relative_eq!(*m, c + 0.75, epsilon = 1e-4));
+
+        let mismatch =
+            ProductQuantizerImpl::<Float32Type>::new(8, 8, DIM, Arc::new(codebook), MetricType::L2)
+                .unwrap();
+        assert!(
+            ProductQuantizerImpl::merge_shards(&[(&shard1, &counts1), (&mismatch, &counts1)])
+                .is_err()
+        );
+        assert!(ProductQuantizerImpl::<Float32Type>::merge_shards(&[]).is_err());
+
+        // The rotation is kept, and must be the same for all shards.
+        let rotation = Arc::new(Float32Array::from_iter_values((0..DIM * DIM).map(|i| {
+            if i % (DIM + 1) == 0 {
+                1.0
+            } else {
+                0.0
+            }
+        })));
+        let rotated1 = shard1.clone().with_rotation(rotation.clone()).unwrap();
+        let rotated2 = shard2.clone().with_rotation(rotation.clone()).unwrap();
+        let merged =
+            ProductQuantizerImpl::merge_shards(&[(&rotated1, &counts1), (&rotated2, &counts2)])
+                .unwrap();
+        assert_eq!(merged.rotation.as_deref(), Some(rotation.as_ref()));
+        assert!(
+            ProductQuantizerImpl::merge_shards(&[(&rotated1, &counts1), (&shard2, &counts2)])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_transposed() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        const TOTAL: usize = 50;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            codebook,
+            MetricType::L2,
+        )
+        .unwrap();
+        let codes = (0..NUM_SUB_VECTORS * TOTAL)
+            .map(|v| (v * 17 % 256) as u8)
+            .collect::<Vec<_>>();
+        let transposed =
+            storage::transpose(&UInt8Array::from(codes.clone()), TOTAL, NUM_SUB_VECTORS).unwrap();
+        let transposed: &[u8] = transposed.values();
+
+        for column in [0, 7, TOTAL - 1] {
+            let expected =
+                pq.reconstruct(&codes[column * NUM_SUB_VECTORS..(column + 1) * NUM_SUB_VECTORS]);
+            let actual = pq.reconstruct_transposed(&transposed, column).unwrap();
+            assert_eq!(actual, expected);
+        }
+        assert!(pq.reconstruct_transposed(&transposed, TOTAL).is_err());
+        assert!(pq.reconstruct_transposed(&transposed[1..], 0).is_err());
+    }
+
+    #[test]
+    fn test_sub_vector_variance() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        // All the centroids are at the origin.
+        let codebook = Arc::new(Float32Array::from(vec![0.0; 256 * DIM]));
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            codebook,
+            MetricType::L2,
+        )
+        .unwrap();
+        // Sub-vector 2 alternates between near and far away from the centroids,
+        // the other sub-vectors are constant.
+        let values = Float32Array::from_iter_values((0..100 * DIM).map(|v| {
+            let row = v / DIM;
+            let sub_vector_idx = (v % DIM) / (DIM / NUM_SUB_VECTORS);
+            if sub_vector_idx == 2 {
+                (row % 2) as f32 * 10.0
+            } else {
+                1.0
+            }
+        }));
+        let vectors = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+        let variance = pq.sub_vector_variance(&vectors).unwrap();
+        assert_eq!(variance.len(), NUM_SUB_VECTORS);
+        for (idx, v) in variance.iter().enumerate() {
+            if idx == 2 {
+                // Distances are 0 or 400, half each.
+                assert_relative_eq!(*v, 40000.0, epsilon = 1e-1);
+            } else {
+                assert_relative_eq!(*v, 0.0, epsilon = 1e-4);
+            }
+        }
+
+        let wrong_dim =
+            FixedSizeListArray::try_new_from_values(generate_random_array(8 * 10), 8).unwrap();
+        assert!(pq.sub_vector_variance(&wrong_dim).is_err());
+    }
+
+    #[test]
+    fn test_shared_codebook() {
+        const DIM: usize = 16;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq1 =
+            ProductQuantizerImpl::<Float32Type>::new(4, 8, DIM, codebook.clone(), MetricType::L2)
+                .unwrap();
+        let pq2 =
+            ProductQuantizerImpl::<Float32Type>::new(4, 8, DIM, codebook.clone(), MetricType::L2)
+                .unwrap()
+                .with_exact_distance_threshold(10);
+        let pq3 = pq2.clone();
+        assert!(Arc::ptr_eq(&pq1.codebook, &codebook));
+        assert!(Arc::ptr_eq(&pq2.codebook, &codebook));
+        assert!(Arc::ptr_eq(&pq3.codebook, &codebook));
+        assert_eq!(Arc::strong_count(&codebook), 4);
+        assert_eq!(pq3.exact_distance_threshold, Some(10));
+
+        // The FixedSizeList view does not copy the values either.
+        let fsl = pq1.codebook_as_fsl();
+        assert_eq!(
+            fsl.values().as_primitive::<Float32Type>().values().as_ptr(),
+            codebook.values().as_ptr()
+        );
+    }
+
+    #[test]
+    fn test_compute_distances_code_query() {
+        const DIM: usize = 32;
+        const NUM_SUB_VECTORS: usize = 8;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code =
+            UInt8Array::from_iter_values((0..NUM_SUB_VECTORS * 40).map(|v| (v * 29) as u8));
+        let query_code = (0..NUM_SUB_VECTORS)
+            .map(|v| (v * 37 + 5) as u8)
+            .collect::<Vec<_>>();
+
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                8,
+                DIM,
+                codebook.clone(),
+                metric_type,
+            )
+            .unwrap();
+            let actual = pq
+                .compute_distances_code_query(&query_code, &pq_code)
+                .unwrap();
+            let query = pq.reconstruct(&query_code);
+            let expected = pq.compute_distances(query.as_ref(), &pq_code).unwrap();
+            assert_eq!(actual.len(), 40);
+            actual
+                .values()
+                .iter()
+                .zip(expected.values().iter())
+                .for_each(|(a, e)| assert_relative_eq!(*a, *e, epsilon = 1e-4));
+
+            assert!(pq
+                .compute_distances_code_query(&query_code[1..], &pq_code)
+                .is_err());
+        }
+
+        // Bit-packed codes are unpacked before the lookup.
+        let codebook = Arc::new(generate_random_array(64 * DIM));
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            6,
+            DIM,
+            codebook,
+            MetricType::L2,
+        )
+        .unwrap();
+        let codes = (0..NUM_SUB_VECTORS * 40)
+            .map(|v| (v * 29 % 64) as u8)
+            .collect::<Vec<_>>();
+        let pq_code = UInt8Array::from(utils::pack_codes(&codes, 6, NUM_SUB_VECTORS));
+        let query_code = (0..NUM_SUB_VECTORS)
+            .map(|v| (v * 37 + 5) as u8 % 64)
+            .collect::<Vec<_>>();
+        let actual = pq
+            .compute_distances_code_query(&query_code, &pq_code)
+            .unwrap();
+        let query = pq.reconstruct(&query_code);
+        let expected = pq.compute_distances(query.as_ref(), &pq_code).unwrap();
+        assert_eq!(actual.len(), 40);
+        actual
+            .values()
+            .iter()
+            .zip(expected.values().iter())
+            .for_each(|(a, e)| assert_relative_eq!(*a, *e, epsilon = 1e-4));
+        assert!(pq
+            .compute_distances_code_query(&[64; NUM_SUB_VECTORS], &pq_code)
+            .is_err());
+
+        // A 4-bit query byte must be a valid centroid index.
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            4,
+            DIM,
+            Arc::new(generate_random_array(16 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let pq_code =
+            UInt8Array::from_iter_values((0..NUM_SUB_VECTORS * 40).map(|v| (v % 16) as u8));
+        let mut query_code = vec![1_u8; NUM_SUB_VECTORS];
+        pq.compute_distances_code_query(&query_code, &pq_code)
+            .unwrap();
+        query_code[3] = 16;
+        assert!(pq
+            .compute_distances_code_query(&query_code, &pq_code)
+            .is_err());
+        assert!(pq
+            .symmetric_distances(&UInt8Array::from(query_code), &pq_code)
+            .is_err());
+    }
+
+    #[test]
+    fn test_dot_distances_brute_force() {
+        const DIM: usize = 32;
+        const NUM_SUB_VECTORS: usize = 8;
+        const NUM_ROWS: usize = 50;
+        let query = generate_random_array(DIM);
+
+        for num_bits in [4, 8] {
+            let num_centroids = num_centroids(num_bits);
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                num_bits,
+                DIM,
+                Arc::new(generate_random_array(num_centroids * DIM)),
+                MetricType::Dot,
+            )
+            .unwrap();
+            let codes = (0..NUM_ROWS * NUM_SUB_VECTORS)
+                .map(|v| ((v * 29 + 3) % num_centroids) as u8)
+                .collect::<Vec<_>>();
+            let actual = pq
+                .compute_distances(&query, &UInt8Array::from(codes.clone()))
+                .unwrap();
+            assert_eq!(actual.len(), NUM_ROWS);
+            for (code, distance) in codes.chunks_exact(NUM_SUB_VECTORS).zip(actual.values()) {
+                let reconstructed = pq.reconstruct(code);
+                let expected = -Float32Type::dot(query.values(), reconstructed.values());
+                assert_relative_eq!(*distance, expected, epsilon = 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_distance_is_squared() {
+        for (metric_type, squared) in [
+            (MetricType::L2, true),
+            (MetricType::Cosine, true),
+            (MetricType::Dot, false),
+        ] {
+            assert_eq!(
+                ProductQuantizerImpl::<Float32Type>::distance_is_squared(metric_type),
+                squared,
+                "{:?}",
+                metric_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_compact_codebook() {
+        const DIM: usize = 8;
+        const NUM_SUB_VECTORS: usize = 2;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let mut pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            codebook,
+            MetricType::L2,
+        )
+        .unwrap();
+        // Codes use every centroid but 3 in sub-vector 0, and all but 0, 3 and 255 in sub-vector 1.
+        let mut codes = (0..=255_u8)
+            .filter(|c| *c != 3)
+            .flat_map(|c| [c, c.clamp(1, 254)])
+            .collect::<Vec<_>>();
+        let counts = pq.centroid_counts(&codes);
+        let usage = counts
+            .chunks_exact(256)
+            .map(|c| c.iter().map(|n| *n > 0).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert!(!usage[0][3]);
+        assert!(!usage[1][0] && !usage[1][3] && !usage[1][255]);
+
+        let before = codes
+            .chunks_exact(NUM_SUB_VECTORS)
+            .map(|c| pq.reconstruct(c))
+            .collect::<Vec<_>>();
+        let remapping = pq.compact_codebook(&usage).unwrap();
+        assert_eq!(remapping[0][4], 3);
+        assert_eq!(remapping[1][1], 0);
+        ProductQuantizerImpl::<Float32Type>::remap_codes(&mut codes, &remapping);
+
+        // The remapped codes reconstruct the same vectors.
+        codes
+            .chunks_exact(NUM_SUB_VECTORS)
+            .zip(before.iter())
+            .for_each(|(c, expected)| assert_eq!(&pq.reconstruct(c), expected));
+        // Only the first 255 and 252 centroids are used now, too many to shrink the codebook.
+        assert!(codes.chunks_exact(2).all(|c| c[0] < 255 && c[1] < 252));
+        assert_eq!(pq.num_bits, 8);
+        assert_eq!(pq.codebook.len(), 256 * DIM);
+
+        assert!(pq.compact_codebook(&usage[..1]).is_err());
+
+        // With at most 16 centroids used per sub-vector, the codebook shrinks to 4 bits.
+        let mut codes = (0..40_u8)
+            .flat_map(|c| [c % 10 * 20, 255 - c % 16])
+            .collect::<Vec<_>>();
+        let usage = pq
+            .centroid_counts(&codes)
+            .chunks_exact(256)
+            .map(|c| c.iter().map(|n| *n > 0).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let before = codes
+            .chunks_exact(NUM_SUB_VECTORS)
+            .map(|c| pq.reconstruct(c))
+            .collect::<Vec<_>>();
+        let remapping = pq.compact_codebook(&usage).unwrap();
+        ProductQuantizerImpl::<Float32Type>::remap_codes(&mut codes, &remapping);
+        assert_eq!(pq.num_bits, 4);
+        assert_eq!(pq.codebook.len(), 16 * DIM);
+        codes
+            .chunks_exact(NUM_SUB_VECTORS)
+            .zip(before.iter())
+            .for_each(|(c, expected)| assert_eq!(&pq.reconstruct(c), expected));
+        assert!(codes.iter().all(|c| *c < 16));
+    }
+
+    #[tokio::test]
+    async fn test_transform_chunked() {
+        const DIM: usize = 16;
+        const TOTAL: usize = 1000;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(4, 8, DIM, codebook, MetricType::L2).unwrap();
+        let data =
+            FixedSizeListArray::try_new_from_values(generate_random_array(TOTAL * DIM), DIM as i32)
+                .unwrap();
+        let expected = pq.transform(&data).await.unwrap();
+
+        for (chunk_size, parallelism) in [(1, 1), (64, 4), (333, 2), (TOTAL * 2, 8)] {
+            let chunks = pq
+                .transform_chunked(&data, chunk_size, parallelism)
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_eq!(chunks.len(), TOTAL.div_ceil(chunk_size));
+            let chunks = chunks.iter().map(|c| c.as_ref()).collect::<Vec<_>>();
+            let actual = arrow_select::concat::concat(&chunks).unwrap();
+            assert_eq!(actual.as_ref(), expected.as_ref());
+        }
+
+        assert!(pq
+            .transform_chunked(&data, 0, 1)
+            .try_collect::<Vec<_>>()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconstruct_round_trip() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        const SUB_VECTOR_WIDTH: usize = DIM / NUM_SUB_VECTORS;
+        let codebook = Arc::new(Float16Array::from_iter_values(
+            (0..256 * DIM).map(|v| f16::from_f32(v as f32)),
+        ));
+        let pq = ProductQuantizerImpl::<Float16Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            codebook,
+            MetricType::L2,
+        )
+        .unwrap();
+        let vectors = Float16Array::from_iter_values(
+            generate_random_array(100 * DIM)
+                .values()
+                .iter()
+                .map(|v| f16::from_f32(v * 4096.0)),
+        );
+        let fsl = FixedSizeListArray::try_new_from_values(vectors, DIM as i32).unwrap();
+        let codes = pq.transform(&fsl).await.unwrap();
+        let codes = codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>();
+
+        let reconstructed = ProductQuantizer::reconstruct(&pq, codes).unwrap();
+        assert_eq!(reconstructed.len(), fsl.len());
+        assert_eq!(reconstructed.value_type(), DataType::Float16);
+        let values = reconstructed
+            .values()
+            .as_primitive::<Float16Type>()
+            .values();
+        for (vector, code) in values
+            .chunks_exact(DIM)
+            .zip(codes.values().chunks_exact(NUM_SUB_VECTORS))
+        {
+            for (sub_vector_idx, (sub_vector, centroid_idx)) in vector
+                .chunks_exact(SUB_VECTOR_WIDTH)
+                .zip(code.iter())
+                .enumerate()
+            {
+                let start = *centroid_idx as usize * SUB_VECTOR_WIDTH;
+                assert_eq!(
+                    sub_vector,
+                    &pq.centroids(sub_vector_idx)[start..start + SUB_VECTOR_WIDTH]
+                );
+            }
+        }
+
+        // 4 bits PQ, one byte per sub-vector as returned by transform.
+        let pq = ProductQuantizerImpl::<Float32Type> {
+            num_bits: 4,
+            num_sub_vectors: NUM_SUB_VECTORS,
+            dimension: DIM,
+            codebook: Arc::new(Float32Array::from_iter_values(
+                (0..16 * DIM).map(|v| v as f32),
+            )),
+            metric_type: MetricType::L2,
+            training_info: None,
+            tile_size: None,
+            exact_distance_threshold: None,
+            half_precision_distance_table: false,
+            f64_accumulation: false,
+            truncate_padded_vectors: false,
+            rotation: None,
+        };
+        let codes = UInt8Array::from(vec![1, 2, 0, 15, 0, 0, 14, 3]);
+        let reconstructed = ProductQuantizer::reconstruct(&pq, &codes).unwrap();
+        assert_eq!(reconstructed.len(), 2);
+        let values = reconstructed
+            .values()
+            .as_primitive::<Float32Type>()
+            .values();
+        for (vector, code) in values.chunks_exact(DIM).zip([[1, 2, 0, 15], [0, 0, 14, 3]]) {
+            for (sub_vector_idx, sub_vector) in vector.chunks_exact(SUB_VECTOR_WIDTH).enumerate() {
+                let start = code[sub_vector_idx] * SUB_VECTOR_WIDTH;
+                assert_eq!(
+                    sub_vector,
+                    &pq.centroids(sub_vector_idx)[start..start + SUB_VECTOR_WIDTH]
+                );
+            }
+        }
+
+        assert!(ProductQuantizer::reconstruct(&pq, &UInt8Array::from(vec![1, 2, 3])).is_err());
+
+        // The codes returned by transform reconstruct the vectors made of centroids.
+        let vectors = (0..10)
+            .flat_map(|row| {
+                (0..NUM_SUB_VECTORS).flat_map(move |sub_vector_idx| {
+                    let centroid = (row * 3 + sub_vector_idx) % 16;
+                    let start = (sub_vector_idx * 16 + centroid) * SUB_VECTOR_WIDTH;
+                    (start..start + SUB_VECTOR_WIDTH).map(|v| v as f32)
+                })
+            })
+            .collect::<Vec<_>>();
+        let fsl = FixedSizeListArray::try_new_from_values(
+            Float32Array::from(vectors.clone()),
+            DIM as i32,
+        )
+        .unwrap();
+        let codes = pq.transform(&fsl).await.unwrap();
+        let codes = codes.as_fixed_size_list();
+        assert_eq!(codes.value_length() as usize, NUM_SUB_VECTORS);
+        let reconstructed =
+            ProductQuantizer::reconstruct(&pq, codes.values().as_primitive::<UInt8Type>()).unwrap();
+        assert_eq!(reconstructed.len(), 10);
+        assert_eq!(
+            reconstructed
+                .values()
+                .as_primitive::<Float32Type>()
+                .values(),
+            vectors.as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_symmetric_distances() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+
+        // 8 bits
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                8,
+                DIM,
+                Arc::new(generate_random_array(256 * DIM)),
+                metric_type,
+            )
+            .unwrap();
+            let codes =
+                UInt8Array::from_iter_values((0..NUM_SUB_VECTORS * 50).map(|v| (v * 31) as u8));
+            let table = pq.symmetric_distance_table();
+            for query_code in codes.values().chunks_exact(NUM_SUB_VECTORS).take(5) {
+                let expected = pq.compute_distances_code_query(query_code, &codes).unwrap();
+                let actual = pq
+                    .symmetric_distances(&UInt8Array::from(query_code.to_vec()), &codes)
+                    .unwrap();
+                assert_eq!(actual.len(), 50);
+                let reused = table.compute_distances(query_code, codes.values()).unwrap();
+                for ((a, r), e) in actual
+                    .values()
+                    .iter()
+                    .zip(reused.iter())
+                    .zip(expected.values().iter())
+                {
+                    assert_eq!(a, r);
+                    assert_relative_eq!(*a, *e, epsilon = 1e-4);
+                }
+            }
+        }
+
+        // 4 bits, one byte per sub-vector as returned by transform.
+        let pq = ProductQuantizerImpl::<Float32Type> {
+            num_bits: 4,
+            num_sub_vectors: NUM_SUB_VECTORS,
+            dimension: DIM,
+            codebook: Arc::new(generate_random_array(16 * DIM)),
+            metric_type: MetricType::L2,
+            training_info: None,
+            tile_size: None,
+            exact_distance_threshold: None,
+            half_precision_distance_table: false,
+            f64_accumulation: false,
+            truncate_padded_vectors: false,
+            rotation: None,
+        };
+        let vectors = generate_random_array(30 * DIM);
+        let fsl = FixedSizeListArray::try_new_from_values(vectors, DIM as i32).unwrap();
+        let codes = pq.transform(&fsl).await.unwrap();
+        let codes = codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>()
+            .clone();
+        assert_eq!(codes.len(), 30 * NUM_SUB_VECTORS);
+        let query_code = UInt8Array::from(vec![10, 5, 15, 0]);
+        let distances = pq.symmetric_distances(&query_code, &codes).unwrap();
+        assert_eq!(distances.len(), 30);
+
+        let query = ProductQuantizer::reconstruct(&pq, &query_code).unwrap();
+        let query = query.values().as_primitive::<Float32Type>().values();
+        let vectors = ProductQuantizer::reconstruct(&pq, &codes).unwrap();
+        for (vector, distance) in vectors
+            .values()
+            .as_primitive::<Float32Type>()
+            .values()
+            .chunks_exact(DIM)
+            .zip(distances.values().iter())
+        {
+            assert_relative_eq!(l2(query, vector), *distance, epsilon = 1e-4);
+        }
+
+        assert!(pq
+            .symmetric_distances(&UInt8Array::from(vec![10, 5]), &codes)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reencode() {
+        const DIM: usize = 16;
+        const NUM_ROWS: usize = 200;
+        let vectors = generate_random_array(NUM_ROWS * DIM);
+        let fsl = FixedSizeListArray::try_new_from_values(vectors.clone(), DIM as i32).unwrap();
+        let old_pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let new_pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let old_codes = old_pq.transform(&fsl).await.unwrap();
+        let old_codes = old_codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>();
+        let new_codes = new_pq.transform(&fsl).await.unwrap();
+        let new_codes = new_codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>();
+
+        let reencoded = new_pq.reencode(&old_pq, old_codes).await.unwrap();
+        assert_eq!(reencoded.len(), old_codes.len());
+
+        fn error(pq: &ProductQuantizerImpl<Float32Type>, codes: &[u8], target: &[f32]) -> f32 {
+            let reconstructed = pq.reconstruct(codes);
+            l2(reconstructed.values(), target).sqrt()
+        }
+        for ((vector, old_code), (reencoded_code, new_code)) in vectors
+            .values()
+            .chunks_exact(DIM)
+            .zip(old_codes.values().chunks_exact(4))
+            .zip(
+                reencoded
+                    .values()
+                    .chunks_exact(4)
+                    .zip(new_codes.values().chunks_exact(4)),
+            )
+        {
+            let old_vector = old_pq.reconstruct(old_code);
+            let old_vector = old_vector.values();
+            // The re-encoded code is the closest to the old reconstruction.
+            assert!(
+                error(&new_pq, reencoded_code, old_vector)
+                    <= error(&new_pq, new_code, old_vector) + 1e-4
+            );
+            // The loss of the double quantization is bounded by the error of both codebooks.
+            assert!(
+                error(&new_pq, reencoded_code, vector)
+                    <= error(&old_pq, old_code, vector)
+                        + error(&new_pq, new_code, old_vector)
+                        + 1e-4
+            );
+        }
+
+        let other_dim = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM * 2,
+            Arc::new(generate_random_array(256 * DIM * 2)),
+            MetricType::L2,
+        )
+        .unwrap();
+        assert!(other_dim.reencode(&old_pq, old_codes).await.is_err());
+
+        // 6-bit codes of the old quantizer are bit-packed.
+        let old_pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            6,
+            DIM,
+            Arc::new(generate_random_array(64 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let old_codes = old_pq.transform(&fsl).await.unwrap();
+        let old_codes = old_codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>();
+        assert_eq!(old_codes.len(), NUM_ROWS * 3);
+        let reencoded = new_pq.reencode(&old_pq, old_codes).await.unwrap();
+        let reconstructed = ProductQuantizer::reconstruct(&old_pq, old_codes).unwrap();
+        let expected = new_pq.transform(&reconstructed).await.unwrap();
+        assert_eq!(
+            &reencoded,
+            expected
+                .as_fixed_size_list()
+                .values()
+                .as_primitive::<UInt8Type>()
+        );
+        assert!(new_pq
+            .reencode(&old_pq, &UInt8Array::from(vec![1, 2]))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_estimate_memory() {
+        const DIM: usize = 16;
+        const NUM_ROWS: usize = 1000;
+        let fsl = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+        for num_bits in [4, 8] {
+            let params = PQBuildParams::new(4, num_bits);
+            let estimate =
+                ProductQuantizerImpl::<Float32Type>::estimate_memory(NUM_ROWS, DIM, &params);
+
+            let pq = params.build(&fsl, MetricType::L2).await.unwrap();
+            let codes = pq.transform(&fsl).await.unwrap();
+            let actual = pq.codebook_as_fsl().values().len() * std::mem::size_of::<f32>()
+                + codes.as_fixed_size_list().values().len();
+            assert_relative_eq!(estimate as f64, actual as f64, max_relative = 0.01);
+        }
+        // 4-bit codes take one byte per sub-vector, not half of it.
+        assert_eq!(
+            ProductQuantizerImpl::<Float32Type>::estimate_memory(
+                NUM_ROWS,
+                DIM,
+                &PQBuildParams::new(4, 4)
+            ),
+            16 * DIM * 4 + NUM_ROWS * 4
+        );
+
+        let params = PQBuildParams::new(4, 8);
+        let estimate = ProductQuantizerImpl::<Float32Type>::estimate_memory(NUM_ROWS, DIM, &params);
+
+        let f16_estimate =
+            ProductQuantizerImpl::<Float16Type>::estimate_memory(NUM_ROWS, DIM, &params);
+        assert_eq!(estimate - f16_estimate, 256 * DIM * 2);
+    }
+
+    #[cfg(all(feature = "nightly", target_feature = "avx512f"))]
+    #[test]
+    fn test_l2_distance_tile_gather() {
+        use super::distance::build_distance_table_l2;
+
+        const DIM: usize = 128;
+        let codebook = generate_random_array(256 * DIM);
+        let query = generate_random_array(DIM);
+        // 40 sub-vectors leave a partial tile after two full tiles of 16.
+        for num_sub_vectors in [16, 32, 40] {
+            let distance_table =
+                build_distance_table_l2(codebook.values(), 8, num_sub_vectors, query.values());
+            let code = (0..num_sub_vectors * 100)
+                .map(|v| (v * 17 % 256) as u8)
+                .collect::<Vec<_>>();
+            let expected = code
+                .chunks_exact(num_sub_vectors)
+                .map(|c| {
+                    c.iter()
+                        .enumerate()
+                        .map(|(k, c)| distance_table[k * 256 + *c as usize])
+                        .sum::<f32>()
+                })
+                .collect::<Vec<_>>();
+            let actual =
+                compute_l2_distance_with_tile(16, &distance_table, 8, num_sub_vectors, &code)
+                    .unwrap();
+            assert_eq!(actual.len(), expected.len());
+            actual
+                .iter()
+                .zip(expected.iter())
+                .for_each(|(a, e)| assert_relative_eq!(*a, *e, max_relative = 1e-5));
+        }
+    }
+
+    #[cfg(all(feature = "nightly", target_arch = "x86_64"))]
+    #[cfg_attr(not(target_feature = "avx512f"), ignore = "needs AVX512F")]
+    #[test]
+    fn test_l2_distance_avx512() {
+        use super::distance::build_distance_table_l2;
+
+        const DIM: usize = 128;
+        let codebook = generate_random_array(256 * DIM);
+        let query = generate_random_array(DIM);
+        for num_sub_vectors in [4, 8, 16, 32] {
+            let distance_table =
+                build_distance_table_l2(codebook.values(), 8, num_sub_vectors, query.values());
+            // Not a multiple of 16 codes, to cover the remainder.
+            let code = (0..num_sub_vectors * 1000 + num_sub_vectors * 7)
+                .map(|v| (v * 17 % 256) as u8)
+                .collect::<Vec<_>>();
+            let expected = compute_l2_distance_with_tile(
+                suggest_tile_size(num_sub_vectors, 256),
+                &distance_table,
+                8,
+                num_sub_vectors,
+                &code,
+            )
+            .unwrap();
+            let actual =
+                compute_l2_distance_avx512(&distance_table, 8, num_sub_vectors, &code).unwrap();
+            assert_eq!(actual.len(), expected.len());
+            actual
+                .iter()
+                .zip(expected.iter())
+                .for_each(|(a, e)| assert_relative_eq!(*a, *e, max_relative = 1e-5));
+        }
+
+        // Codes out of range of a 4-bit distance table are left to the scalar kernels.
+        let distance_table = vec![0.0_f32; 4 * 16];
+        assert!(compute_l2_distance_avx512(&distance_table, 4, 4, &[0, 1, 2, 15]).is_some());
+        assert!(compute_l2_distance_avx512(&distance_table, 4, 4, &[0, 1, 2, 16]).is_none());
+        assert!(compute_l2_distance_avx512(&distance_table[1..], 4, 4, &[0, 1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn test_f64_accumulation() {
+        const NUM_SUB_VECTORS: usize = 128;
+        // One large entry followed by many entries that are each below half an ulp of it,
+        // so f32 accumulation drops all of them.
+        let mut distance_table = vec![1e-8_f32; NUM_SUB_VECTORS * 256];
+        distance_table[0] = 1.0;
+        let code = vec![0_u8; NUM_SUB_VECTORS];
+        let expected = 1.0 + (NUM_SUB_VECTORS - 1) as f64 * 1e-8_f32 as f64;
+
+        let f32_sum = code
+            .iter()
+            .enumerate()
+            .map(|(i, c)| distance_table[i * 256 + *c as usize])
+            .sum::<f32>();
+        let f64_sum =
+            compute_distance_f64_accumulated(&distance_table, 8, NUM_SUB_VECTORS, &code)[0];
+        let f32_error = (f32_sum as f64 - expected).abs();
+        let f64_error = (f64_sum as f64 - expected).abs();
+        assert!(f32_error > 1e-6, "f32 error: {}", f32_error);
+        assert!(f64_error < 1e-7, "f64 error: {}", f64_error);
+
+        // Same distances, up to f32 rounding, on regular data.
+        const DIM: usize = 128;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code = UInt8Array::from_iter_values((0..64 * 100).map(|v| (v * 7) as u8));
+        let query = generate_random_array(DIM);
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq =
+                ProductQuantizerImpl::<Float32Type>::new(64, 8, DIM, codebook.clone(), metric_type)
+                    .unwrap();
+            let expected = pq.compute_distances(&query, &pq_code).unwrap();
+            let actual = pq
+                .with_f64_accumulation(true)
+                .compute_distances(&query, &pq_code)
+                .unwrap();
+            actual
+                .values()
+                .iter()
+                .zip(expected.values().iter())
+                .for_each(|(a, e)| assert_relative_eq!(*a, *e, max_relative = 1e-5));
+        }
+    }
+
+    #[test]
+    fn test_half_precision_distance_table() {
+        const DIM: usize = 128;
+        const TOTAL: usize = 100;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code = UInt8Array::from_iter_values((0..32 * TOTAL).map(|v| (v * 13) as u8));
+        let query = generate_random_array(DIM);
+
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(32, 8, DIM, codebook.clone(), MetricType::L2)
+                .unwrap();
+        let expected = pq.compute_distances(&query, &pq_code).unwrap();
+        let pq = pq.with_half_precision_distance_table(true);
+        let actual = pq.compute_distances(&query, &pq_code).unwrap();
+        assert_eq!(actual.len(), TOTAL);
+        actual
+            .values()
+            .iter()
+            .zip(expected.values().iter())
+            .for_each(|(a, e)| {
+                // Each entry of the table is rounded to f16, with a relative error of 2^-11.
+                assert_relative_eq!(*a, *e, max_relative = 1.0 / 2048.0);
+            });
+    }
+
+    #[test]
+    fn test_codebook_drift() {
+        const DIM: usize = 16;
+        let codebook = generate_random_array(256 * DIM);
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(codebook.clone()),
+            MetricType::L2,
+        )
+        .unwrap();
+        assert_eq!(pq.codebook_drift(&pq).unwrap(), 0.0);
+
+        // Move every centroid by 0.5 along one axis.
+        let perturbed =
+            Float32Array::from_iter_values(codebook.values().iter().enumerate().map(|(i, v)| {
+                if i % (DIM / 4) == 0 {
+                    v + 0.5
+                } else {
+                    *v
+                }
+            }));
+        let other = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(perturbed),
+            MetricType::L2,
+        )
+        .unwrap();
+        assert_relative_eq!(pq.codebook_drift(&other).unwrap(), 0.5, epsilon = 1e-4);
+
+        let mismatch =
+            ProductQuantizerImpl::<Float32Type>::new(8, 8, DIM, Arc::new(codebook), MetricType::L2)
+                .unwrap();
+        assert!(pq.codebook_drift(&mismatch).is_err());
+    }
+
+    #[test]
+    fn test_reshape_sub_vectors() {
+        const DIM: usize = 32;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(4, 8, DIM, codebook, MetricType::L2).unwrap();
+
+        let reshaped = pq.reshape_sub_vectors(8).unwrap();
+        assert_eq!(reshaped.num_sub_vectors, 8);
+        assert_eq!(reshaped.dimension, DIM);
+        assert_eq!(reshaped.codebook.len(), pq.codebook.len());
+        // The same PQ code decodes to the same vector.
+        let code = (0..4).map(|i| i * 10 + 3).collect::<Vec<u8>>();
+        let reshaped_code = code.iter().flat_map(|c| [*c, *c]).collect::<Vec<u8>>();
+        assert_eq!(
+            pq.reconstruct(&code).as_ref(),
+            reshaped.reconstruct(&reshaped_code).as_ref()
+        );
+
+        // The rotation is kept.
+        assert!(reshaped.rotation.is_none());
+        let rotation = Arc::new(generate_random_array(DIM * DIM));
+        let rotated = pq.clone().with_rotation(rotation.clone()).unwrap();
+        let reshaped = rotated.reshape_sub_vectors(8).unwrap();
+        assert_eq!(reshaped.rotation.as_deref(), Some(rotation.as_ref()));
+
+        // Merging sub-vectors needs retraining.
+        assert!(pq.reshape_sub_vectors(2).is_err());
+        // 32 is not divisible by 12.
+        assert!(pq.reshape_sub_vectors(12).is_err());
+        assert!(pq.reshape_sub_vectors(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transform_binary() {
+        const DIM: usize = 16;
+        let codebook = Arc::new(generate_random_array(16 * DIM));
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(8, 4, DIM, codebook, MetricType::L2).unwrap();
+        let vectors =
+            FixedSizeListArray::try_new_from_values(generate_random_array(DIM * 100), DIM as i32)
+                .unwrap();
+
+        let codes = pq.transform(&vectors).await.unwrap();
+        let binary = pq.transform_binary(&vectors).await.unwrap();
+        assert_eq!(binary.len(), 100);
+        // 8 sub-vectors of 4 bits.
+        assert_eq!(binary.value(0).len(), 4);
+        assert_eq!(
+            &pq_codes_from_binary(&binary, 4, 8).unwrap(),
+            codes.as_fixed_size_list()
+        );
+        assert_eq!(
+            pq_codes_to_binary(codes.as_fixed_size_list(), 4).unwrap(),
+            binary
+        );
+    }
+
+    #[test]
+    fn test_compute_distances_with_tile_size() {
+        const DIM: usize = 64;
+        const NUM_SUB_VECTORS: usize = 32;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code =
+            UInt8Array::from_iter_values((0..NUM_SUB_VECTORS * 70).map(|v| (v * 13) as u8));
+        let query = generate_random_array(DIM);
+
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                8,
+                DIM,
+                codebook.clone(),
+                metric_type,
+            )
+            .unwrap();
+            let expected = pq.compute_distances(&query, &pq_code).unwrap();
+            for tile_size in SUPPORTED_TILE_SIZES {
+                let actual = pq
+                    .compute_distances_with_tile_size(&query, &pq_code, *tile_size)
+                    .unwrap();
+                actual
+                    .values()
+                    .iter()
+                    .zip(expected.values().iter())
+                    .for_each(|(a, e)| assert_relative_eq!(*a, *e, epsilon = 1e-4));
+            }
+            // The per-call tile size does not change the quantizer.
+            assert_eq!(pq.tile_size, None);
+            assert!(pq
+                .compute_distances_with_tile_size(&query, &pq_code, 3)
+                .is_err());
+        }
+
+        // The tile size is used for a small number of sub-vectors too, which otherwise
+        // have a specialized kernel.
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(8, 8, DIM, codebook, MetricType::L2).unwrap();
+        let pq_code = UInt8Array::from_iter_values((0..8 * 70).map(|v| (v * 13) as u8));
+        let distance_table =
+            super::distance::build_distance_table_l2(pq.codebook.values(), 8, 8, query.values());
+        for tile_size in SUPPORTED_TILE_SIZES {
+            let expected =
+                compute_l2_distance_with_tile(*tile_size, &distance_table, 8, 8, pq_code.values())
+                    .unwrap();
+            let actual = pq
+                .compute_distances_with_tile_size(&query, &pq_code, *tile_size)
+                .unwrap();
+            assert_eq!(actual.values().as_ref(), expected.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_coarse_distances() {
+        const DIM: usize = 64;
+        const NUM_SUB_VECTORS: usize = 16;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code =
+            UInt8Array::from_iter_values((0..NUM_SUB_VECTORS * 70).map(|v| (v * 13) as u8));
+        let query = generate_random_array(DIM);
+
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                8,
+                DIM,
+                codebook.clone(),
+                metric_type,
+            )
+            .unwrap();
+            let expected = pq.compute_distances(&query, &pq_code).unwrap();
+            let actual = pq
+                .coarse_distances(&query, &pq_code, NUM_SUB_VECTORS)
+                .unwrap();
+            assert_eq!(actual.len(), 70);
+            actual
+                .values()
+                .iter()
+                .zip(expected.values().iter())
+                .for_each(|(a, e)| assert_relative_eq!(*a, *e, epsilon = 1e-4));
+
+            if metric_type == MetricType::L2 {
+                // Every sub-vector adds to the L2 distance
+                let mut previous = Float32Array::from(vec![0.0; 70]);
+                for num_sub_vectors_used in 1..=NUM_SUB_VECTORS {
+                    let coarse = pq
+                        .coarse_distances(&query, &pq_code, num_sub_vectors_used)
+                        .unwrap();
+                    coarse
+                        .values()
+                        .iter()
+                        .zip(previous.values().iter())
+                        .for_each(|(c, p)| assert!(*c >= *p));
+                    previous = coarse;
+                }
+            }
+            assert!(pq.coarse_distances(&query, &pq_code, 0).is_err());
+            assert!(pq
+                .coarse_distances(&query, &pq_code, NUM_SUB_VECTORS + 1)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_compute_distances_with_threshold() {
+        const DIM: usize = 64;
+        const NUM_SUB_VECTORS: usize = 16;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_code =
+            UInt8Array::from_iter_values((0..NUM_SUB_VECTORS * 70).map(|v| (v * 13) as u8));
+        let query = generate_random_array(DIM);
+
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            codebook.clone(),
+            MetricType::L2,
+        )
+        .unwrap();
+        let expected = pq.compute_distances(&query, &pq_code).unwrap();
+        let mut sorted = expected.values().to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let threshold = sorted[35];
+
+        let pruned = pq
+            .compute_distances_with_threshold(&query, &pq_code, threshold)
+            .unwrap();
+        assert_eq!(pruned.len(), 70);
+        assert!(pruned.null_count() > 0);
+        for (actual, expected) in pruned.iter().zip(expected.values().iter()) {
+            match actual {
+                // The surviving candidates have their full distance
+                Some(actual) => {
+                    assert_relative_eq!(actual, *expected, epsilon = 1e-4);
+                    assert!(actual <= threshold);
+                }
+                None => assert!(*expected > threshold - 1e-4),
+            }
+        }
+
+        // Nothing is pruned with an infinite threshold
+        let all = pq
+            .compute_distances_with_threshold(&query, &pq_code, f32::INFINITY)
+            .unwrap();
+        assert_eq!(all.null_count(), 0);
+
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            codebook,
+            MetricType::Dot,
+        )
+        .unwrap();
+        assert!(pq
+            .compute_distances_with_threshold(&query, &pq_code, threshold)
+            .is_err());
+    }
+
+    #[test]
+    fn test_suggest_tile_size() {
+        for num_sub_vectors in [1, 4, 8, 16, 32, 96] {
+            for num_bits in [4, 8] {
+                let tile = suggest_tile_size(num_sub_vectors, num_centroids(num_bits as u32));
+                assert!(SUPPORTED_TILE_SIZES.contains(&tile));
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_distance_table() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                8,
+                DIM,
+                Arc::new(generate_random_array(256 * DIM)),
+                metric_type,
+            )
+            .unwrap();
+            let query = generate_random_array(DIM);
+            let mut distance_table = pq.build_distance_table(query.values()).unwrap();
+            assert_eq!(distance_table.len(), NUM_SUB_VECTORS * 256);
+
+            // Refine the third sub-vector of the query only.
+            let mut refined = query.values().to_vec();
+            let sub_vec = generate_random_array(DIM / NUM_SUB_VECTORS);
+            refined[8..12].copy_from_slice(sub_vec.values());
+            pq.update_distance_table(&mut distance_table, 2, sub_vec.values())
+                .unwrap();
+            assert_eq!(distance_table, pq.build_distance_table(&refined).unwrap());
+
+            assert!(pq
+                .update_distance_table(&mut distance_table, NUM_SUB_VECTORS, sub_vec.values())
+                .is_err());
+            assert!(pq
+                .update_distance_table(&mut distance_table, 0, &refined)
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transform_with_nulls() {
+        const DIM: usize = 16;
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let mut values = generate_random_array(4 * DIM).values().to_vec();
+        // The values of null vectors are undefined.
+        values[DIM..2 * DIM].fill(f32::NAN);
+        let fsl = FixedSizeListArray::try_new(
+            Arc::new(Field::new("item", DataType::Float32, true)),
+            DIM as i32,
+            Arc::new(Float32Array::from(values.clone())),
+            Some(vec![true, false, true, true].into()),
+        )
+        .unwrap();
+
+        let codes = pq.transform(&fsl).await.unwrap();
+        let codes = codes.as_fixed_size_list();
+        assert_eq!(codes.len(), 4);
+        assert_eq!(codes.null_count(), 1);
+        assert!(codes.is_null(1));
+        assert!(codes
+            .value(1)
+            .as_primitive::<UInt8Type>()
+            .values()
+            .iter()
+            .all(|c| *c == 0));
+
+        // Valid vectors get the same codes as without nulls.
+        let valid = FixedSizeListArray::try_new_from_values(
+            Float32Array::from_iter_values(
+                values[..DIM]
+                    .iter()
+                    .chain(values[2 * DIM..].iter())
+                    .copied(),
+            ),
+            DIM as i32,
+        )
+        .unwrap();
+        let expected = pq.transform(&valid).await.unwrap();
+        let expected = expected.as_fixed_size_list();
+        for (row, expected_row) in [(0, 0), (2, 1), (3, 2)] {
+            assert_eq!(
+                codes.value(row).as_ref(),
+                expected.value(expected_row).as_ref()
+            );
+        }
+    }
+
+    #[test]
+    fn test_compute_distances_batch() {
+        const DIM: usize = 16;
+        const NUM_QUERIES: usize = 37;
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                4,
+                8,
+                DIM,
+                Arc::new(generate_random_array(256 * DIM)),
+                metric_type,
+            )
+            .unwrap();
+            let code = UInt8Array::from_iter_values((0..4 * 100).map(|v| (v * 7 % 256) as u8));
+            let queries = FixedSizeListArray::try_new_from_values(
+                generate_random_array(NUM_QUERIES * DIM),
+                DIM as i32,
+            )
+            .unwrap();
+
+            let distances = pq.compute_distances_batch(&queries, &code).unwrap();
+            assert_eq!(distances.len(), NUM_QUERIES);
+            for (i, actual) in distances.iter().enumerate() {
+                let expected = pq
+                    .compute_distances(queries.value(i).as_ref(), &code)
+                    .unwrap();
+                assert_eq!(actual, &expected);
+            }
+        }
+
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let empty =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(vec![0.0; 0]), DIM as i32)
+                .unwrap();
+        assert!(pq
+            .compute_distances_batch(&empty, &UInt8Array::from(vec![0_u8; 4]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_compute_distance_matrix() {
+        const DIM: usize = 16;
+        const NUM_QUERIES: usize = 5;
+        const NUM_CODES: usize = 100;
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let code = UInt8Array::from_iter_values((0..4 * NUM_CODES).map(|v| (v * 13 % 256) as u8));
+        let queries = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_QUERIES * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+
+        let (matrix, m, n) = pq.compute_distance_matrix(&queries, &code).unwrap();
+        assert_eq!((m, n), (NUM_QUERIES, NUM_CODES));
+        assert_eq!(matrix.len(), NUM_QUERIES * NUM_CODES);
+        for i in 0..NUM_QUERIES {
+            let expected = pq
+                .compute_distances(queries.value(i).as_ref(), &code)
+                .unwrap();
+            assert_eq!(&matrix.values()[i * n..(i + 1) * n], &expected.values()[..]);
+        }
+
+        let empty =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(vec![0.0; 0]), DIM as i32)
+                .unwrap();
+        let (matrix, m, n) = pq.compute_distance_matrix(&empty, &code).unwrap();
+        assert!(matrix.is_empty());
+        assert_eq!((m, n), (0, NUM_CODES));
+    }
+
+    #[tokio::test]
+    async fn test_6bit_codes_round_trip() {
+        const DIM: usize = 20;
+        // 5 x 6 bits = 30 bits, which do not fill the last byte.
+        const NUM_SUB_VECTORS: usize = 5;
+        const NUM_ROWS: usize = 100;
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            6,
+            DIM,
+            Arc::new(generate_random_array(64 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+
+        let codes = pq.transform(&vectors).await.unwrap();
+        let codes = codes.as_fixed_size_list();
+        assert_eq!(codes.value_length(), 4);
+        assert_eq!(ProductQuantizer::code_length(&pq), 4);
+        assert_eq!(
+            codes.data_type(),
+            &DataType::FixedSizeList(Arc::new(Field::new("item", DataType::UInt8, true)), 4)
+        );
+        let codes = codes.values().as_primitive::<UInt8Type>();
+        assert_eq!(codes.len(), NUM_ROWS * 4);
+
+        // Each code is the closest centroid of its sub-vector.
+        let reconstructed = ProductQuantizer::reconstruct(&pq, codes).unwrap();
+        assert_eq!(reconstructed.len(), NUM_ROWS);
+        let sub_vector_width = DIM / NUM_SUB_VECTORS;
+        for (vector, recon) in vectors
+            .values()
+            .as_primitive::<Float32Type>()
+            .values()
+            .chunks_exact(DIM)
+            .zip(
+                reconstructed
+                    .values()
+                    .as_primitive::<Float32Type>()
+                    .values()
+                    .chunks_exact(DIM),
+            )
+        {
+            for (sub_vector_idx, (sub_vector, sub_recon)) in vector
+                .chunks_exact(sub_vector_width)
+                .zip(recon.chunks_exact(sub_vector_width))
+                .enumerate()
+            {
+                let best =
+                    l2_distance_batch(sub_vector, pq.centroids(sub_vector_idx), sub_vector_width)
+                        .fold(f32::INFINITY, f32::min);
+                assert_relative_eq!(l2(sub_vector, sub_recon), best);
+            }
+        }
+
+        // Distances on the packed codes match the distances to the reconstructed vectors.
+        let query = generate_random_array(DIM);
+        let distances = pq.compute_distances(&query, codes).unwrap();
+        assert_eq!(distances.len(), NUM_ROWS);
+        for (distance, recon) in distances.values().iter().zip(
+            reconstructed
+                .values()
+                .as_primitive::<Float32Type>()
+                .values()
+                .chunks_exact(DIM),
+        ) {
+            assert_relative_eq!(*distance, l2(query.values(), recon), max_relative = 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_cosine_is_normalized_l2() {
+        const DIM: usize = 16;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let cosine_pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            codebook.clone(),
+            MetricType::Cosine,
+        )
+        .unwrap();
+        assert_eq!(cosine_pq.metric_type, MetricType::L2);
+        assert!(cosine_pq.use_residual());
+
+        let l2_pq =
+            ProductQuantizerImpl::<Float32Type>::new(4, 8, DIM, codebook, MetricType::L2).unwrap();
+        let query = generate_random_array(DIM);
+        let norm = query.values().iter().map(|v| v * v).sum::<f32>().sqrt();
+        let query = Float32Array::from_iter_values(query.values().iter().map(|v| v / norm));
+        let code = UInt8Array::from_iter_values((0..4 * 50).map(|v| (v * 3 % 256) as u8));
+        let distances = cosine_pq.compute_distances(&query, &code).unwrap();
+        assert_eq!(distances, l2_pq.compute_distances(&query, &code).unwrap());
+        assert_eq!(
+            cosine_pq
+                .compute_distances_code_query(&[1, 2, 3, 4], &code)
+                .unwrap(),
+            l2_pq
+                .compute_distances_code_query(&[1, 2, 3, 4], &code)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_convert_distances() {
+        const DIM: usize = 16;
+        let normalize = |values: Float32Array| {
+            let norm = values.values().iter().map(|v| v * v).sum::<f32>().sqrt();
+            values.values().iter().map(|v| v / norm).collect::<Vec<_>>()
+        };
+        let query = normalize(generate_random_array(DIM));
+        let vectors = (0..20)
+            .map(|_| normalize(generate_random_array(DIM)))
+            .collect::<Vec<_>>();
+        let l2_distances =
+            Float32Array::from_iter_values(vectors.iter().map(|v| l2(query.as_slice(), v)));
+        let dot_distances = Float32Array::from_iter_values(
+            vectors
+                .iter()
+                .map(|v| -v.iter().zip(query.iter()).map(|(a, b)| a * b).sum::<f32>()),
+        );
+
+        let assert_close = |actual: Float32Array, expected: &Float32Array| {
+            actual
+                .values()
+                .iter()
+                .zip(expected.values().iter())
+                .for_each(|(a, e)| assert_relative_eq!(*a, *e, epsilon = 1e-5));
+        };
+        assert_close(
+            convert_distances(&dot_distances, MetricType::Dot, MetricType::L2, true).unwrap(),
+            &l2_distances,
+        );
+        assert_close(
+            convert_distances(&dot_distances, MetricType::Dot, MetricType::Cosine, true).unwrap(),
+            &l2_distances,
+        );
+        assert_close(
+            convert_distances(&l2_distances, MetricType::L2, MetricType::Dot, true).unwrap(),
+            &dot_distances,
+        );
+        assert_close(
+            convert_distances(&l2_distances, MetricType::Cosine, MetricType::L2, true).unwrap(),
+            &l2_distances,
+        );
+
+        // Without unit-norm vectors only the identity is valid
+        assert_eq!(
+            convert_distances(&l2_distances, MetricType::L2, MetricType::L2, false).unwrap(),
+            l2_distances
+        );
+        for (from, to) in [
+            (MetricType::Dot, MetricType::L2),
+            (MetricType::L2, MetricType::Dot),
+            (MetricType::Cosine, MetricType::L2),
+        ] {
+            assert!(convert_distances(&l2_distances, from, to, false).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_effective_bits() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        // Each vector is made of centroids, the first sub-vector cycles through 4 of them
+        // and the others always use the same one.
+        let sub_vector_width = DIM / NUM_SUB_VECTORS;
+        let values = (0..400)
+            .flat_map(|row| {
+                (0..NUM_SUB_VECTORS).flat_map(move |sub_vector_idx| {
+                    let centroid = if sub_vector_idx == 0 { row % 4 } else { 7 };
+                    (0..sub_vector_width).map(move |i| (centroid, sub_vector_idx, i))
+                })
+            })
+            .map(|(centroid, sub_vector_idx, i)| {
+                pq.centroids(sub_vector_idx)[centroid * sub_vector_width + i]
+            })
+            .collect::<Vec<_>>();
+        let vectors =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(values), DIM as i32)
+                .unwrap();
+
+        let bits = pq.effective_bits(&vectors).await.unwrap();
+        assert_eq!(bits.len(), NUM_SUB_VECTORS);
+        assert_relative_eq!(bits[0], 2.0, epsilon = 1e-6);
+        for b in &bits[1..] {
+            assert_relative_eq!(*b, 0.0, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_new_validates_codebook_shape() {
+        const DIM: usize = 16;
+        // Dimension is not divisible by the number of sub-vectors.
+        let err = ProductQuantizerImpl::<Float32Type>::new(
+            3,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("not divisible"), "{}", err);
+
+        // Too few centroids.
+        let err = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(16 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("expect a codebook"), "{}", err);
+
+        // Centroids of the wrong width, e.g., trained on another dimension.
+        let err = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * (DIM + 4))),
+            MetricType::L2,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("expect a codebook"), "{}", err);
+
+        // Codes are at most one byte.
+        for nbits in [0, 9, 16] {
+            let err = ProductQuantizerImpl::<Float32Type>::new(
+                4,
+                nbits,
+                DIM,
+                Arc::new(generate_random_array(DIM)),
+                MetricType::L2,
+            )
+            .unwrap_err();
+            assert!(err.to_string().contains("num_bits"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transform_uses_stored_metric_type() {
+        const DIM: usize = 2;
+        // Under L2, [1, 0] is closest to centroid 0, while under dot product it is
+        // closest to the longer centroid 1.
+        let mut values = vec![-100.0_f32; 256 * DIM];
+        values[..4].copy_from_slice(&[1.0, 0.0, 10.0, 0.0]);
+        let codebook = Arc::new(Float32Array::from(values));
+        let l2_pq =
+            ProductQuantizerImpl::<Float32Type>::new(1, 8, DIM, codebook.clone(), MetricType::L2)
+                .unwrap();
+        let dot_pq =
+            ProductQuantizerImpl::<Float32Type>::new(1, 8, DIM, codebook, MetricType::Dot).unwrap();
+        assert_eq!(l2_pq.metric_type(), MetricType::L2);
+        assert_eq!(dot_pq.metric_type(), MetricType::Dot);
+
+        let vectors =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(vec![1.0, 0.0]), DIM as i32)
+                .unwrap();
+        let code = |codes: ArrayRef| {
+            codes
+                .as_fixed_size_list()
+                .values()
+                .as_primitive::<UInt8Type>()
+                .value(0)
+        };
+        assert_eq!(code(l2_pq.transform(&vectors).await.unwrap()), 0);
+        assert_eq!(code(dot_pq.transform(&vectors).await.unwrap()), 1);
+
+        // The expected metric type does not change the assignment.
+        let codes = l2_pq
+            .transform_with_metric_type(&vectors, MetricType::Dot)
+            .await
+            .unwrap();
+        assert_eq!(code(codes), 0);
+        let codes = dot_pq
+            .transform_with_metric_type(&vectors, MetricType::L2)
+            .await
+            .unwrap();
+        assert_eq!(code(codes), 1);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_transform_matches_serial() {
+        const DIM: usize = 16;
+        const NUM_ROWS: usize = PARALLEL_TRANSFORM_MIN_ROWS * 3;
+        const CHUNK_SIZE: usize = PARALLEL_TRANSFORM_MIN_ROWS / 2;
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+        for num_bits in [4, 6, 8] {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                4,
+                num_bits,
+                DIM,
+                Arc::new(generate_random_array(num_centroids(num_bits) * DIM)),
+                MetricType::L2,
+            )
+            .unwrap();
+            let parallel = pq.transform(&vectors).await.unwrap();
+
+            // Chunks below the threshold are encoded serially.
+            let mut serial = vec![];
+            for offset in (0..NUM_ROWS).step_by(CHUNK_SIZE) {
+                let codes = pq
+                    .transform(&vectors.slice(offset, CHUNK_SIZE))
+                    .await
+                    .unwrap();
+                serial.extend_from_slice(
+                    codes
+                        .as_fixed_size_list()
+                        .values()
+                        .as_primitive::<UInt8Type>()
+                        .values(),
+                );
+            }
+            assert_eq!(
+                parallel
+                    .as_fixed_size_list()
+                    .values()
+                    .as_primitive::<UInt8Type>()
+                    .values()
+                    .as_ref(),
+                serial.as_slice(),
+                "num_bits = {}",
+                num_bits
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_factored_distance_table() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let vectors =
+            FixedSizeListArray::try_new_from_values(generate_random_array(100 * DIM), DIM as i32)
+                .unwrap();
+        let query = generate_random_array(DIM);
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                8,
+                DIM,
+                codebook.clone(),
+                metric_type,
+            )
+            .unwrap();
+            let codes = pq.transform(&vectors).await.unwrap();
+            let codes = codes
+                .as_fixed_size_list()
+                .values()
+                .as_primitive::<UInt8Type>();
+
+            let expected = pq.compute_distances(&query, codes).unwrap();
+            let (table, constant) = pq.build_factored_distance_table(&query).unwrap();
+            if metric_type == MetricType::Dot {
+                assert_eq!(constant, 0.0);
+            }
+            let actual = pq.compute_distances_from_table(&table, codes).unwrap();
+            assert_eq!(actual.len(), expected.len());
+            for (a, e) in actual.values().iter().zip(expected.values()) {
+                assert_relative_eq!(a + constant, *e, epsilon = 1e-4, max_relative = 1e-4);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transform_int8() {
+        const DIM: usize = 16;
+        let codebook = Arc::new(Float32Array::from_iter_values(
+            (0..256 * DIM).map(|v| ((v * 37) % 255) as f32 - 127.0),
+        ));
+        let pq =
+            ProductQuantizerImpl::<Float32Type>::new(4, 8, DIM, codebook, MetricType::L2).unwrap();
+
+        let values = (0..100 * DIM)
+            .map(|v| ((v * 13) % 255) as i16 - 127)
+            .collect::<Vec<_>>();
+        let int8_vectors = FixedSizeListArray::try_new_from_values(
+            Int8Array::from_iter_values(values.iter().map(|v| *v as i8)),
+            DIM as i32,
+        )
+        .unwrap();
+        let float_vectors = FixedSizeListArray::try_new_from_values(
+            Float32Array::from_iter_values(values.iter().map(|v| *v as f32)),
+            DIM as i32,
+        )
+        .unwrap();
+
+        let int8_codes = pq.transform(&int8_vectors).await.unwrap();
+        let float_codes = pq.transform(&float_vectors).await.unwrap();
+        assert_eq!(int8_codes.as_ref(), float_codes.as_ref());
+    }
+
+    #[test]
+    fn test_codebook_flat() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        for num_bits in [4, 8] {
+            let codebook = Arc::new(generate_random_array(num_centroids(num_bits) * DIM));
+            let pq = ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                num_bits,
+                DIM,
+                codebook.clone(),
+                MetricType::L2,
+            )
+            .unwrap();
+            let (rows, cols) = pq.codebook_shape();
+            assert_eq!((rows, cols), (num_centroids(num_bits), DIM));
+            let flat = pq.codebook_flat();
+            assert_eq!(flat.len(), rows * cols);
+            assert_eq!(flat, codebook.values().as_ref());
+
+            let sub_vector_width = DIM / NUM_SUB_VECTORS;
+            for sub_vector_idx in 0..NUM_SUB_VECTORS {
+                let start = sub_vector_idx * num_centroids(num_bits) * sub_vector_width;
+                assert_eq!(
+                    pq.centroids(sub_vector_idx),
+                    &flat[start..start + num_centroids(num_bits) * sub_vector_width]
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transform_padded_vectors() {
+        const DIM: usize = 12;
+        const PADDED_DIM: usize = 16;
+        const NUM_ROWS: usize = 50;
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        )
+        .unwrap();
+        let vectors = generate_random_array(NUM_ROWS * DIM);
+        let padded = vectors
+            .values()
+            .chunks_exact(DIM)
+            .flat_map(|v| {
+                v.iter()
+                    .copied()
+                    .chain(repeat(100.0).take(PADDED_DIM - DIM))
+            })
+            .collect::<Vec<_>>();
+        let vectors = FixedSizeListArray::try_new_from_values(vectors, DIM as i32).unwrap();
+        let padded =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(padded), PADDED_DIM as i32)
+                .unwrap();
+
+        assert!(matches!(
+            pq.transform(&padded).await,
+            Err(Error::Index { .. })
+        ));
+
+        let pq = pq.with_truncate_padded_vectors(true);
+        let expected = pq.transform(&vectors).await.unwrap();
+        let actual = pq.transform(&padded).await.unwrap();
+        assert_eq!(&actual, &expected);
+
+        // Vectors shorter than the dimension are still an error.
+        let short = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * (DIM - 4)),
+            (DIM - 4) as i32,
+        )
+        .unwrap();
+        assert!(pq.transform(&short).await.is_err());
+    }
 }
@@ -19,23 +19,32 @@ use std::sync::Arc;
 
 use crate::pb;
 use arrow_array::types::{Float16Type, Float64Type};
+use arrow_array::FixedSizeListArray;
 use arrow_array::{
-    cast::AsArray, types::Float32Type, Array, ArrayRef, Float32Array, PrimitiveArray,
+    cast::AsArray, types::Float32Type, Array, ArrayRef, Float32Array, UInt32Array, UInt8Array,
 };
-use arrow_array::{ArrowNumericType, FixedSizeListArray};
 use arrow_schema::DataType;
 use futures::{stream, StreamExt, TryStreamExt};
+use lance_arrow::bfloat16::BFloat16Type;
 use lance_arrow::{ArrowFloatType, FloatArray};
 use lance_core::{Error, Result};
 use lance_linalg::distance::{Dot, L2};
+use lance_linalg::kernels::normalize_fsl;
+use lance_linalg::kmeans::KMeanInit;
 use lance_linalg::{distance::MetricType, MatrixView};
 use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
 use snafu::{location, Location};
 
-use super::utils::divide_to_subvectors;
+use super::utils::{
+    apply_rotation, codes_to_fsl, divide_to_subvectors, downcast_float_array, num_centroids,
+};
 use super::ProductQuantizer;
 use crate::pb::Pq;
-use crate::vector::{kmeans::train_kmeans, pq::ProductQuantizerImpl};
+use crate::vector::{
+    kmeans::train_kmeans_with_sample_size, pq::ProductQuantizerImpl,
+    residual::compute_residual_mixed,
+};
 
 /// Parameters for building product quantizer.
 #[derive(Debug, Clone)]
@@ -61,6 +70,73 @@ pub struct PQBuildParams {
 
     /// Sample rate to train PQ codebook.
     pub sample_rate: usize,
+
+    /// How to initialize the codebook centroids before running kmeans.
+    ///
+    /// [`KMeanInit::KMeanPlusPlus`] usually gives a better codebook, at the cost
+    /// of a slower initialization.
+    pub init: KMeanInit,
+
+    /// Optional OPQ rotation, a `dimension x dimension` orthogonal matrix with one
+    /// row per list, of the same float type as the training data.
+    ///
+    /// The training data is rotated before the codebook is trained, and the rotation
+    /// is stored in the [ProductQuantizer] to be applied to all vectors and queries.
+    pub rotation: Option<FixedSizeListArray>,
+
+    /// Fail the build if the mean reconstruction error of the training data exceeds it.
+    ///
+    /// The error of a vector is the squared L2 distance to its reconstruction from the PQ
+    /// code, whatever the metric the PQ is trained with. A large error means the codebook
+    /// is a bad fit for the data.
+    pub max_reconstruction_error: Option<f32>,
+
+    /// Seed of the random number generator used to sample the training data and
+    /// initialize the centroids.
+    ///
+    /// A random seed is picked if it is not set. Either way, the seed is recorded in the
+    /// [PQTrainingInfo] so that the training can be reproduced.
+    pub seed: Option<u64>,
+}
+
+/// Parameters used to train a PQ codebook.
+///
+/// It is persisted alongside the codebook so that a future retrain can be reproduced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PQTrainingInfo {
+    /// Number of vectors used to train the kmeans of each sub-vector.
+    pub sample_size: usize,
+
+    /// Random seed used to sample the training data, if any.
+    pub seed: Option<u64>,
+
+    /// The max number of kmeans iterations.
+    pub max_iters: usize,
+
+    /// Sum of the distances from the training vectors to their closest centroids.
+    pub inertia: f64,
+}
+
+impl From<&PQTrainingInfo> for pb::PqTrainingInfo {
+    fn from(info: &PQTrainingInfo) -> Self {
+        Self {
+            sample_size: info.sample_size as u64,
+            seed: info.seed,
+            max_iters: info.max_iters as u32,
+            inertia: info.inertia,
+        }
+    }
+}
+
+impl From<&pb::PqTrainingInfo> for PQTrainingInfo {
+    fn from(proto: &pb::PqTrainingInfo) -> Self {
+        Self {
+            sample_size: proto.sample_size as usize,
+            seed: proto.seed,
+            max_iters: proto.max_iters as usize,
+            inertia: proto.inertia,
+        }
+    }
 }
 
 impl Default for PQBuildParams {
@@ -73,6 +149,10 @@ impl Default for PQBuildParams {
             max_opq_iters: 50,
             codebook: None,
             sample_rate: 256,
+            init: KMeanInit::Random,
+            rotation: None,
+            max_reconstruction_error: None,
+            seed: None,
         }
     }
 }
@@ -95,37 +175,109 @@ impl PQBuildParams {
         }
     }
 
+    /// Suggest PQ parameters that fit the index into a memory budget.
+    ///
+    /// The footprint of a PQ index is estimated by
+    /// [`ProductQuantizerImpl::estimate_memory`]: the `f32` codebook
+    /// (`num_centroids * dimension`) plus the PQ code of each row. More
+    /// sub-vectors and more bits give better recall, so this picks the largest
+    /// `num_sub_vectors` that divides `dimension` and fits into `memory_budget`
+    /// bytes with `num_bits = 8`. If no 8-bit codebook fits, it falls back to
+    /// `num_bits = 4`, whose codebook is 16 times smaller.
+    pub fn with_memory_budget(
+        dimension: usize,
+        num_rows: usize,
+        memory_budget: usize,
+    ) -> Result<Self> {
+        [8, 4]
+            .into_iter()
+            .flat_map(|num_bits| {
+                (1..=dimension)
+                    .rev()
+                    .filter(|m| dimension % m == 0)
+                    .map(move |num_sub_vectors| Self::new(num_sub_vectors, num_bits))
+            })
+            .find(|params| {
+                ProductQuantizerImpl::<Float32Type>::estimate_memory(num_rows, dimension, params)
+                    <= memory_budget
+            })
+            .ok_or_else(|| Error::Index {
+                message: format!(
+                    "PQ builder: can not fit {} vectors of dimension {} into {} bytes",
+                    num_rows, dimension, memory_budget
+                ),
+                location: location!(),
+            })
+    }
+
     pub async fn build_from_matrix<T: ArrowFloatType + Dot + L2 + 'static>(
         &self,
         data: &MatrixView<T>,
         metric_type: MetricType,
     ) -> Result<Arc<dyn ProductQuantizer + 'static>> {
-        assert_ne!(
-            metric_type,
-            MetricType::Cosine,
-            "PQ code does not support cosine"
-        );
+        Ok(self.build_and_assign(data, metric_type).await?.0)
+    }
+
+    /// Train the [ProductQuantizer], and keep the PQ codes of `data` that are assigned
+    /// to compute the training distortion, one byte per sub-vector.
+    async fn build_and_assign<T: ArrowFloatType + Dot + L2 + 'static>(
+        &self,
+        data: &MatrixView<T>,
+        metric_type: MetricType,
+    ) -> Result<(Arc<dyn ProductQuantizer + 'static>, Vec<u8>)> {
+        if metric_type == MetricType::Cosine {
+            return Err(Error::Index {
+                message: "PQ builder: cosine is not supported, normalize the vectors and use L2, or use build_with_ivf".to_string(),
+                location: location!(),
+            });
+        }
 
         const REDOS: usize = 1;
 
+        let rotation = self
+            .rotation
+            .as_ref()
+            .map(|rotation| typed_rotation::<T>(rotation, data.num_columns()))
+            .transpose()?;
+        let rotated;
+        let data = match &rotation {
+            Some(rotation) => {
+                rotated = MatrixView::<T>::new(
+                    Arc::new(T::ArrayType::from(apply_rotation(
+                        data.data().as_slice(),
+                        rotation.as_slice(),
+                        data.num_columns(),
+                        false,
+                    ))),
+                    data.num_columns(),
+                );
+                &rotated
+            }
+            None => data,
+        };
+
         let sub_vectors = divide_to_subvectors(data, self.num_sub_vectors);
         let num_centroids = 2_usize.pow(self.num_bits as u32);
         let dimension = data.num_columns();
         let sub_vector_dimension = dimension / self.num_sub_vectors;
 
-        let d = stream::iter(sub_vectors.into_iter())
-            .map(|sub_vec| async move {
-                let rng = rand::rngs::SmallRng::from_entropy();
-                train_kmeans::<T>(
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let d = stream::iter(sub_vectors.into_iter().enumerate())
+            .map(|(sub_vector_idx, sub_vec)| async move {
+                // Each sub-vector gets its own stream of random numbers, derived from the seed.
+                let rng =
+                    rand::rngs::SmallRng::seed_from_u64(seed.wrapping_add(sub_vector_idx as u64));
+                train_kmeans_with_sample_size::<T>(
                     sub_vec.as_ref(),
                     None,
                     sub_vector_dimension,
                     num_centroids,
                     self.max_iters as u32,
                     REDOS,
-                    rng.clone(),
+                    rng,
                     metric_type,
                     self.sample_rate,
+                    self.init,
                 )
                 .await
             })
@@ -133,37 +285,63 @@ impl PQBuildParams {
             .try_collect::<Vec<_>>()
             .await?;
         let mut codebook_builder = Vec::with_capacity(num_centroids * dimension);
-        for centroid in d.iter() {
+        for (centroid, _) in d.iter() {
             codebook_builder.extend_from_slice(centroid.as_slice());
         }
+        // All the sub-vectors have the same number of rows, so they are sampled the same way.
+        let sample_size = d.first().map(|(_, sample_size)| *sample_size).unwrap_or(0);
 
         let pd_centroids = T::ArrayType::from(codebook_builder);
 
-        Ok(Arc::new(ProductQuantizerImpl::<T>::new(
+        let pq = ProductQuantizerImpl::<T>::new(
             self.num_sub_vectors,
             self.num_bits as u32,
             dimension,
             Arc::new(pd_centroids),
             metric_type,
-        )))
+        )?;
+        let pq = match rotation {
+            Some(rotation) => pq.with_rotation(rotation)?,
+            None => pq,
+        };
+        let (codes, distortion) = pq.assign_with_distortion(data, metric_type)?;
+        if let Some(max_error) = self.max_reconstruction_error {
+            // The distortion is only the squared L2 error with the L2 metric, e.g., it is
+            // the sum of the negative dot products with the Dot metric.
+            let error = match metric_type {
+                MetricType::L2 => distortion,
+                _ => mean_reconstruction_error(&pq, data, &codes),
+            };
+            if error > max_error as f64 {
+                return Err(Error::Index {
+                    message: format!(
+                        "PQ builder: mean reconstruction error {} exceeds the threshold {}",
+                        error, max_error
+                    ),
+                    location: location!(),
+                });
+            }
+        }
+        let inertia = distortion * data.num_rows() as f64;
+        let training_info = PQTrainingInfo {
+            sample_size,
+            seed: Some(seed),
+            max_iters: self.max_iters,
+            inertia,
+        };
+        Ok((Arc::new(pq.with_training_info(training_info)), codes))
     }
 
     /// Build a [ProductQuantizer] from the given data.
     ///
-    /// If the [MetricType] is [MetricType::Cosine], the input data will be normalized.
+    /// [MetricType::Cosine] is not supported, see [`Self::build_with_ivf`] which
+    /// normalizes the input data.
     pub async fn build(
         &self,
         data: &dyn Array,
         metric_type: MetricType,
     ) -> Result<Arc<dyn ProductQuantizer>> {
-        assert_eq!(data.null_count(), 0);
-        let fsl = data.as_fixed_size_list_opt().ok_or(Error::Index {
-            message: format!(
-                "PQ builder: input is not a FixedSizeList: {}",
-                data.data_type()
-            ),
-            location: location!(),
-        })?;
+        let fsl = training_vectors(data)?;
         // TODO: support bf16 later.
         match fsl.value_type() {
             DataType::Float16 => {
@@ -184,22 +362,307 @@ impl PQBuildParams {
             }),
         }
     }
+
+    /// Build a [ProductQuantizer] from the given data, and the PQ codes of the data.
+    ///
+    /// It returns the same codes as [`ProductQuantizer::transform`] on `data`, but
+    /// the codes are kept from the assignment that computes the training distortion,
+    /// instead of assigning the data a second time.
+    pub async fn build_and_quantize(
+        &self,
+        data: &dyn Array,
+        metric_type: MetricType,
+    ) -> Result<(Arc<dyn ProductQuantizer>, ArrayRef)> {
+        let fsl = training_vectors(data)?;
+        let (pq, codes) = match fsl.value_type() {
+            DataType::Float16 => {
+                let data = MatrixView::<Float16Type>::try_from(fsl)?;
+                self.build_and_assign(&data, metric_type).await?
+            }
+            DataType::Float32 => {
+                let data = MatrixView::<Float32Type>::try_from(fsl)?;
+                self.build_and_assign(&data, metric_type).await?
+            }
+            DataType::Float64 => {
+                let data = MatrixView::<Float64Type>::try_from(fsl)?;
+                self.build_and_assign(&data, metric_type).await?
+            }
+            _ => {
+                return Err(Error::Index {
+                    message: format!("PQ builder: unsupported data type: {}", fsl.value_type()),
+                    location: location!(),
+                })
+            }
+        };
+        let codes = codes_to_fsl(
+            UInt8Array::from(codes),
+            self.num_bits as u32,
+            self.num_sub_vectors,
+            None,
+        )?;
+        Ok((pq, Arc::new(codes)))
+    }
+
+    /// Build a [ProductQuantizer] for an IVF_PQ index.
+    ///
+    /// It runs the same steps on the training data as the IVF_PQ index does on the
+    /// indexed vectors, so that the codebook matches the PQ codes it will be used for:
+    ///
+    /// 1. If the [MetricType] is [MetricType::Cosine], the vectors are normalized, and
+    ///    cosine distance is then computed as L2 distance of the normalized vectors.
+    /// 2. If `ivf_centroids` is provided, the residuals of the vectors to their closest
+    ///    IVF centroids are computed.
+    /// 3. The codebook is trained on the result with L2 distance.
+    pub async fn build_with_ivf(
+        &self,
+        data: &dyn Array,
+        ivf_centroids: Option<&FixedSizeListArray>,
+        metric_type: MetricType,
+    ) -> Result<Arc<dyn ProductQuantizer>> {
+        let fsl = data.as_fixed_size_list_opt().ok_or(Error::Index {
+            message: format!(
+                "PQ builder: input is not a FixedSizeList: {}",
+                data.data_type()
+            ),
+            location: location!(),
+        })?;
+        check_no_nulls(fsl)?;
+        let training_data = if metric_type == MetricType::Cosine {
+            normalize_fsl(fsl)?
+        } else {
+            fsl.clone()
+        };
+        let training_data = match ivf_centroids {
+            Some(centroids) => {
+                // Int8 vectors are trained on f32 residuals, which may not fit into Int8.
+                let output_type = match training_data.value_type() {
+                    value_type if value_type.is_floating() => value_type,
+                    _ => DataType::Float32,
+                };
+                compute_residual_mixed(
+                    centroids,
+                    &training_data,
+                    None,
+                    MetricType::L2,
+                    &output_type,
+                )
+                .await?
+            }
+            None => training_data,
+        };
+        self.build(&training_data, MetricType::L2).await
+    }
+
+    /// Build a [ProductQuantizer] and the PQ codes from an existing clustering, without
+    /// running kmeans.
+    ///
+    /// `centroids` is the codebook, with the same layout as [`Self::codebook`], and
+    /// `assignments` holds the centroid id of each sub-vector of each vector, that is
+    /// `num_sub_vectors` ids per vector. The PQ codes are returned in the same format as
+    /// [`ProductQuantizer::transform`].
+    pub fn build_from_assignments(
+        &self,
+        centroids: &dyn Array,
+        assignments: &UInt32Array,
+        dimension: usize,
+        metric_type: MetricType,
+    ) -> Result<(Arc<dyn ProductQuantizer>, FixedSizeListArray)> {
+        let metric_type = if metric_type == MetricType::Cosine {
+            MetricType::L2
+        } else {
+            metric_type
+        };
+        let pq: Arc<dyn ProductQuantizer> = match centroids.data_type() {
+            DataType::Float16 => {
+                self.pq_from_centroids::<Float16Type>(centroids, dimension, metric_type)?
+            }
+            DataType::Float32 => {
+                self.pq_from_centroids::<Float32Type>(centroids, dimension, metric_type)?
+            }
+            DataType::Float64 => {
+                self.pq_from_centroids::<Float64Type>(centroids, dimension, metric_type)?
+            }
+            _ => {
+                return Err(Error::Index {
+                    message: format!(
+                        "PQ builder: unsupported centroids type: {}",
+                        centroids.data_type()
+                    ),
+                    location: location!(),
+                })
+            }
+        };
+
+        if assignments.null_count() > 0 || assignments.len() % self.num_sub_vectors != 0 {
+            return Err(Error::Index {
+                message: format!(
+                    "PQ builder: expect {} non-null assignments per vector, got {} assignments with {} nulls",
+                    self.num_sub_vectors,
+                    assignments.len(),
+                    assignments.null_count()
+                ),
+                location: location!(),
+            });
+        }
+        let num_centroids = num_centroids(self.num_bits as u32);
+        let codes = assignments
+            .values()
+            .iter()
+            .map(|&id| {
+                if id as usize >= num_centroids {
+                    return Err(Error::Index {
+                        message: format!(
+                            "PQ builder: assignment {} is out of range, there are {} centroids per sub-vector",
+                            id, num_centroids
+                        ),
+                        location: location!(),
+                    });
+                }
+                Ok(id as u8)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let codes = codes_to_fsl(
+            UInt8Array::from(codes),
+            self.num_bits as u32,
+            self.num_sub_vectors,
+            None,
+        )?;
+        Ok((pq, codes))
+    }
+
+    fn pq_from_centroids<T: ArrowFloatType + Dot + L2 + 'static>(
+        &self,
+        centroids: &dyn Array,
+        dimension: usize,
+        metric_type: MetricType,
+    ) -> Result<Arc<dyn ProductQuantizer>> {
+        let codebook = downcast_float_array::<T>(centroids).ok_or(Error::Index {
+            message: format!(
+                "PQ builder: expect {} centroids, got {}",
+                T::FLOAT_TYPE,
+                centroids.data_type()
+            ),
+            location: location!(),
+        })?;
+        Ok(Arc::new(ProductQuantizerImpl::<T>::new(
+            self.num_sub_vectors,
+            self.num_bits as u32,
+            dimension,
+            Arc::new(codebook),
+            metric_type,
+        )?))
+    }
+}
+
+/// The vectors to train PQ on, which must be a `FixedSizeList` without nulls.
+fn training_vectors(data: &dyn Array) -> Result<&FixedSizeListArray> {
+    check_no_nulls(data)?;
+    data.as_fixed_size_list_opt().ok_or(Error::Index {
+        message: format!(
+            "PQ builder: input is not a FixedSizeList: {}",
+            data.data_type()
+        ),
+        location: location!(),
+    })
+}
+
+/// PQ can not be trained on null vectors.
+fn check_no_nulls(data: &dyn Array) -> Result<()> {
+    if data.null_count() > 0 {
+        return Err(Error::Index {
+            message: format!(
+                "PQ builder: training data has {} null vectors out of {}",
+                data.null_count(),
+                data.len()
+            ),
+            location: location!(),
+        });
+    }
+    Ok(())
+}
+
+/// Check the shape and type of a rotation matrix.
+fn typed_rotation<T: ArrowFloatType>(
+    rotation: &FixedSizeListArray,
+    dimension: usize,
+) -> Result<Arc<T::ArrayType>> {
+    if rotation.len() != dimension || rotation.value_length() as usize != dimension {
+        return Err(Error::Index {
+            message: format!(
+                "PQ builder: rotation must be a {}x{} matrix, got {}x{}",
+                dimension,
+                dimension,
+                rotation.len(),
+                rotation.value_length()
+            ),
+            location: location!(),
+        });
+    }
+    let values = downcast_float_array::<T>(rotation.values()).ok_or(Error::Index {
+        message: format!(
+            "PQ builder: expect a {} rotation, got {}",
+            T::FLOAT_TYPE,
+            rotation.value_type()
+        ),
+        location: location!(),
+    })?;
+    Ok(Arc::new(values))
 }
 
-fn create_typed_pq<
-    T: ArrowFloatType<ArrayType = PrimitiveArray<T>> + ArrowNumericType + L2 + Dot,
->(
+fn create_typed_pq<T: ArrowFloatType + L2 + Dot + 'static>(
     proto: &Pq,
     metric_type: MetricType,
     array: &dyn Array,
-) -> Arc<dyn ProductQuantizer> {
-    Arc::new(ProductQuantizerImpl::<T>::new(
+) -> Result<Arc<dyn ProductQuantizer>> {
+    let codebook = downcast_float_array::<T>(array).ok_or(Error::Index {
+        message: format!(
+            "PQ builder: expect a {} codebook, got {}",
+            T::FLOAT_TYPE,
+            array.data_type()
+        ),
+        location: location!(),
+    })?;
+    let pq = ProductQuantizerImpl::<T>::new(
         proto.num_sub_vectors as usize,
         proto.num_bits,
         proto.dimension as usize,
-        Arc::new(array.as_primitive::<T>().clone()),
+        Arc::new(codebook),
         metric_type,
-    ))
+    )?;
+    let pq = match proto.training_info.as_ref() {
+        Some(info) => pq.with_training_info(info.into()),
+        None => pq,
+    };
+    let pq = match proto.rotation.as_ref() {
+        Some(tensor) => {
+            let rotation = FixedSizeListArray::try_from(tensor)?;
+            pq.with_rotation(typed_rotation::<T>(&rotation, proto.dimension as usize)?)?
+        }
+        None => pq,
+    };
+    Ok(Arc::new(pq))
+}
+
+/// Mean squared L2 distance from the vectors of `data` to their reconstruction from
+/// `codes`, one byte per sub-vector.
+///
+/// `data` must be rotated already if the PQ has a rotation.
+fn mean_reconstruction_error<T: ArrowFloatType + Dot + L2>(
+    pq: &ProductQuantizerImpl<T>,
+    data: &MatrixView<T>,
+    codes: &[u8],
+) -> f64 {
+    if data.num_rows() == 0 {
+        return 0.0;
+    }
+    let total = data
+        .data()
+        .as_slice()
+        .chunks_exact(data.num_columns())
+        .zip(codes.chunks_exact(pq.num_sub_vectors))
+        .map(|(vector, code)| T::l2(vector, pq.reconstruct(code).as_slice()) as f64)
+        .sum::<f64>();
+    total / data.num_rows() as f64
 }
 
 /// Load ProductQuantizer from Protobuf
@@ -215,16 +678,16 @@ pub fn from_proto(proto: &Pq, metric_type: MetricType) -> Result<Arc<dyn Product
 
         match pb::tensor::DataType::try_from(tensor.data_type)? {
             pb::tensor::DataType::Bfloat16 => {
-                unimplemented!()
+                create_typed_pq::<BFloat16Type>(proto, mt, fsl.values())
             }
             pb::tensor::DataType::Float16 => {
-                Ok(create_typed_pq::<Float16Type>(proto, mt, fsl.values()))
+                create_typed_pq::<Float16Type>(proto, mt, fsl.values())
             }
             pb::tensor::DataType::Float32 => {
-                Ok(create_typed_pq::<Float32Type>(proto, mt, fsl.values()))
+                create_typed_pq::<Float32Type>(proto, mt, fsl.values())
             }
             pb::tensor::DataType::Float64 => {
-                Ok(create_typed_pq::<Float64Type>(proto, mt, fsl.values()))
+                create_typed_pq::<Float64Type>(proto, mt, fsl.values())
             }
             _ => Err(Error::Index {
                 message: format!("PQ builder: unsupported data type: {:?}", tensor.data_type),
@@ -240,6 +703,491 @@ pub fn from_proto(proto: &Pq, metric_type: MetricType) -> Result<Arc<dyn Product
                 proto.codebook.iter().copied(),
             )),
             metric_type,
-        )))
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow_array::types::UInt8Type;
+    use lance_arrow::FixedSizeListArrayExt;
+
+    use crate::vector::pq::utils::{is_bit_packed, unpack_codes};
+    use lance_testing::datagen::generate_random_array_with_range;
+
+    #[tokio::test]
+    async fn test_kmeans_plusplus_init() {
+        const DIM: usize = 8;
+        // 256 tight clusters per sub-vector, far away from each other.
+        let values = Float32Array::from_iter((0..256 * 4 * DIM).map(|v| {
+            let row = v / DIM;
+            (row % 256) as f32 * 100.0 + (v % 5) as f32 * 0.01
+        }));
+        let fsl = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+
+        let mut inertia = vec![];
+        for init in [KMeanInit::Random, KMeanInit::KMeanPlusPlus] {
+            let params = PQBuildParams {
+                num_sub_vectors: 2,
+                init,
+                seed: Some(42),
+                ..Default::default()
+            };
+            let pq = params.build(&fsl, MetricType::L2).await.unwrap();
+            inertia.push(pq.training_info().unwrap().inertia);
+        }
+        assert!(
+            inertia[1] <= inertia[0] + 1e-3,
+            "kmeans++ inertia {} is worse than random inertia {}",
+            inertia[1],
+            inertia[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_with_ivf_cosine_recall() {
+        const DIM: usize = 16;
+        const NUM_ROWS: usize = 2000;
+        const K: usize = 10;
+
+        let values = generate_random_array_with_range(NUM_ROWS * DIM, -1.0..1.0);
+        let fsl = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+        let normalized = normalize_fsl(&fsl).unwrap();
+        // A single IVF partition, so the residual of the query is against the same centroid.
+        let centroids = normalized.slice(0, 1);
+
+        let params = PQBuildParams::new(4, 8);
+        let pq = params
+            .build_with_ivf(&fsl, Some(&centroids), MetricType::Cosine)
+            .await
+            .unwrap();
+
+        let residuals = compute_residual_mixed(
+            &centroids,
+            &normalized,
+            None,
+            MetricType::L2,
+            &DataType::Float32,
+        )
+        .await
+        .unwrap();
+        let codes = pq.transform(&residuals).await.unwrap();
+        let codes = codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>();
+
+        fn top_k(distances: &[f32]) -> Vec<usize> {
+            let mut indices = (0..distances.len()).collect::<Vec<_>>();
+            indices.sort_by(|a, b| distances[*a].total_cmp(&distances[*b]));
+            indices.truncate(K);
+            indices
+        }
+
+        let queries = normalize_fsl(
+            &FixedSizeListArray::try_new_from_values(
+                generate_random_array_with_range(10 * DIM, -1.0..1.0),
+                DIM as i32,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let centroid = centroids.value(0);
+        let centroid = centroid.as_primitive::<Float32Type>().values();
+        let mut hits = 0;
+        for i in 0..queries.len() {
+            let query = queries.value(i);
+            let query = query.as_primitive::<Float32Type>().values();
+            let expected = top_k(
+                &normalized
+                    .values()
+                    .as_primitive::<Float32Type>()
+                    .values()
+                    .chunks_exact(DIM)
+                    .map(|v| 1.0 - v.iter().zip(query).map(|(a, b)| a * b).sum::<f32>())
+                    .collect::<Vec<_>>(),
+            );
+
+            let query_residual =
+                Float32Array::from_iter_values(query.iter().zip(centroid).map(|(q, c)| q - c));
+            let distances = pq.compute_distances(&query_residual, codes).unwrap();
+            // L2 distance of normalized vectors is `2 * cosine distance`.
+            assert!(distances.values().iter().all(|d| (0.0..=4.5).contains(d)));
+            let actual = top_k(distances.values());
+            hits += actual.iter().filter(|idx| expected.contains(idx)).count();
+        }
+        let recall = hits as f32 / (queries.len() * K) as f32;
+        // Random guesses would have a recall of K / NUM_ROWS.
+        assert!(recall >= 0.3, "recall {} is too low", recall);
+    }
+
+    #[tokio::test]
+    async fn test_training_info_round_trip() {
+        let values = Float32Array::from_iter((0..16000).map(|v| v as f32));
+        let fsl = FixedSizeListArray::try_new_from_values(values, 16).unwrap();
+        let params = PQBuildParams::new(4, 8);
+        let pq = params.build(&fsl, MetricType::L2).await.unwrap();
+
+        let info = pq.training_info().cloned().unwrap();
+        assert_eq!(info.sample_size, 1000);
+        assert!(info.seed.is_some());
+        assert_eq!(info.max_iters, params.max_iters);
+        assert!(info.inertia >= 0.0);
+
+        // Fewer rows are sampled than there are, with the recorded seed the training is
+        // reproducible.
+        let params = PQBuildParams {
+            sample_rate: 2,
+            seed: Some(42),
+            ..params
+        };
+        let pq = params.build(&fsl, MetricType::L2).await.unwrap();
+        let seeded_info = pq.training_info().cloned().unwrap();
+        assert_eq!(seeded_info.sample_size, 2 * 256);
+        assert_eq!(seeded_info.seed, Some(42));
+        let again = params.build(&fsl, MetricType::L2).await.unwrap();
+        assert_eq!(
+            again.codebook_as_fsl().values().as_ref(),
+            pq.codebook_as_fsl().values().as_ref()
+        );
+
+        let proto = pb::Pq::try_from(pq.as_ref()).unwrap();
+        let loaded = from_proto(&proto, MetricType::L2).unwrap();
+        assert_eq!(loaded.training_info(), Some(&info));
+
+        // Indices written before training info was recorded.
+        let legacy = pb::Pq {
+            training_info: None,
+            ..proto
+        };
+        let loaded = from_proto(&legacy, MetricType::L2).unwrap();
+        assert!(loaded.training_info().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_build_with_rotation() {
+        const DIM: usize = 8;
+        const NUM_ROWS: usize = 1000;
+        let values = generate_random_array_with_range(NUM_ROWS * DIM, -1.0..1.0);
+        let fsl = FixedSizeListArray::try_new_from_values(values.clone(), DIM as i32).unwrap();
+
+        // A permutation matrix that swaps the two halves of each vector.
+        let rotation = Float32Array::from_iter((0..DIM * DIM).map(|i| {
+            let (row, col) = (i / DIM, i % DIM);
+            if col == (row + DIM / 2) % DIM {
+                1.0
+            } else {
+                0.0
+            }
+        }));
+        let rotation = FixedSizeListArray::try_new_from_values(rotation, DIM as i32).unwrap();
+        let params = PQBuildParams {
+            num_sub_vectors: 2,
+            rotation: Some(rotation),
+            ..Default::default()
+        };
+        let pq = params.build(&fsl, MetricType::L2).await.unwrap();
+        assert!(pq.rotation().is_some());
+        let codes = pq.transform(&fsl).await.unwrap();
+
+        // The same codebook without the rotation, applied to manually rotated vectors.
+        let proto = pb::Pq::try_from(pq.as_ref()).unwrap();
+        let unrotated = from_proto(
+            &pb::Pq {
+                rotation: None,
+                ..proto.clone()
+            },
+            MetricType::L2,
+        )
+        .unwrap();
+        let rotated = Float32Array::from_iter(
+            values
+                .values()
+                .chunks_exact(DIM)
+                .flat_map(|v| v[DIM / 2..].iter().chain(v[..DIM / 2].iter()).copied()),
+        );
+        let rotated = FixedSizeListArray::try_new_from_values(rotated, DIM as i32).unwrap();
+        assert_eq!(
+            codes.as_ref(),
+            unrotated.transform(&rotated).await.unwrap().as_ref()
+        );
+        assert_ne!(
+            codes.as_ref(),
+            unrotated.transform(&fsl).await.unwrap().as_ref()
+        );
+
+        let loaded = from_proto(&proto, MetricType::L2).unwrap();
+        assert_eq!(loaded.rotation(), pq.rotation());
+        assert_eq!(
+            codes.as_ref(),
+            loaded.transform(&fsl).await.unwrap().as_ref()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_with_nulls() {
+        const DIM: usize = 8;
+        let values = generate_random_array_with_range(1000 * DIM, -1.0..1.0);
+        let fsl = FixedSizeListArray::try_new(
+            Arc::new(arrow_schema::Field::new("item", DataType::Float32, true)),
+            DIM as i32,
+            Arc::new(values),
+            Some((0..1000).map(|i| i % 10 != 0).collect::<Vec<_>>().into()),
+        )
+        .unwrap();
+        let params = PQBuildParams::new(2, 8);
+        assert!(matches!(
+            params.build(&fsl, MetricType::L2).await,
+            Err(Error::Index { .. })
+        ));
+        assert!(matches!(
+            params.build_with_ivf(&fsl, None, MetricType::Cosine).await,
+            Err(Error::Index { .. })
+        ));
+        assert!(matches!(
+            params.build_and_quantize(&fsl, MetricType::L2).await,
+            Err(Error::Index { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_build_cosine() {
+        const DIM: usize = 8;
+        let values = generate_random_array_with_range(1000 * DIM, -1.0..1.0);
+        let fsl = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+        let params = PQBuildParams::new(2, 8);
+        assert!(matches!(
+            params.build(&fsl, MetricType::Cosine).await,
+            Err(Error::Index { .. })
+        ));
+        assert!(matches!(
+            params.build_and_quantize(&fsl, MetricType::Cosine).await,
+            Err(Error::Index { .. })
+        ));
+        assert!(params
+            .build_with_ivf(&fsl, None, MetricType::Cosine)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_max_reconstruction_error() {
+        const DIM: usize = 64;
+        let values = generate_random_array_with_range(1000 * DIM, 0.0..1.0);
+        let fsl = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+
+        // 16 centroids can not fit 16-dimensional random sub-vectors.
+        let params = PQBuildParams {
+            num_sub_vectors: 4,
+            num_bits: 4,
+            max_iters: 10,
+            max_reconstruction_error: Some(0.1),
+            ..Default::default()
+        };
+        let err = params.build(&fsl, MetricType::L2).await.unwrap_err();
+        assert!(matches!(err, Error::Index { .. }));
+        assert!(err.to_string().contains("reconstruction error"), "{}", err);
+
+        let params = PQBuildParams {
+            max_reconstruction_error: Some(f32::MAX),
+            ..params
+        };
+        let pq = params.build(&fsl, MetricType::L2).await.unwrap();
+        let inertia = pq.training_info().unwrap().inertia;
+        assert!(inertia / 1000.0 > 0.1);
+
+        // The threshold is a squared L2 error for the Dot metric too.
+        let params = PQBuildParams {
+            max_reconstruction_error: Some(0.1),
+            ..params
+        };
+        let err = params.build(&fsl, MetricType::Dot).await.unwrap_err();
+        assert!(err.to_string().contains("reconstruction error"), "{}", err);
+        let params = PQBuildParams {
+            max_reconstruction_error: Some(f32::MAX),
+            ..params
+        };
+        assert!(params.build(&fsl, MetricType::Dot).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_distortion() {
+        const DIM: usize = 16;
+        let values = generate_random_array_with_range(1000 * DIM, 0.0..1.0);
+        let fsl = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+
+        let params = PQBuildParams {
+            num_sub_vectors: 4,
+            num_bits: 8,
+            max_iters: 10,
+            ..Default::default()
+        };
+        let trained = params.build(&fsl, MetricType::L2).await.unwrap();
+        let random = ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array_with_range(256 * DIM, 0.0..1.0)),
+            MetricType::L2,
+        )
+        .unwrap();
+
+        let trained_distortion = trained.distortion(&fsl).await.unwrap();
+        let random_distortion = random.distortion(&fsl).await.unwrap();
+        assert!(trained_distortion > 0.0);
+        assert!(
+            trained_distortion < random_distortion,
+            "trained: {}, random: {}",
+            trained_distortion,
+            random_distortion
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_and_quantize() {
+        const DIM: usize = 16;
+        let values = generate_random_array_with_range(1000 * DIM, 0.0..1.0);
+        let fsl = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+
+        for (num_bits, metric_type) in [
+            (8, MetricType::L2),
+            (4, MetricType::L2),
+            (8, MetricType::Dot),
+        ] {
+            let params = PQBuildParams {
+                num_sub_vectors: 4,
+                num_bits,
+                max_iters: 10,
+                ..Default::default()
+            };
+            let (pq, codes) = params.build_and_quantize(&fsl, metric_type).await.unwrap();
+            let expected = pq.transform(&fsl).await.unwrap();
+            assert_eq!(
+                codes.as_ref(),
+                expected.as_ref(),
+                "{} bits, {:?}",
+                num_bits,
+                metric_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_pq_params_with_memory_budget() {
+        const DIM: usize = 128;
+        const NUM_ROWS: usize = 10000;
+        let codebook_size = 256 * DIM * 4;
+
+        let params =
+            PQBuildParams::with_memory_budget(DIM, NUM_ROWS, codebook_size + NUM_ROWS * 20)
+                .unwrap();
+        assert_eq!(params.num_bits, 8);
+        assert_eq!(params.num_sub_vectors, 16);
+        assert!(NUM_ROWS * params.num_sub_vectors <= NUM_ROWS * 20);
+
+        let params = PQBuildParams::with_memory_budget(DIM, NUM_ROWS, 1024 * 1024 * 1024).unwrap();
+        assert_eq!(params.num_sub_vectors, DIM);
+
+        // No 8-bit codebook fits, fall back to 4 bits.
+        let params = PQBuildParams::with_memory_budget(DIM, NUM_ROWS, codebook_size).unwrap();
+        assert_eq!(params.num_bits, 4);
+        assert_eq!(params.num_sub_vectors, 8);
+        assert!(
+            ProductQuantizerImpl::<Float32Type>::estimate_memory(NUM_ROWS, DIM, &params)
+                <= codebook_size
+        );
+
+        let min_size = 16 * DIM * 4 + NUM_ROWS;
+        let params = PQBuildParams::with_memory_budget(DIM, NUM_ROWS, min_size).unwrap();
+        assert_eq!((params.num_bits, params.num_sub_vectors), (4, 1));
+        assert!(PQBuildParams::with_memory_budget(DIM, NUM_ROWS, min_size - 1).is_err());
+    }
+
+    #[test]
+    fn test_from_proto_validates_codebook_shape() {
+        let proto = Pq {
+            num_bits: 8,
+            num_sub_vectors: 4,
+            dimension: 16,
+            codebook: vec![0.0; 255 * 16],
+            ..Default::default()
+        };
+        assert!(matches!(
+            from_proto(&proto, MetricType::L2),
+            Err(Error::Index { .. })
+        ));
+
+        let proto = Pq {
+            num_sub_vectors: 5,
+            codebook: vec![0.0; 256 * 16],
+            ..proto
+        };
+        assert!(matches!(
+            from_proto(&proto, MetricType::L2),
+            Err(Error::Index { .. })
+        ));
+    }
+
+    #[test]
+    fn test_build_from_assignments() {
+        const DIM: usize = 8;
+        const NUM_SUB_VECTORS: usize = 2;
+        const NUM_ROWS: usize = 10;
+        let sub_dim = DIM / NUM_SUB_VECTORS;
+
+        for num_bits in [4, 6, 8] {
+            let num_centroids = num_centroids(num_bits as u32);
+            let params = PQBuildParams::new(NUM_SUB_VECTORS, num_bits);
+            let centroids = generate_random_array_with_range(num_centroids * DIM, -1.0..1.0);
+            let assignments = UInt32Array::from_iter_values(
+                (0..NUM_ROWS * NUM_SUB_VECTORS).map(|v| (v * 7 % num_centroids) as u32),
+            );
+            let (pq, codes) = params
+                .build_from_assignments(&centroids, &assignments, DIM, MetricType::L2)
+                .unwrap();
+            assert_eq!(codes.len(), NUM_ROWS);
+            assert!(pq.training_info().is_none());
+
+            let code_values = codes.values().as_primitive::<UInt8Type>().values();
+            let ids = if is_bit_packed(num_bits as u32) {
+                unpack_codes(code_values, num_bits as u32, NUM_SUB_VECTORS)
+            } else {
+                code_values.to_vec()
+            };
+            assert_eq!(
+                ids,
+                assignments
+                    .values()
+                    .iter()
+                    .map(|id| *id as u8)
+                    .collect::<Vec<_>>()
+            );
+
+            // The vector made of the assigned centroids is at distance 0 from its code.
+            for row in 0..NUM_ROWS {
+                let vector = Float32Array::from_iter_values((0..NUM_SUB_VECTORS).flat_map(
+                    |sub_vector_idx| {
+                        let id = assignments.value(row * NUM_SUB_VECTORS + sub_vector_idx);
+                        let start = (sub_vector_idx * num_centroids + id as usize) * sub_dim;
+                        centroids.values()[start..start + sub_dim].to_vec()
+                    },
+                ));
+                let distances = pq
+                    .compute_distances(&vector, codes.values().as_primitive::<UInt8Type>())
+                    .unwrap();
+                assert!(distances.value(row) < 1e-6, "num_bits={}", num_bits);
+            }
+
+            let out_of_range = UInt32Array::from(vec![0, num_centroids as u32]);
+            assert!(params
+                .build_from_assignments(&centroids, &out_of_range, DIM, MetricType::L2)
+                .is_err());
+            let incomplete = UInt32Array::from(vec![0, 1, 2]);
+            assert!(params
+                .build_from_assignments(&centroids, &incomplete, DIM, MetricType::L2)
+                .is_err());
+        }
     }
 }
@@ -14,10 +14,16 @@
 
 use std::cmp::min;
 
+use half::f16;
 use lance_arrow::FloatToArrayType;
-use lance_linalg::distance::{l2_distance_batch, L2};
+use lance_core::{Error, Result};
+use lance_linalg::distance::{dot_distance_batch, l2_distance_batch, Dot, MetricType, L2};
+use snafu::{location, Location};
 
-use super::{num_centroids, utils::get_sub_vector_centroids};
+use super::{
+    num_centroids,
+    utils::{code_length, decode_code, get_sub_vector_centroids},
+};
 
 /// Build a Distance Table from the query to each PQ centroid
 /// using L2 distance.
@@ -44,6 +50,150 @@ where
         .collect()
 }
 
+/// Pre-computed distances between the centroids of each sub-vector.
+///
+/// The table is `num_sub_vectors x num_centroids x num_centroids` f32, so the distance
+/// between two PQ codes is the sum of one table entry per sub-vector, without
+/// reconstructing either vector. It only depends on the codebook, so it can be built
+/// once and reused for many queries.
+#[derive(Debug, Clone)]
+pub struct SymmetricDistanceTable {
+    num_bits: u32,
+    num_sub_vectors: usize,
+    table: Vec<f32>,
+}
+
+impl SymmetricDistanceTable {
+    /// Build the table from a codebook of `num_sub_vectors` sub-vectors.
+    ///
+    /// The entries are squared L2 distances, or dot distances if `metric_type` is
+    /// [`MetricType::Dot`].
+    pub fn new<T: FloatToArrayType>(
+        codebook: &[T],
+        dimension: usize,
+        num_bits: u32,
+        num_sub_vectors: usize,
+        metric_type: MetricType,
+    ) -> Self
+    where
+        T::ArrowType: L2 + Dot,
+    {
+        let sub_vector_width = dimension / num_sub_vectors;
+        let table = (0..num_sub_vectors)
+            .flat_map(|sub_vector_idx| {
+                let centroids = get_sub_vector_centroids(
+                    codebook,
+                    dimension,
+                    num_bits,
+                    num_sub_vectors,
+                    sub_vector_idx,
+                );
+                centroids
+                    .chunks_exact(sub_vector_width)
+                    .flat_map(move |centroid| match metric_type {
+                        MetricType::Dot => {
+                            dot_distance_batch(centroid, centroids, sub_vector_width)
+                        }
+                        _ => l2_distance_batch(centroid, centroids, sub_vector_width),
+                    })
+            })
+            .collect();
+        Self {
+            num_bits,
+            num_sub_vectors,
+            table,
+        }
+    }
+
+    /// Distance between centroids `a` and `b` of one sub-vector.
+    #[inline]
+    pub fn distance(&self, sub_vector_idx: usize, a: usize, b: usize) -> f32 {
+        let num_centroids = num_centroids(self.num_bits);
+        self.table[(sub_vector_idx * num_centroids + a) * num_centroids + b]
+    }
+
+    /// The distance table of a query given as one centroid index per sub-vector.
+    ///
+    /// It is the `[num_sub_vectors, num_centroids]` table used by [`compute_l2_distance`],
+    /// i.e., the rows of this table selected by `query`. Returns an error if `query` does
+    /// not have one centroid index per sub-vector, or if any index is out of range.
+    pub fn distance_table(&self, query: &[u8]) -> Result<Vec<f32>> {
+        if query.len() != self.num_sub_vectors {
+            return Err(Error::Index {
+                message: format!(
+                    "Query PQ code has {} sub-vectors, expect {}",
+                    query.len(),
+                    self.num_sub_vectors
+                ),
+                location: location!(),
+            });
+        }
+        let query = query.iter().map(|c| *c as usize).collect::<Vec<_>>();
+        self.check_centroids(&query)?;
+        let num_centroids = num_centroids(self.num_bits);
+        Ok(query
+            .iter()
+            .enumerate()
+            .flat_map(|(sub_vector_idx, q)| {
+                let start = (sub_vector_idx * num_centroids + q) * num_centroids;
+                self.table[start..start + num_centroids].iter().copied()
+            })
+            .collect())
+    }
+
+    fn check_centroids(&self, query: &[usize]) -> Result<()> {
+        let num_centroids = num_centroids(self.num_bits);
+        if let Some((sub_vector_idx, c)) =
+            query.iter().enumerate().find(|(_, c)| **c >= num_centroids)
+        {
+            return Err(Error::Index {
+                message: format!(
+                    "Query PQ code {} of sub-vector {} is out of range, there are {} centroids",
+                    c, sub_vector_idx, num_centroids
+                ),
+                location: location!(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Distances from `query_code` to each PQ code in `codes`.
+    ///
+    /// The codes use the layout of [`super::ProductQuantizer::transform`]: one byte per
+    /// sub-vector for 4 and 8 bits, bit-packed for the other widths.
+    pub fn compute_distances(&self, query_code: &[u8], codes: &[u8]) -> Result<Vec<f32>> {
+        let code_length = code_length(self.num_bits, self.num_sub_vectors);
+        if query_code.len() != code_length || codes.len() % code_length != 0 {
+            return Err(Error::Index {
+                message: format!(
+                    "Symmetric PQ distance: expect {} bytes per PQ code, got a query of {} bytes and {} bytes of codes",
+                    code_length,
+                    query_code.len(),
+                    codes.len()
+                ),
+                location: location!(),
+            });
+        }
+        let query = (0..self.num_sub_vectors)
+            .map(|sub_vector_idx| decode_code(query_code, self.num_bits, sub_vector_idx))
+            .collect::<Vec<_>>();
+        self.check_centroids(&query)?;
+        Ok(codes
+            .chunks_exact(code_length)
+            .map(|code| {
+                query
+                    .iter()
+                    .enumerate()
+                    .map(|(sub_vector_idx, q)| {
+                        let c = decode_code(code, self.num_bits, sub_vector_idx);
+                        self.distance(sub_vector_idx, *q, c)
+                    })
+                    .sum::<f32>()
+            })
+            .collect())
+    }
+}
+
 /// Compute L2 distance from the query to all code.
 ///
 /// Type parameters
@@ -81,11 +231,17 @@ pub(super) fn compute_l2_distance<const C: usize, const V: usize>(
                 #[cfg(all(feature = "nightly", target_feature = "avx512f"))]
                 {
                     use std::arch::x86_64::*;
-                    if i + C <= num_sub_vectors {
-                        let mut offsets = [(i * num_centroids) as i32; C];
-                        for k in 0..C {
-                            offsets[k] += (k * num_centroids) as i32 + c[vec_start + k] as i32;
-                        }
+                    let codes = &c[vec_start + i..vec_start + min(i + C, num_sub_vectors)];
+                    // The gather loads exactly 16 lanes and does not check the bounds.
+                    let mut offsets = [0_i32; 16];
+                    let gather = codes.len() == 16
+                        && codes.iter().enumerate().all(|(k, code)| {
+                            let offset = (i + k) * num_centroids + *code as usize;
+                            offsets[k] = offset as i32;
+                            offset < distance_table.len()
+                        });
+                    if gather {
+                        // SAFETY: the 16 offsets are all in the distance table.
                         unsafe {
                             let simd_offsets = _mm512_loadu_epi32(offsets.as_ptr());
                             let v = _mm512_i32gather_ps(
@@ -96,11 +252,11 @@ pub(super) fn compute_l2_distance<const C: usize, const V: usize>(
                             *sum += _mm512_reduce_add_ps(v);
                         }
                     } else {
-                        let mut s = 0.0;
-                        for k in 0..num_sub_vectors - i {
-                            *sum +=
-                                distance_table[(i + k) * num_centroids + c[vec_start + k] as usize];
-                        }
+                        *sum += codes
+                            .iter()
+                            .enumerate()
+                            .map(|(k, c)| distance_table[(i + k) * num_centroids + *c as usize])
+                            .sum::<f32>();
                     }
                 }
                 #[cfg(not(all(feature = "nightly", target_feature = "avx512f")))]
@@ -126,3 +282,253 @@ pub(super) fn compute_l2_distance<const C: usize, const V: usize>(
     });
     distances.chain(remainder).collect()
 }
+
+/// Compute L2 distance from the query to all code with AVX512, 16 codes at a time.
+///
+/// Each of the 16 f32 lanes accumulates the distance of one code: for every sub-vector,
+/// the distance table entries of 16 codes are gathered at once and added to the lanes,
+/// so no horizontal reduction is needed.  The entries are summed in the same order as
+/// the scalar kernels.
+///
+/// Returns `None` if the CPU does not support AVX512F, or if the distance table is too
+/// small or a code is out of its range, in which case the caller falls back to the scalar
+/// kernels, which check the bounds.
+#[cfg(all(feature = "nightly", target_arch = "x86_64"))]
+pub(super) fn compute_l2_distance_avx512(
+    distance_table: &[f32],
+    num_bits: u32,
+    num_sub_vectors: usize,
+    code: &[u8],
+) -> Option<Vec<f32>> {
+    if !is_x86_feature_detected!("avx512f") {
+        return None;
+    }
+    // The gather does not check the bounds, so every offset must be in the table.
+    let num_centroids = num_centroids(num_bits);
+    if num_sub_vectors == 0
+        || distance_table.len() < num_sub_vectors * num_centroids
+        || (num_centroids < 256 && code.iter().any(|c| *c as usize >= num_centroids))
+    {
+        return None;
+    }
+    // SAFETY: the CPU supports AVX512F, and all the codes are in the distance table.
+    Some(unsafe {
+        compute_l2_distance_avx512_impl(distance_table, num_bits, num_sub_vectors, code)
+    })
+}
+
+#[cfg(all(feature = "nightly", target_arch = "x86_64"))]
+#[target_feature(enable = "avx512f")]
+unsafe fn compute_l2_distance_avx512_impl(
+    distance_table: &[f32],
+    num_bits: u32,
+    num_sub_vectors: usize,
+    code: &[u8],
+) -> Vec<f32> {
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 16;
+    let num_centroids = num_centroids(num_bits);
+    let mut distances = Vec::with_capacity(code.len() / num_sub_vectors);
+    let blocks = code.chunks_exact(num_sub_vectors * LANES);
+    let remainder = blocks.remainder();
+    let mut offsets = [0_i32; LANES];
+    let mut sums = [0.0_f32; LANES];
+    for block in blocks {
+        let mut acc = _mm512_setzero_ps();
+        for i in 0..num_sub_vectors {
+            for (lane, offset) in offsets.iter_mut().enumerate() {
+                *offset = (i * num_centroids) as i32 + block[lane * num_sub_vectors + i] as i32;
+            }
+            let simd_offsets = _mm512_loadu_epi32(offsets.as_ptr());
+            let entries =
+                _mm512_i32gather_ps(simd_offsets, distance_table.as_ptr() as *const u8, 4);
+            acc = _mm512_add_ps(acc, entries);
+        }
+        _mm512_storeu_ps(sums.as_mut_ptr(), acc);
+        distances.extend_from_slice(&sums);
+    }
+    distances.extend(remainder.chunks_exact(num_sub_vectors).map(|c| {
+        c.iter()
+            .enumerate()
+            .map(|(sub_vec_idx, code)| distance_table[sub_vec_idx * num_centroids + *code as usize])
+            .sum::<f32>()
+    }));
+    distances
+}
+
+/// Compute L2 distance from the query to all code, with a half-precision distance table.
+///
+/// The f16 table takes half the memory of the f32 one, which keeps more of it in cache
+/// for large `num_sub_vectors`. Each entry is upcast to f32 when read, and the sums are
+/// accumulated in f32, so the relative error of each distance is bounded by the f16
+/// rounding error of the table entries (`2^-11`). Entries over `65504` saturate to infinity.
+pub(super) fn compute_l2_distance_f16(
+    distance_table: &[f16],
+    num_bits: u32,
+    num_sub_vectors: usize,
+    code: &[u8],
+) -> Vec<f32> {
+    let num_centroids = num_centroids(num_bits);
+    code.chunks_exact(num_sub_vectors)
+        .map(|c| {
+            c.iter()
+                .enumerate()
+                .map(|(sub_vec_idx, code)| {
+                    distance_table[sub_vec_idx * num_centroids + *code as usize].to_f32()
+                })
+                .sum::<f32>()
+        })
+        .collect()
+}
+
+/// Sum the distance table entries of each PQ code, accumulating in f64.
+///
+/// The summation error of f32 accumulation grows with the number of sub-vectors, this
+/// keeps the f32 distance table but accumulates in f64, and only rounds the final sum
+/// to f32. It works for any distance table, L2 or Dot.
+pub(super) fn compute_distance_f64_accumulated(
+    distance_table: &[f32],
+    num_bits: u32,
+    num_sub_vectors: usize,
+    code: &[u8],
+) -> Vec<f32> {
+    let num_centroids = num_centroids(num_bits);
+    code.chunks_exact(num_sub_vectors)
+        .map(|c| {
+            c.iter()
+                .enumerate()
+                .map(|(sub_vec_idx, code)| {
+                    distance_table[sub_vec_idx * num_centroids + *code as usize] as f64
+                })
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
+
+/// Compute L2 distance from the query to all code, for a small number of sub-vectors.
+///
+/// With only a handful of sub-vectors per code, the tiled loop in [`compute_l2_distance`]
+/// spends more time on bookkeeping than on table lookups. Here `M`, the number of
+/// sub-vectors, is known at compile time so the lookups of one code are fully unrolled,
+/// and reduced into 4 independent accumulators that stay in registers.
+#[inline]
+fn compute_l2_distance_small<const M: usize>(
+    distance_table: &[f32],
+    num_bits: u32,
+    code: &[u8],
+) -> Vec<f32> {
+    let num_centroids = num_centroids(num_bits);
+    code.chunks_exact(M)
+        .map(|c| {
+            let mut sums = [0.0_f32; 4];
+            for k in 0..M {
+                sums[k % 4] += distance_table[k * num_centroids + c[k] as usize];
+            }
+            (sums[0] + sums[1]) + (sums[2] + sums[3])
+        })
+        .collect()
+}
+
+/// Use the specialized kernel for small `num_sub_vectors`, if there is one.
+///
+/// Returns `None` if `num_sub_vectors` does not have a specialized kernel.
+pub(super) fn compute_l2_distance_small_sub_vectors(
+    distance_table: &[f32],
+    num_bits: u32,
+    num_sub_vectors: usize,
+    code: &[u8],
+) -> Option<Vec<f32>> {
+    match num_sub_vectors {
+        4 => Some(compute_l2_distance_small::<4>(
+            distance_table,
+            num_bits,
+            code,
+        )),
+        8 => Some(compute_l2_distance_small::<8>(
+            distance_table,
+            num_bits,
+            code,
+        )),
+        16 => Some(compute_l2_distance_small::<16>(
+            distance_table,
+            num_bits,
+            code,
+        )),
+        _ => None,
+    }
+}
+
+/// Tile sizes, in number of sub-vectors, that the L2 distance kernel is compiled for.
+#[cfg(not(all(feature = "nightly", target_feature = "avx512f")))]
+pub const SUPPORTED_TILE_SIZES: &[usize] = &[4, 8, 16, 32];
+
+/// Tile sizes, in number of sub-vectors, that the L2 distance kernel is compiled for.
+///
+/// The AVX512 kernel gathers exactly 16 lanes at once.
+#[cfg(all(feature = "nightly", target_feature = "avx512f"))]
+pub const SUPPORTED_TILE_SIZES: &[usize] = &[16];
+
+/// Tile size, in number of sub-vectors, used when none is set on the quantizer.
+#[cfg(not(target_feature = "avx512f"))]
+pub const DEFAULT_TILE_SIZE: usize = 8;
+
+/// Tile size, in number of sub-vectors, used when none is set on the quantizer.
+#[cfg(target_feature = "avx512f")]
+pub const DEFAULT_TILE_SIZE: usize = 16;
+
+/// Pick a tile size for computing L2 distances from a PQ distance table.
+///
+/// A tile of `C` sub-vectors touches `C` rows of the distance table, each of
+/// `num_centroids` f32. This picks the largest supported tile whose rows fit
+/// into half of a typical 32KB L1 data cache, without exceeding `num_sub_vectors`.
+///
+/// It is not used by default, pass it to `ProductQuantizerImpl::with_tile_size`
+/// to opt in.
+pub fn suggest_tile_size(num_sub_vectors: usize, num_centroids: usize) -> usize {
+    const CACHE_BUDGET: usize = 16 * 1024;
+    SUPPORTED_TILE_SIZES
+        .iter()
+        .rev()
+        .copied()
+        .find(|&tile| {
+            tile <= num_sub_vectors
+                && tile * num_centroids * std::mem::size_of::<f32>() <= CACHE_BUDGET
+        })
+        .unwrap_or(SUPPORTED_TILE_SIZES[0])
+}
+
+/// Compute L2 distance from the query to all code, with a tile size chosen at runtime.
+///
+/// See [`compute_l2_distance`] for the parameters. `tile_size` must be one of
+/// [`SUPPORTED_TILE_SIZES`].
+pub(super) fn compute_l2_distance_with_tile(
+    tile_size: usize,
+    distance_table: &[f32],
+    num_bits: u32,
+    num_sub_vectors: usize,
+    code: &[u8],
+) -> Result<Vec<f32>> {
+    let distances = match tile_size {
+        #[cfg(all(feature = "nightly", target_feature = "avx512f"))]
+        16 => compute_l2_distance::<16, 64>(distance_table, num_bits, num_sub_vectors, code),
+        #[cfg(not(all(feature = "nightly", target_feature = "avx512f")))]
+        4 => compute_l2_distance::<4, 64>(distance_table, num_bits, num_sub_vectors, code),
+        #[cfg(not(all(feature = "nightly", target_feature = "avx512f")))]
+        8 => compute_l2_distance::<8, 64>(distance_table, num_bits, num_sub_vectors, code),
+        #[cfg(not(all(feature = "nightly", target_feature = "avx512f")))]
+        16 => compute_l2_distance::<16, 64>(distance_table, num_bits, num_sub_vectors, code),
+        #[cfg(not(all(feature = "nightly", target_feature = "avx512f")))]
+        32 => compute_l2_distance::<32, 64>(distance_table, num_bits, num_sub_vectors, code),
+        _ => {
+            return Err(Error::Index {
+                message: format!(
+                    "Unsupported PQ distance tile size: {}, supported: {:?}",
+                    tile_size, SUPPORTED_TILE_SIZES
+                ),
+                location: location!(),
+            })
+        }
+    };
+    Ok(distances)
+}
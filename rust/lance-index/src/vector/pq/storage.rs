@@ -37,7 +37,9 @@ use object_store::path::Path;
 use serde::{Deserialize, Serialize};
 use snafu::{location, Location};
 
-use super::{distance::build_distance_table_l2, num_centroids, ProductQuantizerImpl};
+use super::{
+    distance::build_distance_table_l2, num_centroids, PQTrainingInfo, ProductQuantizerImpl,
+};
 use crate::{
     pb,
     vector::{
@@ -52,12 +54,93 @@ use crate::{
 
 pub const PQ_METADTA_KEY: &str = "lance:pq";
 
+/// Transpose PQ codes from the row-major layout to the sub-vector major layout.
+///
+/// `codes` holds the codes of `num_rows` vectors, one byte per sub-vector, as returned by
+/// [`super::ProductQuantizer::transform`] with 4 or 8 bits. They are laid out row by row:
+///
+/// ```text
+/// [r0s0, r0s1, .., r0s(m-1), r1s0, r1s1, .., r(n-1)s(m-1)]
+/// ```
+///
+/// The transposed codes store the codes of each sub-vector together, i.e., the code of row
+/// `r` and sub-vector `s` is at `s * num_rows + r`:
+///
+/// ```text
+/// [r0s0, r1s0, .., r(n-1)s0, r0s1, r1s1, .., r(n-1)s(m-1)]
+/// ```
+///
+/// Scanning the codes of one sub-vector for all the rows is then sequential in memory,
+/// which is the layout used to compute the distances of a block of vectors. It is also the
+/// layout read by [`ProductQuantizerImpl::reconstruct_transposed`].
+///
+/// Returns an error if `codes` does not hold `num_rows * num_sub_vectors` values.
+pub fn transpose(
+    codes: &UInt8Array,
+    num_rows: usize,
+    num_sub_vectors: usize,
+) -> Result<UInt8Array> {
+    check_codes_len(codes, num_rows, num_sub_vectors)?;
+    if codes.is_empty() {
+        return Ok(codes.clone());
+    }
+    let mut transposed = vec![0_u8; codes.len()];
+    for (row_idx, code) in codes.values().chunks(num_sub_vectors).enumerate() {
+        for (sub_vector_idx, c) in code.iter().enumerate() {
+            transposed[sub_vector_idx * num_rows + row_idx] = *c;
+        }
+    }
+    Ok(UInt8Array::from(transposed))
+}
+
+/// Transpose PQ codes from the sub-vector major layout back to the row-major layout.
+///
+/// It is the inverse of [`transpose`].
+///
+/// Returns an error if `codes` does not hold `num_rows * num_sub_vectors` values.
+pub fn transpose_back(
+    codes: &UInt8Array,
+    num_rows: usize,
+    num_sub_vectors: usize,
+) -> Result<UInt8Array> {
+    check_codes_len(codes, num_rows, num_sub_vectors)?;
+    if codes.is_empty() {
+        return Ok(codes.clone());
+    }
+    let mut codes_by_row = vec![0_u8; codes.len()];
+    for (sub_vector_idx, column) in codes.values().chunks(num_rows).enumerate() {
+        for (row_idx, c) in column.iter().enumerate() {
+            codes_by_row[row_idx * num_sub_vectors + sub_vector_idx] = *c;
+        }
+    }
+    Ok(UInt8Array::from(codes_by_row))
+}
+
+fn check_codes_len(codes: &UInt8Array, num_rows: usize, num_sub_vectors: usize) -> Result<()> {
+    if codes.len() != num_rows * num_sub_vectors {
+        return Err(Error::Index {
+            message: format!(
+                "PQ codes of {} rows and {} sub-vectors, got {} values",
+                num_rows,
+                num_sub_vectors,
+                codes.len()
+            ),
+            location: location!(),
+        });
+    }
+    Ok(())
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ProductQuantizationMetadata {
     pub codebook_position: usize,
     pub num_bits: u32,
     pub num_sub_vectors: usize,
     pub dimension: usize,
+
+    /// Parameters used to train the codebook, absent in older indices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub training_info: Option<PQTrainingInfo>,
 }
 
 impl ProductQuantizationMetadata {
@@ -202,6 +285,7 @@ pub struct ProductQuantizationStorage {
     num_sub_vectors: usize,
     dimension: usize,
     metric_type: MetricType,
+    training_info: Option<PQTrainingInfo>,
 
     // For easy access
     pq_code: Arc<UInt8Array>,
@@ -215,6 +299,7 @@ impl PartialEq for ProductQuantizationStorage {
             && self.num_bits.eq(&other.num_bits)
             && self.num_sub_vectors.eq(&other.num_sub_vectors)
             && self.dimension.eq(&other.dimension)
+            && self.training_info.eq(&other.training_info)
             // Ignore the schema because they might have different metadata.
             && self.batch.columns().eq(other.batch.columns())
     }
@@ -280,9 +365,21 @@ impl ProductQuantizationStorage {
             num_bits,
             dimension,
             metric_type,
+            training_info: None,
         })
     }
 
+    /// Attach the parameters used to train the codebook, they are written with the storage.
+    pub fn with_training_info(mut self, training_info: Option<PQTrainingInfo>) -> Self {
+        self.training_info = training_info;
+        self
+    }
+
+    /// The parameters used to train the codebook, if they are known.
+    pub fn training_info(&self) -> Option<&PQTrainingInfo> {
+        self.training_info.as_ref()
+    }
+
     /// Build a PQ storage from ProductQuantizer and a RecordBatch.
     ///
     /// Parameters
@@ -303,17 +400,19 @@ impl ProductQuantizationStorage {
         let dimension = quantizer.dimension;
         let num_sub_vectors = quantizer.num_sub_vectors;
         let metric_type = quantizer.metric_type;
+        let training_info = quantizer.training_info.clone();
         let transform = PQTransformer::new(quantizer, vector_col, PQ_CODE_COLUMN);
         let batch = transform.transform(batch).await?;
 
-        Self::new(
+        Ok(Self::new(
             codebook,
             batch,
             num_bits,
             num_sub_vectors,
             dimension,
             metric_type,
-        )
+        )?
+        .with_training_info(training_info))
     }
 
     /// Load a partition of PQ storage from disk.
@@ -331,14 +430,15 @@ impl ProductQuantizationStorage {
         let schema = reader.schema();
         let batch = reader.read_range(range, schema, None).await?;
 
-        Self::new(
+        Ok(Self::new(
             codebook,
             batch,
             metadata.num_bits,
             metadata.num_sub_vectors,
             metadata.dimension,
             metric_type,
-        )
+        )?
+        .with_training_info(metadata.training_info.clone()))
     }
 
     /// Load full PQ storage from disk.
@@ -439,6 +539,7 @@ impl ProductQuantizationStorage {
             num_bits: self.num_bits,
             num_sub_vectors: self.num_sub_vectors,
             dimension: self.dimension,
+            training_info: self.training_info.clone(),
         };
 
         let index_metadata = IndexMetadata {
@@ -553,13 +654,22 @@ mod tests {
         let codebook = Arc::new(Float32Array::from_iter_values(
             (0..256 * DIM).map(|v| v as f32),
         ));
-        let pq = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
-            NUM_SUB_VECTORS,
-            8,
-            DIM,
-            codebook,
-            MetricType::L2,
-        ));
+        let pq = Arc::new(
+            ProductQuantizerImpl::<Float32Type>::new(
+                NUM_SUB_VECTORS,
+                8,
+                DIM,
+                codebook,
+                MetricType::L2,
+            )
+            .unwrap()
+            .with_training_info(PQTrainingInfo {
+                sample_size: TOTAL,
+                seed: Some(42),
+                max_iters: 50,
+                inertia: 1.5,
+            }),
+        );
 
         let schema = ArrowSchema::new(vec![
             Field::new(
@@ -616,5 +726,37 @@ mod tests {
             .unwrap();
 
         assert_eq!(storage, storage2);
+        assert_eq!(storage2.training_info().unwrap().seed, Some(42));
+    }
+
+    #[test]
+    fn test_transpose_round_trip() {
+        // 8-bit codes use the whole byte, 4-bit codes only the lower half of it.
+        for (num_rows, num_sub_vectors, max_code) in [(TOTAL, NUM_SUB_VECTORS, 256), (37, 6, 16)] {
+            let codes = UInt8Array::from_iter_values(
+                (0..num_rows * num_sub_vectors).map(|v| (v * 7 % max_code) as u8),
+            );
+            let transposed = transpose(&codes, num_rows, num_sub_vectors).unwrap();
+            for row_idx in [0, 1, num_rows - 1] {
+                for sub_vector_idx in 0..num_sub_vectors {
+                    assert_eq!(
+                        transposed.value(sub_vector_idx * num_rows + row_idx),
+                        codes.value(row_idx * num_sub_vectors + sub_vector_idx)
+                    );
+                }
+            }
+            assert_eq!(
+                transpose_back(&transposed, num_rows, num_sub_vectors).unwrap(),
+                codes
+            );
+        }
+
+        let empty = UInt8Array::from(Vec::<u8>::new());
+        assert_eq!(transpose(&empty, 0, NUM_SUB_VECTORS).unwrap(), empty);
+        assert_eq!(transpose_back(&empty, 0, NUM_SUB_VECTORS).unwrap(), empty);
+
+        let codes = UInt8Array::from_iter_values(0..10);
+        assert!(transpose(&codes, 3, 4).is_err());
+        assert!(transpose_back(&codes, 3, 4).is_err());
     }
 }
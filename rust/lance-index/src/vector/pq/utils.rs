@@ -12,10 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::any::Any;
 use std::sync::Arc;
 
-use lance_arrow::{ArrowFloatType, FloatToArrayType};
+use arrow::buffer::{Buffer, NullBuffer, OffsetBuffer};
+use arrow_array::{
+    cast::AsArray, types::UInt8Type, Array, BinaryArray, FixedSizeBinaryArray, FixedSizeListArray,
+    UInt8Array,
+};
+use arrow_schema::{DataType, Field};
+use lance_arrow::bfloat16::BFloat16Array;
+use lance_arrow::{ArrowFloatType, FloatToArrayType, FloatType};
+use lance_core::{Error, Result};
 use lance_linalg::MatrixView;
+use snafu::{location, Location};
+
+/// Downcast a float array to the array type of `T`.
+///
+/// [`BFloat16Array`] wraps a `FixedSizeBinary(2)` array, and its `as_any()` returns the
+/// binary array, so it can not be downcast directly. It is rebuilt around the binary
+/// array instead, sharing its buffers.
+pub(super) fn downcast_float_array<T: ArrowFloatType>(array: &dyn Array) -> Option<T::ArrayType> {
+    if matches!(T::FLOAT_TYPE, FloatType::BFloat16) {
+        let values = array.as_any().downcast_ref::<FixedSizeBinaryArray>()?;
+        let values: Box<dyn Any> = Box::new(BFloat16Array::try_from(values.clone()).ok()?);
+        return values.downcast::<T::ArrayType>().ok().map(|values| *values);
+    }
+    array.as_any().downcast_ref::<T::ArrayType>().cloned()
+}
 
 /// Divide a 2D vector in [`T::Array`] to `m` sub-vectors.
 ///
@@ -53,6 +77,149 @@ pub fn num_centroids(num_bits: impl Into<u32>) -> usize {
     2_usize.pow(num_bits.into())
 }
 
+/// Multiply row-major vectors of `dimension` values by a `dimension x dimension` rotation.
+///
+/// It computes `x * R`, or `x * R^T` if `inverse` is true, which undoes the rotation
+/// when `R` is orthogonal.
+pub(super) fn apply_rotation<F: num_traits::Float>(
+    values: &[F],
+    rotation: &[F],
+    dimension: usize,
+    inverse: bool,
+) -> Vec<F> {
+    let mut output = vec![F::zero(); values.len()];
+    for (x, y) in values
+        .chunks_exact(dimension)
+        .zip(output.chunks_exact_mut(dimension))
+    {
+        for (k, row) in rotation.chunks_exact(dimension).enumerate() {
+            if inverse {
+                y[k] = row
+                    .iter()
+                    .zip(x.iter())
+                    .fold(F::zero(), |acc, (r, v)| acc + *r * *v);
+            } else {
+                y.iter_mut()
+                    .zip(row.iter())
+                    .for_each(|(y, r)| *y = *y + x[k] * *r);
+            }
+        }
+    }
+    output
+}
+
+/// Number of bytes of the PQ code of one vector, as returned by
+/// [`super::ProductQuantizer::transform`].
+///
+/// It is one byte per sub-vector, unless the codes are [bit-packed](is_bit_packed).
+pub(super) fn code_length(num_bits: u32, num_sub_vectors: usize) -> usize {
+    if is_bit_packed(num_bits) {
+        packed_code_length(num_bits, num_sub_vectors)
+    } else {
+        num_sub_vectors
+    }
+}
+
+/// Number of bytes of the PQ code of one vector packed by [`pack_codes`].
+fn packed_code_length(num_bits: u32, num_sub_vectors: usize) -> usize {
+    (num_sub_vectors * num_bits as usize + 7) / 8
+}
+
+/// Whether [`super::ProductQuantizer::transform`] packs the PQ codes into a bitstream.
+///
+/// 8-bit codes take one byte per sub-vector, and so do 4-bit codes for compatibility
+/// with the existing indices. Other widths, i.e., 6 bits, are bit-packed.
+pub(super) fn is_bit_packed(num_bits: u32) -> bool {
+    num_bits != 4 && num_bits != 8
+}
+
+/// The centroid index of one sub-vector in the PQ code of one vector.
+///
+/// The codes of a vector are packed into a contiguous little-endian bitstream of
+/// `num_bits` per sub-vector, i.e., with `num_bits = 6`, four codes are packed in three
+/// bytes, the lower 6 bits of the first byte being the code of the first sub-vector.
+///
+/// This is only the layout of the widths that are [`is_bit_packed`]. The 4 and 8 bit
+/// codes use one byte per sub-vector, see [`decode_code`].
+#[inline]
+pub(super) fn unpack_code(code: &[u8], num_bits: u32, sub_vector_idx: usize) -> usize {
+    let bit_offset = sub_vector_idx * num_bits as usize;
+    let (byte, shift) = (bit_offset / 8, bit_offset % 8);
+    let mut value = (code[byte] as u16) >> shift;
+    if shift + num_bits as usize > 8 {
+        value |= (code[byte + 1] as u16) << (8 - shift);
+    }
+    (value & ((1_u16 << num_bits) - 1)) as usize
+}
+
+/// The centroid index of one sub-vector in the PQ code of one vector, in the layout of
+/// [`super::ProductQuantizer::transform`].
+#[inline]
+pub(super) fn decode_code(code: &[u8], num_bits: u32, sub_vector_idx: usize) -> usize {
+    if is_bit_packed(num_bits) {
+        unpack_code(code, num_bits, sub_vector_idx)
+    } else {
+        code[sub_vector_idx] as usize
+    }
+}
+
+/// Pack one-byte-per-sub-vector PQ codes into `ceil(num_sub_vectors * num_bits / 8)`
+/// bytes per vector.
+///
+/// Each vector starts on a byte boundary, its sub-vector codes are laid out as in
+/// [`unpack_code`]. The unused high bits of the last byte are zero.
+pub(super) fn pack_codes(codes: &[u8], num_bits: u32, num_sub_vectors: usize) -> Vec<u8> {
+    let code_length = packed_code_length(num_bits, num_sub_vectors);
+    let mut packed = vec![0_u8; codes.len() / num_sub_vectors * code_length];
+    for (code, output) in codes
+        .chunks_exact(num_sub_vectors)
+        .zip(packed.chunks_exact_mut(code_length))
+    {
+        for (sub_vector_idx, c) in code.iter().enumerate() {
+            let bit_offset = sub_vector_idx * num_bits as usize;
+            let (byte, shift) = (bit_offset / 8, bit_offset % 8);
+            let value = (*c as u16) << shift;
+            output[byte] |= value as u8;
+            if shift + num_bits as usize > 8 {
+                output[byte + 1] |= (value >> 8) as u8;
+            }
+        }
+    }
+    packed
+}
+
+/// The reverse of [`pack_codes`], one byte per sub-vector.
+pub(super) fn unpack_codes(packed: &[u8], num_bits: u32, num_sub_vectors: usize) -> Vec<u8> {
+    packed
+        .chunks_exact(packed_code_length(num_bits, num_sub_vectors))
+        .flat_map(|code| {
+            (0..num_sub_vectors)
+                .map(move |sub_vector_idx| unpack_code(code, num_bits, sub_vector_idx) as u8)
+        })
+        .collect()
+}
+
+/// Wrap one-byte-per-sub-vector PQ codes into the array of codes returned by
+/// [`super::ProductQuantizer::transform`], which are packed if [`is_bit_packed`].
+pub(super) fn codes_to_fsl(
+    codes: UInt8Array,
+    num_bits: u32,
+    num_sub_vectors: usize,
+    nulls: Option<NullBuffer>,
+) -> Result<FixedSizeListArray> {
+    let values = if is_bit_packed(num_bits) {
+        UInt8Array::from(pack_codes(codes.values(), num_bits, num_sub_vectors))
+    } else {
+        codes
+    };
+    Ok(FixedSizeListArray::try_new(
+        Arc::new(Field::new("item", DataType::UInt8, true)),
+        code_length(num_bits, num_sub_vectors) as i32,
+        Arc::new(values),
+        nulls,
+    )?)
+}
+
 pub fn get_sub_vector_centroids<T: FloatToArrayType>(
     codebook: &[T],
     dimension: usize,
@@ -73,10 +240,87 @@ pub fn get_sub_vector_centroids<T: FloatToArrayType>(
         ..(sub_vector_idx + 1) * num_centroids * sub_vector_width]
 }
 
+/// Convert PQ codes in the layout of [`super::ProductQuantizer::transform`] into a
+/// [`BinaryArray`], where each value holds the bit-packed PQ code of one vector.
+///
+/// Every width is packed at `num_bits` per sub-vector as described in [`unpack_code`],
+/// so two 4-bit codes share one byte. The other widths are already in this layout, their
+/// code bytes are shared with the input and only the offsets are allocated.
+pub fn pq_codes_to_binary(codes: &FixedSizeListArray, num_bits: u32) -> Result<BinaryArray> {
+    let values = codes
+        .values()
+        .as_primitive_opt::<UInt8Type>()
+        .ok_or(Error::Index {
+            message: format!(
+                "PQ codes must be FixedSizeList<UInt8>, got: {}",
+                codes.data_type()
+            ),
+            location: location!(),
+        })?;
+    let code_len = codes.value_length() as usize;
+    // 4-bit codes are stored one byte per sub-vector by `transform`.
+    let (buffer, code_len) = if num_bits == 4 && code_len > 0 {
+        (
+            Buffer::from_vec(pack_codes(values.values(), num_bits, code_len)),
+            packed_code_length(num_bits, code_len),
+        )
+    } else {
+        (values.values().inner().clone(), code_len)
+    };
+    let offsets = OffsetBuffer::from_lengths(std::iter::repeat(code_len).take(codes.len()));
+    Ok(BinaryArray::try_new(
+        offsets,
+        buffer,
+        codes.nulls().cloned(),
+    )?)
+}
+
+/// Convert PQ codes produced by [`pq_codes_to_binary`] back to the
+/// `FixedSizeList<UInt8>` layout of [`super::ProductQuantizer::transform`].
+///
+/// Every non-null value must hold exactly the packed code of `num_sub_vectors` codes.
+pub fn pq_codes_from_binary(
+    codes: &BinaryArray,
+    num_bits: u32,
+    num_sub_vectors: usize,
+) -> Result<FixedSizeListArray> {
+    let code_len = packed_code_length(num_bits, num_sub_vectors);
+    let mut values = Vec::with_capacity(codes.len() * code_len);
+    for (idx, code) in codes.iter().enumerate() {
+        match code {
+            Some(code) if code.len() == code_len => values.extend_from_slice(code),
+            Some(code) => {
+                return Err(Error::Index {
+                    message: format!(
+                        "PQ code at {} has {} bytes, expected {}",
+                        idx,
+                        code.len(),
+                        code_len
+                    ),
+                    location: location!(),
+                })
+            }
+            None => values.extend(std::iter::repeat(0).take(code_len)),
+        }
+    }
+    let values = if num_bits == 4 {
+        unpack_codes(&values, num_bits, num_sub_vectors)
+    } else {
+        values
+    };
+    Ok(FixedSizeListArray::try_new(
+        Arc::new(Field::new("item", DataType::UInt8, true)),
+        code_length(num_bits, num_sub_vectors) as i32,
+        Arc::new(UInt8Array::from(values)),
+        codes.nulls().cloned(),
+    )?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use arrow_array::{types::Float32Type, Float32Array};
+    use lance_arrow::FixedSizeListArrayExt;
 
     #[test]
     fn test_divide_to_subvectors() {
@@ -94,4 +338,94 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_pq_codes_binary_round_trip() {
+        let codes = FixedSizeListArray::try_new_from_values(
+            UInt8Array::from_iter_values((0..40).map(|v| v as u8)),
+            4,
+        )
+        .unwrap();
+        let binary = pq_codes_to_binary(&codes, 8).unwrap();
+        assert_eq!(binary.len(), 10);
+        assert_eq!(binary.value(2), &[8, 9, 10, 11]);
+        assert_eq!(pq_codes_from_binary(&binary, 8, 4).unwrap(), codes);
+
+        // Sliced codes.
+        let sliced = codes.slice(3, 5);
+        let binary = pq_codes_to_binary(&sliced, 8).unwrap();
+        assert_eq!(binary.value(0), &[12, 13, 14, 15]);
+        assert_eq!(pq_codes_from_binary(&binary, 8, 4).unwrap(), sliced);
+
+        let binary = BinaryArray::from_iter_values([vec![1_u8, 2], vec![3]]);
+        assert!(pq_codes_from_binary(&binary, 8, 2).is_err());
+    }
+
+    #[test]
+    fn test_pq_codes_binary_4bit() {
+        // 4-bit codes are packed two per byte, the first code in the lower half.
+        let codes = FixedSizeListArray::try_new_from_values(
+            UInt8Array::from_iter_values((0..40).map(|v| (v % 16) as u8)),
+            4,
+        )
+        .unwrap();
+        let binary = pq_codes_to_binary(&codes, 4).unwrap();
+        assert_eq!(binary.len(), 10);
+        assert_eq!(binary.value(2), &[0x98, 0xBA]);
+        assert_eq!(pq_codes_from_binary(&binary, 4, 4).unwrap(), codes);
+        assert!(pq_codes_from_binary(&binary, 4, 8).is_err());
+
+        // An odd number of sub-vectors leaves the upper half of the last byte empty.
+        let codes = FixedSizeListArray::try_new_from_values(
+            UInt8Array::from_iter_values((0..25).map(|v| (v % 16) as u8)),
+            5,
+        )
+        .unwrap();
+        let binary = pq_codes_to_binary(&codes, 4).unwrap();
+        assert_eq!(binary.value(0), &[0x10, 0x32, 0x04]);
+        assert_eq!(pq_codes_from_binary(&binary, 4, 5).unwrap(), codes);
+
+        // 6-bit codes are already packed by `transform`.
+        let packed = codes_to_fsl(
+            UInt8Array::from_iter_values((0..20).map(|v| v as u8)),
+            6,
+            4,
+            None,
+        )
+        .unwrap();
+        let binary = pq_codes_to_binary(&packed, 6).unwrap();
+        assert_eq!(binary.value(0).len(), 3);
+        assert_eq!(pq_codes_from_binary(&binary, 6, 4).unwrap(), packed);
+    }
+
+    #[test]
+    fn test_pack_codes_round_trip() {
+        for num_bits in 1..=8 {
+            // 5 sub-vectors do not align to byte boundaries for most widths.
+            for num_sub_vectors in [1, 4, 5, 7, 16] {
+                let codes = (0..num_sub_vectors * 10)
+                    .map(|v| (v * 37 % (1 << num_bits)) as u8)
+                    .collect::<Vec<_>>();
+                let packed = pack_codes(&codes, num_bits, num_sub_vectors);
+                assert_eq!(
+                    packed.len(),
+                    10 * packed_code_length(num_bits, num_sub_vectors),
+                    "num_bits={}, num_sub_vectors={}",
+                    num_bits,
+                    num_sub_vectors
+                );
+                assert_eq!(unpack_codes(&packed, num_bits, num_sub_vectors), codes);
+            }
+        }
+
+        // Four 6-bit codes fit in three bytes.
+        let packed = pack_codes(&[1, 2, 3, 63], 6, 4);
+        assert_eq!(packed, vec![0b1000_0001, 0b0011_0000, 0b1111_1100]);
+        // 4-bit codes keep the nibble layout when packed, but transform does not pack them.
+        assert_eq!(pack_codes(&[1, 2], 4, 2), vec![0x21]);
+        assert_eq!(code_length(4, 2), 2);
+        assert_eq!(code_length(6, 4), 3);
+        assert_eq!(decode_code(&[1, 2], 4, 1), 2);
+        assert_eq!(decode_code(&packed, 6, 3), 63);
+    }
 }
@@ -12,20 +12,319 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use arrow_array::types::UInt32Type;
-use arrow_array::{cast::AsArray, Array, FixedSizeListArray, RecordBatch};
-use arrow_schema::Field;
+use arrow_array::types::{Float32Type, UInt32Type};
+use arrow_array::{
+    cast::AsArray, Array, ArrowPrimitiveType, FixedSizeListArray, Float32Array, RecordBatch,
+    UInt32Array,
+};
+use arrow_schema::{DataType, Field};
 use async_trait::async_trait;
 use lance_arrow::{ArrowFloatType, FixedSizeListArrayExt, FloatArray, RecordBatchExt};
 use lance_core::{Error, Result};
+use lance_linalg::distance::{Dot, MetricType, L2};
 use lance_linalg::MatrixView;
+use num_traits::AsPrimitive;
+use rand::distributions::{Distribution, Standard};
 use snafu::{location, Location};
 use std::sync::Arc;
 
 use super::transform::Transformer;
+use crate::pb;
 
 pub const RESIDUAL_COLUMN: &str = "__residual_vector";
 
+/// Per-partition aggregates collected while computing residual vectors.
+///
+/// Useful to detect imbalanced IVF partitions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PartitionResidualStats {
+    /// Number of vectors assigned to this partition.
+    pub num_vectors: usize,
+
+    /// Mean L2 norm of the residual vectors in this partition.
+    ///
+    /// It is `0.0` if the partition is empty.
+    pub mean_residual_norm: f64,
+}
+
+/// Compute `vector - centroid` for each vector, and optionally accumulate
+/// per-partition statistics in the same pass.
+fn residuals_impl<T: ArrowFloatType>(
+    centroids: &MatrixView<T>,
+    data: &[T::Native],
+    dim: usize,
+    part_ids: &[u32],
+    mut stats: Option<&mut [PartitionResidualStats]>,
+) -> Result<Vec<T::Native>> {
+    let mut residual_arr: Vec<T::Native> = Vec::with_capacity(data.len());
+    for (vector, &part_id) in data.chunks_exact(dim).zip(part_ids.iter()) {
+        let centroid = centroids
+            .row(part_id as usize)
+            .ok_or_else(|| Error::Index {
+                message: format!(
+                    "Compute residual vector: partition id {} out of range, num partitions: {}",
+                    part_id,
+                    centroids.num_rows()
+                ),
+                location: location!(),
+            })?;
+        let start = residual_arr.len();
+        // TODO: SIMD
+        residual_arr.extend(
+            vector
+                .iter()
+                .zip(centroid.iter())
+                .map(|(v, cent)| *v - *cent),
+        );
+        if let Some(stats) = stats.as_deref_mut() {
+            let norm = residual_arr[start..]
+                .iter()
+                .map(|v| {
+                    let v: f64 = AsPrimitive::<f32>::as_(*v) as f64;
+                    v * v
+                })
+                .sum::<f64>()
+                .sqrt();
+            let s = &mut stats[part_id as usize];
+            s.num_vectors += 1;
+            // Accumulate the sum of norms here, averaged at the end.
+            s.mean_residual_norm += norm;
+        }
+    }
+    if let Some(stats) = stats {
+        stats
+            .iter_mut()
+            .filter(|s| s.num_vectors > 0)
+            .for_each(|s| s.mean_residual_norm /= s.num_vectors as f64);
+    }
+    Ok(residual_arr)
+}
+
+fn flatten_vectors<T: ArrowFloatType>(vectors: &FixedSizeListArray) -> Result<&T::ArrayType> {
+    vectors
+        .values()
+        .as_any()
+        .downcast_ref::<T::ArrayType>()
+        .ok_or(Error::Index {
+            message: format!(
+                "Compute residual vector: expect vector type {}, got {}",
+                T::FLOAT_TYPE,
+                vectors.value_type(),
+            ),
+            location: location!(),
+        })
+}
+
+async fn resolve_partitions<T: ArrowFloatType + Dot + L2>(
+    centroids: &MatrixView<T>,
+    vectors: &FixedSizeListArray,
+    partitions: Option<&UInt32Array>,
+    assignment_metric_type: MetricType,
+) -> Result<Vec<u32>> {
+    if let Some(partitions) = partitions {
+        if partitions.len() != vectors.len() {
+            return Err(Error::Index {
+                message: format!(
+                    "Compute residual vector: number of partition ids {} does not match number of vectors {}",
+                    partitions.len(),
+                    vectors.len()
+                ),
+                location: location!(),
+            });
+        }
+        if partitions.null_count() > 0 {
+            return Err(Error::Index {
+                message: "Compute residual vector: partition ids must not be null".to_string(),
+                location: location!(),
+            });
+        }
+        return Ok(partitions.values().to_vec());
+    }
+
+    let data = Arc::new(flatten_vectors::<T>(vectors)?.clone());
+    lance_linalg::kmeans::compute_partitions::<T>(
+        centroids.data(),
+        data,
+        vectors.value_length() as usize,
+        assignment_metric_type,
+    )
+    .await
+    .into_iter()
+    .map(|p| {
+        p.ok_or_else(|| Error::Index {
+            message: "Compute residual vector: can not assign partition to an invalid vector"
+                .to_string(),
+            location: location!(),
+        })
+    })
+    .collect()
+}
+
+/// Compute the residual vectors of `vectors` to their partition centroids.
+///
+/// If `partitions` is `None`, the partition of each vector is computed
+/// against `centroids` using `assignment_metric_type`.
+///
+/// The metric type is only used to assign partitions. The residual is always the plain
+/// difference `vector - centroid`, whatever the metric type, so a caller can assign
+/// partitions with one metric type and use the residuals with another.
+pub async fn compute_residual<T: ArrowFloatType + Dot + L2>(
+    centroids: &MatrixView<T>,
+    vectors: &FixedSizeListArray,
+    partitions: Option<&UInt32Array>,
+    assignment_metric_type: MetricType,
+) -> Result<FixedSizeListArray> {
+    let part_ids =
+        resolve_partitions(centroids, vectors, partitions, assignment_metric_type).await?;
+    let dim = vectors.value_length();
+    let data = flatten_vectors::<T>(vectors)?;
+    let residuals = residuals_impl(centroids, data.as_slice(), dim as usize, &part_ids, None)?;
+    Ok(FixedSizeListArray::try_new_from_values(
+        T::ArrayType::from(residuals),
+        dim,
+    )?)
+}
+
+/// Same as [`compute_residual`], but also returns [`PartitionResidualStats`]
+/// for every partition, collected in the same pass over the data.
+pub async fn compute_residual_with_stats<T: ArrowFloatType + Dot + L2>(
+    centroids: &MatrixView<T>,
+    vectors: &FixedSizeListArray,
+    partitions: Option<&UInt32Array>,
+    assignment_metric_type: MetricType,
+) -> Result<(FixedSizeListArray, Vec<PartitionResidualStats>)> {
+    let part_ids =
+        resolve_partitions(centroids, vectors, partitions, assignment_metric_type).await?;
+    let dim = vectors.value_length();
+    let data = flatten_vectors::<T>(vectors)?;
+    let mut stats = vec![PartitionResidualStats::default(); centroids.num_rows()];
+    let residuals = residuals_impl(
+        centroids,
+        data.as_slice(),
+        dim as usize,
+        &part_ids,
+        Some(&mut stats),
+    )?;
+    let residuals = FixedSizeListArray::try_new_from_values(T::ArrayType::from(residuals), dim)?;
+    Ok((residuals, stats))
+}
+
+/// Compute the residual vectors of `vectors` against several levels of centroids.
+///
+/// This is used by hierarchical IVF, where each vector is assigned to a coarse centroid,
+/// and then to a finer centroid of the coarse residual. The result is
+/// `vector - coarse_centroid - fine_centroid - ...`, subtracting one centroid per level,
+/// in order.
+///
+/// Each level is a `(centroids, partitions)` pair. If the partition ids of a level are
+/// `None`, they are computed with `metric_type` against the residuals of the previous levels.
+/// The centroids of every level must have the same dimension as the vectors.
+pub async fn compute_residual_multi_level<T: ArrowFloatType + Dot + L2>(
+    levels: &[(&MatrixView<T>, Option<&UInt32Array>)],
+    vectors: &FixedSizeListArray,
+    metric_type: MetricType,
+) -> Result<FixedSizeListArray> {
+    let dim = vectors.value_length();
+    for (level, (centroids, _)) in levels.iter().enumerate() {
+        if centroids.num_columns() != dim as usize {
+            return Err(Error::Index {
+                message: format!(
+                    "Compute residual vector: centroids of level {} have dimension {}, expect {}",
+                    level,
+                    centroids.num_columns(),
+                    dim
+                ),
+                location: location!(),
+            });
+        }
+    }
+
+    let mut residuals = vectors.clone();
+    for (centroids, partitions) in levels {
+        let part_ids = resolve_partitions(centroids, &residuals, *partitions, metric_type).await?;
+        let data = flatten_vectors::<T>(&residuals)?;
+        let next = residuals_impl(centroids, data.as_slice(), dim as usize, &part_ids, None)?;
+        residuals = FixedSizeListArray::try_new_from_values(T::ArrayType::from(next), dim)?;
+    }
+    Ok(residuals)
+}
+
+fn check_residual_type(data_type: &DataType, what: &str) -> Result<()> {
+    if data_type.is_floating() || data_type == &DataType::Int8 {
+        Ok(())
+    } else {
+        Err(Error::Index {
+            message: format!(
+                "Compute residual vector: {} must be float or int8, got {}",
+                what, data_type
+            ),
+            location: location!(),
+        })
+    }
+}
+
+fn upcast_to_f32(vectors: &FixedSizeListArray, what: &str) -> Result<FixedSizeListArray> {
+    check_residual_type(&vectors.value_type(), what)?;
+    let values = arrow::compute::cast(vectors.values(), &DataType::Float32)?;
+    Ok(FixedSizeListArray::try_new_from_values(
+        values.as_primitive::<Float32Type>().clone(),
+        vectors.value_length(),
+    )?)
+}
+
+/// Compute residual vectors when the centroids and the vectors have different element types.
+///
+/// Both are upcast to f32 for the subtraction, and the residuals are returned as
+/// `output_type`. Float16, Float32, Float64 and Int8 elements are supported, but
+/// `output_type` must be a float type: residuals are not bounded to the range of the
+/// input, so they can not be stored as Int8.
+pub async fn compute_residual_mixed(
+    centroids: &FixedSizeListArray,
+    vectors: &FixedSizeListArray,
+    partitions: Option<&UInt32Array>,
+    metric_type: MetricType,
+    output_type: &DataType,
+) -> Result<FixedSizeListArray> {
+    if centroids.value_length() != vectors.value_length() {
+        return Err(Error::Index {
+            message: format!(
+                "Compute residual vector: centroids dimension {} does not match vectors dimension {}",
+                centroids.value_length(),
+                vectors.value_length()
+            ),
+            location: location!(),
+        });
+    }
+    if !output_type.is_floating() {
+        return Err(Error::Index {
+            message: format!(
+                "Compute residual vector: output type must be float, got {}",
+                output_type
+            ),
+            location: location!(),
+        });
+    }
+
+    let dim = vectors.value_length();
+    let centroids = upcast_to_f32(centroids, "centroids")?;
+    let centroids = MatrixView::<Float32Type>::new(
+        Arc::new(centroids.values().as_primitive::<Float32Type>().clone()),
+        dim as usize,
+    );
+    let vectors = upcast_to_f32(vectors, "vectors")?;
+    let residuals = compute_residual(&centroids, &vectors, partitions, metric_type).await?;
+    if output_type == &DataType::Float32 {
+        return Ok(residuals);
+    }
+    let values = arrow::compute::cast(residuals.values(), output_type)?;
+    Ok(FixedSizeListArray::try_new(
+        Arc::new(Field::new("item", output_type.clone(), true)),
+        dim,
+        values,
+        None,
+    )?)
+}
+
 /// Compute the residual vector of a Vector Matrix to their centroids.
 ///
 /// The residual vector is the difference between the original vector and the centroid.
@@ -57,6 +356,139 @@ impl<T: ArrowFloatType> ResidualTransform<T> {
     }
 }
 
+impl<T> ResidualTransform<T>
+where
+    T: ArrowFloatType + ArrowPrimitiveType,
+    Standard: Distribution<T::Native>,
+{
+    /// Serialize the transform, with the centroids embedded in it
+    pub fn to_proto(&self) -> pb::Residual {
+        self.proto_with_centroids(pb::residual::Centroids::CentroidsTensor(pb::Tensor::from(
+            &self.centroids,
+        )))
+    }
+
+    /// Serialize the transform, referring to centroids that are stored elsewhere
+    ///
+    /// This avoids storing the centroids twice when they are also stored by another part of
+    /// the index, e.g., the IVF centroids.  The centroids are not serialized, and
+    /// `centroids_ref` must be resolvable by the loader passed to [`Self::from_proto`].
+    pub fn to_proto_with_centroids_ref(&self, centroids_ref: &str) -> pb::Residual {
+        self.proto_with_centroids(pb::residual::Centroids::CentroidsRef(
+            centroids_ref.to_string(),
+        ))
+    }
+
+    fn proto_with_centroids(&self, centroids: pb::residual::Centroids) -> pb::Residual {
+        pb::Residual {
+            partition_column: self.part_col.clone(),
+            vector_column: self.vec_col.clone(),
+            centroids: Some(centroids),
+        }
+    }
+
+    /// Load a transform serialized by [`Self::to_proto`] or [`Self::to_proto_with_centroids_ref`]
+    ///
+    /// `resolve_centroids` is called to load centroids that are referred to rather than
+    /// embedded, with the reference they were serialized with.
+    pub fn from_proto(
+        proto: &pb::Residual,
+        resolve_centroids: impl FnOnce(&str) -> Result<MatrixView<T>>,
+    ) -> Result<Self> {
+        let centroids = match &proto.centroids {
+            Some(pb::residual::Centroids::CentroidsTensor(tensor)) => {
+                MatrixView::try_from(&FixedSizeListArray::try_from(tensor)?)?
+            }
+            Some(pb::residual::Centroids::CentroidsRef(centroids_ref)) => {
+                resolve_centroids(centroids_ref)?
+            }
+            None => {
+                return Err(Error::Index {
+                    message: "Residual transform: no centroids in the serialized transform"
+                        .to_string(),
+                    location: location!(),
+                })
+            }
+        };
+        Ok(Self::new(
+            centroids,
+            &proto.partition_column,
+            &proto.vector_column,
+        ))
+    }
+}
+
+impl ResidualTransform<Float32Type> {
+    /// Replace the original vectors with residual vectors like [`Transformer::transform`],
+    /// writing the residuals over the original values when possible.
+    ///
+    /// The values are overwritten if the vector column is `FixedSizeList<Float32>` and
+    /// `batch` is the only owner of its buffer, which saves allocating a new column.
+    /// Otherwise, it falls back to [`Transformer::transform`].
+    pub async fn transform_in_place(&self, mut batch: RecordBatch) -> Result<RecordBatch> {
+        let vec_idx = match batch.schema().index_of(&self.vec_col) {
+            Ok(idx) => idx,
+            Err(_) => return self.transform(&batch).await,
+        };
+        let is_f32_vectors = matches!(
+            batch.column(vec_idx).data_type(),
+            DataType::FixedSizeList(field, _) if field.data_type() == &DataType::Float32
+        );
+        let part_ids = batch
+            .column_by_name(&self.part_col)
+            .and_then(|part_ids| part_ids.as_primitive_opt::<UInt32Type>())
+            .cloned();
+        let (true, Some(part_ids)) = (is_f32_vectors, part_ids) else {
+            return self.transform(&batch).await;
+        };
+
+        // Remove the column from the batch, so only `values` refers to the vector buffer.
+        let column = batch.remove_column(vec_idx);
+        let (_, dim, values, _) = column.as_fixed_size_list().clone().into_parts();
+        drop(column);
+        // Consume `values` so the builder can take over the buffer if nothing else holds it.
+        let values = Float32Array::from(values.into_data());
+        let residuals = match values.into_builder() {
+            Ok(mut builder) => {
+                let data = builder.values_slice_mut();
+                for (vector, &part_id) in data.chunks_exact_mut(dim as usize).zip(part_ids.values())
+                {
+                    let centroid = self.centroid(part_id)?;
+                    vector
+                        .iter_mut()
+                        .zip(centroid.iter())
+                        .for_each(|(v, cent)| *v -= *cent);
+                }
+                builder.finish()
+            }
+            // The buffer is shared, e.g., with the caller or another batch.
+            Err(values) => Float32Array::from(residuals_impl(
+                &self.centroids,
+                values.values(),
+                dim as usize,
+                part_ids.values(),
+                None,
+            )?),
+        };
+        let residual_arr = FixedSizeListArray::try_new_from_values(residuals, dim)?;
+        let residual_field = Field::new(RESIDUAL_COLUMN, residual_arr.data_type().clone(), false);
+        Ok(batch.try_with_column(residual_field, Arc::new(residual_arr))?)
+    }
+
+    fn centroid(&self, part_id: u32) -> Result<&[f32]> {
+        self.centroids
+            .row(part_id as usize)
+            .ok_or_else(|| Error::Index {
+                message: format!(
+                    "Compute residual vector: partition id {} out of range, num partitions: {}",
+                    part_id,
+                    self.centroids.num_rows()
+                ),
+                location: location!(),
+            })
+    }
+}
+
 #[async_trait]
 impl<T: ArrowFloatType> Transformer for ResidualTransform<T> {
     /// Replace the original vector in the [`RecordBatch`] to residual vectors.
@@ -102,21 +534,13 @@ impl<T: ArrowFloatType> Transformer for ResidualTransform<T> {
                 location: location!(),
             })?;
         let dim = original_vectors.value_length();
-        let mut residual_arr: Vec<T::Native> = Vec::with_capacity(flatten_data.len());
-        flatten_data
-            .as_slice()
-            .chunks_exact(dim as usize)
-            .zip(part_ids.as_primitive::<UInt32Type>().values().iter())
-            .for_each(|(vector, &part_id)| {
-                let centroid = self.centroids.row(part_id as usize).unwrap();
-                // TODO: SIMD
-                residual_arr.extend(
-                    vector
-                        .iter()
-                        .zip(centroid.iter())
-                        .map(|(v, cent)| *v - *cent),
-                );
-            });
+        let residual_arr = residuals_impl(
+            &self.centroids,
+            flatten_data.as_slice(),
+            dim as usize,
+            part_ids.as_primitive::<UInt32Type>().values(),
+            None,
+        )?;
         let residual_arr =
             FixedSizeListArray::try_new_from_values(T::ArrayType::from(residual_arr), dim)?;
 
@@ -129,3 +553,402 @@ impl<T: ArrowFloatType> Transformer for ResidualTransform<T> {
         Ok(batch)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow_array::{Float16Array, Float32Array, Float64Array, Int8Array, StringArray};
+    use half::f16;
+
+    #[tokio::test]
+    async fn test_residual_mixed_types() {
+        const DIM: usize = 4;
+        let centroids = FixedSizeListArray::try_new_from_values(
+            Float32Array::from_iter_values([0.0, 0.0, 0.0, 0.0, 10.0, 10.0, 10.0, 10.0]),
+            DIM as i32,
+        )
+        .unwrap();
+        let partitions = UInt32Array::from(vec![0, 1, 1]);
+        let values = [
+            1.0_f32, 2.0, 3.0, 4.0, 11.0, 12.0, 13.0, 14.0, 9.0, 8.0, 7.0, 6.0,
+        ];
+        let expected = [
+            1.0_f32, 2.0, 3.0, 4.0, 1.0, 2.0, 3.0, 4.0, -1.0, -2.0, -3.0, -4.0,
+        ];
+
+        let f32_vectors =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(values.to_vec()), 4)
+                .unwrap();
+        let f16_vectors = FixedSizeListArray::try_new_from_values(
+            Float16Array::from_iter_values(values.iter().map(|v| f16::from_f32(*v))),
+            DIM as i32,
+        )
+        .unwrap();
+        let f64_vectors = FixedSizeListArray::try_new_from_values(
+            Float64Array::from_iter_values(values.iter().map(|v| *v as f64)),
+            DIM as i32,
+        )
+        .unwrap();
+        let i8_vectors = FixedSizeListArray::try_new_from_values(
+            Int8Array::from_iter_values(values.iter().map(|v| *v as i8)),
+            DIM as i32,
+        )
+        .unwrap();
+        let f64_centroids = FixedSizeListArray::try_new_from_values(
+            Float64Array::from_iter_values(
+                centroids
+                    .values()
+                    .as_primitive::<Float32Type>()
+                    .values()
+                    .iter()
+                    .map(|v| *v as f64),
+            ),
+            DIM as i32,
+        )
+        .unwrap();
+
+        for (centroids, vectors, output_type) in [
+            (&centroids, &f16_vectors, DataType::Float16),
+            (&centroids, &f16_vectors, DataType::Float32),
+            (&centroids, &f64_vectors, DataType::Float64),
+            (&f64_centroids, &f32_vectors, DataType::Float32),
+            (&f64_centroids, &f16_vectors, DataType::Float64),
+            (&centroids, &i8_vectors, DataType::Float32),
+        ] {
+            let residuals = compute_residual_mixed(
+                centroids,
+                vectors,
+                Some(&partitions),
+                MetricType::L2,
+                &output_type,
+            )
+            .await
+            .unwrap();
+            assert_eq!(residuals.value_type(), output_type);
+            let actual = arrow::compute::cast(residuals.values(), &DataType::Float32).unwrap();
+            assert_eq!(
+                actual.as_primitive::<Float32Type>().values(),
+                &expected[..],
+                "{} vs {} -> {}",
+                centroids.value_type(),
+                vectors.value_type(),
+                output_type
+            );
+        }
+
+        let strings = FixedSizeListArray::try_new_from_values(
+            StringArray::from_iter_values(["a", "b", "c", "d"]),
+            DIM as i32,
+        )
+        .unwrap();
+        assert!(compute_residual_mixed(
+            &centroids,
+            &strings,
+            None,
+            MetricType::L2,
+            &DataType::Float32
+        )
+        .await
+        .is_err());
+        assert!(compute_residual_mixed(
+            &centroids,
+            &f32_vectors,
+            Some(&partitions),
+            MetricType::L2,
+            &DataType::Utf8
+        )
+        .await
+        .is_err());
+        // Int8 is only accepted as input, the residuals may not fit into it.
+        assert!(compute_residual_mixed(
+            &centroids,
+            &i8_vectors,
+            Some(&partitions),
+            MetricType::L2,
+            &DataType::Int8
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_residual_multi_level() {
+        const DIM: usize = 2;
+        let coarse = MatrixView::<Float32Type>::new(
+            Arc::new(Float32Array::from_iter_values([0.0, 0.0, 100.0, 100.0])),
+            DIM,
+        );
+        let fine = MatrixView::<Float32Type>::new(
+            Arc::new(Float32Array::from_iter_values([
+                1.0, 1.0, -1.0, -1.0, 5.0, 5.0,
+            ])),
+            DIM,
+        );
+        let vectors = FixedSizeListArray::try_new_from_values(
+            Float32Array::from_iter_values([1.5, 1.5, 99.0, 98.0, 105.0, 104.0]),
+            DIM as i32,
+        )
+        .unwrap();
+        let coarse_parts = UInt32Array::from(vec![0, 1, 1]);
+        let fine_parts = UInt32Array::from(vec![0, 1, 2]);
+
+        let residuals = compute_residual_multi_level(
+            &[(&coarse, Some(&coarse_parts)), (&fine, Some(&fine_parts))],
+            &vectors,
+            MetricType::L2,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            residuals.values().as_primitive::<Float32Type>().values(),
+            &[0.5, 0.5, 0.0, -1.0, 0.0, -1.0]
+        );
+
+        // Partitions are computed from the residuals of the previous level.
+        let computed = compute_residual_multi_level(
+            &[(&coarse, None), (&fine, None)],
+            &vectors,
+            MetricType::L2,
+        )
+        .await
+        .unwrap();
+        assert_eq!(computed, residuals);
+
+        // A single level is the same as compute_residual.
+        let single = compute_residual_multi_level(&[(&coarse, None)], &vectors, MetricType::L2)
+            .await
+            .unwrap();
+        let expected = compute_residual(&coarse, &vectors, None, MetricType::L2)
+            .await
+            .unwrap();
+        assert_eq!(single, expected);
+
+        let wrong_dim = MatrixView::<Float32Type>::new(
+            Arc::new(Float32Array::from_iter_values([0.0, 0.0, 0.0])),
+            3,
+        );
+        assert!(compute_residual_multi_level(
+            &[(&coarse, None), (&wrong_dim, None)],
+            &vectors,
+            MetricType::L2
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_residual_stats() {
+        const DIM: usize = 4;
+        let centroids =
+            Float32Array::from_iter_values([0.0, 0.0, 0.0, 0.0, 10.0, 10.0, 10.0, 10.0]);
+        let centroids = MatrixView::<Float32Type>::new(Arc::new(centroids), DIM);
+
+        let values = Float32Array::from_iter_values((0..100 * DIM).map(|v| (v % 13) as f32));
+        let vectors = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+
+        let (residuals, stats) =
+            compute_residual_with_stats(&centroids, &vectors, None, MetricType::L2)
+                .await
+                .unwrap();
+        assert_eq!(residuals.len(), vectors.len());
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats.iter().map(|s| s.num_vectors).sum::<usize>(),
+            vectors.len()
+        );
+        assert!(stats.iter().all(|s| s.num_vectors > 0));
+
+        let plain = compute_residual(&centroids, &vectors, None, MetricType::L2)
+            .await
+            .unwrap();
+        assert_eq!(plain, residuals);
+
+        // Provided partition ids.
+        let partitions = UInt32Array::from_iter_values((0..100).map(|i| (i % 2) as u32));
+        let (_, stats) =
+            compute_residual_with_stats(&centroids, &vectors, Some(&partitions), MetricType::L2)
+                .await
+                .unwrap();
+        assert_eq!(stats[0].num_vectors, 50);
+        assert_eq!(stats[1].num_vectors, 50);
+        assert!(stats[0].mean_residual_norm > 0.0);
+
+        let bad = UInt32Array::from_iter_values((0..100).map(|_| 5));
+        assert!(
+            compute_residual(&centroids, &vectors, Some(&bad), MetricType::L2)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ivf_compute_residual() {
+        const DIM: usize = 4;
+        let centroids =
+            Float32Array::from_iter_values([0.0, 0.0, 0.0, 0.0, 10.0, 10.0, 10.0, 10.0]);
+        let ivf =
+            crate::vector::ivf::new_ivf(&centroids, DIM, MetricType::L2, vec![], None).unwrap();
+        let centroids = MatrixView::<Float32Type>::new(Arc::new(centroids), DIM);
+
+        let values = Float32Array::from_iter_values((0..100 * DIM).map(|v| (v % 13) as f32));
+        let vectors = FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap();
+
+        let expected = compute_residual(&centroids, &vectors, None, MetricType::L2)
+            .await
+            .unwrap();
+        assert_eq!(
+            ivf.compute_residual(&vectors, None).await.unwrap(),
+            expected
+        );
+
+        let bad = UInt32Array::from_iter_values((0..100).map(|_| 5));
+        assert!(ivf.compute_residual(&vectors, Some(&bad)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_residual_assignment_metric_type() {
+        let centroids = MatrixView::<Float32Type>::new(
+            Arc::new(Float32Array::from_iter_values([1.0, 0.0, 10.0, 10.0])),
+            2,
+        );
+        // The closest centroid is the first one, while the second one has the largest
+        // dot product.
+        let vectors =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(vec![2.0_f32, 0.0]), 2)
+                .unwrap();
+
+        for (metric_type, expected) in [
+            (MetricType::L2, [1.0_f32, 0.0]),
+            (MetricType::Dot, [-8.0, -10.0]),
+        ] {
+            let residuals = compute_residual(&centroids, &vectors, None, metric_type)
+                .await
+                .unwrap();
+            assert_eq!(
+                residuals.values().as_primitive::<Float32Type>().values(),
+                &expected[..],
+                "{:?}",
+                metric_type
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_residual_transform_in_place() {
+        const DIM: usize = 4;
+        let centroids =
+            Float32Array::from_iter_values([0.0, 0.0, 0.0, 0.0, 10.0, 10.0, 10.0, 10.0]);
+        let transform = ResidualTransform::new(
+            MatrixView::<Float32Type>::new(Arc::new(centroids), DIM),
+            "part_id",
+            "vec",
+        );
+
+        let values = (0..100 * DIM).map(|v| (v % 13) as f32).collect::<Vec<_>>();
+        let vectors =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(values.clone()), DIM as i32)
+                .unwrap();
+        let part_ids = UInt32Array::from_iter_values((0..100).map(|i| (i % 2) as u32));
+        let batch = RecordBatch::try_from_iter(vec![
+            ("part_id", Arc::new(part_ids) as Arc<dyn Array>),
+            ("vec", Arc::new(vectors) as Arc<dyn Array>),
+        ])
+        .unwrap();
+
+        let expected = transform.transform(&batch).await.unwrap();
+
+        // The vector buffer is shared with `shared`, so the original must stay intact.
+        let shared = batch.clone();
+        let actual = transform.transform_in_place(shared).await.unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(
+            batch["vec"]
+                .as_fixed_size_list()
+                .values()
+                .as_primitive::<Float32Type>()
+                .values(),
+            &values[..]
+        );
+
+        // Sole owner of the buffer: the residuals are written over the original values.
+        let values_ptr = batch["vec"]
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<Float32Type>()
+            .values()
+            .as_ptr();
+        let actual = transform.transform_in_place(batch).await.unwrap();
+        assert_eq!(actual, expected);
+        let residual_ptr = actual[RESIDUAL_COLUMN]
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<Float32Type>()
+            .values()
+            .as_ptr();
+        assert_eq!(values_ptr, residual_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_residual_proto_round_trip() {
+        const DIM: usize = 4;
+        let centroids = MatrixView::<Float32Type>::new(
+            Arc::new(Float32Array::from_iter_values([
+                0.0, 0.0, 0.0, 0.0, 10.0, 10.0, 10.0, 10.0,
+            ])),
+            DIM,
+        );
+        let transform = ResidualTransform::new(centroids.clone(), "part_id", "vec");
+
+        let vectors = FixedSizeListArray::try_new_from_values(
+            Float32Array::from_iter_values((0..10 * DIM).map(|v| v as f32)),
+            DIM as i32,
+        )
+        .unwrap();
+        let batch = RecordBatch::try_from_iter(vec![
+            (
+                "part_id",
+                Arc::new(UInt32Array::from_iter_values((0..10).map(|i| i % 2))) as Arc<dyn Array>,
+            ),
+            ("vec", Arc::new(vectors) as Arc<dyn Array>),
+        ])
+        .unwrap();
+        let expected = transform.transform(&batch).await.unwrap();
+
+        let no_external = |centroids_ref: &str| -> Result<MatrixView<Float32Type>> {
+            panic!("Unexpected centroids reference {}", centroids_ref)
+        };
+        let embedded = transform.to_proto();
+        let loaded = ResidualTransform::from_proto(&embedded, no_external).unwrap();
+        assert_eq!(loaded.transform(&batch).await.unwrap(), expected);
+
+        // The centroids are resolved from the reference, and not stored in the transform
+        let external = transform.to_proto_with_centroids_ref("ivf");
+        assert_eq!(
+            external.centroids,
+            Some(pb::residual::Centroids::CentroidsRef("ivf".to_string()))
+        );
+        let loaded = ResidualTransform::from_proto(&external, |centroids_ref| {
+            assert_eq!(centroids_ref, "ivf");
+            Ok(centroids.clone())
+        })
+        .unwrap();
+        assert_eq!(loaded.transform(&batch).await.unwrap(), expected);
+
+        // Errors resolving the reference are returned
+        assert!(
+            ResidualTransform::<Float32Type>::from_proto(&external, |centroids_ref| {
+                Err(Error::Index {
+                    message: format!("unknown centroids {}", centroids_ref),
+                    location: location!(),
+                })
+            })
+            .is_err()
+        );
+        let no_centroids = pb::Residual {
+            centroids: None,
+            ..embedded
+        };
+        assert!(ResidualTransform::from_proto(&no_centroids, no_external).is_err());
+    }
+}
@@ -46,7 +46,8 @@ impl From<pb::tensor::DataType> for DataType {
             pb::tensor::DataType::Float16 => Self::Float16,
             pb::tensor::DataType::Float32 => Self::Float32,
             pb::tensor::DataType::Float64 => Self::Float64,
-            pb::tensor::DataType::Bfloat16 => unimplemented!(),
+            // bf16 arrays are stored as `FixedSizeBinary(2)`, see [lance_arrow::bfloat16].
+            pb::tensor::DataType::Bfloat16 => Self::FixedSizeBinary(2),
         }
     }
 }
@@ -63,6 +64,7 @@ impl TryFrom<&DataType> for pb::tensor::DataType {
             DataType::Float16 => Ok(Self::Float16),
             DataType::Float32 => Ok(Self::Float32),
             DataType::Float64 => Ok(Self::Float64),
+            DataType::FixedSizeBinary(2) => Ok(Self::Bfloat16),
             _ => Err(Error::Index {
                 message: format!("pb tensor type not supported: {:?}", dt),
                 location: location!(),
@@ -50,9 +50,14 @@ use crate::{
 use crate::{Error, Result};
 
 /// KMean initialization method.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum KMeanInit {
+    /// Pick `k` vectors uniformly at random.
+    #[default]
     Random,
+
+    /// kmeans++: pick each centroid with probability proportional to its
+    /// squared distance to the closest centroid chosen so far.
     KMeanPlusPlus,
 }
 
@@ -78,6 +83,10 @@ pub struct KMeansParams<T: ArrowFloatType> {
     /// Centroids to continuous training. If present, it will continuously train
     /// from the given centroids. If None, it will initialize centroids via init method.
     pub centroids: Option<Arc<T::ArrayType>>,
+
+    /// Seed of the random generator used to initialize the centroids.
+    /// If None, a random seed is used.
+    pub seed: Option<u64>,
 }
 
 impl<T: ArrowFloatType> Default for KMeansParams<T> {
@@ -89,6 +98,7 @@ impl<T: ArrowFloatType> Default for KMeansParams<T> {
             init: KMeanInit::Random,
             metric_type: MetricType::L2,
             centroids: None,
+            seed: None,
         }
     }
 }
@@ -150,6 +160,55 @@ where
     Ok(kmeans)
 }
 
+/// Initialize kmeans centroids with kmeans++.
+///
+/// The first centroid is chosen uniformly at random. Each following centroid is
+/// sampled with probability proportional to the squared L2 distance from a vector
+/// to its closest centroid chosen so far.
+async fn kmeans_plusplus_init<T: ArrowFloatType + Dot + L2 + Normalize>(
+    data: &T::ArrayType,
+    dimension: usize,
+    k: usize,
+    mut rng: impl Rng,
+    metric_type: MetricType,
+) -> Result<KMeans<T>>
+where
+    T::Native: AsPrimitive<f32>,
+{
+    use rand::distributions::WeightedIndex;
+
+    assert!(data.len() >= k * dimension);
+    let vectors = data.as_slice();
+    let num_rows = vectors.len() / dimension;
+
+    let mut builder: Vec<T::Native> = Vec::with_capacity(k * dimension);
+    let first = rng.gen_range(0..num_rows);
+    builder.extend_from_slice(&vectors[first * dimension..(first + 1) * dimension]);
+
+    let mut min_dists = vec![f32::MAX; num_rows];
+    for _ in 1..k {
+        let last = &builder[builder.len() - dimension..];
+        l2_distance_batch(last, vectors, dimension)
+            .zip(min_dists.iter_mut())
+            .for_each(|(d, min_dist)| {
+                if d < *min_dist {
+                    *min_dist = d;
+                }
+            });
+        // All vectors coincide with the chosen centroids (i.e., duplicated data),
+        // fall back to uniform sampling.
+        let next = match WeightedIndex::new(min_dists.iter()) {
+            Ok(dist) => dist.sample(&mut rng),
+            Err(_) => rng.gen_range(0..num_rows),
+        };
+        builder.extend_from_slice(&vectors[next * dimension..(next + 1) * dimension]);
+    }
+
+    let mut kmeans = KMeans::empty(k, dimension, metric_type);
+    kmeans.centroids = Arc::new(builder.into());
+    Ok(kmeans)
+}
+
 pub struct KMeanMembership {
     dimension: usize,
 
@@ -344,6 +403,29 @@ where
         .await
     }
 
+    /// Initialize a [`KMeans`] with kmeans++.
+    ///
+    /// Parameters
+    /// - *data*: training data.
+    /// - *k*: the number of clusters.
+    /// - *metric_type*: the metric type to calculate distance.
+    /// - *rng*: random generator.
+    pub async fn init_plusplus(
+        data: &MatrixView<T>,
+        k: usize,
+        metric_type: MetricType,
+        rng: impl Rng,
+    ) -> Result<Self> {
+        kmeans_plusplus_init(
+            data.data().as_ref(),
+            data.num_columns(),
+            k,
+            rng,
+            metric_type,
+        )
+        .await
+    }
+
     /// Train a KMeans model on data with `k` clusters.
     pub async fn new(data: &FixedSizeListArray, k: usize, max_iters: u32) -> Result<Self> {
         let params = KMeansParams {
@@ -394,8 +476,10 @@ where
         let mut best_kmeans = Self::empty(k, dimension, params.metric_type);
         let mut best_stddev = f32::MAX;
 
-        // TODO: use seed for Rng.
-        let rng = SmallRng::from_entropy();
+        let rng = match params.seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
         for redo in 1..=params.redos {
             let mut kmeans = if let Some(centroids) = params.centroids.as_ref() {
                 // Use existing centroids.
@@ -406,7 +490,7 @@ where
                         Self::init_random(&mat, k, params.metric_type, rng.clone()).await?
                     }
                     KMeanInit::KMeanPlusPlus => {
-                        unimplemented!()
+                        Self::init_plusplus(&mat, k, params.metric_type, rng.clone()).await?
                     }
                 }
             };
@@ -693,6 +777,30 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_kmeans_plusplus_init() {
+        const DIM: usize = 4;
+        const K: usize = 8;
+        // K tight clusters far away from each other.
+        let data = Float32Array::from_iter_values((0..K * 50).flat_map(|i| {
+            let cluster = (i % K) as f32 * 1000.0;
+            (0..DIM).map(move |d| cluster + ((i * DIM + d) % 7) as f32 * 0.01)
+        }));
+        let mat = MatrixView::<Float32Type>::new(Arc::new(data), DIM);
+        let kmeans = KMeans::init_plusplus(&mat, K, MetricType::L2, SmallRng::seed_from_u64(42))
+            .await
+            .unwrap();
+        // Each centroid should be picked from a different cluster.
+        let mut clusters = kmeans
+            .centroids
+            .values()
+            .chunks(DIM)
+            .map(|c| (c[0] / 1000.0).round() as usize)
+            .collect::<Vec<_>>();
+        clusters.sort();
+        assert_eq!(clusters, (0..K).collect::<Vec<_>>());
+    }
+
     #[tokio::test]
     async fn test_compute_partitions() {
         const DIM: usize = 256;
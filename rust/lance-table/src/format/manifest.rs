@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::ops::Range;
 use std::sync::Arc;
 
@@ -76,6 +77,9 @@ pub struct Manifest {
     /// The path to the transaction file, relative to the root of the dataset
     pub transaction_file: Option<String>,
 
+    /// Dataset level key-value configuration, carried over to new versions.
+    pub config: HashMap<String, String>,
+
     /// Precomputed logic offset of each fragment
     /// accelerating the fragment search using offset ranges.
     fragment_offsets: Vec<usize>,
@@ -110,6 +114,7 @@ impl Manifest {
             writer_feature_flags: 0,
             max_fragment_id: 0,
             transaction_file: None,
+            config: HashMap::new(),
             fragment_offsets,
         }
     }
@@ -134,6 +139,7 @@ impl Manifest {
             writer_feature_flags: 0, // These will be set on commit
             max_fragment_id: previous.max_fragment_id,
             transaction_file: None,
+            config: previous.config.clone(),
             fragment_offsets,
         }
     }
@@ -360,6 +366,7 @@ impl From<pb::Manifest> for Manifest {
             } else {
                 Some(p.transaction_file)
             },
+            config: p.config,
             fragment_offsets,
         }
     }
@@ -398,6 +405,7 @@ impl From<&Manifest> for pb::Manifest {
             writer_feature_flags: m.writer_feature_flags,
             max_fragment_id: m.max_fragment_id,
             transaction_file: m.transaction_file.clone().unwrap_or_default(),
+            config: m.config.clone(),
         }
     }
 }
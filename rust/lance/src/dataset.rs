@@ -67,7 +67,10 @@ mod write;
 
 use self::builder::DatasetBuilder;
 use self::cleanup::RemovalStats;
-use self::feature_flags::{apply_feature_flags, can_read_dataset, can_write_dataset};
+use self::feature_flags::{
+    apply_feature_flags_with_stable_row_ids, can_write_dataset, check_can_read_dataset,
+    FLAG_MOVE_STABLE_ROW_IDS,
+};
 use self::fragment::FileFragment;
 use self::scanner::{DatasetRecordBatchStream, Scanner};
 use self::transaction::{Operation, Transaction};
@@ -372,17 +375,7 @@ impl Dataset {
         let offset = read_metadata_offset(&bytes)?;
         let mut manifest: Manifest = read_struct(object_reader.as_ref(), offset).await?;
 
-        if !can_read_dataset(manifest.reader_feature_flags) {
-            let message = format!(
-                "This dataset cannot be read by this version of Lance. \
-                 Please upgrade Lance to read this dataset.\n Flags: {}",
-                manifest.reader_feature_flags
-            );
-            return Err(Error::NotSupported {
-                source: message.into(),
-                location: location!(),
-            });
-        }
+        check_can_read_dataset(manifest.reader_feature_flags)?;
 
         populate_schema_dictionary(&mut manifest.schema, object_reader.as_ref()).await?;
         Ok(Self {
@@ -504,7 +497,7 @@ impl Dataset {
                 &object_store,
                 commit_handler.as_ref(),
                 &transaction,
-                &Default::default(),
+                &ManifestWriteConfig::from(&params),
                 &Default::default(),
             )
             .await?
@@ -514,7 +507,7 @@ impl Dataset {
                 commit_handler.as_ref(),
                 &base,
                 &transaction,
-                &Default::default(),
+                &ManifestWriteConfig::from(&params),
             )
             .await?
         };
@@ -591,7 +584,7 @@ impl Dataset {
             &self.object_store,
             self.commit_handler.as_ref(),
             &transaction,
-            &Default::default(),
+            &ManifestWriteConfig::from(&params),
             &Default::default(),
         )
         .await?;
@@ -1936,6 +1929,11 @@ impl Dataset {
 pub(crate) struct ManifestWriteConfig {
     auto_set_feature_flags: bool,  // default true
     timestamp: Option<SystemTime>, // default None
+    /// Whether row ids are stable when rows are moved, see
+    /// [`feature_flags::FLAG_MOVE_STABLE_ROW_IDS`].
+    ///
+    /// `None` keeps the setting of the previous version of the dataset.
+    use_move_stable_row_ids: Option<bool>, // default None
 }
 
 impl Default for ManifestWriteConfig {
@@ -1943,6 +1941,16 @@ impl Default for ManifestWriteConfig {
         Self {
             auto_set_feature_flags: true,
             timestamp: None,
+            use_move_stable_row_ids: None,
+        }
+    }
+}
+
+impl From<&WriteParams> for ManifestWriteConfig {
+    fn from(params: &WriteParams) -> Self {
+        Self {
+            use_move_stable_row_ids: params.enable_move_stable_row_ids.then_some(true),
+            ..Default::default()
         }
     }
 }
@@ -1957,7 +1965,10 @@ pub(crate) async fn write_manifest_file(
     config: &ManifestWriteConfig,
 ) -> std::result::Result<(), CommitError> {
     if config.auto_set_feature_flags {
-        apply_feature_flags(manifest);
+        // The stable row id flag has been checked against the previous version when
+        // the manifest was built, keep it as is.
+        let stable_row_ids = manifest.reader_feature_flags & FLAG_MOVE_STABLE_ROW_IDS != 0;
+        apply_feature_flags_with_stable_row_ids(manifest, stable_row_ids);
     }
     manifest.set_timestamp(timestamp_to_nanos(config.timestamp));
 
@@ -2374,6 +2385,7 @@ mod tests {
             &ManifestWriteConfig {
                 auto_set_feature_flags: false,
                 timestamp: None,
+                use_move_stable_row_ids: None,
             },
         )
         .await
@@ -2403,6 +2415,56 @@ mod tests {
         assert!(matches!(write_result, Err(Error::NotSupported { .. })));
     }
 
+    #[tokio::test]
+    async fn test_write_with_stable_row_ids() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let schema = Arc::new(ArrowSchema::new(vec![Field::new(
+            "i",
+            DataType::Int32,
+            false,
+        )]));
+        let make_batches = || {
+            let batches = vec![RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int32Array::from_iter_values(0..20))],
+            )
+            .unwrap()];
+            RecordBatchIterator::new(batches.into_iter().map(Ok), schema.clone())
+        };
+
+        let write_params = WriteParams {
+            enable_move_stable_row_ids: true,
+            ..Default::default()
+        };
+        let mut dataset = Dataset::write(make_batches(), test_uri, Some(write_params))
+            .await
+            .unwrap();
+        assert_eq!(
+            dataset.manifest.reader_feature_flags,
+            feature_flags::FLAG_MOVE_STABLE_ROW_IDS
+        );
+        assert_eq!(
+            dataset.manifest.writer_feature_flags,
+            feature_flags::FLAG_MOVE_STABLE_ROW_IDS
+        );
+
+        // Appending with the default params keeps the flag.
+        dataset.append(make_batches(), None).await.unwrap();
+        assert_eq!(
+            dataset.manifest.reader_feature_flags,
+            feature_flags::FLAG_MOVE_STABLE_ROW_IDS
+        );
+
+        let dataset = Dataset::open(test_uri).await.unwrap();
+        assert_eq!(dataset.version().version, 2);
+        assert_eq!(
+            dataset.manifest.reader_feature_flags,
+            feature_flags::FLAG_MOVE_STABLE_ROW_IDS
+        );
+    }
+
     #[tokio::test]
     async fn append_dataset() {
         let test_dir = tempdir().unwrap();
@@ -14,9 +14,82 @@
 
 // Feature flags
 
+use std::collections::HashSet;
+
 use lance_table::format::Manifest;
+use snafu::{location, Location};
+
+use crate::{Error, Result};
 
 pub const FLAG_DELETION_FILES: u64 = 1;
+/// Row ids are stable when rows are moved, e.g., by compaction.
+///
+/// Once a dataset has been written with stable row ids, the stored row ids rely
+/// on it and the flag can never be cleared again.
+pub const FLAG_MOVE_STABLE_ROW_IDS: u64 = 2;
+/// Some columns are encrypted, see [`ENCRYPTED_COLUMNS_KEY`].
+///
+/// Readers that can not decrypt them must refuse the dataset rather than return
+/// the ciphertext as if it were data.
+pub const FLAG_COLUMN_ENCRYPTION: u64 = 4;
+/// The first flag bit not assigned yet, every bit from here on is unknown.
+pub const FLAG_UNKNOWN: u64 = 8;
+
+/// The flags this version of Lance can read and write datasets with.
+const SUPPORTED_FLAGS: u64 = FLAG_DELETION_FILES | FLAG_MOVE_STABLE_ROW_IDS;
+
+/// Key in the manifest config listing the encrypted columns, as comma-separated names.
+pub const ENCRYPTED_COLUMNS_KEY: &str = "lance.encrypted_columns";
+
+/// Key in the manifest config recording the minimum Lance version able to read the dataset.
+///
+/// It is informational only, [`can_read_dataset`] on the reader feature flags is what
+/// actually decides whether a dataset can be read.
+pub const MIN_READER_VERSION_KEY: &str = "lance.min_reader_version";
+
+/// The first Lance release able to read each reader feature flag, in ascending order.
+///
+/// - Deletion files are honored by readers since 0.5.0, older readers return the deleted
+///   rows, see the "Deleting rows" section of `docs/read_and_write.rst`.
+/// - Stable row ids are accepted by readers since 0.10.5, the release that introduced them.
+///
+/// [`FLAG_COLUMN_ENCRYPTION`] has no entry, no release can read it yet.
+const FLAG_MIN_READER_VERSIONS: &[(u64, &str)] = &[
+    (FLAG_DELETION_FILES, "0.5.0"),
+    (FLAG_MOVE_STABLE_ROW_IDS, "0.10.5"),
+];
+
+/// The minimum Lance version required to read a dataset with the given reader flags.
+///
+/// Returns `None` if no flag requiring a specific version is set.
+pub fn min_reader_version(reader_flags: u64) -> Option<&'static str> {
+    FLAG_MIN_READER_VERSIONS
+        .iter()
+        .rev()
+        .find(|(flag, _)| reader_flags & flag != 0)
+        .map(|(_, version)| *version)
+}
+
+/// Record [`min_reader_version`] of the manifest's reader flags in its config.
+fn apply_min_reader_version(manifest: &mut Manifest) {
+    match min_reader_version(manifest.reader_feature_flags) {
+        Some(version) => {
+            manifest
+                .config
+                .insert(MIN_READER_VERSION_KEY.to_string(), version.to_string());
+        }
+        None => {
+            manifest.config.remove(MIN_READER_VERSION_KEY);
+        }
+    }
+}
+
+fn has_encrypted_columns(manifest: &Manifest) -> bool {
+    manifest
+        .config
+        .get(ENCRYPTED_COLUMNS_KEY)
+        .is_some_and(|columns| columns.split(',').any(|column| !column.trim().is_empty()))
+}
 
 /// Set the reader and writer feature flags in the manifest based on the contents of the manifest.
 pub fn apply_feature_flags(manifest: &mut Manifest) {
@@ -33,31 +106,333 @@ pub fn apply_feature_flags(manifest: &mut Manifest) {
         manifest.reader_feature_flags |= FLAG_DELETION_FILES;
         manifest.writer_feature_flags |= FLAG_DELETION_FILES;
     }
+    if has_encrypted_columns(manifest) {
+        manifest.reader_feature_flags |= FLAG_COLUMN_ENCRYPTION;
+        manifest.writer_feature_flags |= FLAG_COLUMN_ENCRYPTION;
+    }
+    apply_min_reader_version(manifest);
+}
+
+/// Set the feature flags like [`apply_feature_flags`], and also set
+/// [`FLAG_MOVE_STABLE_ROW_IDS`] if `enable_stable_row_id` is true.
+pub fn apply_feature_flags_with_stable_row_ids(
+    manifest: &mut Manifest,
+    enable_stable_row_id: bool,
+) {
+    apply_feature_flags(manifest);
+    if enable_stable_row_id {
+        manifest.reader_feature_flags |= FLAG_MOVE_STABLE_ROW_IDS;
+        manifest.writer_feature_flags |= FLAG_MOVE_STABLE_ROW_IDS;
+        apply_min_reader_version(manifest);
+    }
+}
+
+/// Whether `manifest` still holds fragments whose row ids are stable.
+///
+/// Those are the fragments kept from `previous`, i.e., sharing a data file with it,
+/// when `previous` was written with [`FLAG_MOVE_STABLE_ROW_IDS`].
+fn has_stable_row_ids(manifest: &Manifest, previous: &Manifest) -> bool {
+    if previous.reader_feature_flags & FLAG_MOVE_STABLE_ROW_IDS == 0 {
+        return false;
+    }
+    let previous_files = previous
+        .fragments
+        .iter()
+        .flat_map(|frag| frag.files.iter().map(|file| file.path.as_str()))
+        .collect::<HashSet<_>>();
+    manifest
+        .fragments
+        .iter()
+        .flat_map(|frag| frag.files.iter())
+        .any(|file| previous_files.contains(file.path.as_str()))
+}
+
+/// Set the feature flags like [`apply_feature_flags_with_stable_row_ids`], but
+/// refuse to clear a flag that the existing data still depends on, or to set a
+/// flag this version of Lance can not honor.
+///
+/// `previous` is the previous version of the dataset, if any. Clearing
+/// [`FLAG_MOVE_STABLE_ROW_IDS`] while fragments written with it are still part of
+/// the dataset would silently change the meaning of their row ids, so it returns
+/// an error instead. Clearing it once those fragments are gone, e.g., by an
+/// overwrite, is fine.
+///
+/// Setting [`ENCRYPTED_COLUMNS_KEY`] is also an error, this version of Lance
+/// would commit a dataset with [`FLAG_COLUMN_ENCRYPTION`] that it then refuses
+/// to read or write.
+pub fn apply_feature_flags_checked(
+    manifest: &mut Manifest,
+    enable_stable_row_id: bool,
+    previous: Option<&Manifest>,
+) -> Result<()> {
+    if !enable_stable_row_id && previous.is_some_and(|prev| has_stable_row_ids(manifest, prev)) {
+        return Err(Error::NotSupported {
+            source: "Can not disable stable row ids on a dataset that already uses them".into(),
+            location: location!(),
+        });
+    }
+
+    if has_encrypted_columns(manifest) {
+        return Err(Error::NotSupported {
+            source: format!(
+                "Column encryption is not supported by this version of Lance, remove \"{}\" from the dataset config",
+                ENCRYPTED_COLUMNS_KEY
+            )
+            .into(),
+            location: location!(),
+        });
+    }
+
+    apply_feature_flags_with_stable_row_ids(manifest, enable_stable_row_id);
+    Ok(())
 }
 
 pub fn can_read_dataset(reader_flags: u64) -> bool {
-    reader_flags <= 1
+    reader_flags & !SUPPORTED_FLAGS == 0
+}
+
+/// Like [`can_read_dataset`], but returns an error naming the unsupported flag bits.
+pub fn check_can_read_dataset(reader_flags: u64) -> Result<()> {
+    if can_read_dataset(reader_flags) {
+        return Ok(());
+    }
+    let unsupported = reader_flags & !SUPPORTED_FLAGS;
+    let bits = (0..u64::BITS)
+        .filter(|bit| unsupported & (1 << bit) != 0)
+        .map(|bit| format!("{} (bit {})", 1_u64 << bit, bit))
+        .collect::<Vec<_>>();
+    Err(Error::NotSupported {
+        source: format!(
+            "This dataset cannot be read by this version of Lance, unsupported reader feature flags: {}. \
+            Please upgrade Lance to read this dataset.",
+            bits.join(", ")
+        )
+        .into(),
+        location: location!(),
+    })
 }
 
 pub fn can_write_dataset(writer_flags: u64) -> bool {
-    writer_flags <= 1
+    writer_flags & !SUPPORTED_FLAGS == 0
+}
+
+/// The names of the known feature flags.
+const FLAG_NAMES: &[(u64, &str)] = &[
+    (FLAG_DELETION_FILES, "deletion_files"),
+    (FLAG_MOVE_STABLE_ROW_IDS, "move_stable_row_ids"),
+    (FLAG_COLUMN_ENCRYPTION, "column_encryption"),
+];
+
+/// Human-readable names of the known flags set in `flags`, in bit order.
+///
+/// Known flags are named after their constant, e.g., "deletion_files". Set bits
+/// without a name are left out, see [`unknown_flags`].
+pub fn describe_flags(flags: u64) -> Vec<&'static str> {
+    FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// The bit positions set in `flags` that are not a known flag, in ascending order.
+pub fn unknown_flags(flags: u64) -> Vec<u32> {
+    let known = FLAG_NAMES.iter().fold(0, |acc, (flag, _)| acc | flag);
+    let unknown = flags & !known;
+    (0..u64::BITS)
+        .filter(|bit| unknown & (1 << bit) != 0)
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::sync::Arc;
+
+    use arrow_schema::{DataType, Field as ArrowField, Schema as ArrowSchema};
+    use lance_core::datatypes::Schema;
+    use lance_table::format::{DeletionFile, DeletionFileType, Fragment};
+
     #[test]
     fn test_read_check() {
         assert!(can_read_dataset(0));
         assert!(can_read_dataset(super::FLAG_DELETION_FILES));
-        assert!(!can_read_dataset(super::FLAG_DELETION_FILES + 1));
+        assert!(can_read_dataset(super::FLAG_MOVE_STABLE_ROW_IDS));
+        assert!(can_read_dataset(
+            super::FLAG_DELETION_FILES | super::FLAG_MOVE_STABLE_ROW_IDS
+        ));
+        assert!(!can_read_dataset(super::FLAG_UNKNOWN));
+    }
+
+    #[test]
+    fn test_describe_flags() {
+        assert!(describe_flags(0).is_empty());
+        assert_eq!(
+            describe_flags(FLAG_DELETION_FILES | FLAG_COLUMN_ENCRYPTION),
+            vec!["deletion_files", "column_encryption"]
+        );
+        let flags = FLAG_MOVE_STABLE_ROW_IDS | FLAG_UNKNOWN | (1 << 63);
+        assert_eq!(describe_flags(flags), vec!["move_stable_row_ids"]);
+        assert_eq!(unknown_flags(flags), vec![3, 63]);
+        assert!(unknown_flags(FLAG_DELETION_FILES | FLAG_COLUMN_ENCRYPTION).is_empty());
+    }
+
+    #[test]
+    fn test_column_encryption_flag() {
+        assert!(!can_read_dataset(FLAG_COLUMN_ENCRYPTION));
+        assert!(!can_read_dataset(
+            FLAG_DELETION_FILES | FLAG_COLUMN_ENCRYPTION
+        ));
+        assert!(!can_write_dataset(FLAG_COLUMN_ENCRYPTION));
+        assert!(!can_read_dataset(FLAG_UNKNOWN));
+        assert!(FLAG_COLUMN_ENCRYPTION < FLAG_UNKNOWN);
+
+        let schema = Schema::try_from(&ArrowSchema::new(vec![ArrowField::new(
+            "a",
+            DataType::Int32,
+            false,
+        )]))
+        .unwrap();
+        let mut manifest = Manifest::new(schema, Arc::new(vec![]));
+        apply_feature_flags(&mut manifest);
+        assert_eq!(manifest.reader_feature_flags, 0);
+
+        manifest
+            .config
+            .insert(ENCRYPTED_COLUMNS_KEY.to_string(), String::new());
+        apply_feature_flags(&mut manifest);
+        assert_eq!(manifest.reader_feature_flags, 0);
+
+        manifest
+            .config
+            .insert(ENCRYPTED_COLUMNS_KEY.to_string(), "a".to_string());
+        apply_feature_flags(&mut manifest);
+        assert_eq!(manifest.reader_feature_flags, FLAG_COLUMN_ENCRYPTION);
+        assert_eq!(manifest.writer_feature_flags, FLAG_COLUMN_ENCRYPTION);
+        assert!(check_can_read_dataset(manifest.reader_feature_flags).is_err());
+
+        // The commit path refuses to write a dataset it could not read back.
+        let err = apply_feature_flags_checked(&mut manifest, false, None).unwrap_err();
+        assert!(matches!(err, Error::NotSupported { .. }));
+        assert!(err.to_string().contains(ENCRYPTED_COLUMNS_KEY), "{}", err);
+
+        manifest
+            .config
+            .insert(ENCRYPTED_COLUMNS_KEY.to_string(), " ".to_string());
+        apply_feature_flags_checked(&mut manifest, false, None).unwrap();
+        assert_eq!(manifest.reader_feature_flags, 0);
+    }
+
+    #[test]
+    fn test_check_can_read_dataset() {
+        check_can_read_dataset(0).unwrap();
+        check_can_read_dataset(FLAG_DELETION_FILES).unwrap();
+
+        check_can_read_dataset(FLAG_DELETION_FILES | FLAG_MOVE_STABLE_ROW_IDS).unwrap();
+
+        let err = check_can_read_dataset(FLAG_DELETION_FILES | FLAG_MOVE_STABLE_ROW_IDS | 4 | 8)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("4 (bit 2), 8 (bit 3)"), "{}", err);
+        assert!(!err.contains("bit 0"), "{}", err);
+        assert!(!err.contains("bit 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_stable_row_id_flag_regression() {
+        let schema = Schema::try_from(&ArrowSchema::new(vec![ArrowField::new(
+            "a",
+            DataType::Int32,
+            false,
+        )]))
+        .unwrap();
+        let fragments = vec![Fragment::with_file(0, "data/a.lance", &schema, Some(10))];
+        let mut previous = Manifest::new(schema.clone(), Arc::new(fragments.clone()));
+
+        // Enabling is always fine.
+        apply_feature_flags_checked(&mut previous, true, None).unwrap();
+        assert_eq!(previous.reader_feature_flags, FLAG_MOVE_STABLE_ROW_IDS);
+        assert_eq!(previous.writer_feature_flags, FLAG_MOVE_STABLE_ROW_IDS);
+
+        // Keeping the flag is fine.
+        let mut appended = fragments.clone();
+        appended.push(Fragment::with_file(1, "data/b.lance", &schema, Some(10)));
+        let mut manifest =
+            Manifest::new_from_previous(&previous, schema.clone(), Arc::new(appended));
+        apply_feature_flags_checked(&mut manifest, true, Some(&previous)).unwrap();
+        assert_eq!(manifest.reader_feature_flags, FLAG_MOVE_STABLE_ROW_IDS);
+
+        // Dropping it while the old fragments are still there would corrupt their row ids.
+        let err = apply_feature_flags_checked(&mut manifest, false, Some(&previous)).unwrap_err();
+        assert!(matches!(err, Error::NotSupported { .. }));
+        assert_eq!(manifest.reader_feature_flags, FLAG_MOVE_STABLE_ROW_IDS);
+
+        // Once the old fragments are gone, e.g., overwritten, there is nothing to protect.
+        let overwritten = vec![Fragment::with_file(0, "data/c.lance", &schema, Some(10))];
+        let mut manifest =
+            Manifest::new_from_previous(&previous, schema.clone(), Arc::new(overwritten));
+        apply_feature_flags_checked(&mut manifest, false, Some(&previous)).unwrap();
+        assert_eq!(manifest.reader_feature_flags, 0);
+
+        // Datasets without stable row ids are not affected.
+        let mut plain = Manifest::new(schema.clone(), Arc::new(fragments.clone()));
+        apply_feature_flags(&mut plain);
+        let mut manifest = Manifest::new_from_previous(&plain, schema, Arc::new(fragments));
+        apply_feature_flags_checked(&mut manifest, false, Some(&plain)).unwrap();
+        assert_eq!(manifest.reader_feature_flags, 0);
+    }
+
+    #[test]
+    fn test_min_reader_version() {
+        assert_eq!(min_reader_version(0), None);
+        assert_eq!(min_reader_version(FLAG_DELETION_FILES), Some("0.5.0"));
+        assert_eq!(min_reader_version(FLAG_MOVE_STABLE_ROW_IDS), Some("0.10.5"));
+        assert_eq!(
+            min_reader_version(FLAG_DELETION_FILES | FLAG_MOVE_STABLE_ROW_IDS),
+            Some("0.10.5")
+        );
+        assert_eq!(min_reader_version(FLAG_COLUMN_ENCRYPTION), None);
+
+        let schema = Schema::try_from(&ArrowSchema::new(vec![ArrowField::new(
+            "a",
+            DataType::Int32,
+            false,
+        )]))
+        .unwrap();
+        let mut fragment = Fragment::with_file(0, "data/a.lance", &schema, Some(10));
+        fragment.deletion_file = Some(DeletionFile {
+            read_version: 1,
+            id: 0,
+            file_type: DeletionFileType::Array,
+            num_deleted_rows: Some(1),
+        });
+        let mut manifest = Manifest::new(schema, Arc::new(vec![fragment]));
+        let min_version = |manifest: &Manifest| {
+            manifest
+                .config
+                .get(MIN_READER_VERSION_KEY)
+                .map(String::to_string)
+        };
+        apply_feature_flags(&mut manifest);
+        assert_eq!(min_version(&manifest).as_deref(), Some("0.5.0"));
+        apply_feature_flags_with_stable_row_ids(&mut manifest, true);
+        assert_eq!(min_version(&manifest).as_deref(), Some("0.10.5"));
+
+        // Stable row ids alone also need a reader that accepts them.
+        manifest.fragments = Arc::new(vec![]);
+        apply_feature_flags_with_stable_row_ids(&mut manifest, true);
+        assert_eq!(min_version(&manifest).as_deref(), Some("0.10.5"));
+        apply_feature_flags(&mut manifest);
+        assert_eq!(min_version(&manifest), None);
     }
 
     #[test]
     fn test_write_check() {
         assert!(can_write_dataset(0));
         assert!(can_write_dataset(super::FLAG_DELETION_FILES));
-        assert!(!can_write_dataset(super::FLAG_DELETION_FILES + 1));
+        assert!(can_write_dataset(super::FLAG_MOVE_STABLE_ROW_IDS));
+        assert!(!can_write_dataset(super::FLAG_COLUMN_ENCRYPTION));
+        assert!(!can_write_dataset(super::FLAG_UNKNOWN));
     }
 }
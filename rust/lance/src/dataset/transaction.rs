@@ -66,7 +66,10 @@ use roaring::RoaringBitmap;
 use snafu::{location, Location};
 use uuid::Uuid;
 
-use super::{feature_flags::apply_feature_flags, ManifestWriteConfig};
+use super::{
+    feature_flags::{apply_feature_flags_checked, FLAG_MOVE_STABLE_ROW_IDS},
+    ManifestWriteConfig,
+};
 use crate::utils::temporal::timestamp_to_nanos;
 
 /// A change to a dataset that can be retried
@@ -518,7 +521,11 @@ impl Transaction {
         manifest.tag = self.tag.clone();
 
         if config.auto_set_feature_flags {
-            apply_feature_flags(&mut manifest);
+            let use_move_stable_row_ids = config.use_move_stable_row_ids.unwrap_or_else(|| {
+                current_manifest
+                    .is_some_and(|m| m.reader_feature_flags & FLAG_MOVE_STABLE_ROW_IDS != 0)
+            });
+            apply_feature_flags_checked(&mut manifest, use_move_stable_row_ids, current_manifest)?;
         }
         manifest.set_timestamp(timestamp_to_nanos(config.timestamp));
 
@@ -1147,4 +1154,56 @@ mod tests {
 
         assert_eq!(final_fragments, expected_fragments);
     }
+
+    #[test]
+    fn test_build_manifest_keeps_stable_row_ids() {
+        use arrow_schema::{DataType, Field as ArrowField, Schema as ArrowSchema};
+
+        let schema = Schema::try_from(&ArrowSchema::new(vec![ArrowField::new(
+            "a",
+            DataType::Int32,
+            false,
+        )]))
+        .unwrap();
+        let existing = vec![Fragment::with_file(0, "data/a.lance", &schema, Some(10))];
+        let mut current = Manifest::new(schema.clone(), Arc::new(existing));
+        current.reader_feature_flags = FLAG_MOVE_STABLE_ROW_IDS;
+        current.writer_feature_flags = FLAG_MOVE_STABLE_ROW_IDS;
+
+        let append = Transaction::new(
+            1,
+            Operation::Append {
+                fragments: vec![Fragment::with_file(0, "data/b.lance", &schema, Some(10))],
+            },
+            None,
+        );
+        let (manifest, _) = append
+            .build_manifest(Some(&current), vec![], "txn", &Default::default())
+            .unwrap();
+        assert_eq!(manifest.reader_feature_flags, FLAG_MOVE_STABLE_ROW_IDS);
+        assert_eq!(manifest.writer_feature_flags, FLAG_MOVE_STABLE_ROW_IDS);
+
+        let disable = ManifestWriteConfig {
+            use_move_stable_row_ids: Some(false),
+            ..Default::default()
+        };
+        let err = append
+            .build_manifest(Some(&current), vec![], "txn", &disable)
+            .unwrap_err();
+        assert!(matches!(err, Error::NotSupported { .. }));
+
+        // An overwrite drops every fragment with stable row ids.
+        let overwrite = Transaction::new(
+            1,
+            Operation::Overwrite {
+                schema: schema.clone(),
+                fragments: vec![Fragment::with_file(0, "data/c.lance", &schema, Some(10))],
+            },
+            None,
+        );
+        let (manifest, _) = overwrite
+            .build_manifest(Some(&current), vec![], "txn", &disable)
+            .unwrap();
+        assert_eq!(manifest.reader_feature_flags, 0);
+    }
 }
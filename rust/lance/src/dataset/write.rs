@@ -103,6 +103,12 @@ pub struct WriteParams {
     /// If a custom object store is provided (via store_params.object_store) then this
     /// must also be provided.
     pub commit_handler: Option<Arc<dyn CommitHandler>>,
+
+    /// Keep row ids stable when rows are moved, e.g. by compaction.
+    ///
+    /// Once a dataset has stable row ids they stay enabled, so leaving this
+    /// `false` keeps whatever the existing dataset uses.
+    pub enable_move_stable_row_ids: bool,
 }
 
 impl Default for WriteParams {
@@ -117,6 +123,7 @@ impl Default for WriteParams {
             store_params: None,
             progress: Arc::new(NoopFragmentWriteProgress::new()),
             commit_handler: None,
+            enable_move_stable_row_ids: false,
         }
     }
 }
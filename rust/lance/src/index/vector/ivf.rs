@@ -70,6 +70,7 @@ use lance_io::{
 use lance_linalg::kernels::{normalize_arrow, normalize_fsl};
 use lance_linalg::{
     distance::{Cosine, DistanceType, Dot, MetricType, L2},
+    kmeans::KMeanInit,
     MatrixView,
 };
 use log::{debug, info};
@@ -1226,7 +1227,7 @@ async fn write_ivf_hnsw_file(
             PQ_CODE_COLUMN,
             DataType::FixedSizeList(
                 Arc::new(arrow_schema::Field::new("item", DataType::UInt8, true)),
-                pq.num_sub_vectors() as i32,
+                pq.code_length() as i32,
             ),
             false,
         ),
@@ -1265,6 +1266,7 @@ async fn write_ivf_hnsw_file(
             num_bits: pq.num_bits(),
             num_sub_vectors: pq.num_sub_vectors(),
             dimension: pq.dimension(),
+            training_info: pq.training_info().cloned(),
         })
         .to_string()
         .as_str(),
@@ -1328,6 +1330,7 @@ async fn do_train_ivf_model<T: ArrowFloatType + Dot + Cosine + L2 + 'static>(
         rng,
         metric_type,
         params.sample_rate,
+        KMeanInit::Random,
     )
     .await?;
     Ok(Ivf::new(Arc::new(FixedSizeListArray::try_new_from_values(
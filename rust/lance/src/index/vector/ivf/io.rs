@@ -189,13 +189,10 @@ pub(super) async fn write_pq_partitions(
                             location: location!(),
                         })?;
                 if let Some(pq_code) = pq_index.code.as_ref() {
-                    let fsl = Arc::new(
-                        FixedSizeListArray::try_new_from_values(
-                            pq_code.as_ref().clone(),
-                            pq_index.pq.num_sub_vectors() as i32,
-                        )
-                        .unwrap(),
-                    );
+                    let fsl = Arc::new(FixedSizeListArray::try_new_from_values(
+                        pq_code.as_ref().clone(),
+                        pq_index.pq.code_length() as i32,
+                    )?);
                     pq_array.push(fsl);
                     row_id_array.push(pq_index.row_ids.as_ref().unwrap().clone());
                 }
@@ -39,7 +39,6 @@ use tracing::{instrument, span, Level};
 
 // Re-export
 pub use lance_index::vector::pq::{PQBuildParams, ProductQuantizerImpl};
-use lance_linalg::kernels::normalize_fsl;
 
 use super::ivf::Ivf;
 use super::VectorIndex;
@@ -93,7 +92,7 @@ impl PQIndex {
         pre_filter: &PreFilter,
         code: Arc<UInt8Array>,
         row_ids: Arc<UInt64Array>,
-        num_sub_vectors: i32,
+        code_length: i32,
     ) -> Result<(Arc<UInt8Array>, Arc<UInt64Array>)> {
         let indices_to_keep = pre_filter.filter_row_ids(row_ids.values());
         let indices_to_keep = UInt64Array::from(indices_to_keep);
@@ -101,8 +100,7 @@ impl PQIndex {
         let row_ids = take(row_ids.as_ref(), &indices_to_keep, None)?;
         let row_ids = Arc::new(as_primitive_array(&row_ids).clone());
 
-        let code = FixedSizeListArray::try_new_from_values(code.as_ref().clone(), num_sub_vectors)
-            .unwrap();
+        let code = FixedSizeListArray::try_new_from_values(code.as_ref().clone(), code_length)?;
         let code = take(&code, &indices_to_keep, None)?;
         let code = as_fixed_size_list_array(&code).values().clone();
         let code = Arc::new(as_primitive_array(&code).clone());
@@ -173,12 +171,12 @@ impl VectorIndex for PQIndex {
 
         let pq = self.pq.clone();
         let query = query.clone();
-        let num_sub_vectors = self.pq.num_sub_vectors() as i32;
+        let code_length = self.pq.code_length() as i32;
         spawn_cpu(move || {
             let (code, row_ids) = if pre_filter.is_empty() {
                 Ok((code, row_ids))
             } else {
-                Self::filter_arrays(pre_filter.as_ref(), code, row_ids, num_sub_vectors)
+                Self::filter_arrays(pre_filter.as_ref(), code, row_ids, code_length)
             }?;
 
             // Pre-compute distance table for each sub-vector.
@@ -215,7 +213,7 @@ impl VectorIndex for PQIndex {
         offset: usize,
         length: usize,
     ) -> Result<Box<dyn VectorIndex>> {
-        let pq_code_length = self.pq.num_sub_vectors() * length;
+        let pq_code_length = self.pq.code_length() * length;
         let pq_code = read_fixed_stride_array(
             reader.as_ref(),
             &DataType::UInt8,
@@ -311,21 +309,21 @@ pub(super) async fn build_pq_model(
                 dim,
                 Arc::new(codebook.as_primitive().clone()),
                 mt,
-            ))),
+            )?)),
             DataType::Float32 => Ok(Arc::new(ProductQuantizerImpl::<Float32Type>::new(
                 params.num_sub_vectors,
                 params.num_bits as u32,
                 dim,
                 Arc::new(codebook.as_primitive().clone()),
                 mt,
-            ))),
+            )?)),
             DataType::Float64 => Ok(Arc::new(ProductQuantizerImpl::<Float64Type>::new(
                 params.num_sub_vectors,
                 params.num_bits as u32,
                 dim,
                 Arc::new(codebook.as_primitive().clone()),
                 mt,
-            ))),
+            )?)),
             _ => {
                 return Err(Error::Index {
                     message: format!("Wrong codebook data type: {:?}", codebook.data_type()),
@@ -345,8 +343,7 @@ pub(super) async fn build_pq_model(
         expected_sample_size
     );
     let start = std::time::Instant::now();
-    let mut training_data =
-        maybe_sample_training_data(dataset, column, expected_sample_size).await?;
+    let training_data = maybe_sample_training_data(dataset, column, expected_sample_size).await?;
     info!(
         "Finished loading training data in {:02} seconds",
         start.elapsed().as_secs_f32()
@@ -357,30 +354,16 @@ pub(super) async fn build_pq_model(
         training_data.value_length()
     );
 
-    if metric_type == MetricType::Cosine {
-        info!("Normalize training data for PQ training: Cosine");
-        training_data = normalize_fsl(&training_data)?;
-    }
-
-    let training_data = if let Some(ivf) = ivf {
-        // Compute residual for PQ training.
-        //
-        // TODO: consolidate IVF models to `lance_index`.
-        let ivf2 = lance_index::vector::ivf::new_ivf(
-            ivf.centroids.values(),
-            ivf.dimension(),
-            MetricType::L2,
-            vec![],
-            None,
-        )?;
-        span!(Level::INFO, "compute residual for PQ training")
-            .in_scope(|| ivf2.compute_residual(&training_data, None))
-            .await?
-    } else {
-        training_data
-    };
     info!("Start train PQ: params={:#?}", params);
-    let pq = params.build(&training_data, MetricType::L2).await?;
+    let pq = span!(Level::INFO, "train PQ with residuals")
+        .in_scope(|| {
+            params.build_with_ivf(
+                &training_data,
+                ivf.map(|ivf| ivf.centroids.as_ref()),
+                metric_type,
+            )
+        })
+        .await?;
     info!("Trained PQ in: {} seconds", start.elapsed().as_secs_f32());
     Ok(pq)
 }
@@ -392,6 +375,7 @@ mod tests {
     use arrow_array::RecordBatchIterator;
     use arrow_schema::{Field, Schema};
     use lance_index::vector::ivf::IvfBuildParams;
+    use lance_linalg::kernels::normalize_fsl;
     use lance_testing::datagen::generate_random_array_with_range;
     use std::ops::Range;
     use tempfile::tempdir;
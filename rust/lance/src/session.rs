@@ -73,13 +73,16 @@ mod tests {
         assert!(no_cache.index_cache.get_vector("abc").is_none());
         let no_cache = Arc::new(no_cache);
 
-        let pq = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
-            1,
-            8,
-            1,
-            Arc::new(vec![0.0f32; 8].into()),
-            MetricType::L2,
-        ));
+        let pq = Arc::new(
+            ProductQuantizerImpl::<Float32Type>::new(
+                1,
+                8,
+                1,
+                Arc::new(vec![0.0f32; 256].into()),
+                MetricType::L2,
+            )
+            .unwrap(),
+        );
         let idx = Arc::new(PQIndex::new(pq, MetricType::L2));
         no_cache.index_cache.insert_vector("abc", idx);
 
@@ -92,13 +95,16 @@ mod tests {
         let session = Session::new(10, 1);
         let session = Arc::new(session);
 
-        let pq = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
-            1,
-            8,
-            1,
-            Arc::new(vec![0.0f32; 8].into()),
-            MetricType::L2,
-        ));
+        let pq = Arc::new(
+            ProductQuantizerImpl::<Float32Type>::new(
+                1,
+                8,
+                1,
+                Arc::new(vec![0.0f32; 256].into()),
+                MetricType::L2,
+            )
+            .unwrap(),
+        );
         let idx = Arc::new(PQIndex::new(pq, MetricType::L2));
         assert_eq!(session.index_cache.get_size(), 0);
 
@@ -116,13 +122,16 @@ mod tests {
         assert_eq!(session.index_cache.get_size(), 1);
 
         for iter_idx in 0..100 {
-            let pq_other = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
-                1,
-                8,
-                1,
-                Arc::new(vec![0.0f32; 8].into()),
-                MetricType::L2,
-            ));
+            let pq_other = Arc::new(
+                ProductQuantizerImpl::<Float32Type>::new(
+                    1,
+                    8,
+                    1,
+                    Arc::new(vec![0.0f32; 256].into()),
+                    MetricType::L2,
+                )
+                .unwrap(),
+            );
             let idx_other = Arc::new(PQIndex::new(pq_other, MetricType::L2));
             session
                 .index_cache